@@ -0,0 +1,65 @@
+// Cache hit-path benchmark
+// Compares a cache hit (shared message) against cloning the full Message,
+// which is what the hit path used to do.
+// Run with: cargo bench --bench cache
+
+use hickory_proto::op::{Message, MessageType, ResponseCode};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use leshy::dns::cache::DnsCache;
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 200_000;
+
+fn make_response(answers: u8) -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response);
+    msg.set_response_code(ResponseCode::NoError);
+    for i in 0..answers {
+        msg.add_answer(Record::from_rdata(
+            Name::from_str("www.example.com.").unwrap(),
+            300,
+            RData::A(hickory_proto::rr::rdata::A(Ipv4Addr::new(10, 0, 0, i))),
+        ));
+    }
+    msg
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let per_op = elapsed.as_nanos() / u128::from(ITERATIONS);
+    println!("{name:<36} {per_op:>8} ns/op");
+}
+
+fn main() {
+    for answers in [1u8, 8, 32] {
+        let msg = make_response(answers);
+        let cache = DnsCache::new(1000);
+        cache.insert(
+            "www.example.com.",
+            RecordType::A,
+            None,
+            msg.clone(),
+            Duration::from_secs(3600),
+        );
+
+        let start = Instant::now();
+        for i in 0..ITERATIONS {
+            let hit = cache.lookup("www.example.com.", RecordType::A).unwrap();
+            let mut header = *hit.message.header();
+            header.set_id(i as u16);
+            black_box((hit, header));
+        }
+        report(&format!("cache hit ({answers} answers)"), start.elapsed());
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            black_box(msg.clone());
+        }
+        report(
+            &format!("Message::clone ({answers} answers)"),
+            start.elapsed(),
+        );
+    }
+}
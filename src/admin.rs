@@ -0,0 +1,745 @@
+use crate::config::{Config, DnsProtocol};
+use crate::dns::cache::name_pattern;
+use crate::dns::SharedHandler;
+use crate::error::LeshyError;
+use crate::reload::ReloadHistory;
+use crate::zones::ZoneMatcher;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Largest request head read; request bodies are ignored
+const MAX_REQUEST: usize = 8192;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the admin API listens, from `server.admin_listen`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminListen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl AdminListen {
+    /// `host:port` is a TCP address; anything with a `/` a Unix socket path.
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        if let Ok(addr) = value.parse() {
+            return Ok(Self::Tcp(addr));
+        }
+        if value.contains('/') {
+            return Ok(Self::Unix(PathBuf::from(value)));
+        }
+        anyhow::bail!("invalid admin_listen '{value}' (expected ip:port or a Unix socket path)")
+    }
+}
+
+impl std::fmt::Display for AdminListen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// JSON-over-HTTP control API: read runtime state (zones, routes, cache,
+/// upstreams) and act on it (flush the cache, disable or enable a zone,
+/// reload the config). One request per connection.
+pub struct AdminApi {
+    handler: SharedHandler,
+    config_path: PathBuf,
+    /// Where forced reloads go, the same channel the config watcher uses
+    reload_tx: mpsc::UnboundedSender<Config>,
+    history: ReloadHistory,
+    /// Profile applied to reloaded configs (`--profile`)
+    profile: Option<String>,
+    started: Instant,
+}
+
+impl AdminApi {
+    pub fn new(
+        handler: SharedHandler,
+        config_path: PathBuf,
+        reload_tx: mpsc::UnboundedSender<Config>,
+        history: ReloadHistory,
+    ) -> Self {
+        Self {
+            handler,
+            config_path,
+            reload_tx,
+            history,
+            profile: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Apply `profile` to configs reloaded through `/reload`, as at startup.
+    pub fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Bind `listen` and serve requests in the background. A stale Unix
+    /// socket left by a previous run is replaced; the new one is only
+    /// accessible to its owner.
+    pub async fn spawn(self, listen: &AdminListen) -> anyhow::Result<JoinHandle<()>> {
+        let api = Arc::new(self);
+        match listen {
+            AdminListen::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("failed to bind admin API on {addr}: {e}"))?;
+                if !addr.ip().is_loopback() {
+                    tracing::warn!(addr = %addr, "Admin API is reachable from the network");
+                }
+                tracing::info!(addr = %addr, "Admin API listening");
+                Ok(tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => api.clone().spawn_connection(stream),
+                            Err(e) => tracing::warn!(error = %e, "Admin API accept failed"),
+                        }
+                    }
+                }))
+            }
+            AdminListen::Unix(path) => {
+                if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path).map_err(|e| {
+                    anyhow::anyhow!("failed to bind admin API on '{}': {e}", path.display())
+                })?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                tracing::info!(path = %path.display(), "Admin API listening");
+                Ok(tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => api.clone().spawn_connection(stream),
+                            Err(e) => tracing::warn!(error = %e, "Admin API accept failed"),
+                        }
+                    }
+                }))
+            }
+        }
+    }
+
+    fn spawn_connection<S>(self: Arc<Self>, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            if let Err(e) = self.serve_connection(stream).await {
+                tracing::debug!(error = %e, "Admin API connection failed");
+            }
+        });
+    }
+
+    async fn serve_connection<S>(&self, mut stream: S) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+            .await
+            .map_err(|_| anyhow::anyhow!("request timed out"))??;
+        let request_line = head.lines().next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => {
+                tracing::debug!(method = method, target = target, "Admin API request");
+                self.respond(method, target).await
+            }
+            _ => (400, json!({ "error": "malformed request" })),
+        };
+
+        let body = format!("{}\n", serde_json::to_string_pretty(&body)?);
+        let response = format!(
+            "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            reason(status),
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Status code and JSON body for a request.
+    pub async fn respond(&self, method: &str, target: &str) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["status"]) => (200, self.status().await),
+            ("GET", ["zones"]) => (200, self.zones().await),
+            ("GET", ["routes"]) => (200, json!(self.handler.load_full().zone_routes().await)),
+            ("GET", ["cache"]) => (200, self.cache().await),
+            ("GET", ["stats"]) => (200, json!(self.handler.load_full().zone_stats())),
+            ("GET", ["top"]) => self.top(query).await,
+            ("GET", ["upstreams"]) => (200, self.upstreams().await),
+            ("GET", ["fake-ips"]) => (200, json!(self.handler.load_full().fake_ips())),
+            ("GET", ["routed-names"]) => (200, json!(self.handler.load_full().routed_names())),
+            ("GET", ["routed-names", ip]) => self.routed_name(ip),
+            ("GET", ["resolve", name]) => self.resolve(name, query).await,
+            ("GET", ["cache", "entries"]) => self.cache_entries(query).await,
+            ("POST", ["cache", "purge"]) => self.purge_cache(query).await,
+            ("POST", ["cache", "flush"]) => {
+                let flushed = self.handler.load_full().flush_cache();
+                tracing::info!(entries = flushed, "Cache flushed through admin API");
+                (200, json!({ "flushed": flushed }))
+            }
+            ("POST", ["zones", zone, action @ ("enable" | "disable")]) => {
+                let handler = self.handler.load_full();
+                let result = if *action == "enable" {
+                    handler.enable_zone(zone).await
+                } else {
+                    handler.disable_zone(zone).await
+                };
+                match result {
+                    Ok(changed) => (
+                        200,
+                        json!({ "zone": zone, "enabled": *action == "enable", "changed": changed }),
+                    ),
+                    Err(e) => error_response(&e),
+                }
+            }
+            ("POST", ["zones", zone, action @ ("up" | "down")]) => {
+                let handler = self.handler.load_full();
+                let result = if *action == "up" {
+                    handler.tunnel_up(zone).await
+                } else {
+                    handler.tunnel_down(zone).await
+                };
+                match result {
+                    Ok(routes) => {
+                        tracing::info!(zone = zone, action = action, routes, "Tunnel hook");
+                        (
+                            200,
+                            json!({ "zone": zone, "up": *action == "up", "routes": routes }),
+                        )
+                    }
+                    Err(e) => error_response(&e),
+                }
+            }
+            ("POST", ["reload"]) => self.reload(),
+            _ => (
+                404,
+                json!({ "error": format!("no such endpoint: {method} {path}") }),
+            ),
+        }
+    }
+
+    async fn status(&self) -> Value {
+        let handler = self.handler.load_full();
+        let routes = handler.zone_routes().await;
+        let disabled: Vec<&str> = handler
+            .config()
+            .zones
+            .iter()
+            .map(|z| z.name.as_str())
+            .filter(|name| handler.is_zone_disabled(name))
+            .collect();
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "config": self.config_path,
+            "zones": handler.config().zones.len(),
+            "disabled_zones": disabled,
+            "routes": routes.values().map(|r| r.routes.len()).sum::<usize>(),
+            "ips": routes.values().map(|r| r.ips).sum::<usize>(),
+            "reloads": self.history.recent(),
+        })
+    }
+
+    async fn zones(&self) -> Value {
+        let handler = self.handler.load_full();
+        let routes = handler.zone_routes().await;
+        let zones: Vec<Value> = handler
+            .config()
+            .zones
+            .iter()
+            .map(|zone| {
+                let zone_routes = routes.get(&zone.name);
+                json!({
+                    "name": zone.name,
+                    "mode": zone.mode,
+                    "route_type": zone.route_type,
+                    "route_target": zone.route_target,
+                    "enabled": !handler.is_zone_disabled(&zone.name),
+                    "withdrawn": zone_routes.is_some_and(|r| r.withdrawn),
+                    "routes": zone_routes.map_or(0, |r| r.routes.len()),
+                })
+            })
+            .collect();
+        json!(zones)
+    }
+
+    async fn cache(&self) -> Value {
+        let handler = self.handler.load_full();
+        let stats = handler.zone_stats();
+        let cache = handler.cache_stats();
+        json!({
+            "entries": cache.entries,
+            "max_entries": cache.max_entries,
+            "queries": stats.values().map(|s| s.queries).sum::<u64>(),
+            "hits": stats.values().map(|s| s.cache_hits).sum::<u64>(),
+        })
+    }
+
+    /// The most queried, slowest and most route-generating names lately;
+    /// `limit=<n>` entries of each (default 10).
+    async fn top(&self, query: &str) -> (u16, Value) {
+        let limit = match query_param(query, "limit").map(|limit| limit.parse()) {
+            None => 10,
+            Some(Ok(limit)) => limit,
+            Some(Err(e)) => return (400, json!({ "error": format!("invalid limit: {e}") })),
+        };
+        (200, json!(self.handler.load_full().top_domains(limit)))
+    }
+
+    /// Cached answers, those whose name matches `pattern=<glob>` if given.
+    async fn cache_entries(&self, query: &str) -> (u16, Value) {
+        let pattern = match query_param(query, "pattern")
+            .as_deref()
+            .map(name_pattern)
+            .transpose()
+        {
+            Ok(pattern) => pattern,
+            Err(e) => return error_response(&e),
+        };
+        let entries = self.handler.load_full().cache_entries(pattern.as_ref());
+        (200, json!(entries))
+    }
+
+    /// Drop cached answers whose name matches `name=<name or glob>`.
+    async fn purge_cache(&self, query: &str) -> (u16, Value) {
+        let Some(name) = query_param(query, "name") else {
+            return (
+                400,
+                json!({ "error": "missing name (use /cache/flush to drop everything)" }),
+            );
+        };
+        let pattern = match name_pattern(&name) {
+            Ok(pattern) => pattern,
+            Err(e) => return error_response(&e),
+        };
+        let purged = self.handler.load_full().purge_cache(&pattern);
+        tracing::info!(
+            name = name,
+            entries = purged,
+            "Cache entries purged through admin API"
+        );
+        (200, json!({ "purged": purged }))
+    }
+
+    /// Every configured upstream (default and zones' DNS servers), per
+    /// protocol it is queried over, with its counters and round-trip
+    /// times; those not queried yet count as healthy.
+    async fn upstreams(&self) -> Value {
+        let handler = self.handler.load_full();
+        let config = handler.config();
+        let mut upstreams: BTreeMap<(SocketAddr, DnsProtocol), Vec<&str>> = BTreeMap::new();
+        for &upstream in &config.server.default_upstream {
+            upstreams.entry((upstream, DnsProtocol::Udp)).or_default();
+        }
+        for zone in &config.zones {
+            for server in zone
+                .dns_servers
+                .iter()
+                .chain(zone.type_dns_servers.values().flatten())
+            {
+                let zones = upstreams
+                    .entry((server.address, zone.dns_protocol))
+                    .or_default();
+                if !zones.contains(&zone.name.as_str()) {
+                    zones.push(&zone.name);
+                }
+            }
+        }
+        let stats = handler.upstream_stats();
+        let upstreams: Vec<Value> = upstreams
+            .into_iter()
+            .map(|(key @ (address, protocol), zones)| {
+                let stats = stats.get(&key).cloned().unwrap_or_default();
+                json!({
+                    "address": address,
+                    "protocol": protocol,
+                    "zones": zones,
+                    "healthy": stats.consecutive_failures == 0,
+                    "answered": stats.answered,
+                    "failures": stats.failures,
+                    "consecutive_failures": stats.consecutive_failures,
+                    "servfail": stats.servfail,
+                    "refused": stats.refused,
+                    "unreachable": stats.unreachable,
+                    "rtt": stats.rtt,
+                })
+            })
+            .collect();
+        json!(upstreams)
+    }
+
+    /// `leshy resolve` against the running daemon; `client=<ip>` resolves
+    /// as that client would.
+    async fn resolve(&self, name: &str, query: &str) -> (u16, Value) {
+        let client = match query_param(query, "client")
+            .map(|client| client.parse())
+            .transpose()
+        {
+            Ok(client) => client,
+            Err(e) => return (400, json!({ "error": format!("invalid client: {e}") })),
+        };
+        match self.handler.load_full().explain(name, client).await {
+            Ok(resolution) => (200, json!(resolution)),
+            Err(e) => error_response(&e),
+        }
+    }
+
+    /// The name whose answer got `ip` routed.
+    fn routed_name(&self, ip: &str) -> (u16, Value) {
+        let ip: IpAddr = match ip.parse() {
+            Ok(ip) => ip,
+            Err(e) => return (400, json!({ "error": format!("invalid address: {e}") })),
+        };
+        match self.handler.load_full().routed_name(ip) {
+            Some(entry) => (200, json!(entry)),
+            None => (404, json!({ "error": format!("no routed name for {ip}") })),
+        }
+    }
+
+    /// Load the config file and hand it to the reload task, reporting load
+    /// and validation errors right away.
+    fn reload(&self) -> (u16, Value) {
+        let loaded =
+            Config::from_file_with_includes_and_profile(&self.config_path, self.profile.as_deref())
+                .and_then(|config| ZoneMatcher::new(config.zones.clone()).map(|_| config));
+        match loaded {
+            Ok(config) => {
+                tracing::info!("Reload requested through admin API");
+                if self.reload_tx.send(config).is_err() {
+                    return (500, json!({ "error": "reload task is not running" }));
+                }
+                (202, json!({ "status": "reloading" }))
+            }
+            Err(e) => {
+                self.history.failed(&e);
+                // A config that can't be read is as unusable as an invalid one
+                (422, error_response(&e).1)
+            }
+        }
+    }
+}
+
+/// Send one request to a running daemon's admin API and return the JSON
+/// body. Error statuses fail with the daemon's error message.
+pub async fn request(listen: &AdminListen, method: &str, path: &str) -> anyhow::Result<Value> {
+    let request = format!("{method} {path} HTTP/1.1\r\nHost: leshy\r\nConnection: close\r\n\r\n");
+    let unreachable = |e: std::io::Error| {
+        anyhow::anyhow!("failed to connect to the admin API at {listen}: {e} (is leshy running?)")
+    };
+    let response = match listen {
+        AdminListen::Tcp(addr) => {
+            exchange(
+                TcpStream::connect(addr).await.map_err(unreachable)?,
+                &request,
+            )
+            .await?
+        }
+        AdminListen::Unix(path) => {
+            exchange(
+                UnixStream::connect(path).await.map_err(unreachable)?,
+                &request,
+            )
+            .await?
+        }
+    };
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("malformed response from the admin API"))?;
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("malformed response from the admin API"))?;
+    let body: Value = serde_json::from_str(body)?;
+    if !(200..300).contains(&status) {
+        match body["error"].as_str() {
+            Some(error) => anyhow::bail!("{error}"),
+            None => anyhow::bail!("admin API returned {status}"),
+        }
+    }
+    Ok(body)
+}
+
+async fn exchange<S>(mut stream: S, request: &str) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    tokio::time::timeout(REQUEST_TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .map_err(|_| anyhow::anyhow!("admin API did not answer"))??;
+    Ok(response)
+}
+
+/// Value of `key` in a `a=1&b=2` query string.
+fn query_param(query: &str, key: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| percent_decode(value))
+}
+
+/// Percent-encode a query parameter value (glob patterns may hold `?`,
+/// `[` and `&`).
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~*:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Read up to the end of the request head.
+pub(crate) async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST {
+            anyhow::bail!("request too large");
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Status and `{"error", "code"}` body for a failed handler call.
+fn error_response(error: &LeshyError) -> (u16, Value) {
+    let status = match error {
+        LeshyError::Config(_) => 422,
+        LeshyError::UnknownZone(_) => 404,
+        LeshyError::Parse(_) => 400,
+        LeshyError::PermissionDenied(_) => 403,
+        LeshyError::Dns(_) | LeshyError::Routing(_) | LeshyError::Io { .. } => 500,
+    };
+    (
+        status,
+        json!({ "error": error.to_string(), "code": error.code() }),
+    )
+}
+
+pub(crate) fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        422 => "Unprocessable Entity",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsHandler;
+    use arc_swap::ArcSwap;
+
+    const CONFIG: &str = r#"
+        [server]
+        listen_address = "127.0.0.1:15390"
+        default_upstream = ["198.51.100.53:53"]
+
+        [routing]
+        dry_run = true
+
+        [[zones]]
+        name = "corp"
+        route_type = "via"
+        route_target = "198.51.100.1"
+        dns_servers = ["198.51.100.54:53"]
+        domains = ["corp.example"]
+    "#;
+
+    fn admin_api(config_path: PathBuf) -> (AdminApi, mpsc::UnboundedReceiver<Config>) {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let (reload_tx, reload_rx) = mpsc::unbounded_channel();
+        let api = AdminApi::new(
+            Arc::new(ArcSwap::from_pointee(handler)),
+            config_path,
+            reload_tx,
+            ReloadHistory::new(),
+        );
+        (api, reload_rx)
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        assert_eq!(
+            AdminListen::parse("127.0.0.1:5380").unwrap(),
+            AdminListen::Tcp("127.0.0.1:5380".parse().unwrap())
+        );
+        assert_eq!(
+            AdminListen::parse("/run/leshy/admin.sock").unwrap(),
+            AdminListen::Unix(PathBuf::from("/run/leshy/admin.sock"))
+        );
+        assert!(AdminListen::parse("localhost:5380").is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_state_and_toggles_zones() {
+        let dir = tempfile::tempdir().unwrap();
+        let (api, _reload_rx) = admin_api(dir.path().join("leshy.toml"));
+
+        let (status, zones) = api.respond("GET", "/zones").await;
+        assert_eq!(status, 200);
+        assert_eq!(zones[0]["name"], "corp");
+        assert_eq!(zones[0]["enabled"], true);
+
+        let (status, body) = api.respond("POST", "/zones/corp/disable").await;
+        assert_eq!((status, &body["changed"]), (200, &json!(true)));
+        assert!(api.handler.load().is_zone_disabled("corp"));
+        let (_, zones) = api.respond("GET", "/zones/").await;
+        assert_eq!(zones[0]["enabled"], false);
+        let (status, body) = api.respond("POST", "/zones/corp/enable").await;
+        assert_eq!((status, &body["changed"]), (200, &json!(true)));
+        let (status, body) = api.respond("POST", "/zones/nope/disable").await;
+        assert_eq!((status, &body["code"]), (404, &json!("unknown_zone")));
+        let (status, body) = api.respond("POST", "/zones/corp/down").await;
+        assert_eq!((status, &body["up"]), (200, &json!(false)));
+        let (status, body) = api.respond("POST", "/zones/corp/up").await;
+        assert_eq!((status, &body["routes"]), (200, &json!(0)));
+
+        let (_, upstreams) = api.respond("GET", "/upstreams").await;
+        assert_eq!(upstreams[1]["address"], "198.51.100.54:53");
+        assert_eq!(upstreams[1]["zones"], json!(["corp"]));
+        assert_eq!(upstreams[1]["healthy"], true);
+        assert_eq!(upstreams[1]["protocol"], "udp");
+        assert_eq!(upstreams[1]["rtt"]["responses"], 0);
+
+        let (_, cache) = api.respond("GET", "/cache").await;
+        assert_eq!(cache["max_entries"], 1000);
+        assert_eq!(api.respond("POST", "/cache/flush").await.0, 200);
+        let (status, entries) = api
+            .respond("GET", "/cache/entries?pattern=*.corp.example")
+            .await;
+        assert_eq!((status, entries), (200, json!([])));
+        let (status, body) = api
+            .respond("POST", "/cache/purge?name=www.corp.example")
+            .await;
+        assert_eq!((status, body), (200, json!({ "purged": 0 })));
+        assert_eq!(api.respond("POST", "/cache/purge").await.0, 400);
+        let (status, top) = api.respond("GET", "/top?limit=5").await;
+        assert_eq!((status, &top["queried"]), (200, &json!([])));
+        assert_eq!(api.respond("GET", "/top?limit=x").await.0, 400);
+        let pattern = encode_query_value("w?w.[a-c]*");
+        assert_eq!(pattern, "w%3Fw.%5Ba-c%5D*");
+        assert_eq!(
+            query_param(&format!("pattern={pattern}"), "pattern").unwrap(),
+            "w?w.[a-c]*"
+        );
+        assert_eq!(api.respond("GET", "/reload").await.0, 404);
+        let (status, body) = api.respond("GET", "/resolve/corp.example?client=x").await;
+        assert_eq!(status, 400, "{body}");
+    }
+
+    #[tokio::test]
+    async fn reload_sends_valid_configs_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("leshy.toml");
+        let (api, mut reload_rx) = admin_api(config_path.clone());
+
+        std::fs::write(&config_path, "[server]\nlisten_address = 1\n").unwrap();
+        let (status, body) = api.respond("POST", "/reload").await;
+        assert_eq!((status, &body["code"]), (422, &json!("config_invalid")));
+        assert!(body["error"].as_str().unwrap().contains("listen_address"));
+        assert!(reload_rx.try_recv().is_err());
+        let (_, status) = api.respond("GET", "/status").await;
+        assert_eq!(status["reloads"][0]["applied"], false);
+
+        std::fs::write(&config_path, CONFIG).unwrap();
+        assert_eq!(api.respond("POST", "/reload").await.0, 202);
+        assert_eq!(reload_rx.try_recv().unwrap().zones[0].name, "corp");
+    }
+
+    #[tokio::test]
+    async fn reload_applies_the_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("leshy.toml");
+        let (api, mut reload_rx) = admin_api(config_path.clone());
+        let api = api.with_profile(Some("home".to_string()));
+
+        let profile =
+            "[profiles.home]\nzones = [{ name = \"corp\", route_target = \"198.51.100.9\" }]\n";
+        std::fs::write(&config_path, format!("{CONFIG}\n{profile}")).unwrap();
+        assert_eq!(api.respond("POST", "/reload").await.0, 202);
+        assert_eq!(
+            reload_rx.try_recv().unwrap().zones[0].route_target,
+            "198.51.100.9"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_http_over_tcp() {
+        let dir = tempfile::tempdir().unwrap();
+        let (api, _reload_rx) = admin_api(dir.path().join("leshy.toml"));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let listen = AdminListen::Tcp(addr);
+        let server = api.spawn(&listen).await.unwrap();
+
+        let stats = request(&listen, "GET", "/stats").await.unwrap();
+        assert_eq!(stats, json!({}));
+        let status = request(&listen, "GET", "/status").await.unwrap();
+        assert_eq!(status["zones"], 1);
+        let err = request(&listen, "POST", "/zones/nope/enable").await;
+        assert!(err.unwrap_err().to_string().contains("not configured"));
+        server.abort();
+    }
+}
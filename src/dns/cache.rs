@@ -1,7 +1,9 @@
+use crate::error::LeshyError;
 use hickory_proto::op::Message;
 use hickory_proto::rr::RecordType;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 pub struct DnsCache {
@@ -15,10 +17,48 @@ struct CacheKey {
     qtype: RecordType,
 }
 
+/// Cached responses are shared rather than cloned: a hit only bumps a
+/// reference count while the lock is held, and the caller patches the
+/// header (ID/flags) on its own copy.
 struct CacheEntry {
-    message: Message,
+    message: Arc<Message>,
+    /// Zone the response was resolved through; None = default upstream
+    zone: Option<String>,
     inserted_at: Instant,
     ttl: Duration,
+    hits: usize,
+}
+
+/// Cache occupancy, for the admin API.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub max_entries: usize,
+}
+
+/// One live cache entry, for `leshy cache dump`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CacheEntryInfo {
+    /// Query name, without the trailing dot
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: String,
+    /// Zone the response was resolved through; None = default upstream
+    pub zone: Option<String>,
+    /// Cached apart for a client-scoped or scheduled zone
+    pub scoped: bool,
+    /// Seconds until it expires
+    pub ttl: u64,
+    pub hits: usize,
+    pub rcode: String,
+    /// Answer records' data
+    pub answers: Vec<String>,
+}
+
+/// A cache hit: the shared response and how many times it was served before.
+pub struct CacheHit {
+    pub message: Arc<Message>,
+    pub hits: usize,
 }
 
 impl DnsCache {
@@ -33,22 +73,34 @@ impl DnsCache {
         self.max_entries > 0
     }
 
-    pub fn lookup(&self, qname: &str, qtype: RecordType) -> Option<Message> {
+    pub fn lookup(&self, qname: &str, qtype: RecordType) -> Option<CacheHit> {
         let key = CacheKey {
             qname: qname.to_lowercase(),
             qtype,
         };
         let mut entries = self.entries.lock().unwrap();
-        if let Some(entry) = entries.get(&key) {
+        if let Some(entry) = entries.get_mut(&key) {
             if entry.inserted_at.elapsed() < entry.ttl {
-                return Some(entry.message.clone());
+                let hit = CacheHit {
+                    message: Arc::clone(&entry.message),
+                    hits: entry.hits,
+                };
+                entry.hits = entry.hits.wrapping_add(1);
+                return Some(hit);
             }
             entries.remove(&key);
         }
         None
     }
 
-    pub fn insert(&self, qname: &str, qtype: RecordType, message: Message, ttl: Duration) {
+    pub fn insert(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        zone: Option<&str>,
+        message: impl Into<Arc<Message>>,
+        ttl: Duration,
+    ) {
         if !self.is_enabled() {
             return;
         }
@@ -71,16 +123,85 @@ impl DnsCache {
         entries.insert(
             key,
             CacheEntry {
-                message,
+                message: message.into(),
+                zone: zone.map(String::from),
                 inserted_at: Instant::now(),
                 ttl,
+                hits: 0,
             },
         );
     }
 
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.lock().unwrap().len(),
+            max_entries: self.max_entries,
+        }
+    }
+
+    /// Live entries whose name matches `pattern` (all if None), by name
+    /// and type.
+    pub fn entries(&self, pattern: Option<&glob::Pattern>) -> Vec<CacheEntryInfo> {
+        let entries = self.entries.lock().unwrap();
+        let mut infos: Vec<CacheEntryInfo> = entries
+            .iter()
+            .filter(|(key, _)| {
+                pattern.is_none_or(|pattern| pattern.matches(entry_name(&key.qname)))
+            })
+            .filter_map(|(key, entry)| {
+                let ttl = entry.ttl.checked_sub(entry.inserted_at.elapsed())?;
+                Some(CacheEntryInfo {
+                    name: entry_name(&key.qname).to_string(),
+                    qtype: key.qtype.to_string(),
+                    zone: entry.zone.clone(),
+                    scoped: key.qname.contains('@'),
+                    ttl: ttl.as_secs(),
+                    hits: entry.hits,
+                    rcode: entry.message.response_code().to_string(),
+                    answers: entry
+                        .message
+                        .answers()
+                        .iter()
+                        .filter_map(|record| record.data().map(|data| data.to_string()))
+                        .collect(),
+                })
+            })
+            .collect();
+        infos.sort_by(|a, b| (&a.name, &a.qtype).cmp(&(&b.name, &b.qtype)));
+        infos
+    }
+
+    /// Drop every entry whose name matches `pattern`, of any type and
+    /// scope. Returns the number of entries dropped.
+    pub fn purge(&self, pattern: &glob::Pattern) -> usize {
+        self.retain(|qname, _| !pattern.matches(entry_name(qname)))
+    }
+
     pub fn clear(&self) {
         self.entries.lock().unwrap().clear();
     }
+
+    /// Keep only the entries `keep(qname, zone)` accepts. Returns the
+    /// number of entries dropped.
+    pub fn retain(&self, keep: impl Fn(&str, Option<&str>) -> bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|key, entry| keep(&key.qname, entry.zone.as_deref()));
+        before - entries.len()
+    }
+}
+
+/// Pattern for `entries` and `purge`: a name, or a glob such as
+/// `*.corp.example`, matched case-insensitively without the trailing dot.
+pub fn name_pattern(pattern: &str) -> crate::error::Result<glob::Pattern> {
+    glob::Pattern::new(&pattern.trim_end_matches('.').to_lowercase())
+        .map_err(|e| LeshyError::Parse(format!("invalid name pattern '{pattern}': {e}")))
+}
+
+/// Name of a cache key, without the scope suffix and trailing dot.
+fn entry_name(qname: &str) -> &str {
+    let name = qname.split('@').next().unwrap_or_default();
+    name.trim_end_matches('.')
 }
 
 #[cfg(test)]
@@ -112,6 +233,7 @@ mod tests {
         cache.insert(
             "example.com",
             RecordType::A,
+            None,
             Message::new(),
             Duration::from_secs(60),
         );
@@ -126,13 +248,14 @@ mod tests {
         cache.insert(
             "example.com.",
             RecordType::A,
+            None,
             msg.clone(),
             Duration::from_secs(60),
         );
 
         let cached = cache.lookup("example.com.", RecordType::A);
         assert!(cached.is_some());
-        assert_eq!(cached.unwrap().answers().len(), 1);
+        assert_eq!(cached.unwrap().message.answers().len(), 1);
     }
 
     #[test]
@@ -140,7 +263,13 @@ mod tests {
         let cache = DnsCache::new(100);
         let msg = make_response("Example.COM.", Ipv4Addr::new(1, 2, 3, 4), 300);
 
-        cache.insert("Example.COM.", RecordType::A, msg, Duration::from_secs(60));
+        cache.insert(
+            "Example.COM.",
+            RecordType::A,
+            None,
+            msg,
+            Duration::from_secs(60),
+        );
         assert!(cache.lookup("example.com.", RecordType::A).is_some());
     }
 
@@ -149,7 +278,13 @@ mod tests {
         let cache = DnsCache::new(100);
         let msg = make_response("example.com.", Ipv4Addr::new(1, 2, 3, 4), 300);
 
-        cache.insert("example.com.", RecordType::A, msg, Duration::from_millis(1));
+        cache.insert(
+            "example.com.",
+            RecordType::A,
+            None,
+            msg,
+            Duration::from_millis(1),
+        );
         std::thread::sleep(Duration::from_millis(5));
 
         assert!(cache.lookup("example.com.", RecordType::A).is_none());
@@ -160,7 +295,13 @@ mod tests {
         let cache = DnsCache::new(100);
         let msg = make_response("example.com.", Ipv4Addr::new(1, 2, 3, 4), 300);
 
-        cache.insert("example.com.", RecordType::A, msg, Duration::from_secs(60));
+        cache.insert(
+            "example.com.",
+            RecordType::A,
+            None,
+            msg,
+            Duration::from_secs(60),
+        );
         assert!(cache.lookup("example.com.", RecordType::A).is_some());
         assert!(cache.lookup("example.com.", RecordType::AAAA).is_none());
     }
@@ -170,7 +311,13 @@ mod tests {
         let cache = DnsCache::new(100);
         let msg = make_response("example.com.", Ipv4Addr::new(1, 2, 3, 4), 300);
 
-        cache.insert("example.com.", RecordType::A, msg, Duration::from_secs(60));
+        cache.insert(
+            "example.com.",
+            RecordType::A,
+            None,
+            msg,
+            Duration::from_secs(60),
+        );
         cache.clear();
         assert!(cache.lookup("example.com.", RecordType::A).is_none());
     }
@@ -183,12 +330,91 @@ mod tests {
         let msg3 = make_response("c.com.", Ipv4Addr::new(3, 3, 3, 3), 300);
 
         // Insert with very short TTL so they expire
-        cache.insert("a.com.", RecordType::A, msg1, Duration::from_millis(1));
-        cache.insert("b.com.", RecordType::A, msg2, Duration::from_millis(1));
+        cache.insert(
+            "a.com.",
+            RecordType::A,
+            None,
+            msg1,
+            Duration::from_millis(1),
+        );
+        cache.insert(
+            "b.com.",
+            RecordType::A,
+            None,
+            msg2,
+            Duration::from_millis(1),
+        );
         std::thread::sleep(Duration::from_millis(5));
 
         // This should trigger sweep of expired entries and succeed
-        cache.insert("c.com.", RecordType::A, msg3, Duration::from_secs(60));
+        cache.insert("c.com.", RecordType::A, None, msg3, Duration::from_secs(60));
         assert!(cache.lookup("c.com.", RecordType::A).is_some());
     }
+
+    #[test]
+    fn test_entries_and_purge_by_pattern() {
+        let cache = DnsCache::new(100);
+        let ttl = Duration::from_secs(60);
+        for (qname, zone) in [
+            ("www.corp.example.", Some("corp")),
+            ("www.corp.example.@corp", Some("corp")),
+            ("corp.example.", Some("corp")),
+            ("other.example.", None),
+        ] {
+            let msg = make_response(entry_name(qname), Ipv4Addr::new(198, 51, 100, 7), 300);
+            cache.insert(qname, RecordType::A, zone, msg, ttl);
+        }
+
+        let pattern = name_pattern("*.Corp.Example.").unwrap();
+        let entries = cache.entries(Some(&pattern));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "www.corp.example");
+        assert_eq!(entries[0].qtype, "A");
+        assert_eq!(entries[0].answers, vec!["198.51.100.7"]);
+        assert_eq!(entries.iter().filter(|e| e.scoped).count(), 1);
+        assert_eq!(cache.entries(None).len(), 4);
+
+        assert_eq!(cache.purge(&pattern), 2);
+        assert_eq!(cache.purge(&name_pattern("corp.example").unwrap()), 1);
+        assert!(cache.lookup("other.example.", RecordType::A).is_some());
+    }
+
+    #[test]
+    fn test_retain_by_zone() {
+        let cache = DnsCache::new(100);
+        let msg = make_response("example.com.", Ipv4Addr::new(1, 2, 3, 4), 300);
+
+        cache.insert(
+            "a.com.",
+            RecordType::A,
+            Some("corp"),
+            msg.clone(),
+            Duration::from_secs(60),
+        );
+        cache.insert("b.com.", RecordType::A, None, msg, Duration::from_secs(60));
+
+        assert_eq!(cache.retain(|_, zone| zone.is_none()), 1);
+        assert!(cache.lookup("a.com.", RecordType::A).is_none());
+        assert!(cache.lookup("b.com.", RecordType::A).is_some());
+    }
+
+    #[test]
+    fn test_lookup_shares_message() {
+        let cache = DnsCache::new(100);
+        let msg = make_response("example.com.", Ipv4Addr::new(1, 2, 3, 4), 300);
+
+        cache.insert(
+            "example.com.",
+            RecordType::A,
+            None,
+            msg,
+            Duration::from_secs(60),
+        );
+
+        let first = cache.lookup("example.com.", RecordType::A).unwrap();
+        let second = cache.lookup("example.com.", RecordType::A).unwrap();
+        assert!(Arc::ptr_eq(&first.message, &second.message));
+        assert_eq!(first.hits, 0);
+        assert_eq!(second.hits, 1);
+    }
 }
@@ -0,0 +1,147 @@
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::rdata::AAAA;
+use hickory_proto::rr::{RData, Record, RecordType};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The network of a `dns64_prefix`, which must be an IPv6 /96.
+pub fn parse_prefix(prefix: &str) -> Option<Ipv6Addr> {
+    match crate::routing::parse_cidr(prefix).ok()? {
+        (IpAddr::V6(network), 96) => Some(network),
+        _ => None,
+    }
+}
+
+/// `ip` embedded in the last 32 bits of a /96 prefix (RFC 6052).
+pub fn embed(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(prefix) & !u128::from(u32::MAX) | u128::from(u32::from(ip)))
+}
+
+/// Whether an AAAA response leaves the name without IPv6 addresses, so
+/// DNS64 answers from its A records instead.
+pub fn needs_synthesis(aaaa_response: &Message) -> bool {
+    aaaa_response.response_code() == ResponseCode::NoError
+        && !aaaa_response
+            .answers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::AAAA)
+}
+
+/// The AAAA response rewritten to answer with the A response's records
+/// mapped into `prefix`, CNAMEs kept. None if there were no A records.
+pub fn synthesize(
+    aaaa_response: &Message,
+    a_response: &Message,
+    prefix: Ipv6Addr,
+) -> Option<Message> {
+    if a_response.response_code() != ResponseCode::NoError {
+        return None;
+    }
+    let mut answers = Vec::new();
+    let mut synthesized = false;
+    for record in a_response.answers() {
+        match record.data() {
+            Some(RData::A(a)) => {
+                let aaaa = RData::AAAA(AAAA(embed(prefix, a.0)));
+                answers.push(Record::from_rdata(
+                    record.name().clone(),
+                    record.ttl(),
+                    aaaa,
+                ));
+                synthesized = true;
+            }
+            Some(RData::CNAME(_)) => answers.push(record.clone()),
+            _ => {}
+        }
+    }
+    if !synthesized {
+        return None;
+    }
+
+    let mut response = aaaa_response.clone();
+    response.take_answers();
+    response.take_name_servers();
+    // Made up here, so nothing upstream vouches for it
+    response.set_authentic_data(false);
+    response.add_answers(answers);
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::rdata::{A, CNAME, SOA};
+    use hickory_proto::rr::Name;
+    use std::str::FromStr;
+
+    fn response(qtype: RecordType, answers: Vec<Record>) -> Message {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("www.corp.example.").unwrap(),
+            qtype,
+        ));
+        message.add_answers(answers);
+        message
+    }
+
+    #[test]
+    fn maps_a_records_into_the_prefix() {
+        let prefix = parse_prefix("64:ff9b::/96").unwrap();
+        assert_eq!(parse_prefix("64:ff9b::/64"), None);
+        assert_eq!(parse_prefix("192.0.2.0/24"), None);
+        assert_eq!(
+            embed(prefix, Ipv4Addr::new(10, 20, 0, 5)),
+            "64:ff9b::a14:5".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let name = |s: &str| Name::from_str(s).unwrap();
+        let mut nodata = response(RecordType::AAAA, vec![]);
+        let soa = SOA::new(
+            name("corp.example."),
+            name("admin.corp.example."),
+            1,
+            60,
+            60,
+            60,
+            60,
+        );
+        nodata.add_name_server(Record::from_rdata(
+            name("corp.example."),
+            60,
+            RData::SOA(soa),
+        ));
+        assert!(needs_synthesis(&nodata));
+
+        let a = response(
+            RecordType::A,
+            vec![
+                Record::from_rdata(
+                    name("www.corp.example."),
+                    300,
+                    RData::CNAME(CNAME(name("app.corp.example."))),
+                ),
+                Record::from_rdata(
+                    name("app.corp.example."),
+                    120,
+                    RData::A(A(Ipv4Addr::new(10, 20, 0, 5))),
+                ),
+            ],
+        );
+        let synthesized = synthesize(&nodata, &a, prefix).unwrap();
+        assert!(synthesized.name_servers().is_empty());
+        let answers = synthesized.answers();
+        assert_eq!(answers[0].record_type(), RecordType::CNAME);
+        assert_eq!(answers[1].ttl(), 120);
+        assert_eq!(
+            answers[1].data().and_then(|d| d.as_aaaa()),
+            Some(&AAAA("64:ff9b::a14:5".parse().unwrap()))
+        );
+        assert!(!needs_synthesis(&synthesized));
+
+        // A real AAAA answer, or a name without A records, stays as it is
+        assert!(synthesize(&nodata, &response(RecordType::A, vec![]), prefix).is_none());
+        let mut nxdomain = nodata.clone();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        assert!(!needs_synthesis(&nxdomain));
+    }
+}
@@ -0,0 +1,272 @@
+use crate::routing::{interfaces, parse_cidr, prefix_contains};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+
+/// The Docker API socket.
+pub const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Where containers find the daemon's DNS settings.
+pub const DAEMON_JSON: &str = "/etc/docker/daemon.json";
+
+/// How long fetched networks and containers are used before asking again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// TTL of the PTR records answered for containers, which come and go.
+pub const PTR_TTL: u32 = 5;
+
+/// How long a Docker API request may take.
+const API_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Docker networks and the containers on them, read from the Docker API,
+/// to answer reverse queries for container addresses.
+pub struct DockerNetworks {
+    socket: PathBuf,
+    snapshot: Mutex<Snapshot>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    fetched: Option<Instant>,
+    /// Subnets of every network, as (network, prefix length)
+    subnets: Vec<(IpAddr, u8)>,
+    /// `<container>.<network>.` per container address
+    names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl DockerNetworks {
+    pub fn new() -> Self {
+        Self::with_socket(PathBuf::from(DOCKER_SOCKET))
+    }
+
+    fn with_socket(socket: PathBuf) -> Self {
+        Self {
+            socket,
+            snapshot: Mutex::default(),
+        }
+    }
+
+    /// Names for a reverse query (`qname` under in-addr.arpa or ip6.arpa):
+    /// None if the address isn't in a Docker network, so the query is
+    /// forwarded as usual, else its containers' names (none = NXDOMAIN).
+    pub async fn reverse(&self, qname: &str) -> Option<Vec<String>> {
+        let ip = ptr_address(qname)?;
+        let mut snapshot = self.snapshot.lock().await;
+        if snapshot
+            .fetched
+            .is_none_or(|at| at.elapsed() >= REFRESH_INTERVAL)
+        {
+            match self.fetch().await {
+                Ok(fetched) => *snapshot = fetched,
+                Err(e) => {
+                    tracing::debug!(error = %e, "Failed to read networks from the Docker API");
+                    snapshot.fetched = Some(Instant::now());
+                }
+            }
+        }
+        snapshot
+            .subnets
+            .iter()
+            .any(|&(network, len)| prefix_contains(network, len, ip))
+            .then(|| snapshot.names.get(&ip).cloned().unwrap_or_default())
+    }
+
+    async fn fetch(&self) -> anyhow::Result<Snapshot> {
+        let networks = self.get("/networks").await?;
+        let containers = self.get("/containers/json").await?;
+        Ok(Snapshot {
+            fetched: Some(Instant::now()),
+            subnets: subnets(&networks),
+            names: container_names(&containers),
+        })
+    }
+
+    /// GET `path` from the Docker API as JSON. HTTP/1.0, so the body is
+    /// never chunked.
+    async fn get(&self, path: &str) -> anyhow::Result<Value> {
+        let exchange = async {
+            let mut stream = UnixStream::connect(&self.socket).await?;
+            stream
+                .write_all(format!("GET {path} HTTP/1.0\r\nHost: docker\r\n\r\n").as_bytes())
+                .await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            anyhow::Ok(response)
+        };
+        let response = tokio::time::timeout(API_TIMEOUT, exchange)
+            .await
+            .map_err(|_| anyhow::anyhow!("Docker API timed out"))??;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow::anyhow!("malformed response from the Docker API"))?;
+        if !head.starts_with("HTTP/1.0 200") && !head.starts_with("HTTP/1.1 200") {
+            let status = head.lines().next().unwrap_or_default();
+            anyhow::bail!("Docker API answered {status} for {path}");
+        }
+        Ok(serde_json::from_str(body)?)
+    }
+}
+
+impl Default for DockerNetworks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn subnets(networks: &Value) -> Vec<(IpAddr, u8)> {
+    let configs = networks
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|network| network["IPAM"]["Config"].as_array())
+        .flatten();
+    configs
+        .filter_map(|config| config["Subnet"].as_str())
+        .filter_map(|subnet| parse_cidr(subnet).ok())
+        .collect()
+}
+
+fn container_names(containers: &Value) -> HashMap<IpAddr, Vec<String>> {
+    let mut names: HashMap<IpAddr, Vec<String>> = HashMap::new();
+    for container in containers.as_array().into_iter().flatten() {
+        let Some(name) = container["Names"][0].as_str() else {
+            continue;
+        };
+        let name = name.trim_start_matches('/');
+        let Some(networks) = container["NetworkSettings"]["Networks"].as_object() else {
+            continue;
+        };
+        for (network, settings) in networks {
+            for key in ["IPAddress", "GlobalIPv6Address"] {
+                if let Some(ip) = settings[key].as_str().and_then(|ip| ip.parse().ok()) {
+                    names
+                        .entry(ip)
+                        .or_default()
+                        .push(format!("{name}.{network}."));
+                }
+            }
+        }
+    }
+    names
+}
+
+/// The address a reverse query asks about, e.g. 172.17.0.2 for
+/// `2.0.17.172.in-addr.arpa.`.
+pub(crate) fn ptr_address(qname: &str) -> Option<IpAddr> {
+    let name = qname.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(octets) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets: Vec<u8> = octets
+            .split('.')
+            .map(|octet| octet.parse().ok())
+            .collect::<Option<_>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into());
+    }
+    let nibbles = name.strip_suffix(".ip6.arpa")?;
+    let mut value: u128 = 0;
+    let mut count = 0;
+    for nibble in nibbles.split('.').rev() {
+        let digit = u32::from_str_radix(nibble, 16)
+            .ok()
+            .filter(|_| nibble.len() == 1)?;
+        value = (value << 4) | u128::from(digit);
+        count += 1;
+    }
+    (count == 32).then(|| Ipv6Addr::from(value).into())
+}
+
+/// The IPv4 address of the Docker bridge `interface` (e.g. docker0), if it
+/// is up and has one.
+pub fn bridge_address(interface: &str) -> Option<Ipv4Addr> {
+    interfaces::addresses()
+        .into_iter()
+        .find_map(|(name, ip)| match ip {
+            IpAddr::V4(ip) if name == interface => Some(ip),
+            _ => None,
+        })
+}
+
+/// The `dns` servers /etc/docker/daemon.json gives containers.
+pub fn daemon_dns() -> Option<Vec<IpAddr>> {
+    let daemon: Value = serde_json::from_str(&std::fs::read_to_string(DAEMON_JSON).ok()?).ok()?;
+    let servers = daemon["dns"].as_array()?;
+    Some(
+        servers
+            .iter()
+            .filter_map(|server| server.as_str()?.parse().ok())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn parses_reverse_names() {
+        assert_eq!(
+            ptr_address("2.0.17.172.in-addr.arpa."),
+            Some("172.17.0.2".parse().unwrap())
+        );
+        let v6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.";
+        assert_eq!(ptr_address(v6), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(ptr_address("17.172.in-addr.arpa."), None);
+        assert_eq!(ptr_address("www.example.com."), None);
+    }
+
+    /// Answer each request with the next of `bodies`.
+    fn fake_docker(dir: &std::path::Path, bodies: Vec<&'static str>) -> PathBuf {
+        let path = dir.join("docker.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response =
+                    format!("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{body}");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        path
+    }
+
+    #[tokio::test]
+    async fn answers_for_container_networks() {
+        let dir = tempfile::tempdir().unwrap();
+        let networks = r#"[{"Name": "bridge", "IPAM": {"Config": [{"Subnet": "172.17.0.0/16"}]}}]"#;
+        let containers = r#"[{"Names": ["/web"], "NetworkSettings": {"Networks":
+            {"bridge": {"IPAddress": "172.17.0.2", "GlobalIPv6Address": ""}}}}]"#;
+        let socket = fake_docker(dir.path(), vec![networks, containers]);
+        let docker = DockerNetworks::with_socket(socket);
+
+        assert_eq!(
+            docker.reverse("2.0.17.172.in-addr.arpa.").await,
+            Some(vec!["web.bridge.".to_string()])
+        );
+        // In the network but no container: NXDOMAIN
+        assert_eq!(
+            docker.reverse("9.0.17.172.in-addr.arpa.").await,
+            Some(Vec::new())
+        );
+        // Elsewhere: forwarded
+        assert_eq!(docker.reverse("7.100.51.198.in-addr.arpa.").await, None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finds_loopback_address() {
+        assert_eq!(bridge_address("lo"), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(bridge_address("nosuchif0"), None);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::ZoneConfig;
+use crate::routing::parse_cidr;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+
+/// TTL of synthetic answers: clients ask again rather than keep an address
+/// that may be handed to another name once the pool runs out
+pub const FAKE_IP_TTL: u32 = 1;
+
+/// Synthetic addresses handed out for the names of `fake_ip_pool` zones,
+/// one pool per configured range, kept across reloads.
+#[derive(Default)]
+pub struct FakeIps {
+    pools: Mutex<HashMap<(Ipv4Addr, u8), Pool>>,
+}
+
+/// One name's synthetic address, for the admin API.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct FakeIpEntry {
+    pub ip: Ipv4Addr,
+    pub name: String,
+}
+
+/// Addresses of one range. Offsets 0 and 1 (the network and the address
+/// left for the tunnel itself) and the broadcast address are never handed
+/// out; once the rest are taken, the least recently asked-for name loses
+/// its address.
+struct Pool {
+    network: u32,
+    size: u32,
+    next: u32,
+    by_name: HashMap<String, u32>,
+    by_offset: HashMap<u32, (String, u64)>,
+    /// Offsets by when their name was last asked for
+    recency: BTreeMap<u64, u32>,
+    clock: u64,
+}
+
+impl Pool {
+    fn new(network: Ipv4Addr, prefix_len: u8) -> Self {
+        let size = 1u32 << (32 - prefix_len);
+        Self {
+            network: u32::from(network) & !(size - 1),
+            size,
+            next: 2,
+            by_name: HashMap::new(),
+            by_offset: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn assign(&mut self, name: &str) -> Ipv4Addr {
+        self.clock += 1;
+        let offset = match self.by_name.get(name) {
+            Some(&offset) => {
+                let (_, last) = self.by_offset[&offset];
+                self.recency.remove(&last);
+                offset
+            }
+            None => {
+                let offset = if self.next < self.size - 1 {
+                    self.next += 1;
+                    self.next - 1
+                } else {
+                    self.evict()
+                };
+                self.by_name.insert(name.to_string(), offset);
+                offset
+            }
+        };
+        self.by_offset
+            .insert(offset, (name.to_string(), self.clock));
+        self.recency.insert(self.clock, offset);
+        Ipv4Addr::from(self.network + offset)
+    }
+
+    /// Free the least recently asked-for address.
+    fn evict(&mut self) -> u32 {
+        let (_, offset) = self
+            .recency
+            .pop_first()
+            .expect("a full pool has assigned addresses");
+        if let Some((name, _)) = self.by_offset.remove(&offset) {
+            tracing::debug!(name = name, "Fake IP pool full, reusing the oldest address");
+            self.by_name.remove(&name);
+        }
+        offset
+    }
+
+    fn name(&self, ip: Ipv4Addr) -> Option<&str> {
+        let offset = u32::from(ip).checked_sub(self.network)?;
+        self.by_offset.get(&offset).map(|(name, _)| name.as_str())
+    }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip).wrapping_sub(self.network) < self.size
+    }
+}
+
+impl FakeIps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The synthetic address of `name` in `pool` (an IPv4 CIDR), handing
+    /// out a new one on first use. None if `pool` isn't a valid range.
+    pub fn assign(&self, pool: &str, name: &str) -> Option<Ipv4Addr> {
+        let range = parse_range(pool)?;
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut pools = self.pools.lock().unwrap();
+        Some(
+            pools
+                .entry(range)
+                .or_insert_with(|| Pool::new(range.0, range.1))
+                .assign(&name),
+        )
+    }
+
+    /// The name `ip` was handed out for. `Some("")` for an address of a
+    /// pool that no name holds.
+    pub fn name(&self, ip: IpAddr) -> Option<String> {
+        let IpAddr::V4(ip) = ip else {
+            return None;
+        };
+        let pools = self.pools.lock().unwrap();
+        let pool = pools.values().find(|pool| pool.contains(ip))?;
+        Some(pool.name(ip).unwrap_or_default().to_string())
+    }
+
+    /// Every name's address, by address.
+    pub fn entries(&self) -> Vec<FakeIpEntry> {
+        let pools = self.pools.lock().unwrap();
+        let mut entries: Vec<FakeIpEntry> = pools
+            .values()
+            .flat_map(|pool| {
+                pool.by_offset
+                    .iter()
+                    .map(|(&offset, (name, _))| FakeIpEntry {
+                        ip: Ipv4Addr::from(pool.network + offset),
+                        name: name.clone(),
+                    })
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.ip);
+        entries
+    }
+
+    /// Forget the pools no zone uses any more.
+    pub fn retain(&self, zones: &[ZoneConfig]) {
+        let ranges: Vec<(Ipv4Addr, u8)> = zones
+            .iter()
+            .filter_map(|zone| parse_range(zone.fake_ip_pool.as_deref()?))
+            .collect();
+        self.pools
+            .lock()
+            .unwrap()
+            .retain(|range, _| ranges.contains(range));
+    }
+}
+
+/// An IPv4 CIDR usable as a pool: /8 to /30.
+pub fn parse_range(pool: &str) -> Option<(Ipv4Addr, u8)> {
+    match parse_cidr(pool).ok()? {
+        (IpAddr::V4(network), prefix_len @ 8..=30) => Some((network, prefix_len)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_stable_addresses_and_recycles_the_oldest() {
+        let fake = FakeIps::new();
+        let pool = "198.18.0.0/29";
+        let github = fake.assign(pool, "GitHub.com.").unwrap();
+        assert_eq!(github, Ipv4Addr::new(198, 18, 0, 2));
+        assert_eq!(fake.assign(pool, "github.com").unwrap(), github);
+        assert_eq!(fake.name(github.into()).as_deref(), Some("github.com"));
+        assert_eq!(
+            fake.name(Ipv4Addr::new(198, 18, 0, 6).into()).as_deref(),
+            Some("")
+        );
+        assert_eq!(fake.name(Ipv4Addr::new(198, 18, 0, 8).into()), None);
+
+        // .2 to .6 usable; github.com asked again, so example.net goes first
+        for name in ["example.net", "a.example", "b.example", "c.example"] {
+            fake.assign(pool, name).unwrap();
+        }
+        fake.assign(pool, "github.com").unwrap();
+        let reused = fake.assign(pool, "d.example").unwrap();
+        assert_eq!(reused, Ipv4Addr::new(198, 18, 0, 3));
+        assert_eq!(fake.entries().len(), 5);
+        assert!(!fake.entries().iter().any(|e| e.name == "example.net"));
+
+        assert_eq!(fake.assign("198.18.0.0/31", "x.example"), None);
+        fake.retain(&[]);
+        assert!(fake.entries().is_empty());
+    }
+}
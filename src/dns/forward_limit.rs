@@ -0,0 +1,84 @@
+use crate::config::ServerConfig;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Bounds the queries being forwarded upstream at once. Queries past the
+/// limit wait for a slot, up to `queue` of them; the rest are turned away
+/// at once.
+pub struct ForwardLimiter {
+    max: usize,
+    queue: usize,
+    slots: Semaphore,
+    waiting: AtomicUsize,
+}
+
+/// Counts a query as waiting for a slot until dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ForwardLimiter {
+    /// The limiter `[server]` asks for; None when forwards are unlimited.
+    pub fn from_config(server: &ServerConfig) -> Option<Self> {
+        (server.max_concurrent_forwards > 0)
+            .then(|| Self::new(server.max_concurrent_forwards, server.forward_queue_size))
+    }
+
+    pub fn new(max: usize, queue: usize) -> Self {
+        Self {
+            max,
+            queue,
+            slots: Semaphore::new(max),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Slots and queue length, to tell whether a reload changed them.
+    pub fn limits(&self) -> (usize, usize) {
+        (self.max, self.queue)
+    }
+
+    /// A slot to forward one query, held until dropped; None when every
+    /// slot is taken and the queue is full.
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.slots.try_acquire() {
+            return Some(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::AcqRel) >= self.queue {
+            self.waiting.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        let _waiting = Waiting(&self.waiting);
+        self.slots.acquire().await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn queues_then_turns_away() {
+        let limiter = Arc::new(ForwardLimiter::new(2, 1));
+        let first = limiter.acquire().await.unwrap();
+        let _second = limiter.acquire().await.unwrap();
+
+        // Third waits in the queue, fourth finds it full
+        let queued = {
+            let limiter = Arc::clone(&limiter);
+            tokio::spawn(async move { limiter.acquire().await.is_some() })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(limiter.acquire().await.is_none());
+
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(limiter.waiting.load(Ordering::Acquire), 0);
+    }
+}
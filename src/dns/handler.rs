@@ -1,102 +1,220 @@
-use crate::config::{Config, DnsProtocol, DnsServerConfig, ServerConfig, ZoneConfig, ZoneMode};
-use crate::dns::cache::DnsCache;
-use crate::routing::RouteManager;
-use crate::zones::{MatchedZone, ZoneMatcher};
-use hickory_proto::op::{Message, MessageType, OpCode, ResponseCode};
-use hickory_proto::rr::RecordType;
+use crate::config::{
+    CleanupMode, Config, DnsProtocol, DnsServerConfig, MatchMode, RateLimitAction,
+    RouteFailureMode, ServerConfig, ShutdownMode, ZoneConfig, ZoneMode,
+};
+use crate::dns::cache::{CacheEntryInfo, CacheStats, DnsCache};
+use crate::dns::dns64;
+use crate::dns::docker::{self, DockerNetworks};
+use crate::dns::fake_ip::{FakeIpEntry, FakeIps, FAKE_IP_TTL};
+use crate::dns::forward_limit::ForwardLimiter;
+use crate::dns::local_zone::LocalZones;
+use crate::dns::query_log::{AddedRoute, QueryLog, QueryLogEntry, QueryLogSettings};
+use crate::dns::rate_limit::{RateLimitSettings, RateLimiter};
+use crate::dns::rewrite;
+use crate::dns::routed_names::{RoutedName, RoutedNames, ROUTED_PTR_TTL};
+use crate::dns::stats::{QueryStats, UpstreamFailure, UpstreamStats, ZoneStats};
+use crate::dns::top::{SlowQuery, TopDomains, TopReport};
+use crate::dns::udp_pool::UdpPool;
+use crate::error::{self, LeshyError};
+use crate::events::{Event, Events};
+use crate::routing::endpoints::EndpointRoutes;
+use crate::routing::{RouteManager, RouteOptions, RouteState};
+use crate::zones::{GeoIp, MatchedZone, ZoneMatcher};
+use arc_swap::ArcSwap;
+use hickory_proto::op::{Header, Message, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::rdata::{CNAME, PTR};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
 use hickory_server::authority::MessageResponseBuilder;
-use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
-use std::net::{IpAddr, SocketAddr};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
 
+/// The handler serving queries, swapped for a new snapshot on reload.
+pub type SharedHandler = Arc<ArcSwap<DnsHandler>>;
+
+/// Routes being installed in the background for one answer.
+struct RouteTask {
+    /// Yields the routes newly installed, for the query log
+    added: JoinHandle<Vec<AddedRoute>>,
+    /// Whether every route went in, sent once all were tried
+    installed: oneshot::Receiver<bool>,
+    /// How long the answer waits for `installed` before SERVFAIL; None =
+    /// answer at once ("fallback")
+    wait: Option<Duration>,
+}
+
+/// An immutable snapshot of the config and what is built from it, over
+/// state shared by every snapshot (routes, cache, counters, disabled
+/// zones, upstream sockets). Clones share all of it.
+#[derive(Clone)]
 pub struct DnsHandler {
     config: Arc<Config>,
     matcher: Arc<ZoneMatcher>,
     route_manager: Arc<RwLock<RouteManager>>,
+    /// Whether routes are managed at all (not `routing = "disabled"`);
+    /// fixed at startup
+    routes_enabled: bool,
     cache: Arc<DnsCache>,
+    /// Prefixes applied from each zone's `static_routes_url`
+    remote_routes: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    geoip: Option<Arc<GeoIp>>,
+    stats: Arc<QueryStats>,
+    top: Arc<TopDomains>,
+    /// Zones turned off through the admin API, kept across reloads
+    disabled_zones: Arc<std::sync::RwLock<HashSet<String>>>,
+    /// Tunnel endpoints kept out of every zone's routes and the routes
+    /// applied for them, kept across reloads
+    endpoints: Arc<std::sync::RwLock<EndpointRoutes>>,
+    query_log: Option<Arc<QueryLog>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    forward_limiter: Option<Arc<ForwardLimiter>>,
+    /// Container names for reverse queries, with `docker_dns`
+    docker: Option<Arc<DockerNetworks>>,
+    /// Sockets to upstreams, kept across reloads
+    udp_pool: Arc<UdpPool>,
+    /// Addresses handed out for `fake_ip_pool` zones, kept across reloads
+    fake_ips: Arc<FakeIps>,
+    /// The names that got addresses routed, kept across reloads
+    routed_names: Arc<RoutedNames>,
+    /// Zones answered authoritatively (`local_zones`), reread on reload
+    local_zones: Arc<LocalZones>,
+    events: Arc<Events>,
+}
+
+/// How a name resolves and routes, for `leshy resolve`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Resolution {
+    pub name: String,
+    /// Zone whose DNS settings the query used; None = no zone matched
+    pub zone: Option<String>,
+    /// Upstreams the A query tried in order, and how
+    pub upstreams: Vec<SocketAddr>,
+    pub protocol: DnsProtocol,
+    /// First upstream that answered the A query
+    pub answered_by: Option<SocketAddr>,
+    /// Response code of the A query (or of its last failed attempt)
+    pub rcode: String,
+    /// A and AAAA addresses returned
+    pub addresses: Vec<IpAddr>,
+    pub routes: Vec<PlannedRoute>,
+}
+
+/// The route one resolved address gets in one zone.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PlannedRoute {
+    pub ip: IpAddr,
+    pub zone: String,
+    /// Where the route points, e.g. `via 198.51.100.1` or `blackhole`
+    pub target: String,
+    /// Prefix covering the address: the tracked one if already installed,
+    /// else the one that would be installed. None if excluded.
+    pub prefix: Option<String>,
+    pub installed: bool,
+    /// In the zone's excluded ranges, so not routed
+    pub excluded: bool,
+}
+
+/// A zone's tracked kernel routes, for the admin API.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct ZoneRoutes {
+    /// Installed prefixes (aggregates and direct routes) as CIDRs
+    pub routes: Vec<String>,
+    /// Resolved IPs the routes cover
+    pub ips: usize,
+    /// Taken out of the kernel (gateway down or zone disabled)
+    pub withdrawn: bool,
 }
 
 impl DnsHandler {
-    pub fn new(config: Config, matcher: ZoneMatcher) -> anyhow::Result<Self> {
-        let route_manager = RouteManager::new(config.server.route_aggregation_prefix)?;
+    pub fn new(config: Config, matcher: ZoneMatcher) -> error::Result<Self> {
+        let events = Arc::new(Events::new(config.hooks.clone()));
+        let route_manager =
+            RouteManager::new(config.server.route_aggregation_prefix, &config.routing)?
+                .with_events(Arc::clone(&events));
         let cache = Arc::new(DnsCache::new(config.server.cache_size));
+        let geoip = open_geoip(&config)?;
+        let query_log = open_query_log(&config.server)?;
+        let rate_limiter = RateLimitSettings::from_config(&config.server)
+            .map(|settings| Arc::new(RateLimiter::new(settings)));
+        let forward_limiter = ForwardLimiter::from_config(&config.server).map(Arc::new);
+        let docker = docker_networks(&config.server);
+        let local_zones = LocalZones::load(&config.local_zones)?;
+        let routes_enabled = !config.routing.is_disabled();
 
         Ok(Self {
             config: Arc::new(config),
             matcher: Arc::new(matcher),
             route_manager: Arc::new(RwLock::new(route_manager)),
+            routes_enabled,
             cache,
+            remote_routes: Arc::default(),
+            geoip,
+            stats: Arc::new(QueryStats::new()),
+            top: Arc::new(TopDomains::new()),
+            disabled_zones: Arc::default(),
+            endpoints: Arc::default(),
+            query_log,
+            rate_limiter,
+            forward_limiter,
+            docker,
+            udp_pool: Arc::new(UdpPool::new()),
+            fake_ips: Arc::new(FakeIps::new()),
+            routed_names: Arc::new(RoutedNames::new()),
+            local_zones: Arc::new(local_zones),
+            events,
         })
     }
 
     async fn forward_query(
         &self,
-        request: &Request,
+        query_msg: &Message,
         upstream: SocketAddr,
     ) -> Result<Message, ResponseCode> {
-        // Create UDP socket
-        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0")
-            .await
-            .map_err(|e| {
-                tracing::error!(error = %e, "Failed to bind UDP socket");
-                ResponseCode::ServFail
-            })?;
-
-        // Connect to upstream
-        socket.connect(upstream).await.map_err(|e| {
-            tracing::error!(upstream = %upstream, error = %e, "Failed to connect to upstream");
-            ResponseCode::ServFail
-        })?;
-
-        // Serialize the DNS query message
-        let query_msg = Message::new();
-        let mut query_msg = query_msg.clone();
-        query_msg.add_query(hickory_proto::op::Query::query(
-            request.query().name().clone().into(),
-            request.query().query_type(),
-        ));
-        query_msg.set_id(request.id());
-        query_msg.set_message_type(MessageType::Query);
-        query_msg.set_op_code(request.op_code());
-        query_msg.set_recursion_desired(request.recursion_desired());
-
-        let request_bytes = query_msg.to_vec().map_err(|e| {
-            tracing::error!(error = %e, "Failed to serialize query");
-            ResponseCode::ServFail
-        })?;
-
-        // Send request
-        socket.send(&request_bytes).await.map_err(|e| {
+        let mut sent = outgoing_query(query_msg, self.config.server.randomize_case);
+        let mut pending = self.udp_pool.send(upstream, &mut sent).await.map_err(|e| {
             tracing::error!(upstream = %upstream, error = %e, "Failed to send request");
             ResponseCode::ServFail
         })?;
 
-        // Receive response with timeout
-        let mut buf = vec![0u8; 4096];
-        let len = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv(&mut buf))
-            .await
-            .map_err(|_| {
-                tracing::warn!(upstream = %upstream, "Query timeout");
-                ResponseCode::ServFail
-            })?
-            .map_err(|e| {
-                tracing::error!(upstream = %upstream, error = %e, "Failed to receive response");
-                ResponseCode::ServFail
-            })?;
-
-        // Parse response
-        Message::from_vec(&buf[..len]).map_err(|e| {
-            tracing::error!(error = %e, "Failed to parse response");
-            ResponseCode::ServFail
-        })
+        // Receive responses until one answers the query, or time out
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let response = tokio::time::timeout_at(deadline, pending.recv())
+                .await
+                .map_err(|_| {
+                    tracing::warn!(upstream = %upstream, "Query timeout");
+                    ResponseCode::ServFail
+                })?
+                .map_err(|e| {
+                    tracing::error!(upstream = %upstream, error = %e, "Failed to receive response");
+                    ResponseCode::ServFail
+                })?;
+
+            match Message::from_vec(&response) {
+                Ok(response) if answers_query(&sent, &response) => {
+                    return Ok(restore_query(response, query_msg));
+                }
+                Ok(_) => {
+                    tracing::warn!(upstream = %upstream, "Dropped response not matching the query");
+                }
+                Err(e) => {
+                    tracing::warn!(upstream = %upstream, error = %e, "Dropped unparseable response");
+                }
+            }
+        }
     }
 
     async fn forward_query_tcp(
         &self,
-        request: &Request,
+        query_msg: &Message,
         upstream: SocketAddr,
     ) -> Result<Message, ResponseCode> {
         let mut stream = tokio::time::timeout(
@@ -113,18 +231,8 @@ impl DnsHandler {
             ResponseCode::ServFail
         })?;
 
-        // Build query message
-        let mut query_msg = Message::new();
-        query_msg.add_query(hickory_proto::op::Query::query(
-            request.query().name().clone().into(),
-            request.query().query_type(),
-        ));
-        query_msg.set_id(request.id());
-        query_msg.set_message_type(MessageType::Query);
-        query_msg.set_op_code(request.op_code());
-        query_msg.set_recursion_desired(request.recursion_desired());
-
-        let request_bytes = query_msg.to_vec().map_err(|e| {
+        let sent = outgoing_query(query_msg, self.config.server.randomize_case);
+        let request_bytes = sent.to_vec().map_err(|e| {
             tracing::error!(error = %e, "Failed to serialize query");
             ResponseCode::ServFail
         })?;
@@ -170,130 +278,1506 @@ impl DnsHandler {
             ResponseCode::ServFail
         })?;
 
-        Message::from_vec(&buf).map_err(|e| {
+        let response = Message::from_vec(&buf).map_err(|e| {
             tracing::error!(error = %e, "Failed to parse TCP response");
             ResponseCode::ServFail
+        })?;
+        if !answers_query(&sent, &response) {
+            tracing::warn!(upstream = %upstream, "TCP response doesn't match the query");
+            return Err(ResponseCode::ServFail);
+        }
+        Ok(restore_query(response, query_msg))
+    }
+
+    /// Upstream servers to try in order for a query matching `zone`, and
+    /// the protocol to use.
+    fn upstreams_for<'a>(
+        &'a self,
+        qname: &str,
+        qtype: RecordType,
+        zone: Option<&'a MatchedZone>,
+    ) -> (Vec<(SocketAddr, Option<&'a DnsServerConfig>)>, DnsProtocol) {
+        // A zone's servers for the query type, else its servers for any
+        let servers = zone.map(|z| {
+            z.config
+                .type_dns_servers
+                .get(&qtype.to_string())
+                .unwrap_or(&z.config.dns_servers)
+        });
+        match (zone, servers) {
+            (Some(z), Some(servers)) if !servers.is_empty() => {
+                tracing::debug!(
+                    qname = qname,
+                    qtype = ?qtype,
+                    zone = z.config.name,
+                    servers = ?servers.iter().map(|s| s.address).collect::<Vec<_>>(),
+                    protocol = ?z.config.dns_protocol,
+                    "Routing to zone DNS"
+                );
+                let ups = servers.iter().map(|s| (s.address, Some(s))).collect();
+                (ups, z.config.dns_protocol)
+            }
+            _ => {
+                tracing::debug!(
+                    qname = qname,
+                    upstreams = ?self.config.server.default_upstream,
+                    "Routing to default DNS"
+                );
+                let ups = self
+                    .config
+                    .server
+                    .default_upstream
+                    .iter()
+                    .map(|&a| (a, None))
+                    .collect();
+                (ups, DnsProtocol::Udp)
+            }
+        }
+    }
+
+    /// Zones the IPs a query for `qname` resolves to are routed through.
+    fn route_zones(&self, qname: &str, client: Option<IpAddr>) -> RouteZones {
+        let matched_zones: Vec<MatchedZone> = match self.config.server.match_mode {
+            MatchMode::First => self.matcher.find_zone(qname, client).into_iter().collect(),
+            MatchMode::All => self.matcher.find_zones(qname, client),
+        };
+        let matched: Vec<MatchedZone> = matched_zones
+            .into_iter()
+            .filter(|z| !self.is_zone_disabled(&z.config.name))
+            .collect();
+
+        // Domain matches of inclusive zones win; otherwise each IP may be
+        // routed by the zone claiming its country
+        let geoip = match matched.first() {
+            Some(z) if z.config.mode == ZoneMode::Inclusive => None,
+            _ => self.geoip.clone(),
+        };
+        RouteZones {
+            matched,
+            geoip,
+            matcher: Arc::clone(&self.matcher),
+            disabled: self.disabled_zones.read().unwrap().clone(),
+            client,
+        }
+    }
+
+    /// Install routes for the answer's addresses in the background. None
+    /// when the answer needs no routes.
+    fn add_routes_from_response(
+        &self,
+        message: &Message,
+        qname: &str,
+        client: IpAddr,
+    ) -> Option<RouteTask> {
+        if !self.routes_enabled {
+            return None;
+        }
+        let route_zones = self.route_zones(qname, Some(client));
+        if route_zones.is_empty() {
+            return None; // No zone match, no routing needed
+        }
+
+        let ips = answer_ips(message);
+        if ips.is_empty() {
+            tracing::debug!(qname = qname, "No A/AAAA records in response");
+            return None;
+        }
+        let wait = self.route_wait(&route_zones.matched);
+
+        // Add routes in background (don't block DNS response)
+        let route_manager = Arc::clone(&self.route_manager);
+        let stats = Arc::clone(&self.stats);
+        let top = Arc::clone(&self.top);
+        let routed_names = Arc::clone(&self.routed_names);
+        let endpoints = Arc::clone(&self.endpoints);
+        let qname = qname.to_string();
+        let (done, installed) = oneshot::channel();
+
+        let added = tokio::spawn(async move {
+            let mut added = Vec::new();
+            let mut failed = false;
+            for ip in ips {
+                for matched_zone in route_zones.for_ip(ip) {
+                    // Per-zone exclusion check (exclude_routes, and exclusive
+                    // zones' static_routes), and tunnel endpoints
+                    if matched_zone.is_excluded(ip) || endpoints.read().unwrap().protects(ip) {
+                        tracing::debug!(
+                            ip = %ip,
+                            zone = matched_zone.config.name,
+                            "IP is in zone's excluded range, skipping route"
+                        );
+                        continue;
+                    }
+                    // Locked per install, not across the pacer waits of the
+                    // whole response
+                    let result = route_manager
+                        .read()
+                        .await
+                        .add_route(ip, &matched_zone.config)
+                        .await;
+                    if result.is_ok() {
+                        routed_names.record(ip, &qname, &matched_zone.config.name);
+                    }
+                    match result {
+                        Ok(true) => {
+                            stats.record_route_installed(&matched_zone.config.name);
+                            added.push(AddedRoute {
+                                ip,
+                                zone: matched_zone.config.name.clone(),
+                            });
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            failed = true;
+                            tracing::warn!(
+                                ip = %ip,
+                                zone = matched_zone.config.name,
+                                qname = qname,
+                                error = %e,
+                                "Failed to add route"
+                            )
+                        }
+                    }
+                }
+            }
+            let _ = done.send(!failed);
+            if !added.is_empty() {
+                top.record_routes(&qname, added.len() as u64);
+            }
+            added
+        });
+        Some(RouteTask {
+            added,
+            installed,
+            wait,
         })
     }
 
-    async fn add_routes_from_response(&self, message: &Message, qname: &str) {
-        let matched_zone = match self.matcher.find_zone(qname) {
-            Some(z) => z,
-            None => return, // No zone match, no routing needed
+    /// How long an answer for names of `zones` waits for its routes, from
+    /// each zone's `route_failure_mode` and `route_wait_ms` or the server's:
+    /// the longest of the zones asking for "servfail", None if none does.
+    /// Answers routed only by GeoIP follow the server's.
+    fn route_wait(&self, zones: &[MatchedZone]) -> Option<Duration> {
+        let server = &self.config.server;
+        let wait = |mode: Option<RouteFailureMode>, wait_ms: Option<u64>| {
+            (mode.unwrap_or(server.route_failure_mode) == RouteFailureMode::Servfail)
+                .then(|| wait_ms.unwrap_or(server.route_wait_ms))
+        };
+        let wait_ms = if zones.is_empty() {
+            wait(None, None)
+        } else {
+            zones
+                .iter()
+                .filter_map(|z| wait(z.config.route_failure_mode, z.config.route_wait_ms))
+                .max()
+        };
+        wait_ms.map(Duration::from_millis)
+    }
+
+    /// Whether an answer may go out: in "servfail" mode, only once all its
+    /// routes are in, waited for up to the zones' `route_wait_ms`.
+    async fn routes_ready(&self, routes: Option<&mut RouteTask>, qname: &str) -> bool {
+        let Some(RouteTask {
+            installed,
+            wait: Some(wait),
+            ..
+        }) = routes
+        else {
+            return true;
         };
+        match tokio::time::timeout(*wait, installed).await {
+            Ok(Ok(true)) => true,
+            Ok(_) => {
+                tracing::warn!(
+                    qname = qname,
+                    "Route installation failed, answering SERVFAIL"
+                );
+                false
+            }
+            Err(_) => {
+                tracing::warn!(
+                    qname = qname,
+                    wait_ms = wait.as_millis() as u64,
+                    "Routes not installed in time, answering SERVFAIL"
+                );
+                false
+            }
+        }
+    }
 
-        // Extract A and AAAA records from answers
-        let ips: Vec<IpAddr> = message
-            .answers()
+    /// Answer from the fake-IP pools: a synthetic address for a name of a
+    /// `fake_ip_pool` zone (and no AAAA, so clients use it), or the name a
+    /// pool address was handed out for in reverse. None for queries the
+    /// pools don't answer.
+    fn fake_ip_answer(
+        &self,
+        zone: Option<&MatchedZone>,
+        qname: &str,
+        qtype: RecordType,
+    ) -> Option<(Vec<RData>, u32)> {
+        match qtype {
+            RecordType::PTR => {
+                let name = self.fake_ips.name(docker::ptr_address(qname)?)?;
+                let names: Vec<String> = (!name.is_empty())
+                    .then(|| format!("{name}."))
+                    .into_iter()
+                    .collect();
+                Some((ptr_rdata(&names), FAKE_IP_TTL))
+            }
+            RecordType::A | RecordType::AAAA => {
+                let pool = zone?.config.fake_ip_pool.as_deref()?;
+                if qtype == RecordType::AAAA {
+                    return Some((Vec::new(), FAKE_IP_TTL));
+                }
+                let ip = self.fake_ips.assign(pool, qname)?;
+                Some((vec![RData::A(ip.into())], FAKE_IP_TTL))
+            }
+            _ => None,
+        }
+    }
+
+    /// An upstream answer for `zone` as clients see it: without private
+    /// addresses with `reject_private_answers`, then the zone's rewrite
+    /// rules applied.
+    fn filter_answer(
+        &self,
+        zone: Option<&MatchedZone>,
+        qname: &str,
+        qtype: RecordType,
+        mut response: Message,
+    ) -> Message {
+        let reject_private = zone
+            .and_then(|z| z.config.reject_private_answers)
+            .unwrap_or(self.config.server.reject_private_answers);
+        if reject_private {
+            let dropped;
+            (response, dropped) = rewrite::drop_private(response);
+            if dropped > 0 {
+                tracing::warn!(
+                    qname = qname,
+                    dropped = dropped,
+                    "Dropped private addresses from upstream answer"
+                );
+            }
+        }
+        match zone {
+            Some(z) => rewrite::apply(&z.config, qtype, response),
+            None => response,
+        }
+    }
+
+    /// Try the single-label name of `query` under each `search_domains`
+    /// suffix, each a query of its own through its zone, until one exists.
+    /// Its answer comes back as the answer to `query`, behind a CNAME from
+    /// the asked-for name, with the routes being installed for it.
+    async fn expand_search(
+        &self,
+        query: &Message,
+        client: IpAddr,
+    ) -> Option<(Message, Option<RouteTask>)> {
+        let question = query.queries().first()?;
+        for domain in &self.config.server.search_domains {
+            let Ok(expanded) = Name::from_ascii(domain)
+                .and_then(|domain| question.name().clone().append_domain(&domain))
+            else {
+                continue;
+            };
+            let qname = expanded.to_string();
+            let zone = self
+                .matcher
+                .find_zone(&qname, Some(client))
+                .filter(|z| !self.is_zone_disabled(&z.config.name));
+            let (upstreams, protocol) =
+                self.upstreams_for(&qname, question.query_type(), zone.as_ref());
+            let expanded_query =
+                query_message(expanded.clone(), question.query_type(), rand_id(), true);
+            for (upstream, _) in &upstreams {
+                let res = match protocol {
+                    DnsProtocol::Udp => self.forward_query(&expanded_query, *upstream).await,
+                    DnsProtocol::Tcp => self.forward_query_tcp(&expanded_query, *upstream).await,
+                };
+                let Ok(response) = res else {
+                    continue;
+                };
+                match response.response_code() {
+                    ResponseCode::ServFail | ResponseCode::Refused => continue,
+                    ResponseCode::NoError => {}
+                    _ => break, // Not under this suffix either
+                }
+                tracing::debug!(qname = qname, "Resolved under search domain");
+                let response =
+                    self.filter_answer(zone.as_ref(), &qname, question.query_type(), response);
+                let routes = self.add_routes_from_response(&response, &qname, client);
+                return Some((search_answer(query, expanded, response), routes));
+            }
+        }
+        None
+    }
+
+    fn dns64_prefix(&self) -> Option<Ipv6Addr> {
+        dns64::parse_prefix(self.config.server.dns64_prefix.as_deref()?)
+    }
+
+    /// Ask `upstream` for the A records of an AAAA query's name and answer
+    /// with them mapped into the DNS64 prefix. None if it has none.
+    async fn synthesize_aaaa(
+        &self,
+        aaaa_query: &Message,
+        upstream: SocketAddr,
+        protocol: DnsProtocol,
+        prefix: Ipv6Addr,
+        aaaa_response: &Message,
+    ) -> Option<Message> {
+        let a_query = query_message(
+            aaaa_query.queries().first()?.name().clone(),
+            RecordType::A,
+            aaaa_query.id(),
+            aaaa_query.recursion_desired(),
+        );
+        let a_response = match protocol {
+            DnsProtocol::Udp => self.forward_query(&a_query, upstream).await,
+            DnsProtocol::Tcp => self.forward_query_tcp(&a_query, upstream).await,
+        };
+        let synthesized = dns64::synthesize(aaaa_response, &a_response.ok()?, prefix)?;
+        tracing::debug!(
+            name = %a_query.queries()[0].name(),
+            answers = synthesized.answers().len(),
+            "Synthesized DNS64 answer"
+        );
+        Some(synthesized)
+    }
+
+    /// Synthetic addresses handed out by the fake-IP pools.
+    pub fn fake_ips(&self) -> Vec<FakeIpEntry> {
+        self.fake_ips.entries()
+    }
+
+    /// Every routed address and the name that got it routed.
+    pub fn routed_names(&self) -> Vec<RoutedName> {
+        self.routed_names.entries()
+    }
+
+    /// The name that got `ip` routed, if any did.
+    pub fn routed_name(&self, ip: IpAddr) -> Option<RoutedName> {
+        self.routed_names.get(ip)
+    }
+
+    /// Answer a reverse query for a routed address with the name that got
+    /// it routed. None for other queries and addresses.
+    fn routed_name_answer(&self, qname: &str, qtype: RecordType) -> Option<(Vec<RData>, u32)> {
+        if qtype != RecordType::PTR {
+            return None;
+        }
+        let entry = self.routed_names.get(docker::ptr_address(qname)?)?;
+        Some((ptr_rdata(&[format!("{}.", entry.name)]), ROUTED_PTR_TTL))
+    }
+
+    /// Count a failed upstream attempt, reporting an upstream that just
+    /// went from healthy to failing.
+    fn record_upstream_failure(
+        &self,
+        zone_name: Option<&str>,
+        upstream: SocketAddr,
+        protocol: DnsProtocol,
+        failure: UpstreamFailure,
+    ) {
+        let failures = self
+            .stats
+            .record_upstream_failure(zone_name, upstream, protocol, failure);
+        if failures == 1 {
+            self.events.emit(Event::UpstreamUnhealthy {
+                upstream,
+                zone: zone_name.map(str::to_string),
+            });
+        }
+    }
+
+    /// Write `entry` to the query log, if enabled, once `routes` are added.
+    fn log_query(&self, mut entry: QueryLogEntry, routes: Option<RouteTask>) {
+        let Some(query_log) = &self.query_log else {
+            return;
+        };
+        let query_log = Arc::clone(query_log);
+        tokio::spawn(async move {
+            if let Some(routes) = routes {
+                entry.routes_added = routes.added.await.unwrap_or_default();
+            }
+            query_log.record(entry);
+        });
+    }
+
+    /// Send one `qtype` query for `name` straight to `upstream`: the
+    /// response code, or None when it didn't answer.
+    pub async fn probe_upstream(
+        &self,
+        upstream: SocketAddr,
+        protocol: DnsProtocol,
+        name: &str,
+        qtype: RecordType,
+    ) -> error::Result<Option<ResponseCode>> {
+        let mut name = Name::from_ascii(name)
+            .map_err(|e| LeshyError::Parse(format!("invalid name '{name}': {e}")))?;
+        name.set_fqdn(true);
+        let query_msg = query_message(name, qtype, rand_id(), true);
+        let res = match protocol {
+            DnsProtocol::Udp => self.forward_query(&query_msg, upstream).await,
+            DnsProtocol::Tcp => self.forward_query_tcp(&query_msg, upstream).await,
+        };
+        Ok(res.ok().map(|response| response.response_code()))
+    }
+
+    /// Resolve `name` the way a query from `client` would be, without
+    /// caching, counting or installing anything: the zone it matches, the
+    /// upstreams tried, the addresses returned and the routes they get.
+    pub async fn explain(&self, name: &str, client: Option<IpAddr>) -> error::Result<Resolution> {
+        let mut name = Name::from_ascii(name)
+            .map_err(|e| LeshyError::Parse(format!("invalid name '{name}': {e}")))?;
+        name.set_fqdn(true);
+        let qname = name.to_string();
+
+        let zone = self
+            .matcher
+            .find_zone(&qname, client)
+            .filter(|z| !self.is_zone_disabled(&z.config.name));
+        let (upstreams, protocol) = self.upstreams_for(&qname, RecordType::A, zone.as_ref());
+
+        let mut answered_by = None;
+        let mut rcode = ResponseCode::ServFail;
+        let mut addresses = Vec::new();
+        for qtype in [RecordType::A, RecordType::AAAA] {
+            let query_msg = query_message(name.clone(), qtype, rand_id(), true);
+            let (upstreams, protocol) = self.upstreams_for(&qname, qtype, zone.as_ref());
+            for (upstream, _) in &upstreams {
+                let res = match protocol {
+                    DnsProtocol::Udp => self.forward_query(&query_msg, *upstream).await,
+                    DnsProtocol::Tcp => self.forward_query_tcp(&query_msg, *upstream).await,
+                };
+                let res =
+                    res.map(|response| self.filter_answer(zone.as_ref(), &qname, qtype, response));
+                let code = match &res {
+                    Ok(response) => response.response_code(),
+                    Err(code) => *code,
+                };
+                if qtype == RecordType::A {
+                    rcode = code;
+                }
+                if let Ok(response) = res {
+                    if code != ResponseCode::ServFail && code != ResponseCode::Refused {
+                        if qtype == RecordType::A {
+                            answered_by = Some(*upstream);
+                        }
+                        addresses.extend(answer_ips(&response));
+                        break;
+                    }
+                }
+            }
+        }
+
+        let route_zones = self.route_zones(&qname, client);
+        let manager = self.route_manager.read().await;
+        let mut routes = Vec::new();
+        for &ip in &addresses {
+            for matched_zone in route_zones.for_ip(ip) {
+                let zone = &matched_zone.config;
+                let excluded = matched_zone.is_excluded(ip) || self.is_endpoint(ip);
+                let tracked = manager
+                    .tracked_prefixes(&zone.name)
+                    .await
+                    .into_iter()
+                    .filter(|&(network, prefix_len)| {
+                        crate::routing::prefix_contains(network, prefix_len, ip)
+                    })
+                    .max_by_key(|&(_, prefix_len)| prefix_len);
+                let prefix = match (excluded, tracked) {
+                    (true, _) => None,
+                    (false, Some((network, prefix_len))) => Some(format!("{network}/{prefix_len}")),
+                    (false, None) => Some(self.route_prefix(ip)),
+                };
+                let route_type = format!("{:?}", zone.route_type).to_lowercase();
+                routes.push(PlannedRoute {
+                    ip,
+                    zone: zone.name.clone(),
+                    target: format!("{route_type} {}", zone.route_target)
+                        .trim_end()
+                        .to_string(),
+                    prefix,
+                    installed: !excluded && tracked.is_some(),
+                    excluded,
+                });
+            }
+        }
+
+        Ok(Resolution {
+            name: qname,
+            zone: zone.as_ref().map(|z| z.config.name.clone()),
+            upstreams: upstreams.iter().map(|&(upstream, _)| upstream).collect(),
+            protocol,
+            answered_by,
+            rcode: rcode.to_string(),
+            addresses,
+            routes,
+        })
+    }
+
+    /// Prefix a new route for `ip` is installed as: its aggregate under
+    /// `route_aggregation_prefix`, else the address itself.
+    fn route_prefix(&self, ip: IpAddr) -> String {
+        match (ip, self.config.server.route_aggregation_prefix) {
+            (IpAddr::V4(v4), Some(prefix_len)) if prefix_len < 32 => {
+                let mask = u32::MAX << (32 - prefix_len);
+                let network = std::net::Ipv4Addr::from(u32::from(v4) & mask);
+                format!("{network}/{prefix_len}")
+            }
+            (IpAddr::V4(v4), _) => format!("{v4}/32"),
+            (IpAddr::V6(v6), _) => format!("{v6}/128"),
+        }
+    }
+
+    /// Get current config
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Where lifecycle events are reported for `[[hooks]]`.
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Per-zone query counters, by zone name (`(default)` for queries no
+    /// zone matched).
+    pub fn zone_stats(&self) -> std::collections::BTreeMap<String, ZoneStats> {
+        self.stats.snapshot()
+    }
+
+    /// Answer and failure counters of every upstream queried so far.
+    pub fn upstream_stats(&self) -> BTreeMap<(SocketAddr, DnsProtocol), UpstreamStats> {
+        self.stats.upstream_snapshot()
+    }
+
+    /// The most queried, slowest and most route-generating names lately.
+    pub fn top_domains(&self, limit: usize) -> TopReport {
+        self.top.report(limit)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Live cached answers whose name matches `pattern` (all if None).
+    pub fn cache_entries(&self, pattern: Option<&glob::Pattern>) -> Vec<CacheEntryInfo> {
+        self.cache.entries(pattern)
+    }
+
+    /// Drop cached answers whose name matches `pattern`. Returns the
+    /// number of entries dropped.
+    pub fn purge_cache(&self, pattern: &glob::Pattern) -> usize {
+        self.cache.purge(pattern)
+    }
+
+    /// Drop every cached answer. Returns the number of entries dropped.
+    pub fn flush_cache(&self) -> usize {
+        self.cache.retain(|_, _| false)
+    }
+
+    /// Tracked routes of every configured zone, and of zones restored
+    /// from the state file that are no longer configured.
+    pub async fn zone_routes(&self) -> BTreeMap<String, ZoneRoutes> {
+        let manager = self.route_manager.read().await;
+        let mut zones: HashSet<String> = manager.tracked_zones().await.into_iter().collect();
+        zones.extend(self.config.zones.iter().map(|z| z.name.clone()));
+        let mut routes = BTreeMap::new();
+        for zone in zones {
+            let mut prefixes = manager.tracked_prefixes(&zone).await;
+            prefixes.sort();
+            let zone_routes = ZoneRoutes {
+                routes: prefixes
+                    .into_iter()
+                    .map(|(ip, prefix_len)| format!("{ip}/{prefix_len}"))
+                    .collect(),
+                ips: manager.get_zone_route_count(&zone).await,
+                withdrawn: manager.is_withdrawn(&zone).await,
+            };
+            routes.insert(zone, zone_routes);
+        }
+        routes
+    }
+
+    pub fn is_zone_disabled(&self, zone_name: &str) -> bool {
+        self.disabled_zones.read().unwrap().contains(zone_name)
+    }
+
+    /// Stop applying a zone until `enable_zone`: its queries resolve as if
+    /// no zone matched, and its routes are taken out of the kernel (but
+    /// stay tracked). Returns false if it was already disabled.
+    pub async fn disable_zone(&self, zone_name: &str) -> error::Result<bool> {
+        if !self.config.zones.iter().any(|z| z.name == zone_name) {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        }
+        if !self
+            .disabled_zones
+            .write()
+            .unwrap()
+            .insert(zone_name.to_string())
+        {
+            return Ok(false);
+        }
+        self.drop_cached_zone(zone_name);
+        tracing::info!(zone = zone_name, "Zone disabled");
+        let manager = self.route_manager.read().await;
+        manager.withdraw_zone(zone_name).await?;
+        Ok(true)
+    }
+
+    /// Apply a zone turned off by `disable_zone` again and reinstall its
+    /// routes. Returns false if it wasn't disabled.
+    pub async fn enable_zone(&self, zone_name: &str) -> error::Result<bool> {
+        let Some(zone) = self.config.zones.iter().find(|z| z.name == zone_name) else {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        };
+        if !self.disabled_zones.write().unwrap().remove(zone_name) {
+            return Ok(false);
+        }
+        self.drop_cached_zone(zone_name);
+        tracing::info!(zone = zone_name, "Zone enabled");
+        let manager = self.route_manager.read().await;
+        manager.restore_zone(zone).await?;
+        Ok(true)
+    }
+
+    /// Drop cached answers resolved through `zone_name`, and those of
+    /// names it matches that were resolved without it.
+    fn drop_cached_zone(&self, zone_name: &str) {
+        self.cache.retain(|key, zone| match zone {
+            Some(zone) => zone != zone_name,
+            None => self
+                .matcher
+                .find_zone(key, None)
+                .is_none_or(|z| z.config.name != zone_name),
+        });
+    }
+
+    /// Cleanup routes for a specific zone, honoring its `cleanup_mode`
+    pub async fn cleanup_zone(&self, zone_name: &str) -> error::Result<()> {
+        let mode = self
+            .config
+            .zones
+            .iter()
+            .find(|z| z.name == zone_name)
+            .map(|z| z.cleanup_mode)
+            .unwrap_or_default();
+        self.routed_names.forget_zone(zone_name);
+        let manager = self.route_manager.read().await;
+        manager.cleanup_zone(zone_name, mode).await
+    }
+
+    /// Delete the routes of every zone with `cleanup_mode = "delete"`, or
+    /// of every tracked zone with `on_shutdown = "flush"`.
+    /// Called once on shutdown.
+    pub async fn cleanup_on_shutdown(&self) {
+        if !self.routes_enabled {
+            return;
+        }
+        if self.config.routing.on_shutdown == ShutdownMode::Flush {
+            let manager = self.route_manager.read().await;
+            let zones = manager.tracked_zones().await;
+            tracing::info!(zones = zones.len(), "Flushing all leshy routes");
+            for zone in &zones {
+                if let Err(e) = manager.cleanup_zone(zone, CleanupMode::Delete).await {
+                    tracing::error!(zone = zone, error = %e, "Failed to flush zone on shutdown");
+                }
+            }
+            return;
+        }
+
+        for zone in &self.config.zones {
+            if zone.cleanup_mode != CleanupMode::Delete {
+                continue;
+            }
+            if let Err(e) = self.cleanup_zone(&zone.name).await {
+                tracing::error!(zone = zone.name, error = %e, "Failed to cleanup zone on shutdown");
+            }
+        }
+    }
+
+    /// Apply static routes for all zones that have them.
+    /// Returns the number of failed routes (0 = all applied successfully).
+    pub async fn apply_static_routes(&self) -> usize {
+        if !self.routes_enabled {
+            return 0;
+        }
+        let route_manager = self.route_manager.read().await;
+        let mut failures = 0;
+        for zone in &self.config.zones {
+            // Exclusive zones use static_routes as exclusion ranges, not actual routes
+            if zone.mode == ZoneMode::Exclusive {
+                continue;
+            }
+            for cidr in zone.static_routes.iter().chain(&zone.fake_ip_pool) {
+                if let Err(e) = route_manager.add_static_route(cidr, zone).await {
+                    tracing::warn!(
+                        cidr = cidr,
+                        zone = zone.name,
+                        error = %e,
+                        "Failed to add static route"
+                    );
+                    failures += 1;
+                }
+            }
+        }
+        failures
+    }
+
+    /// Keep `routes`' tunnel endpoints out of every zone's routes and
+    /// route them as static routes, replacing what the previous call
+    /// applied. Routes that fail to apply are retried by the next call.
+    pub async fn protect_endpoints(&self, routes: EndpointRoutes) {
+        if !self.routes_enabled {
+            return;
+        }
+        let previous = {
+            let mut endpoints = self.endpoints.write().unwrap();
+            endpoints.endpoints = routes.endpoints;
+            endpoints.zone.take()
+        };
+        let route_manager = self.route_manager.read().await;
+        // Routes via the same gateway stay as they are
+        let kept: Vec<String> = match (&previous, &routes.zone) {
+            (Some(old), Some(new)) if old.route_target == new.route_target => old
+                .static_routes
+                .iter()
+                .filter(|cidr| new.static_routes.contains(cidr))
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        if let Some(old) = &previous {
+            for cidr in old.static_routes.iter().filter(|cidr| !kept.contains(cidr)) {
+                if let Err(e) = route_manager.remove_static_route(cidr, old).await {
+                    tracing::warn!(cidr = cidr, error = %e, "Failed to remove VPN endpoint route");
+                }
+            }
+        }
+
+        let Some(mut zone) = routes.zone else {
+            return;
+        };
+        let mut applied = kept;
+        for cidr in &zone.static_routes {
+            if applied.contains(cidr) {
+                continue;
+            }
+            match route_manager.add_static_route(cidr, &zone).await {
+                Ok(()) => applied.push(cidr.clone()),
+                Err(e) => {
+                    tracing::warn!(cidr = cidr, error = %e, "Failed to add VPN endpoint route")
+                }
+            }
+        }
+        zone.static_routes = applied;
+        self.endpoints.write().unwrap().zone = Some(zone);
+    }
+
+    fn is_endpoint(&self, ip: IpAddr) -> bool {
+        self.endpoints.read().unwrap().protects(ip)
+    }
+
+    /// Bring a zone's routes from its `static_routes_url` in line with a
+    /// freshly fetched list: add new prefixes and remove dropped ones.
+    /// Prefixes that fail to apply are retried on the next sync.
+    pub async fn sync_remote_routes(
+        &self,
+        zone_name: &str,
+        prefixes: Vec<String>,
+    ) -> error::Result<()> {
+        let Some(zone) = self.config.zones.iter().find(|z| z.name == zone_name) else {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        };
+        let route_manager = self.route_manager.read().await;
+        let mut remote_routes = self.remote_routes.lock().await;
+        let applied = remote_routes.entry(zone_name.to_string()).or_default();
+        let wanted: HashSet<String> = prefixes.into_iter().collect();
+
+        let mut added = 0;
+        let mut removed = 0;
+        let mut failures = 0;
+        for cidr in wanted.difference(&applied.clone()) {
+            match route_manager.add_static_route(cidr, zone).await {
+                Ok(()) => {
+                    applied.insert(cidr.clone());
+                    added += 1;
+                }
+                Err(e) => {
+                    tracing::warn!(cidr = cidr, zone = zone_name, error = %e, "Failed to add remote route");
+                    failures += 1;
+                }
+            }
+        }
+        let stale: Vec<String> = applied.difference(&wanted).cloned().collect();
+        for cidr in stale {
+            // Also listed in static_routes: the route stays
+            if !zone.static_routes.contains(&cidr) {
+                if let Err(e) = route_manager.remove_static_route(&cidr, zone).await {
+                    tracing::warn!(cidr = cidr, zone = zone_name, error = %e, "Failed to remove remote route");
+                    failures += 1;
+                    continue;
+                }
+            }
+            applied.remove(&cidr);
+            removed += 1;
+        }
+
+        tracing::info!(
+            zone = zone_name,
+            prefixes = wanted.len(),
+            added = added,
+            removed = removed,
+            failed = failures,
+            "Synced remote static routes"
+        );
+        Ok(())
+    }
+
+    /// Remove routes fetched for zones that no longer have a
+    /// `static_routes_url` (called after a reload).
+    pub async fn prune_remote_routes(&self) {
+        let stale: Vec<String> = self
+            .remote_routes
+            .lock()
+            .await
+            .keys()
+            .filter(|name| {
+                !self
+                    .config
+                    .zones
+                    .iter()
+                    .any(|z| &z.name == *name && z.static_routes_url.is_some())
+            })
+            .cloned()
+            .collect();
+        for zone_name in stale {
+            // Removed zones were already cleaned up with their routes
+            if !self.config.zones.iter().any(|z| z.name == zone_name) {
+                self.remote_routes.lock().await.remove(&zone_name);
+                continue;
+            }
+            if let Err(e) = self.sync_remote_routes(&zone_name, Vec::new()).await {
+                tracing::warn!(zone = zone_name, error = %e, "Failed to remove remote routes");
+            }
+            self.remote_routes.lock().await.remove(&zone_name);
+        }
+    }
+
+    /// Resolve a `preresolve` zone's domains (A and AAAA) and route the
+    /// addresses, so the routes are in before clients connect. Returns how
+    /// many names resolved.
+    pub async fn preresolve(&self, zone_name: &str) -> error::Result<usize> {
+        let Some(zone) = self.matcher.zone(zone_name) else {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        };
+        if !self.routes_enabled || self.is_zone_disabled(zone_name) {
+            return Ok(0);
+        }
+
+        let mut resolved = 0;
+        for domain in &zone.config.domains {
+            if domain.starts_with("*.") {
+                continue;
+            }
+            let Ok(name) = Name::from_ascii(domain).and_then(|n| n.append_domain(&Name::root()))
+            else {
+                continue;
+            };
+            let qname = name.to_string();
+            let mut ips = Vec::new();
+            for qtype in [RecordType::A, RecordType::AAAA] {
+                let query_msg = query_message(name.clone(), qtype, rand_id(), true);
+                let (upstreams, protocol) = self.upstreams_for(&qname, qtype, Some(&zone));
+                for (upstream, _) in &upstreams {
+                    let res = match protocol {
+                        DnsProtocol::Udp => self.forward_query(&query_msg, *upstream).await,
+                        DnsProtocol::Tcp => self.forward_query_tcp(&query_msg, *upstream).await,
+                    };
+                    match res {
+                        Ok(response)
+                            if !matches!(
+                                response.response_code(),
+                                ResponseCode::ServFail | ResponseCode::Refused
+                            ) =>
+                        {
+                            let response = self.filter_answer(Some(&zone), &qname, qtype, response);
+                            ips.extend(answer_ips(&response));
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if ips.is_empty() {
+                tracing::debug!(
+                    zone = zone_name,
+                    qname = qname,
+                    "Preresolve found no addresses"
+                );
+                continue;
+            }
+            resolved += 1;
+
+            let manager = self.route_manager.read().await;
+            for ip in ips
+                .into_iter()
+                .filter(|&ip| !zone.is_excluded(ip) && !self.is_endpoint(ip))
+            {
+                match manager.add_route(ip, &zone.config).await {
+                    Ok(installed) => {
+                        self.routed_names.record(ip, &qname, zone_name);
+                        if installed {
+                            self.stats.record_route_installed(zone_name);
+                        }
+                    }
+                    Err(e) => tracing::warn!(
+                        ip = %ip,
+                        zone = zone_name,
+                        qname = qname,
+                        error = %e,
+                        "Failed to add preresolved route"
+                    ),
+                }
+            }
+        }
+        tracing::info!(
+            zone = zone_name,
+            names = resolved,
+            "Preresolved zone domains"
+        );
+        Ok(resolved)
+    }
+
+    /// Reinstall a zone's tracked routes, e.g. after its device changed.
+    pub async fn reapply_zone(&self, zone_name: &str) -> error::Result<()> {
+        let Some(zone) = self.config.zones.iter().find(|z| z.name == zone_name) else {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        };
+        let manager = self.route_manager.read().await;
+        manager.reapply_zone(zone).await?;
+        Ok(())
+    }
+
+    /// Take a zone's routes out of the kernel while its gateway is down.
+    pub async fn withdraw_zone(&self, zone_name: &str) -> error::Result<()> {
+        let manager = self.route_manager.read().await;
+        manager.withdraw_zone(zone_name).await?;
+        Ok(())
+    }
+
+    /// Reinstall a withdrawn zone's routes once its gateway is back
+    /// (unless the zone is disabled).
+    pub async fn restore_zone(&self, zone_name: &str) -> error::Result<()> {
+        let Some(zone) = self.config.zones.iter().find(|z| z.name == zone_name) else {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        };
+        if self.is_zone_disabled(zone_name) {
+            return Ok(());
+        }
+        let manager = self.route_manager.read().await;
+        manager.restore_zone(zone).await?;
+        Ok(())
+    }
+
+    /// `leshy hook up`: reinstall a zone's routes on its current device or
+    /// gateway, bringing back those `hook down` withdrew (a disabled zone's
+    /// stay out). Returns the number of routes reinstalled.
+    pub async fn tunnel_up(&self, zone_name: &str) -> error::Result<usize> {
+        let Some(zone) = self.config.zones.iter().find(|z| z.name == zone_name) else {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        };
+        if self.is_zone_disabled(zone_name) {
+            return Ok(0);
+        }
+        let manager = self.route_manager.read().await;
+        if manager.is_withdrawn(zone_name).await {
+            manager.restore_zone(zone).await
+        } else {
+            manager.reapply_zone(zone).await
+        }
+    }
+
+    /// `leshy hook down`: take a zone's routes out while its tunnel is
+    /// gone, keeping them tracked for `tunnel_up`. Returns the number of
+    /// routes removed.
+    pub async fn tunnel_down(&self, zone_name: &str) -> error::Result<usize> {
+        if !self.config.zones.iter().any(|z| z.name == zone_name) {
+            return Err(LeshyError::UnknownZone(zone_name.to_string()));
+        }
+        let manager = self.route_manager.read().await;
+        manager.withdraw_zone(zone_name).await
+    }
+
+    /// Install `ip_rules` for every zone (and drop rules zones no longer list).
+    /// Returns the number of zones whose rules failed to apply.
+    pub async fn apply_ip_rules(&self) -> usize {
+        if !self.routes_enabled {
+            return 0;
+        }
+        let route_manager = self.route_manager.read().await;
+        let mut failures = 0;
+        for zone in &self.config.zones {
+            if let Err(e) = route_manager.apply_rules(zone).await {
+                tracing::warn!(zone = zone.name, error = %e, "Failed to apply ip rules");
+                failures += 1;
+            }
+        }
+        failures
+    }
+
+    /// Whether routes are managed, i.e. routing isn't disabled.
+    pub fn routes_enabled(&self) -> bool {
+        self.routes_enabled
+    }
+
+    /// Counter that changes whenever tracked route state changes
+    pub async fn state_generation(&self) -> u64 {
+        self.route_manager.read().await.generation()
+    }
+
+    /// Persist tracked routes and aggregator state to `path`
+    pub async fn save_state(&self, path: &Path) -> error::Result<()> {
+        let manager = self.route_manager.read().await;
+        // Dry-run tracking describes routes that were never installed
+        if manager.is_dry_run() {
+            tracing::debug!(path = %path.display(), "Dry-run, not saving route state");
+            return Ok(());
+        }
+        let mut state = manager.snapshot().await;
+        for zone in &self.config.zones {
+            if let Some(zone_state) = state.zones.get_mut(&zone.name) {
+                zone_state.cleanup_mode = zone.cleanup_mode;
+            }
+        }
+        state.save(path)
+    }
+
+    /// Restore route state saved by a previous run. Zones that are no longer
+    /// configured are cleaned up according to their recorded cleanup mode.
+    pub async fn restore_state(&self, path: &Path) -> error::Result<()> {
+        let Some(state) = RouteState::load(path)? else {
+            tracing::debug!(path = %path.display(), "No route state file, starting fresh");
+            return Ok(());
+        };
+
+        let orphaned: Vec<(String, CleanupMode)> = state
+            .zones
+            .iter()
+            .filter(|(name, _)| !self.config.zones.iter().any(|z| &z.name == *name))
+            .map(|(name, zone_state)| (name.clone(), zone_state.cleanup_mode))
+            .collect();
+
+        tracing::info!(
+            path = %path.display(),
+            zones = state.zones.len(),
+            aggregates = state.aggregator.installed.len(),
+            "Restoring route state"
+        );
+
+        let manager = self.route_manager.read().await;
+        manager.restore(state).await;
+        for (zone_name, mode) in orphaned {
+            tracing::info!(
+                zone = zone_name,
+                "Zone from previous run is no longer configured"
+            );
+            if let Err(e) = manager.cleanup_zone(&zone_name, mode).await {
+                tracing::warn!(zone = zone_name, error = %e, "Failed to clean up orphaned zone");
+            }
+        }
+        Ok(())
+    }
+
+    /// Adopt leshy routes already present in the kernel into tracking.
+    pub async fn reconcile_routes(&self) -> error::Result<()> {
+        if !self.routes_enabled {
+            return Ok(());
+        }
+        let manager = self.route_manager.read().await;
+        manager.reconcile(&self.config.zones).await?;
+        Ok(())
+    }
+
+    /// Route installs queued by `routing.max_installs_per_second`.
+    pub async fn queued_route_installs(&self) -> usize {
+        self.route_manager.read().await.queued_installs()
+    }
+
+    /// Reinstall tracked routes that went missing from the kernel table
+    /// (`routing.audit_interval`). Returns how many were.
+    pub async fn audit_routes(&self) -> error::Result<usize> {
+        if !self.routes_enabled {
+            return Ok(0);
+        }
+        let manager = self.route_manager.read().await;
+        let endpoint_zone = self.endpoints.read().unwrap().zone.clone();
+        let zones: Vec<ZoneConfig> = self
+            .config
+            .zones
+            .iter()
+            .cloned()
+            .chain(endpoint_zone)
+            .collect();
+        manager.audit(&zones).await
+    }
+
+    /// Returns true if any zone has static routes configured
+    pub fn has_static_routes(&self) -> bool {
+        self.config
+            .zones
+            .iter()
+            .any(|z| z.mode != ZoneMode::Exclusive && !z.static_routes.is_empty())
+    }
+
+    /// A snapshot of this handler with a new config and matcher, for hot
+    /// reload; the caller swaps it in for this one.
+    ///
+    /// Only what changed is rebuilt: cached answers of zones whose
+    /// definition is unchanged (and that still match the same queries) are
+    /// kept, and tracked routes of zones whose route target or options
+    /// changed are reinstalled. Aggregator state and routes of every other
+    /// zone are left alone.
+    pub async fn reconfigure(
+        &self,
+        new_config: Config,
+        new_matcher: ZoneMatcher,
+    ) -> error::Result<DnsHandler> {
+        let mut next = self.clone();
+        // Reopen the GeoIP database first, so a failure changes nothing
+        let geoip_stale = match (&new_config.server.geoip_database, &self.geoip) {
+            (Some(path), Some(geoip)) => geoip.is_stale(path),
+            _ => false,
+        };
+        if geoip_stale || new_config.server.geoip_database != self.config.server.geoip_database {
+            next.geoip = open_geoip(&new_config)?;
+        }
+        let query_log = QueryLogSettings::from_config(&new_config.server);
+        if query_log.as_ref() != self.query_log.as_ref().map(|log| log.settings()) {
+            next.query_log = open_query_log(&new_config.server)?;
+        }
+        // Buckets survive reloads that leave the limit as it was
+        let rate_limit = RateLimitSettings::from_config(&new_config.server);
+        if rate_limit.as_ref() != self.rate_limiter.as_ref().map(|limiter| limiter.settings()) {
+            next.rate_limiter = rate_limit.map(|settings| Arc::new(RateLimiter::new(settings)));
+        }
+        let forward_limits = (
+            new_config.server.max_concurrent_forwards,
+            new_config.server.forward_queue_size,
+        );
+        if self
+            .forward_limiter
+            .as_ref()
+            .map(|limiter| limiter.limits())
+            != Some(forward_limits)
+        {
+            next.forward_limiter = ForwardLimiter::from_config(&new_config.server).map(Arc::new);
+        }
+        if new_config.server.docker_dns != self.docker.is_some() {
+            next.docker = docker_networks(&new_config.server);
+        }
+        next.local_zones = Arc::new(LocalZones::load(&new_config.local_zones)?);
+        // Close sockets to upstreams no longer configured
+        let upstreams: HashSet<SocketAddr> = new_config
+            .server
+            .default_upstream
+            .iter()
+            .copied()
+            .chain(
+                new_config
+                    .zones
+                    .iter()
+                    .flat_map(|z| {
+                        z.dns_servers
+                            .iter()
+                            .chain(z.type_dns_servers.values().flatten())
+                    })
+                    .map(|server| server.address),
+            )
+            .collect();
+        self.udp_pool
+            .retain(|upstream| upstreams.contains(&upstream));
+        self.fake_ips.retain(&new_config.zones);
+
+        let old_zones: HashMap<&str, &ZoneConfig> = self
+            .config
+            .zones
             .iter()
-            .filter_map(|record| match record.record_type() {
-                RecordType::A => record
-                    .data()
-                    .and_then(|d| d.as_a())
-                    .map(|a| IpAddr::V4(a.0)),
-                RecordType::AAAA => record
-                    .data()
-                    .and_then(|d| d.as_aaaa())
-                    .map(|aaaa| IpAddr::V6(aaaa.0)),
-                _ => None,
+            .map(|z| (z.name.as_str(), z))
+            .collect();
+        let unchanged: HashSet<&str> = new_config
+            .zones
+            .iter()
+            .filter(|z| old_zones.get(z.name.as_str()) == Some(z))
+            .map(|z| z.name.as_str())
+            .collect();
+        let retargeted: Vec<&ZoneConfig> = new_config
+            .zones
+            .iter()
+            .filter(|z| {
+                old_zones.get(z.name.as_str()).is_some_and(|old| {
+                    old.route_type != z.route_type
+                        || old.route_target != z.route_target
+                        || RouteOptions::for_zone(old) != RouteOptions::for_zone(z)
+                })
             })
             .collect();
 
-        if ips.is_empty() {
-            tracing::debug!(qname = qname, "No A/AAAA records in response");
-            return;
+        let old_server = &self.config.server;
+        let new_server = &new_config.server;
+        if new_server.cache_size != old_server.cache_size {
+            next.cache = Arc::new(DnsCache::new(new_server.cache_size));
+        } else if new_server.cache_min_ttl != old_server.cache_min_ttl
+            || new_server.cache_max_ttl != old_server.cache_max_ttl
+            || new_server.cache_negative_ttl != old_server.cache_negative_ttl
+        {
+            self.cache.clear();
+        } else {
+            // A zone added, changed or moved ahead of a scoped zone may now
+            // take some of its queries; scoped entries can't be re-matched
+            // without the client, so they only survive reloads that leave
+            // the zone list as it was (removals aside)
+            let kept_order: Vec<&str> = self
+                .config
+                .zones
+                .iter()
+                .map(|z| z.name.as_str())
+                .filter(|name| unchanged.contains(name))
+                .collect();
+            let zones_shifted = new_config.zones.len() != unchanged.len()
+                || new_config
+                    .zones
+                    .iter()
+                    .map(|z| z.name.as_str())
+                    .ne(kept_order.iter().copied());
+            let upstream_unchanged = new_server.default_upstream == old_server.default_upstream;
+            let dropped = self.cache.retain(|key, zone| {
+                let Some(zone) = zone else {
+                    return upstream_unchanged && new_matcher.find_zone(key, None).is_none();
+                };
+                if !unchanged.contains(zone) {
+                    return false;
+                }
+                match key.split_once('@') {
+                    Some(_) => !zones_shifted,
+                    None => new_matcher
+                        .find_zone(key, None)
+                        .is_some_and(|z| z.config.name == zone),
+                }
+            });
+            tracing::debug!(dropped = dropped, "Dropped cache entries of changed zones");
         }
 
-        // Add routes in background (don't block DNS response)
-        let route_manager = Arc::clone(&self.route_manager);
-        let qname = qname.to_string();
-
-        tokio::spawn(async move {
-            let manager = route_manager.read().await;
-            for ip in ips {
-                // Per-zone exclusion check (exclusive zones skip IPs in their CIDR ranges)
-                if matched_zone.is_excluded(ip) {
-                    tracing::debug!(
-                        ip = %ip,
-                        zone = matched_zone.config.name,
-                        "IP is in zone's excluded range, skipping route"
-                    );
-                    continue;
-                }
-                if let Err(e) = manager.add_route(ip, &matched_zone.config).await {
-                    tracing::warn!(
-                        ip = %ip,
-                        zone = matched_zone.config.name,
-                        qname = qname,
-                        error = %e,
-                        "Failed to add route"
-                    );
+        if !retargeted.is_empty() {
+            let manager = self.route_manager.read().await;
+            for zone in retargeted {
+                if let Err(e) = manager.reapply_zone(zone).await {
+                    tracing::warn!(zone = zone.name, error = %e, "Failed to re-apply zone routes");
                 }
             }
-        });
+        }
+
+        self.disabled_zones
+            .write()
+            .unwrap()
+            .retain(|name| new_config.zones.iter().any(|z| &z.name == name));
+        self.events.set_hooks(new_config.hooks.clone());
+        next.config = Arc::new(new_config);
+        next.matcher = Arc::new(new_matcher);
+        tracing::debug!("Handler config updated");
+        Ok(next)
     }
+}
 
-    /// Get current config
-    pub fn config(&self) -> &Config {
-        &self.config
+fn docker_networks(server: &ServerConfig) -> Option<Arc<DockerNetworks>> {
+    server.docker_dns.then(|| Arc::new(DockerNetworks::new()))
+}
+
+fn open_query_log(server: &ServerConfig) -> error::Result<Option<Arc<QueryLog>>> {
+    let Some(settings) = QueryLogSettings::from_config(server) else {
+        return Ok(None);
+    };
+    tracing::info!(path = %settings.path.display(), "Logging queries");
+    Ok(Some(Arc::new(QueryLog::open(settings)?)))
+}
+
+/// Which zones a query's resolved IPs are routed through.
+struct RouteZones {
+    /// Zones the name matched, by `match_mode`
+    matched: Vec<MatchedZone>,
+    /// Consulted per IP, unless the name matched an inclusive zone
+    geoip: Option<Arc<GeoIp>>,
+    matcher: Arc<ZoneMatcher>,
+    disabled: HashSet<String>,
+    client: Option<IpAddr>,
+}
+
+impl RouteZones {
+    fn is_empty(&self) -> bool {
+        self.matched.is_empty() && self.geoip.is_none()
     }
 
-    /// Cleanup routes for a specific zone
-    pub async fn cleanup_zone(&self, zone_name: &str) -> anyhow::Result<()> {
-        let manager = self.route_manager.read().await;
-        manager.cleanup_zone(zone_name).await
+    /// The zone claiming `ip`'s country, if any, else the zones the name
+    /// matched.
+    fn for_ip(&self, ip: IpAddr) -> Vec<MatchedZone> {
+        let geo_zone = self
+            .geoip
+            .as_ref()
+            .and_then(|geoip| geoip.country(ip))
+            .and_then(|country| self.matcher.find_zone_by_country(&country, self.client))
+            .filter(|z| !self.disabled.contains(&z.config.name));
+        match geo_zone {
+            Some(geo_zone) => vec![geo_zone],
+            None => self.matched.clone(),
+        }
     }
+}
 
-    /// Apply static routes for all zones that have them.
-    /// Returns the number of failed routes (0 = all applied successfully).
-    pub async fn apply_static_routes(&self) -> usize {
-        let route_manager = self.route_manager.read().await;
-        let mut failures = 0;
-        for zone in &self.config.zones {
-            // Exclusive zones use static_routes as exclusion ranges, not actual routes
-            if zone.mode == ZoneMode::Exclusive {
-                continue;
-            }
-            for cidr in &zone.static_routes {
-                if let Err(e) = route_manager.add_static_route(cidr, zone).await {
-                    tracing::warn!(
-                        cidr = cidr,
-                        zone = zone.name,
-                        error = %e,
-                        "Failed to add static route"
-                    );
-                    failures += 1;
-                }
-            }
+/// PTR records pointing at `names`, skipping any that aren't valid names.
+fn ptr_rdata(names: &[String]) -> Vec<RData> {
+    names
+        .iter()
+        .filter_map(|name| Name::from_ascii(name).ok())
+        .map(|name| RData::PTR(PTR(name)))
+        .collect()
+}
+
+/// A and AAAA addresses in a response's answer section.
+fn answer_ips(message: &Message) -> Vec<IpAddr> {
+    message
+        .answers()
+        .iter()
+        .filter_map(|record| match record.record_type() {
+            RecordType::A => record
+                .data()
+                .and_then(|d| d.as_a())
+                .map(|a| IpAddr::V4(a.0)),
+            RecordType::AAAA => record
+                .data()
+                .and_then(|d| d.as_aaaa())
+                .map(|aaaa| IpAddr::V6(aaaa.0)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Query ID for queries leshy originates.
+fn rand_id() -> u16 {
+    rand::random()
+}
+
+/// The copy of `query` sent upstream: a fresh random ID, so a response
+/// can't be forged from the client's, and with `randomize_case` names in
+/// randomly mixed case (DNS 0x20).
+fn outgoing_query(query: &Message, randomize_case: bool) -> Message {
+    let mut sent = query.clone();
+    sent.set_id(rand_id());
+    if randomize_case {
+        for query in sent.queries_mut() {
+            let name = mix_case(query.name());
+            query.set_name(name);
         }
-        failures
     }
+    sent
+}
 
-    /// Returns true if any zone has static routes configured
-    pub fn has_static_routes(&self) -> bool {
-        self.config
-            .zones
+/// `name` with each letter's case picked at random.
+fn mix_case(name: &Name) -> Name {
+    let mixed: String = name
+        .to_ascii()
+        .chars()
+        .map(|c| {
+            if rand::random() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect();
+    Name::from_ascii(mixed).unwrap_or_else(|_| name.clone())
+}
+
+/// Whether `response` answers `sent`: a response with its ID and exactly
+/// its question, case included.
+fn answers_query(sent: &Message, response: &Message) -> bool {
+    response.message_type() == MessageType::Response
+        && response.id() == sent.id()
+        && response.queries().len() == sent.queries().len()
+        && response
+            .queries()
             .iter()
-            .any(|z| z.mode != ZoneMode::Exclusive && !z.static_routes.is_empty())
-    }
+            .zip(sent.queries())
+            .all(|(answered, asked)| {
+                answered.name().eq_case(asked.name())
+                    && answered.query_type() == asked.query_type()
+                    && answered.query_class() == asked.query_class()
+            })
+}
 
-    /// Update config and matcher (for hot reload)
-    pub async fn update_config(
-        &mut self,
-        new_config: Config,
-        new_matcher: ZoneMatcher,
-    ) -> anyhow::Result<()> {
-        // Recreate cache if size changed, otherwise just clear
-        if new_config.server.cache_size != self.config.server.cache_size {
-            self.cache = Arc::new(DnsCache::new(new_config.server.cache_size));
-        } else {
-            self.cache.clear();
+/// `response` as an answer to `original`: its ID and question, and the
+/// question's name as it asked for it wherever the response echoes it.
+fn restore_query(mut response: Message, original: &Message) -> Message {
+    response.set_id(original.id());
+    for (answered, asked) in response.queries_mut().iter_mut().zip(original.queries()) {
+        answered.set_name(asked.name().clone());
+    }
+    let Some(asked) = original.queries().first().map(|q| q.name()) else {
+        return response;
+    };
+    let restore = |records: &mut Vec<Record>| {
+        for record in records.iter_mut().filter(|r| r.name() == asked) {
+            record.set_name(asked.clone());
         }
-        self.config = Arc::new(new_config);
-        self.matcher = Arc::new(new_matcher);
-        tracing::debug!("Handler config updated, cache cleared");
-        Ok(())
+    };
+    restore(response.answers_mut());
+    restore(response.name_servers_mut());
+    restore(response.additionals_mut());
+    response
+}
+
+/// The answer for a search-domain expansion as the answer to the `original`
+/// query: a CNAME from the asked-for name to `expanded`, then its records.
+fn search_answer(original: &Message, expanded: Name, answer: Message) -> Message {
+    let mut response = original.clone();
+    response.set_message_type(MessageType::Response);
+    response.set_recursion_available(answer.recursion_available());
+    response.set_response_code(answer.response_code());
+    if let Some(question) = original.queries().first() {
+        let ttl = answer.answers().iter().map(Record::ttl).min().unwrap_or(0);
+        response.add_answer(Record::from_rdata(
+            question.name().clone(),
+            ttl,
+            RData::CNAME(CNAME(expanded)),
+        ));
     }
+    response.add_answers(answer.answers().iter().cloned());
+    response.add_name_servers(answer.name_servers().iter().cloned());
+    response
+}
+
+/// A standard query for `name`, to forward upstream.
+fn query_message(name: Name, qtype: RecordType, id: u16, recursion_desired: bool) -> Message {
+    let mut query_msg = Message::new();
+    query_msg.add_query(hickory_proto::op::Query::query(name, qtype));
+    query_msg.set_id(id);
+    query_msg.set_message_type(MessageType::Query);
+    query_msg.set_op_code(OpCode::Query);
+    query_msg.set_recursion_desired(recursion_desired);
+    query_msg
+}
+
+/// Open the configured GeoIP database, if any.
+fn open_geoip(config: &Config) -> error::Result<Option<Arc<GeoIp>>> {
+    config
+        .server
+        .geoip_database
+        .as_deref()
+        .map(|path| GeoIp::open(path).map(Arc::new))
+        .transpose()
+        .map_err(LeshyError::config)
 }
 
 /// Compute cache TTL using the server → zone → global cascade.
@@ -329,6 +1813,59 @@ fn resolve_cache_ttl(
     }
 }
 
+/// Rotate the A/AAAA records of an answer section by `offset` positions,
+/// keeping every other record (e.g. the CNAME chain) in its original slot.
+fn rotate_answers(answers: &[Record], offset: usize) -> Vec<&Record> {
+    let mut ordered: Vec<&Record> = answers.iter().collect();
+    let slots: Vec<usize> = answers
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| matches!(r.record_type(), RecordType::A | RecordType::AAAA))
+        .map(|(i, _)| i)
+        .collect();
+
+    if slots.len() > 1 {
+        let shift = offset % slots.len();
+        for (n, &slot) in slots.iter().enumerate() {
+            ordered[slot] = &answers[slots[(n + shift) % slots.len()]];
+        }
+    }
+    ordered
+}
+
+/// How long an answer with `answers` is held back for the zone's
+/// `aaaa_delay_ms`: only answers with AAAA records are, so dual-stack
+/// clients connect over the routed IPv4 addresses first.
+fn aaaa_delay(zone: Option<&MatchedZone>, answers: &[Record]) -> Option<Duration> {
+    let delay_ms = zone?.config.aaaa_delay_ms?;
+    answers
+        .iter()
+        .any(|r| r.record_type() == RecordType::AAAA)
+        .then(|| Duration::from_millis(delay_ms))
+}
+
+/// `records` with TTLs clamped to a zone's (`client_min_ttl`,
+/// `client_max_ttl`), copying only the records that change.
+fn clamp_ttls<'a>(
+    records: impl IntoIterator<Item = &'a Record>,
+    (min, max): (Option<u32>, Option<u32>),
+) -> Vec<Cow<'a, Record>> {
+    records
+        .into_iter()
+        .map(|record| {
+            let ttl = min.map_or(record.ttl(), |min| record.ttl().max(min));
+            let ttl = max.map_or(ttl, |max| ttl.min(max));
+            if ttl == record.ttl() {
+                Cow::Borrowed(record)
+            } else {
+                let mut record = record.clone();
+                record.set_ttl(ttl);
+                Cow::Owned(record)
+            }
+        })
+        .collect()
+}
+
 #[async_trait::async_trait]
 impl RequestHandler for DnsHandler {
     async fn handle_request<R: ResponseHandler>(
@@ -343,82 +1880,225 @@ impl RequestHandler for DnsHandler {
             return response_handle.send_response(response).await.unwrap();
         }
 
+        let started = Instant::now();
+        let received_at = chrono::Local::now();
+
         // Get query name - convert to string
         let qname = request.query().name().to_string();
         let qtype = request.query().query_type();
 
-        tracing::info!(qname = qname, qtype = ?qtype, "Received query");
+        let client = request.src().ip();
+
+        tracing::info!(qname = qname, qtype = ?qtype, client = %client, "Received query");
+
+        // Find matching zone; answers of client-scoped and scheduled zones
+        // are cached apart so other clients, or the same ones once the zone
+        // is inactive, never see them
+        let zone: Option<MatchedZone> = self
+            .matcher
+            .find_zone(&qname, Some(client))
+            .filter(|z| !self.is_zone_disabled(&z.config.name));
+        let cache_name = match &zone {
+            Some(z)
+                if !z.config.clients.is_empty()
+                    || z.config.active_hours.is_some()
+                    || !z.config.active_days.is_empty() =>
+            {
+                format!("{qname}@{}", z.config.name)
+            }
+            _ => qname.clone(),
+        };
+        let zone_name = zone.as_ref().map(|z| z.config.name.as_str());
+        self.stats.record_query(zone_name);
+
+        // A truncated answer sends the client to TCP, whose source can't be
+        // spoofed: the retry is let through
+        let limiter = self.rate_limiter.as_ref().filter(|limiter| {
+            limiter.settings().action != RateLimitAction::Truncate
+                || !matches!(request.protocol(), Protocol::Tcp)
+        });
+        if let Some(limiter) = limiter {
+            if !limiter.allow(client, &qname) {
+                tracing::debug!(qname = qname, client = %client, "Rate limited");
+                self.stats.record_rate_limited(zone_name);
+                let mut header = Header::response_from_request(request.header());
+                match limiter.settings().action {
+                    RateLimitAction::Refuse => header.set_response_code(ResponseCode::Refused),
+                    RateLimitAction::Truncate => header.set_truncated(true),
+                };
+                let builder = MessageResponseBuilder::from_message_request(request);
+                let response = builder.build_no_records(header);
+                return response_handle.send_response(response).await.unwrap();
+            }
+        }
+        self.top.record_query(&qname);
+        let log_entry =
+            |upstream: Option<SocketAddr>, cached: bool, rcode: ResponseCode| QueryLogEntry {
+                timestamp: received_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+                client,
+                qname: qname.trim_end_matches('.').to_string(),
+                qtype: qtype.to_string(),
+                zone: zone_name.map(str::to_string),
+                upstream,
+                cached,
+                rcode: rcode.to_string(),
+                latency_ms: started.elapsed().as_micros() as f64 / 1000.0,
+                routes_added: Vec::new(),
+            };
+
+        // TTLs clients see of upstream answers, whatever is cached
+        let ttl_bounds = zone.as_ref().map_or((None, None), |z| {
+            (z.config.client_min_ttl, z.config.client_max_ttl)
+        });
+
+        // Names in local zones never go upstream
+        if let Some(answer) = self
+            .local_zones
+            .lookup(&request.query().name().into(), qtype)
+        {
+            let mut header = Header::response_from_request(request.header());
+            header.set_authoritative(true);
+            header.set_response_code(answer.rcode);
+            self.log_query(log_entry(None, false, answer.rcode), None);
+            let builder = MessageResponseBuilder::from_message_request(request);
+            let response = builder.build(
+                header,
+                answer.answers.iter(),
+                answer.authority.iter(),
+                std::iter::empty(),
+                std::iter::empty(),
+            );
+            return response_handle.send_response(response).await.unwrap();
+        }
+
+        // Reverse queries for container and routed addresses, and fake-IP
+        // zones' names and addresses, are answered here
+        let docker_names = match self.docker.as_ref().filter(|_| qtype == RecordType::PTR) {
+            Some(docker) => docker.reverse(&qname).await,
+            None => None,
+        };
+        let local = match docker_names {
+            Some(names) => Some((ptr_rdata(&names), docker::PTR_TTL)),
+            None => self
+                .fake_ip_answer(zone.as_ref(), &qname, qtype)
+                .or_else(|| self.routed_name_answer(&qname, qtype)),
+        };
+        if let Some((answers, ttl)) = local {
+            let mut header = Header::response_from_request(request.header());
+            header.set_authoritative(true);
+            // An empty AAAA answer for a fake-IP name is NODATA
+            let rcode = if answers.is_empty() && qtype == RecordType::PTR {
+                ResponseCode::NXDomain
+            } else {
+                ResponseCode::NoError
+            };
+            header.set_response_code(rcode);
+            let records: Vec<Record> = answers
+                .into_iter()
+                .map(|rdata| Record::from_rdata(request.query().name().into(), ttl, rdata))
+                .collect();
+            self.log_query(log_entry(None, false, rcode), None);
+            let builder = MessageResponseBuilder::from_message_request(request);
+            let response = builder.build(
+                header,
+                records.iter(),
+                std::iter::empty(),
+                std::iter::empty(),
+                std::iter::empty(),
+            );
+            return response_handle.send_response(response).await.unwrap();
+        }
 
         // Check cache before forwarding
         if self.cache.is_enabled() {
-            if let Some(cached) = self.cache.lookup(&qname, qtype) {
+            if let Some(hit) = self.cache.lookup(&cache_name, qtype) {
                 tracing::debug!(qname = qname, qtype = ?qtype, "Cache hit");
+                self.stats.record_cache_hit(zone_name);
+                let cached = hit.message;
 
                 // Still add routes from cached response
-                self.add_routes_from_response(&cached, &qname).await;
+                let mut routes = self.add_routes_from_response(&cached, &qname, client);
+                if !self.routes_ready(routes.as_mut(), &qname).await {
+                    self.log_query(log_entry(None, true, ResponseCode::ServFail), routes);
+                    let builder = MessageResponseBuilder::from_message_request(request);
+                    let response = builder.error_msg(request.header(), ResponseCode::ServFail);
+                    return response_handle.send_response(response).await.unwrap();
+                }
 
-                // Use the current request's ID so the client matches the response
+                // Use the current request's ID and RD flag so the client matches the response
                 let mut header = *cached.header();
                 header.set_id(request.id());
+                header.set_recursion_desired(request.recursion_desired());
+
+                let rotation = if self.config.server.answer_rotation {
+                    hit.hits
+                } else {
+                    0
+                };
+                let answers = clamp_ttls(rotate_answers(cached.answers(), rotation), ttl_bounds);
+                let name_servers = clamp_ttls(cached.name_servers(), ttl_bounds);
+                let additionals = clamp_ttls(cached.additionals(), ttl_bounds);
 
                 let builder = MessageResponseBuilder::from_message_request(request);
                 let response_msg = builder.build(
                     header,
-                    cached.answers().iter(),
-                    cached.name_servers().iter(),
+                    answers.iter().map(|r| &**r),
+                    name_servers.iter().map(|r| &**r),
                     std::iter::empty(),
-                    cached.additionals().iter(),
+                    additionals.iter().map(|r| &**r),
                 );
+                self.log_query(log_entry(None, true, cached.response_code()), routes);
+                if let Some(delay) = aaaa_delay(zone.as_ref(), cached.answers()) {
+                    tokio::time::sleep(delay).await;
+                }
                 return response_handle.send_response(response_msg).await.unwrap();
             }
         }
 
-        // Find matching zone and determine upstream servers + protocol
-        let zone: Option<MatchedZone> = self.matcher.find_zone(&qname);
-        let (upstreams, protocol): (Vec<(SocketAddr, Option<&DnsServerConfig>)>, DnsProtocol) =
-            match &zone {
-                Some(z) if !z.config.dns_servers.is_empty() => {
-                    tracing::debug!(
-                        qname = qname,
-                        zone = z.config.name,
-                        servers = ?z.config.dns_servers.iter().map(|s| s.address).collect::<Vec<_>>(),
-                        protocol = ?z.config.dns_protocol,
-                        "Routing to zone DNS"
-                    );
-                    let ups = z
-                        .config
-                        .dns_servers
-                        .iter()
-                        .map(|s| (s.address, Some(s)))
-                        .collect();
-                    (ups, z.config.dns_protocol)
-                }
-                _ => {
+        // Determine upstream servers + protocol
+        let (upstreams, protocol) = self.upstreams_for(&qname, qtype, zone.as_ref());
+
+        let query_msg = query_message(
+            request.query().name().clone().into(),
+            request.query().query_type(),
+            request.id(),
+            request.recursion_desired(),
+        );
+
+        // Bound the forwards in flight; once the queue is full too, fail
+        // fast rather than pile up tasks and sockets
+        let forward_slot = match &self.forward_limiter {
+            Some(limiter) => match limiter.acquire().await {
+                Some(slot) => Some(slot),
+                None => {
                     tracing::debug!(
                         qname = qname,
-                        upstreams = ?self.config.server.default_upstream,
-                        "Routing to default DNS"
+                        "Too many queries in flight, answering SERVFAIL"
                     );
-                    let ups = self
-                        .config
-                        .server
-                        .default_upstream
-                        .iter()
-                        .map(|&a| (a, None))
-                        .collect();
-                    (ups, DnsProtocol::Udp)
+                    self.stats.record_overloaded(zone_name);
+                    self.log_query(log_entry(None, false, ResponseCode::ServFail), None);
+                    let builder = MessageResponseBuilder::from_message_request(request);
+                    let response = builder.error_msg(request.header(), ResponseCode::ServFail);
+                    return response_handle.send_response(response).await.unwrap();
                 }
-            };
+            },
+            None => None,
+        };
 
         // Sequential failover: try servers in order, fail only when all exhausted.
         // Both transport errors and SERVFAIL/REFUSED responses trigger failover.
         let mut last_err = ResponseCode::ServFail;
-        let mut result: Option<(Message, Option<&DnsServerConfig>)> = None;
+        let mut result: Option<(Message, SocketAddr, Option<&DnsServerConfig>)> = None;
         for (i, (upstream, server_cfg)) in upstreams.iter().enumerate() {
+            let sent = Instant::now();
             let res = match protocol {
-                DnsProtocol::Udp => self.forward_query(request, *upstream).await,
-                DnsProtocol::Tcp => self.forward_query_tcp(request, *upstream).await,
+                DnsProtocol::Udp => self.forward_query(&query_msg, *upstream).await,
+                DnsProtocol::Tcp => self.forward_query_tcp(&query_msg, *upstream).await,
             };
+            if res.is_ok() {
+                self.stats
+                    .record_upstream_rtt(*upstream, protocol, sent.elapsed());
+            }
             match res {
                 Ok(response)
                     if response.response_code() == ResponseCode::ServFail
@@ -431,10 +2111,17 @@ impl RequestHandler for DnsHandler {
                         remaining = upstreams.len() - i - 1,
                         "Upstream returned error response, trying next"
                     );
+                    let failure = if response.response_code() == ResponseCode::Refused {
+                        UpstreamFailure::Refused
+                    } else {
+                        UpstreamFailure::ServFail
+                    };
+                    self.record_upstream_failure(zone_name, *upstream, protocol, failure);
                     last_err = response.response_code();
                 }
                 Ok(response) => {
-                    result = Some((response, *server_cfg));
+                    self.stats.record_upstream_answer(*upstream, protocol);
+                    result = Some((response, *upstream, *server_cfg));
                     break;
                 }
                 Err(rcode) => {
@@ -445,47 +2132,122 @@ impl RequestHandler for DnsHandler {
                         remaining = upstreams.len() - i - 1,
                         "Upstream failed, trying next"
                     );
+                    self.record_upstream_failure(
+                        zone_name,
+                        *upstream,
+                        protocol,
+                        UpstreamFailure::Unreachable,
+                    );
                     last_err = rcode;
                 }
             }
         }
+        drop(forward_slot);
 
         match result {
-            Some((response, server_cfg)) => {
+            Some((response, upstream, server_cfg)) => {
                 tracing::debug!(
                     qname = qname,
                     answers = response.answers().len(),
                     "Got response"
                 );
 
-                // Add routes for resolved IPs (async, don't wait)
-                self.add_routes_from_response(&response, &qname).await;
+                // DNS64: a name without AAAA records gets them made up
+                // from its A records
+                let response = match self.dns64_prefix() {
+                    Some(prefix)
+                        if qtype == RecordType::AAAA && dns64::needs_synthesis(&response) =>
+                    {
+                        self.synthesize_aaaa(&query_msg, upstream, protocol, prefix, &response)
+                            .await
+                            .unwrap_or(response)
+                    }
+                    _ => response,
+                };
+
+                // Private addresses are dropped and the zone's rewrite rules
+                // applied before routing and caching
+                let response = self.filter_answer(zone.as_ref(), &qname, qtype, response);
+
+                // A single-label name that doesn't exist is tried under
+                // search_domains. Expanded answers aren't cached, so their
+                // routes follow the expanded name's zone every time.
+                let expanded = if response.response_code() == ResponseCode::NXDomain
+                    && request.query().name().num_labels() == 1
+                {
+                    self.expand_search(&query_msg, client).await
+                } else {
+                    None
+                };
+                let cacheable = expanded.is_none();
+
+                // Add routes for resolved IPs (async; only waited for with
+                // route_failure_mode = "servfail")
+                let (response, mut routes) = match expanded {
+                    Some(expanded) => expanded,
+                    None => {
+                        let routes = self.add_routes_from_response(&response, &qname, client);
+                        (response, routes)
+                    }
+                };
 
-                // Cache the response (skip ServFail)
-                if self.cache.is_enabled() && response.response_code() != ResponseCode::ServFail {
+                // Cache the response (skip ServFail). The cache shares the
+                // message with this reply instead of cloning it.
+                let response = Arc::new(response);
+                if self.cache.is_enabled()
+                    && cacheable
+                    && response.response_code() != ResponseCode::ServFail
+                {
                     let ttl = resolve_cache_ttl(
                         server_cfg,
                         zone.as_ref().map(|z| z.config.as_ref()),
                         &self.config.server,
                         &response,
                     );
-                    self.cache.insert(&qname, qtype, response.clone(), ttl);
+                    self.cache
+                        .insert(&cache_name, qtype, zone_name, Arc::clone(&response), ttl);
+                }
+
+                // The answer stays cached, so the next query retries the routes
+                if !self.routes_ready(routes.as_mut(), &qname).await {
+                    let entry = log_entry(Some(upstream), false, ResponseCode::ServFail);
+                    self.log_query(entry, routes);
+                    let builder = MessageResponseBuilder::from_message_request(request);
+                    let response = builder.error_msg(request.header(), ResponseCode::ServFail);
+                    return response_handle.send_response(response).await.unwrap();
                 }
 
                 // Convert Message to MessageResponse
+                let answers = clamp_ttls(response.answers(), ttl_bounds);
+                let name_servers = clamp_ttls(response.name_servers(), ttl_bounds);
+                let additionals = clamp_ttls(response.additionals(), ttl_bounds);
                 let builder = MessageResponseBuilder::from_message_request(request);
                 let response_msg = builder.build(
                     *response.header(),
-                    response.answers().iter(),
-                    response.name_servers().iter(),
+                    answers.iter().map(|r| &**r),
+                    name_servers.iter().map(|r| &**r),
                     std::iter::empty(),
-                    response.additionals().iter(),
+                    additionals.iter().map(|r| &**r),
                 );
+                let entry = log_entry(Some(upstream), false, response.response_code());
+                self.top.record_resolution(SlowQuery {
+                    name: entry.qname.clone(),
+                    qtype: entry.qtype.clone(),
+                    zone: entry.zone.clone(),
+                    upstream: entry.upstream,
+                    latency_ms: entry.latency_ms,
+                    at: entry.timestamp.clone(),
+                });
+                self.log_query(entry, routes);
 
+                if let Some(delay) = aaaa_delay(zone.as_ref(), response.answers()) {
+                    tokio::time::sleep(delay).await;
+                }
                 response_handle.send_response(response_msg).await.unwrap()
             }
             None => {
                 tracing::error!(qname = qname, rcode = ?last_err, "All upstreams failed");
+                self.log_query(log_entry(None, false, last_err), None);
                 let builder = MessageResponseBuilder::from_message_request(request);
                 let response = builder.error_msg(request.header(), last_err);
                 response_handle.send_response(response).await.unwrap()
@@ -493,3 +2255,602 @@ impl RequestHandler for DnsHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouteType;
+    use crate::routing::endpoints::ENDPOINT_ZONE;
+    use crate::routing::mock::MockRouteAdder;
+    use crate::routing::{prefix_contains, MAIN_TABLE};
+    use hickory_proto::rr::rdata::{A, AAAA, CNAME};
+    use hickory_proto::rr::{Name, RData};
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    fn a_record(last_octet: u8) -> Record {
+        Record::from_rdata(
+            Name::from_str("www.example.com.").unwrap(),
+            300,
+            RData::A(A(Ipv4Addr::new(10, 0, 0, last_octet))),
+        )
+    }
+
+    fn last_octets(records: &[&Record]) -> Vec<u8> {
+        records
+            .iter()
+            .filter_map(|r| r.data().and_then(|d| d.as_a()).map(|a| a.0.octets()[3]))
+            .collect()
+    }
+
+    #[test]
+    fn rotate_answers_round_robin() {
+        let answers = vec![a_record(1), a_record(2), a_record(3)];
+
+        assert_eq!(last_octets(&rotate_answers(&answers, 0)), vec![1, 2, 3]);
+        assert_eq!(last_octets(&rotate_answers(&answers, 1)), vec![2, 3, 1]);
+        assert_eq!(last_octets(&rotate_answers(&answers, 2)), vec![3, 1, 2]);
+        assert_eq!(last_octets(&rotate_answers(&answers, 3)), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn clamp_ttls_to_client_bounds() {
+        let answers = vec![a_record(1), a_record(2)];
+        let ttls = |bounds| -> Vec<u32> {
+            clamp_ttls(&answers, bounds)
+                .iter()
+                .map(|r| r.ttl())
+                .collect()
+        };
+        assert_eq!(ttls((None, Some(30))), [30, 30]);
+        assert_eq!(ttls((Some(600), None)), [600, 600]);
+        assert_eq!(ttls((Some(10), Some(3600))), [300, 300]);
+        assert!(matches!(
+            clamp_ttls(&answers, (None, None))[0],
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn rotate_answers_keeps_cname_in_place() {
+        let cname = Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            300,
+            RData::CNAME(CNAME(Name::from_str("www.example.com.").unwrap())),
+        );
+        let answers = vec![cname, a_record(1), a_record(2)];
+
+        let rotated = rotate_answers(&answers, 1);
+        assert_eq!(rotated[0].record_type(), RecordType::CNAME);
+        assert_eq!(last_octets(&rotated), vec![2, 1]);
+    }
+
+    fn zones_config(corp_upstream: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            [server]
+            listen_address = "127.0.0.1:15353"
+            default_upstream = ["1.1.1.1:53"]
+
+            [[zones]]
+            name = "corp"
+            route_type = "via"
+            route_target = "198.51.100.1"
+            dns_servers = ["{corp_upstream}"]
+            domains = ["corp.example"]
+
+            [[zones]]
+            name = "media"
+            route_type = "via"
+            route_target = "198.51.100.2"
+            domains = ["media.example"]
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn picks_upstreams_by_record_type() {
+        let mut config = zones_config("10.0.0.53:53");
+        config.zones[0].type_dns_servers = toml::from_str::<ZoneConfig>(
+            "name = \"corp\"\nroute_type = \"via\"\n\
+             type_dns_servers = { mx = [\"1.0.0.1:53\"], TXT = [] }",
+        )
+        .unwrap()
+        .type_dns_servers;
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let zone = handler.matcher.find_zone("www.corp.example.", None);
+
+        let upstreams = |qtype| {
+            let (upstreams, _) = handler.upstreams_for("www.corp.example.", qtype, zone.as_ref());
+            upstreams
+                .into_iter()
+                .map(|(upstream, _)| upstream.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(upstreams(RecordType::A), ["10.0.0.53:53"]);
+        assert_eq!(upstreams(RecordType::MX), ["1.0.0.1:53"]);
+        // An empty list means the default upstream
+        assert_eq!(upstreams(RecordType::TXT), ["1.1.1.1:53"]);
+    }
+
+    #[tokio::test]
+    async fn reconfigure_keeps_unchanged_zones_cache() {
+        let config = zones_config("10.0.0.53:53");
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let ttl = Duration::from_secs(300);
+        for (qname, zone) in [
+            ("www.corp.example.", Some("corp")),
+            ("www.media.example.", Some("media")),
+            ("www.other.example.", None),
+        ] {
+            handler
+                .cache
+                .insert(qname, RecordType::A, zone, Message::new(), ttl);
+        }
+
+        let config = zones_config("10.0.0.54:53");
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = handler.reconfigure(config, matcher).await.unwrap();
+
+        let cached = |qname| handler.cache.lookup(qname, RecordType::A).is_some();
+        assert!(!cached("www.corp.example."));
+        assert!(cached("www.media.example."));
+        assert!(cached("www.other.example."));
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_static_routes_out_of_other_aggregates() {
+        // corp routes 203.0.113.5 statically, media's remote list too
+        let zones = |media_url: Option<&str>| {
+            let mut config = zones_config("10.0.0.53:53");
+            config.zones[0].static_routes = vec!["203.0.113.5".to_string()];
+            config.zones[1].static_routes_url = media_url.map(str::to_string);
+            config.zones[1].route_table = Some(100);
+            let mut eu = config.zones[1].clone();
+            eu.name = "eu".to_string();
+            eu.domains = vec!["eu.example".to_string()];
+            eu.static_routes_url = None;
+            eu.route_table = None;
+            config.zones.push(eu);
+            config
+        };
+        let config = zones(Some("https://example.com/media.txt"));
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let mock = MockRouteAdder::new();
+        *handler.route_manager.write().await =
+            RouteManager::with_adder(Box::new(mock.clone()), Some(24));
+        assert_eq!(handler.apply_static_routes().await, 0);
+        handler
+            .sync_remote_routes("media", vec!["203.0.113.5".to_string()])
+            .await
+            .unwrap();
+
+        // The reload drops media's list, and its route with it
+        let config = zones(None);
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = handler.reconfigure(config, matcher).await.unwrap();
+        handler.prune_remote_routes().await;
+
+        let mut answer = Message::new();
+        answer.add_answer(Record::from_rdata(
+            Name::from_ascii("www.eu.example.").unwrap(),
+            300,
+            RData::A(A(Ipv4Addr::new(203, 0, 113, 200))),
+        ));
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let routes = handler.add_routes_from_response(&answer, "www.eu.example.", client);
+        routes.unwrap().added.await.unwrap();
+
+        let corp_ip = IpAddr::from([203, 0, 113, 5]);
+        let eu_gateway = Some(IpAddr::from([198, 51, 100, 2]));
+        let eu_routes: Vec<_> = mock
+            .routes()
+            .into_iter()
+            .filter(|route| route.gateway == eu_gateway && route.table == MAIN_TABLE)
+            .collect();
+        assert!(!eu_routes.is_empty());
+        assert!(eu_routes.iter().all(|route| !prefix_contains(
+            route.network,
+            route.prefix_len,
+            corp_ip
+        )));
+    }
+
+    #[tokio::test]
+    async fn endpoint_routes_replace_the_previous_ones() {
+        let config = zones_config("10.0.0.53:53");
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let mock = MockRouteAdder::new();
+        *handler.route_manager.write().await =
+            RouteManager::with_adder(Box::new(mock.clone()), Some(24));
+        let endpoint: Ipv4Addr = "203.0.113.7".parse().unwrap();
+        let via = |gateway: &str| EndpointRoutes {
+            endpoints: vec![endpoint],
+            zone: Some(ZoneConfig {
+                static_routes: vec!["203.0.113.7/32".to_string()],
+                ..ZoneConfig::new(ENDPOINT_ZONE, RouteType::Via, gateway)
+            }),
+        };
+        let routed_via = |gateway: [u8; 4]| {
+            mock.routes()
+                .into_iter()
+                .filter(|route| route.gateway == Some(IpAddr::from(gateway)))
+                .collect::<Vec<_>>()
+        };
+        handler.protect_endpoints(via("192.168.1.1")).await;
+        assert_eq!(routed_via([192, 168, 1, 1]).len(), 1);
+
+        // corp resolving the endpoint routes its neighbour but not it
+        let mut answer = Message::new();
+        for ip in [endpoint, Ipv4Addr::new(203, 0, 113, 8)] {
+            answer.add_answer(Record::from_rdata(
+                Name::from_ascii("vpn.corp.example.").unwrap(),
+                300,
+                RData::A(A(ip)),
+            ));
+        }
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let routes = handler.add_routes_from_response(&answer, "vpn.corp.example.", client);
+        routes.unwrap().added.await.unwrap();
+        let corp_routes = routed_via([198, 51, 100, 1]);
+        assert!(!corp_routes.is_empty());
+        assert!(corp_routes.iter().all(|route| !prefix_contains(
+            route.network,
+            route.prefix_len,
+            IpAddr::V4(endpoint)
+        )));
+
+        // A new gateway moves the route, turning it off takes it away
+        handler.protect_endpoints(via("192.168.2.1")).await;
+        assert!(routed_via([192, 168, 1, 1]).is_empty());
+        assert_eq!(routed_via([192, 168, 2, 1]).len(), 1);
+        handler.protect_endpoints(EndpointRoutes::default()).await;
+        assert!(routed_via([192, 168, 2, 1]).is_empty());
+        assert!(!handler.is_endpoint(IpAddr::V4(endpoint)));
+    }
+
+    #[tokio::test]
+    async fn answers_fake_ip_zones_locally() {
+        let mut config = zones_config("10.0.0.53:53");
+        config.zones[0].fake_ip_pool = Some("198.18.0.0/24".to_string());
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let zone = handler.matcher.find_zone("www.corp.example.", None);
+
+        let (answers, ttl) = handler
+            .fake_ip_answer(zone.as_ref(), "www.corp.example.", RecordType::A)
+            .unwrap();
+        assert_eq!(answers, [RData::A(A(Ipv4Addr::new(198, 18, 0, 2)))]);
+        assert_eq!(ttl, FAKE_IP_TTL);
+        let (answers, _) = handler
+            .fake_ip_answer(zone.as_ref(), "www.corp.example.", RecordType::AAAA)
+            .unwrap();
+        assert!(answers.is_empty());
+
+        let (answers, _) = handler
+            .fake_ip_answer(None, "2.0.18.198.in-addr.arpa.", RecordType::PTR)
+            .unwrap();
+        assert_eq!(answers.len(), 1);
+        let (answers, _) = handler
+            .fake_ip_answer(None, "3.0.18.198.in-addr.arpa.", RecordType::PTR)
+            .unwrap();
+        assert!(answers.is_empty());
+
+        // Other zones and addresses go upstream
+        let media = handler.matcher.find_zone("www.media.example.", None);
+        assert!(handler
+            .fake_ip_answer(media.as_ref(), "www.media.example.", RecordType::A)
+            .is_none());
+        assert!(handler
+            .fake_ip_answer(None, "7.100.51.198.in-addr.arpa.", RecordType::PTR)
+            .is_none());
+        assert_eq!(handler.fake_ips().len(), 1);
+    }
+
+    /// Answer every A query with 198.51.100.7 and others with no records.
+    async fn fake_upstream() -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = query.clone();
+                response.set_message_type(MessageType::Response);
+                let question = &query.queries()[0];
+                if question.query_type() == RecordType::A {
+                    response.add_answer(Record::from_rdata(
+                        question.name().clone(),
+                        300,
+                        RData::A(A(Ipv4Addr::new(198, 51, 100, 7))),
+                    ));
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn forward_drops_spoofed_responses_and_restores_query() {
+        // Sends a forged answer under the wrong ID ahead of the real one
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let question = &query.queries()[0];
+                for (id, octet) in [(query.id().wrapping_add(1), 66), (query.id(), 7)] {
+                    let mut response = query.clone();
+                    response.set_id(id);
+                    response.set_message_type(MessageType::Response);
+                    response.add_answer(Record::from_rdata(
+                        question.name().clone(),
+                        300,
+                        RData::A(A(Ipv4Addr::new(198, 51, 100, octet))),
+                    ));
+                    let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+                }
+            }
+        });
+        let mut config = zones_config("10.0.0.53:53");
+        config.server.randomize_case = true;
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+
+        let name = Name::from_ascii("www.corp.example.").unwrap();
+        let query = query_message(name.clone(), RecordType::A, 0x1234, true);
+        let response = handler.forward_query(&query, upstream).await.unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert!(response.queries()[0].name().eq_case(&name));
+        assert!(response.answers()[0].name().eq_case(&name));
+        assert_eq!(
+            response.answers()[0].data().and_then(|d| d.as_a()),
+            Some(&A(Ipv4Addr::new(198, 51, 100, 7)))
+        );
+
+        // An upstream that doesn't echo the mixed case isn't trusted
+        let sent = outgoing_query(&query, true);
+        let mut echo = sent.clone();
+        echo.set_message_type(MessageType::Response);
+        assert!(answers_query(&sent, &echo));
+        echo.queries_mut()[0].set_name(Name::from_ascii("WWW.CORP.EXAMPLE.").unwrap());
+        let mixed = !sent.queries()[0].name().eq_case(echo.queries()[0].name());
+        assert_eq!(answers_query(&sent, &echo), !mixed);
+    }
+
+    #[tokio::test]
+    async fn servfail_mode_waits_for_routes() {
+        // Only the corp zone holds answers back for its routes
+        let mut config = zones_config("10.0.0.53:53");
+        config.zones[0].route_failure_mode = Some(RouteFailureMode::Servfail);
+        config.zones[0].route_wait_ms = Some(200);
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let mock = MockRouteAdder::new();
+        *handler.route_manager.write().await =
+            RouteManager::with_adder(Box::new(mock.clone()), None);
+        let mut answer = Message::new();
+        answer.add_answer(Record::from_rdata(
+            Name::from_ascii("www.example.").unwrap(),
+            300,
+            RData::A(A(Ipv4Addr::new(198, 51, 100, 7))),
+        ));
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let ready = |qname: &'static str| {
+            let handler = handler.clone();
+            let answer = answer.clone();
+            async move {
+                let mut routes = handler.add_routes_from_response(&answer, qname, client);
+                let ready = handler.routes_ready(routes.as_mut(), qname).await;
+                (ready, routes)
+            }
+        };
+
+        mock.set_failing(true);
+        let (corp_ready, routes) = ready("www.corp.example.").await;
+        assert!(!corp_ready);
+        assert_eq!(routes.unwrap().wait, Some(Duration::from_millis(200)));
+        // Fallback answers whatever happens to the routes
+        let (media_ready, routes) = ready("www.media.example.").await;
+        assert!(media_ready);
+        let routes = routes.unwrap();
+        assert_eq!(routes.wait, None);
+        assert!(routes.added.await.unwrap().is_empty());
+
+        // The failed route is tried again
+        mock.set_failing(false);
+        let (corp_ready, routes) = ready("www.corp.example.").await;
+        assert!(corp_ready);
+        assert_eq!(routes.unwrap().added.await.unwrap().len(), 1);
+
+        // Answers without routes to wait for go out at once
+        let (other_ready, routes) = ready("www.other.example.").await;
+        assert!(other_ready);
+        assert!(routes.is_none());
+    }
+
+    #[tokio::test]
+    async fn answers_ptr_for_routed_addresses() {
+        let config = zones_config("10.0.0.53:53");
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        *handler.route_manager.write().await =
+            RouteManager::with_adder(Box::new(MockRouteAdder::new()), None);
+        let mut answer = Message::new();
+        answer.add_answer(Record::from_rdata(
+            Name::from_ascii("www.corp.example.").unwrap(),
+            300,
+            RData::A(A(Ipv4Addr::new(198, 51, 100, 7))),
+        ));
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let routes = handler.add_routes_from_response(&answer, "www.corp.example.", client);
+        routes.unwrap().added.await.unwrap();
+
+        let entry = handler
+            .routed_name(IpAddr::from([198, 51, 100, 7]))
+            .unwrap();
+        assert_eq!(entry.name, "www.corp.example");
+        assert_eq!(entry.zone, "corp");
+        let (answers, ttl) = handler
+            .routed_name_answer("7.100.51.198.in-addr.arpa.", RecordType::PTR)
+            .unwrap();
+        assert_eq!(answers, ptr_rdata(&["www.corp.example.".to_string()]));
+        assert_eq!(ttl, ROUTED_PTR_TTL);
+        assert!(handler
+            .routed_name_answer("8.100.51.198.in-addr.arpa.", RecordType::PTR)
+            .is_none());
+
+        handler.cleanup_zone("corp").await.unwrap();
+        assert!(handler.routed_names().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expands_single_label_names_under_search_domains() {
+        let upstream = fake_upstream().await;
+        let mut config = zones_config(&upstream.to_string());
+        config.server.search_domains = vec!["corp.example".to_string()];
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let mock = MockRouteAdder::new();
+        *handler.route_manager.write().await =
+            RouteManager::with_adder(Box::new(mock.clone()), None);
+
+        let name = Name::from_ascii("git.").unwrap();
+        let query = query_message(name.clone(), RecordType::A, 0x1234, true);
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let (response, routes) = handler.expand_search(&query, client).await.unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.queries()[0].name(), &name);
+        let answers = response.answers();
+        assert_eq!(answers[0].name(), &name);
+        assert_eq!(
+            answers[0].data().and_then(|d| d.as_cname()),
+            Some(&CNAME(Name::from_ascii("git.corp.example.").unwrap()))
+        );
+        assert_eq!(
+            answers[1].data().and_then(|d| d.as_a()),
+            Some(&A(Ipv4Addr::new(198, 51, 100, 7)))
+        );
+
+        // Routed through the zone of the expanded name
+        let added = routes.unwrap().added.await.unwrap();
+        assert_eq!(added[0].zone, "corp");
+    }
+
+    #[tokio::test]
+    async fn explain_reports_zone_upstream_and_routes() {
+        let upstream = fake_upstream().await;
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [server]
+            listen_address = "127.0.0.1:15353"
+            default_upstream = ["{upstream}"]
+            route_aggregation_prefix = 24
+
+            [routing]
+            dry_run = true
+
+            [[zones]]
+            name = "corp"
+            route_type = "via"
+            route_target = "198.51.100.1"
+            dns_servers = ["{upstream}"]
+            domains = ["corp.example"]
+
+            [[zones]]
+            name = "lab"
+            route_type = "blackhole"
+            domains = ["lab.example"]
+            exclude_routes = ["198.51.100.0/25"]
+            "#
+        ))
+        .unwrap();
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+
+        let resolution = handler.explain("www.corp.example", None).await.unwrap();
+        assert_eq!(resolution.name, "www.corp.example.");
+        assert_eq!(resolution.zone.as_deref(), Some("corp"));
+        assert_eq!(resolution.answered_by, Some(upstream));
+        assert_eq!(resolution.addresses, vec![IpAddr::from([198, 51, 100, 7])]);
+        assert_eq!(
+            resolution.routes,
+            vec![PlannedRoute {
+                ip: IpAddr::from([198, 51, 100, 7]),
+                zone: "corp".to_string(),
+                target: "via 198.51.100.1".to_string(),
+                prefix: Some("198.51.100.0/24".to_string()),
+                installed: false,
+                excluded: false,
+            }]
+        );
+
+        let resolution = handler.explain("lab.example", None).await.unwrap();
+        assert!(resolution.routes[0].excluded);
+        assert_eq!(resolution.routes[0].target, "blackhole");
+        let resolution = handler.explain("other.example", None).await.unwrap();
+        assert_eq!(resolution.zone, None);
+        assert!(resolution.routes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn preresolve_routes_zone_domains() {
+        let upstream = fake_upstream().await;
+        let mut config = zones_config(&upstream.to_string());
+        // Client-scoped zones are preresolved all the same
+        config.zones[0].clients = vec!["10.9.0.0/16".to_string()];
+        config.zones[0]
+            .domains
+            .push("*.apps.corp.example".to_string());
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        let mock = MockRouteAdder::new();
+        *handler.route_manager.write().await =
+            RouteManager::with_adder(Box::new(mock.clone()), None);
+
+        assert_eq!(handler.preresolve("corp").await.unwrap(), 1);
+        assert_eq!(mock.routes().len(), 1);
+        let routed = handler
+            .routed_name(IpAddr::from([198, 51, 100, 7]))
+            .unwrap();
+        assert_eq!(routed.name, "corp.example");
+        assert_eq!(routed.zone, "corp");
+
+        assert!(handler.preresolve("missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn delays_aaaa_answers_for_zone() {
+        let mut config = zones_config("10.0.0.53:53");
+        config.zones[0].aaaa_delay_ms = Some(300);
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let corp = matcher.find_zone("www.corp.example.", None);
+        let media = matcher.find_zone("www.media.example.", None);
+        let name = Name::from_ascii("www.corp.example.").unwrap();
+        let aaaa = [Record::from_rdata(
+            name.clone(),
+            300,
+            RData::AAAA(AAAA("2001:db8::7".parse().unwrap())),
+        )];
+        let a = [Record::from_rdata(
+            name,
+            300,
+            RData::A(A(Ipv4Addr::new(198, 51, 100, 7))),
+        )];
+
+        assert_eq!(
+            aaaa_delay(corp.as_ref(), &aaaa),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(aaaa_delay(corp.as_ref(), &a), None);
+        assert_eq!(aaaa_delay(corp.as_ref(), &[]), None);
+        assert_eq!(aaaa_delay(media.as_ref(), &aaaa), None);
+    }
+}
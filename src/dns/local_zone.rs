@@ -0,0 +1,300 @@
+use crate::config::LocalZoneConfig;
+use crate::error::{self, LeshyError};
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::{NS, SOA};
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_proto::serialize::txt::Parser;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// CNAMEs followed inside a local zone for one answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The zones leshy answers for itself (`local_zones`), authoritatively:
+/// their names never go upstream.
+#[derive(Default)]
+pub struct LocalZones {
+    zones: Vec<LocalZone>,
+}
+
+/// An authoritative answer from a local zone, sent with the AA flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalAnswer {
+    pub rcode: ResponseCode,
+    pub answers: Vec<Record>,
+    /// The zone's SOA for NXDOMAIN and NODATA answers
+    pub authority: Vec<Record>,
+}
+
+struct LocalZone {
+    origin: LowerName,
+    soa: Record,
+    /// Records by owner name
+    records: HashMap<LowerName, Vec<Record>>,
+}
+
+impl LocalZones {
+    /// Parse each zone's `records` and `file`, adding an SOA and NS record
+    /// at the apex unless they have their own.
+    pub fn load(configs: &[LocalZoneConfig]) -> error::Result<Self> {
+        let zones = configs
+            .iter()
+            .map(|config| {
+                LocalZone::load(config)
+                    .map_err(|e| LeshyError::Config(format!("Local zone '{}': {e}", config.name)))
+            })
+            .collect::<error::Result<_>>()?;
+        Ok(Self { zones })
+    }
+
+    /// The answer for `name` from the most specific local zone holding it;
+    /// None for names outside every local zone.
+    pub fn lookup(&self, name: &Name, qtype: RecordType) -> Option<LocalAnswer> {
+        let name = LowerName::from(name);
+        let zone = self
+            .zones
+            .iter()
+            .filter(|zone| zone.origin.zone_of(&name))
+            .max_by_key(|zone| zone.origin.num_labels())?;
+        Some(zone.answer(name, qtype))
+    }
+}
+
+impl LocalZone {
+    fn load(config: &LocalZoneConfig) -> anyhow::Result<Self> {
+        let origin = Name::from_ascii(&config.name)?.append_domain(&Name::root())?;
+        let mut records: HashMap<LowerName, Vec<Record>> = HashMap::new();
+
+        // Entries without their own TTL get the zone's
+        let mut sources = vec![(config.records.join("\n"), None)];
+        if let Some(path) = &config.file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("failed to read '{}': {e}", path.display()))?;
+            sources.push((content, Some(path.clone())));
+        }
+        for (content, path) in sources {
+            let text = format!("$TTL {}\n{content}\n", config.ttl);
+            let (_, parsed) = Parser::new(text, path, Some(origin.clone()))
+                .parse()
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            for record in parsed
+                .into_values()
+                .flat_map(|set| set.records_without_rrsigs().cloned().collect::<Vec<_>>())
+            {
+                if !origin.zone_of(record.name()) {
+                    anyhow::bail!("{} is outside the zone", record.name());
+                }
+                records
+                    .entry(LowerName::from(record.name()))
+                    .or_default()
+                    .push(record);
+            }
+        }
+
+        let nameserver = Name::from_ascii(config.nameserver.as_deref().unwrap_or("localhost."))?
+            .append_domain(&Name::root())?;
+        let apex = records.entry(LowerName::from(&origin)).or_default();
+        let soa = match apex.iter().find(|r| r.record_type() == RecordType::SOA) {
+            Some(soa) => soa.clone(),
+            None => {
+                let serial = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |elapsed| elapsed.as_secs() as u32);
+                let rname = Name::from_ascii("hostmaster")?.append_domain(&origin)?;
+                let soa = SOA::new(
+                    nameserver.clone(),
+                    rname,
+                    serial,
+                    3600,
+                    600,
+                    86400,
+                    config.ttl,
+                );
+                let soa = Record::from_rdata(origin.clone(), config.ttl, RData::SOA(soa));
+                apex.push(soa.clone());
+                soa
+            }
+        };
+        if !apex.iter().any(|r| r.record_type() == RecordType::NS) {
+            let ns = RData::NS(NS(nameserver));
+            apex.push(Record::from_rdata(origin.clone(), config.ttl, ns));
+        }
+
+        Ok(Self {
+            origin: LowerName::from(&origin),
+            soa,
+            records,
+        })
+    }
+
+    fn answer(&self, name: LowerName, qtype: RecordType) -> LocalAnswer {
+        let mut answers = Vec::new();
+        let mut owner = name;
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(records) = self.find(&owner) else {
+                // A CNAME into a name that doesn't exist still answers
+                return match answers.is_empty() {
+                    true => self.negative(ResponseCode::NXDomain, answers),
+                    false => self.negative(ResponseCode::NoError, answers),
+                };
+            };
+            // Wildcard records answer as the name asked for
+            let owned = |record: &Record| {
+                let mut record = record.clone();
+                record.set_name(Name::from(&owner));
+                record
+            };
+
+            let matching: Vec<Record> = records
+                .iter()
+                .filter(|r| qtype == RecordType::ANY || r.record_type() == qtype)
+                .map(owned)
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                return LocalAnswer {
+                    rcode: ResponseCode::NoError,
+                    answers,
+                    authority: Vec::new(),
+                };
+            }
+
+            let cname = records.iter().find_map(|r| match r.data() {
+                Some(RData::CNAME(target)) => Some((owned(r), target.0.clone())),
+                _ => None,
+            });
+            let Some((cname, target)) = cname else {
+                return self.negative(ResponseCode::NoError, answers);
+            };
+            answers.push(cname);
+            let target = LowerName::from(&target);
+            if !self.origin.zone_of(&target) {
+                // The client resolves the rest
+                break;
+            }
+            owner = target;
+        }
+        LocalAnswer {
+            rcode: ResponseCode::NoError,
+            answers,
+            authority: Vec::new(),
+        }
+    }
+
+    /// The records of `name`: its own, none for a name that only has
+    /// names below it, else those of the closest wildcard covering it.
+    fn find(&self, name: &LowerName) -> Option<&[Record]> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records);
+        }
+        if self.records.keys().any(|owner| name.zone_of(owner)) {
+            return Some(&[]);
+        }
+        let mut encloser = Name::from(name);
+        while encloser.num_labels() > self.origin.num_labels() {
+            let wildcard = LowerName::from(encloser.clone().into_wildcard());
+            if let Some(records) = self.records.get(&wildcard) {
+                return Some(records);
+            }
+            encloser = encloser.base_name();
+        }
+        None
+    }
+
+    fn negative(&self, rcode: ResponseCode, answers: Vec<Record>) -> LocalAnswer {
+        LocalAnswer {
+            rcode,
+            answers,
+            authority: vec![self.soa.clone()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zones() -> LocalZones {
+        let config: LocalZoneConfig = toml::from_str(
+            r#"
+            name = "home.lan"
+            records = [
+                "nas A 192.168.1.10",
+                "www CNAME nas",
+                "printer.office 60 A 192.168.1.20",
+                "*.apps A 192.168.1.30",
+                "mail CNAME mail.example.com.",
+            ]
+            "#,
+        )
+        .unwrap();
+        LocalZones::load(&[config]).unwrap()
+    }
+
+    fn lookup(zones: &LocalZones, name: &str, qtype: RecordType) -> LocalAnswer {
+        zones
+            .lookup(&Name::from_ascii(name).unwrap(), qtype)
+            .unwrap()
+    }
+
+    fn data(answer: &LocalAnswer) -> Vec<String> {
+        answer
+            .answers
+            .iter()
+            .map(|r| format!("{} {}", r.name(), r.data().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn answers_records_and_follows_cnames() {
+        let zones = zones();
+        let answer = lookup(&zones, "nas.home.lan.", RecordType::A);
+        assert_eq!(answer.rcode, ResponseCode::NoError);
+        assert_eq!(data(&answer), ["nas.home.lan. 192.168.1.10"]);
+
+        let answer = lookup(&zones, "WWW.home.lan.", RecordType::A);
+        assert_eq!(
+            data(&answer),
+            ["www.home.lan. nas.home.lan.", "nas.home.lan. 192.168.1.10"]
+        );
+
+        // A CNAME out of the zone is left to the client
+        let answer = lookup(&zones, "mail.home.lan.", RecordType::A);
+        assert_eq!(data(&answer), ["mail.home.lan. mail.example.com."]);
+
+        let answer = lookup(&zones, "x.apps.home.lan.", RecordType::A);
+        assert_eq!(data(&answer), ["x.apps.home.lan. 192.168.1.30"]);
+        assert_eq!(
+            zones.lookup(&Name::from_ascii("nas.example.").unwrap(), RecordType::A),
+            None
+        );
+    }
+
+    #[test]
+    fn answers_nxdomain_and_nodata_with_soa() {
+        let zones = zones();
+        let answer = lookup(&zones, "missing.home.lan.", RecordType::A);
+        assert_eq!(answer.rcode, ResponseCode::NXDomain);
+        assert_eq!(answer.authority[0].record_type(), RecordType::SOA);
+
+        for name in ["nas.home.lan.", "office.home.lan."] {
+            let answer = lookup(&zones, name, RecordType::AAAA);
+            assert_eq!(answer.rcode, ResponseCode::NoError, "{name}");
+            assert!(answer.answers.is_empty());
+            assert_eq!(answer.authority.len(), 1);
+        }
+
+        let answer = lookup(&zones, "home.lan.", RecordType::NS);
+        assert_eq!(data(&answer), ["home.lan. localhost."]);
+        let answer = lookup(&zones, "home.lan.", RecordType::SOA);
+        assert_eq!(answer.answers.len(), 1);
+    }
+
+    #[test]
+    fn rejects_records_outside_the_zone() {
+        let config: LocalZoneConfig =
+            toml::from_str("name = \"home.lan\"\nrecords = [\"nas.example.com. A 10.0.0.1\"]")
+                .unwrap();
+        assert!(LocalZones::load(&[config]).is_err());
+    }
+}
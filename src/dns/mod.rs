@@ -1,6 +1,18 @@
 pub mod cache;
+pub mod dns64;
+pub mod docker;
+pub mod fake_ip;
+pub mod forward_limit;
 pub mod handler;
+pub mod local_zone;
+pub mod query_log;
+pub mod rate_limit;
+pub mod rewrite;
+pub mod routed_names;
 pub mod server;
+pub mod stats;
+pub mod top;
+pub mod udp_pool;
 
-pub use handler::DnsHandler;
-pub use server::DnsServer;
+pub use handler::{DnsHandler, SharedHandler};
+pub use server::{DnsServer, Listen, Sockets};
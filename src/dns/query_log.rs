@@ -0,0 +1,308 @@
+use crate::config::{QueryLogRotation, ServerConfig};
+use crate::error::{self, LeshyError};
+use chrono::{DateTime, Local, NaiveDate, Timelike};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+
+/// Entries waiting for the writer thread; more are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// One line of the query log.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct QueryLogEntry {
+    /// When the query was received (RFC 3339, local time)
+    pub timestamp: String,
+    pub client: IpAddr,
+    /// Query name, without the trailing dot
+    pub qname: String,
+    pub qtype: String,
+    /// Zone the query matched; None = default upstream
+    pub zone: Option<String>,
+    /// Upstream that answered; None for cache hits and failures
+    pub upstream: Option<SocketAddr>,
+    pub cached: bool,
+    pub rcode: String,
+    /// Milliseconds until the response was ready
+    pub latency_ms: f64,
+    /// Routes the answer's addresses newly installed
+    pub routes_added: Vec<AddedRoute>,
+}
+
+/// A route installed for a query's answer.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AddedRoute {
+    pub ip: IpAddr,
+    pub zone: String,
+}
+
+/// Where and how the query log is written and rotated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryLogSettings {
+    pub path: PathBuf,
+    /// Bytes; 0 = no size limit
+    pub max_size: u64,
+    pub rotation: QueryLogRotation,
+    pub keep: usize,
+}
+
+impl QueryLogSettings {
+    /// The `[server]` query log settings; None if `query_log` is unset.
+    pub fn from_config(server: &ServerConfig) -> Option<Self> {
+        Some(Self {
+            path: server.query_log.clone()?,
+            max_size: server.query_log_max_size.saturating_mul(1024 * 1024),
+            rotation: server.query_log_rotation,
+            keep: server.query_log_keep,
+        })
+    }
+}
+
+/// JSONL query log. Entries are handed to a writer thread, so logging
+/// never blocks a query; dropping the log flushes and closes the file.
+pub struct QueryLog {
+    settings: QueryLogSettings,
+    sender: Option<SyncSender<QueryLogEntry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl QueryLog {
+    pub fn open(settings: QueryLogSettings) -> error::Result<Self> {
+        let writer = Writer::open(settings.clone())?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let writer = std::thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || writer.run(receiver))
+            .map_err(|e| LeshyError::io("failed to start the query log writer", e))?;
+        Ok(Self {
+            settings,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn settings(&self) -> &QueryLogSettings {
+        &self.settings
+    }
+
+    pub fn record(&self, entry: QueryLogEntry) {
+        let Some(sender) = &self.sender else { return };
+        if let Err(TrySendError::Full(entry)) = sender.try_send(entry) {
+            tracing::warn!(qname = entry.qname, "Query log queue full, dropping entry");
+        }
+    }
+}
+
+impl Drop for QueryLog {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+struct Writer {
+    settings: QueryLogSettings,
+    file: BufWriter<File>,
+    size: u64,
+    /// Rotation period the open file belongs to
+    period: Option<(NaiveDate, u32)>,
+    /// Whether the last write failed, so errors are logged once
+    failing: bool,
+}
+
+impl Writer {
+    fn open(settings: QueryLogSettings) -> error::Result<Self> {
+        let file = open_append(&settings.path)?;
+        let metadata = file.metadata().map_err(|e| {
+            LeshyError::io(
+                format!("failed to open query log '{}'", settings.path.display()),
+                e,
+            )
+        })?;
+        let modified = metadata.modified().map(DateTime::<Local>::from);
+        Ok(Self {
+            period: modified.ok().and_then(|at| period(settings.rotation, at)),
+            size: metadata.len(),
+            file: BufWriter::new(file),
+            settings,
+            failing: false,
+        })
+    }
+
+    fn run(mut self, receiver: Receiver<QueryLogEntry>) {
+        while let Ok(entry) = receiver.recv() {
+            let mut result = self.write(&entry, Local::now());
+            while let Ok(entry) = receiver.try_recv() {
+                result = result.and(self.write(&entry, Local::now()));
+            }
+            result = result.and(self.file.flush().map_err(Into::into));
+            match result {
+                Ok(()) => self.failing = false,
+                Err(e) if !self.failing => {
+                    self.failing = true;
+                    tracing::warn!(
+                        path = %self.settings.path.display(),
+                        error = %e,
+                        "Failed to write query log"
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &QueryLogEntry, now: DateTime<Local>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let period = period(self.settings.rotation, now);
+        let oversize = self.settings.max_size > 0
+            && self.size > 0
+            && self.size + line.len() as u64 > self.settings.max_size;
+        if oversize || (self.size > 0 && period != self.period) {
+            self.rotate()?;
+        }
+        self.period = period;
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `path.N` to `path.N+1` (dropping the oldest), move the log to
+    /// `path.1` and start a new one.
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        let path = &self.settings.path;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", path.display()));
+        if self.settings.keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for n in (1..self.settings.keep).rev() {
+                if rotated(n).exists() {
+                    std::fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated(1))?;
+        }
+        self.file = BufWriter::new(open_append(path)?);
+        self.size = 0;
+        tracing::debug!(path = %path.display(), "Rotated query log");
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> error::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| LeshyError::io(format!("failed to open query log '{}'", path.display()), e))
+}
+
+/// The rotation period `at` falls in; None when rotating by size only.
+fn period(rotation: QueryLogRotation, at: DateTime<Local>) -> Option<(NaiveDate, u32)> {
+    match rotation {
+        QueryLogRotation::Never => None,
+        QueryLogRotation::Hourly => Some((at.date_naive(), at.hour())),
+        QueryLogRotation::Daily => Some((at.date_naive(), 0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(qname: &str) -> QueryLogEntry {
+        QueryLogEntry {
+            timestamp: "2026-10-16T12:00:00.000+00:00".to_string(),
+            client: "127.0.0.1".parse().unwrap(),
+            qname: qname.to_string(),
+            qtype: "A".to_string(),
+            zone: Some("corp".to_string()),
+            upstream: Some("198.51.100.53:53".parse().unwrap()),
+            cached: false,
+            rcode: "No Error".to_string(),
+            latency_ms: 1.5,
+            routes_added: vec![AddedRoute {
+                ip: "198.51.100.7".parse().unwrap(),
+                zone: "corp".to_string(),
+            }],
+        }
+    }
+
+    fn lines(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queries.jsonl");
+        let log = QueryLog::open(QueryLogSettings {
+            path: path.clone(),
+            max_size: 0,
+            rotation: QueryLogRotation::Never,
+            keep: 1,
+        })
+        .unwrap();
+        log.record(entry("www.corp.example"));
+        log.record(entry("api.corp.example"));
+        drop(log);
+
+        let lines = lines(&path);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["qname"], "www.corp.example");
+        assert_eq!(lines[0]["upstream"], "198.51.100.53:53");
+        assert_eq!(
+            lines[1]["routes_added"],
+            serde_json::json!([{ "ip": "198.51.100.7", "zone": "corp" }])
+        );
+    }
+
+    #[test]
+    fn rotates_by_size_and_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queries.jsonl");
+        let line_size = serde_json::to_vec(&entry("a.example")).unwrap().len() as u64 + 1;
+        let mut writer = Writer::open(QueryLogSettings {
+            path: path.clone(),
+            max_size: line_size * 2,
+            rotation: QueryLogRotation::Hourly,
+            keep: 2,
+        })
+        .unwrap();
+        let at = |hour| Local.with_ymd_and_hms(2026, 10, 16, hour, 0, 0).unwrap();
+
+        // Two lines fit, the third rotates
+        for name in ["a.example", "b.example", "c.example"] {
+            writer.write(&entry(name), at(9)).unwrap();
+        }
+        // A new hour rotates, and only two rotated files are kept
+        writer.write(&entry("d.example"), at(10)).unwrap();
+        writer.write(&entry("e.example"), at(11)).unwrap();
+        writer.file.flush().unwrap();
+
+        let names = |path: &Path| -> Vec<String> {
+            lines(path)
+                .iter()
+                .map(|line| line["qname"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let rotated = |n| PathBuf::from(format!("{}.{n}", path.display()));
+        assert_eq!(names(&path), ["e.example"]);
+        assert_eq!(names(&rotated(1)), ["d.example"]);
+        assert_eq!(names(&rotated(2)), ["c.example"]);
+        assert!(!rotated(3).exists());
+    }
+}
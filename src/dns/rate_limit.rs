@@ -0,0 +1,209 @@
+use crate::config::{RateLimitAction, ServerConfig};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets tracked at once; past this, full (idle) ones are dropped.
+const MAX_BUCKETS: usize = 100_000;
+
+/// How often a full table may be swept for idle buckets.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `[server]` rate limit options; None when rate limiting is off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitSettings {
+    /// Queries per second refilled into each bucket
+    pub rate: f64,
+    /// Bucket size: queries allowed at once
+    pub burst: f64,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub per_name: bool,
+    pub action: RateLimitAction,
+}
+
+impl RateLimitSettings {
+    pub fn from_config(server: &ServerConfig) -> Option<Self> {
+        if server.rate_limit == 0 {
+            return None;
+        }
+        let burst = server.rate_limit_burst.unwrap_or(server.rate_limit).max(1);
+        Some(Self {
+            rate: f64::from(server.rate_limit),
+            burst: f64::from(burst),
+            ipv4_prefix: server.rate_limit_ipv4_prefix,
+            ipv6_prefix: server.rate_limit_ipv6_prefix,
+            per_name: server.rate_limit_per_name,
+            action: server.rate_limit_action,
+        })
+    }
+}
+
+/// Token buckets per client prefix (and, with `per_name`, per queried
+/// name): each query takes a token, and tokens refill at `rate` per second
+/// up to `burst`.
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<Buckets>,
+}
+
+/// Client prefix and, with `per_name`, the lowercased name.
+type BucketKey = (IpAddr, Option<String>);
+
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    last_sweep: Option<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_sweep: None,
+            }),
+        }
+    }
+
+    pub fn settings(&self) -> &RateLimitSettings {
+        &self.settings
+    }
+
+    /// Take a token for a query for `qname` from `client`; false when the
+    /// client is over its limit.
+    pub fn allow(&self, client: IpAddr, qname: &str) -> bool {
+        self.allow_at(client, qname, Instant::now())
+    }
+
+    fn allow_at(&self, client: IpAddr, qname: &str, now: Instant) -> bool {
+        let settings = &self.settings;
+        let prefix = match client {
+            IpAddr::V4(ip) => IpAddr::V4(mask_v4(ip, settings.ipv4_prefix)),
+            IpAddr::V6(ip) => IpAddr::V6(mask_v6(ip, settings.ipv6_prefix)),
+        };
+        let key = (
+            prefix,
+            settings
+                .per_name
+                .then(|| qname.trim_end_matches('.').to_lowercase()),
+        );
+
+        let mut state = self.buckets.lock().unwrap();
+        if state.buckets.len() >= MAX_BUCKETS && !state.buckets.contains_key(&key) {
+            if state
+                .last_sweep
+                .is_none_or(|at| now.duration_since(at) >= SWEEP_INTERVAL)
+            {
+                state.last_sweep = Some(now);
+                state
+                    .buckets
+                    .retain(|_, bucket| bucket.refilled(settings, now) < settings.burst);
+            }
+            // Every tracked client is busy: let the query through rather
+            // than forget one that is being limited
+            if state.buckets.len() >= MAX_BUCKETS {
+                return true;
+            }
+        }
+
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: settings.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(settings, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Bucket {
+    /// Tokens held at `now`.
+    fn refilled(&self, settings: &RateLimitSettings, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * settings.rate).min(settings.burst)
+    }
+}
+
+fn mask_v4(ip: Ipv4Addr, prefix: u8) -> Ipv4Addr {
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    Ipv4Addr::from(u32::from(ip) & mask)
+}
+
+fn mask_v6(ip: Ipv6Addr, prefix: u8) -> Ipv6Addr {
+    let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(rate: f64, burst: f64) -> RateLimitSettings {
+        RateLimitSettings {
+            rate,
+            burst,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            per_name: false,
+            action: RateLimitAction::Refuse,
+        }
+    }
+
+    #[test]
+    fn limits_bursts_and_refills() {
+        let limiter = RateLimiter::new(settings(10.0, 3.0));
+        let client: IpAddr = "198.51.100.7".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allow_at(client, "a.example.", start));
+        }
+        assert!(!limiter.allow_at(client, "a.example.", start));
+        // Other clients have their own bucket
+        assert!(limiter.allow_at("198.51.100.8".parse().unwrap(), "a.example.", start));
+        // 10/s: one token back after 100 ms, no more than the burst later
+        let later = start + Duration::from_millis(100);
+        assert!(limiter.allow_at(client, "a.example.", later));
+        assert!(!limiter.allow_at(client, "a.example.", later));
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at(client, "a.example.", much_later));
+        }
+        assert!(!limiter.allow_at(client, "a.example.", much_later));
+    }
+
+    #[test]
+    fn keys_by_prefix_and_name() {
+        let limiter = RateLimiter::new(RateLimitSettings {
+            ipv4_prefix: 24,
+            per_name: true,
+            ..settings(1.0, 1.0)
+        });
+        let now = Instant::now();
+        assert!(limiter.allow_at("198.51.100.7".parse().unwrap(), "a.example.", now));
+        // Same /24, same name (case and trailing dot aside)
+        assert!(!limiter.allow_at("198.51.100.200".parse().unwrap(), "A.example", now));
+        assert!(limiter.allow_at("198.51.100.7".parse().unwrap(), "b.example.", now));
+        assert!(limiter.allow_at("203.0.113.7".parse().unwrap(), "a.example.", now));
+
+        assert_eq!(
+            mask_v6("2001:db8::1:2:3:4".parse().unwrap(), 64),
+            "2001:db8::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            mask_v4("198.51.100.7".parse().unwrap(), 0),
+            Ipv4Addr::UNSPECIFIED
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use crate::config::ZoneConfig;
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{RData, Record, RecordType};
+use std::net::IpAddr;
+
+/// TTL of the answers made up from `nxdomain_address`.
+pub const NXDOMAIN_TTL: u32 = 60;
+
+/// `response` to a `qtype` query after the zone's rewrite rules: AAAA
+/// records dropped (`filter_aaaa`), addresses replaced (`rewrite_answers`)
+/// and NXDOMAIN turned into an answer (`nxdomain_address`).
+pub fn apply(zone: &ZoneConfig, qtype: RecordType, mut response: Message) -> Message {
+    if zone.filter_aaaa || !zone.rewrite_answers.is_empty() {
+        let mut rewritten = false;
+        let answers: Vec<Record> = response
+            .take_answers()
+            .into_iter()
+            .filter(|record| !(zone.filter_aaaa && record.record_type() == RecordType::AAAA))
+            .map(|mut record| {
+                let to = match record.data() {
+                    Some(RData::A(a)) => zone.rewrite_answers.get(&IpAddr::V4(a.0)),
+                    Some(RData::AAAA(aaaa)) => zone.rewrite_answers.get(&IpAddr::V6(aaaa.0)),
+                    _ => None,
+                };
+                if let Some(&to) = to {
+                    record.set_data(Some(rdata(to)));
+                    rewritten = true;
+                }
+                record
+            })
+            .collect();
+        if rewritten {
+            // Changed here, so nothing upstream vouches for it
+            response.set_authentic_data(false);
+        }
+        response.add_answers(answers);
+    }
+
+    match zone.nxdomain_address {
+        Some(address) if response.response_code() == ResponseCode::NXDomain => {
+            nxdomain_answer(response, qtype, address)
+        }
+        _ => response,
+    }
+}
+
+/// The NXDOMAIN `response` turned into a NOERROR answer with `address`,
+/// or with no records for query types the address doesn't answer.
+fn nxdomain_answer(mut response: Message, qtype: RecordType, address: IpAddr) -> Message {
+    response.set_response_code(ResponseCode::NoError);
+    response.set_authentic_data(false);
+    let answers = matches!(
+        (qtype, address),
+        (RecordType::A, IpAddr::V4(_)) | (RecordType::AAAA, IpAddr::V6(_))
+    );
+    if answers {
+        let Some(name) = response.queries().first().map(|q| q.name().clone()) else {
+            return response;
+        };
+        // The SOA there was for the negative answer
+        response.take_name_servers();
+        response.add_answer(Record::from_rdata(name, NXDOMAIN_TTL, rdata(address)));
+    }
+    response
+}
+
+/// `response` without the A/AAAA records pointing at private addresses
+/// (`reject_private_answers`), and how many were dropped.
+pub fn drop_private(mut response: Message) -> (Message, usize) {
+    let answers = response.take_answers();
+    let count = answers.len();
+    let kept: Vec<Record> = answers
+        .into_iter()
+        .filter(|record| match record.data() {
+            Some(RData::A(a)) => !is_private(IpAddr::V4(a.0)),
+            Some(RData::AAAA(aaaa)) => !is_private(IpAddr::V6(aaaa.0)),
+            _ => true,
+        })
+        .collect();
+    let dropped = count - kept.len();
+    response.add_answers(kept);
+    (response, dropped)
+}
+
+/// Whether `ip` is private (RFC 1918, unique local), loopback, link-local
+/// or unspecified: nowhere a public name should point.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified()
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_private(IpAddr::V4(v4)),
+            None => {
+                v6.is_unique_local()
+                    || v6.is_loopback()
+                    || v6.is_unicast_link_local()
+                    || v6.is_unspecified()
+            }
+        },
+    }
+}
+
+fn rdata(ip: IpAddr) -> RData {
+    match ip {
+        IpAddr::V4(v4) => RData::A(A(v4)),
+        IpAddr::V6(v6) => RData::AAAA(AAAA(v6)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::Name;
+    use std::str::FromStr;
+
+    fn zone(rules: &str) -> ZoneConfig {
+        toml::from_str(&format!(
+            "name = \"corp\"\nroute_type = \"via\"\nroute_target = \"10.0.0.1\"\n{rules}"
+        ))
+        .unwrap()
+    }
+
+    fn response(qtype: RecordType, rcode: ResponseCode, answers: &[&str]) -> Message {
+        let name = Name::from_str("www.corp.example.").unwrap();
+        let mut message = Message::new();
+        message.add_query(Query::query(name.clone(), qtype));
+        message.set_response_code(rcode);
+        for answer in answers {
+            let ip: IpAddr = answer.parse().unwrap();
+            message.add_answer(Record::from_rdata(name.clone(), 300, rdata(ip)));
+        }
+        message
+    }
+
+    fn addresses(message: &Message) -> Vec<String> {
+        message
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::A(a)) => Some(a.0.to_string()),
+                Some(RData::AAAA(aaaa)) => Some(aaaa.0.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filters_aaaa_and_rewrites_addresses() {
+        let corp = zone(
+            "filter_aaaa = true\n\
+             rewrite_answers = { \"203.0.113.10\" = \"10.20.0.10\" }",
+        );
+        let upstream = response(
+            RecordType::A,
+            ResponseCode::NoError,
+            &["203.0.113.10", "203.0.113.11"],
+        );
+        let rewritten = apply(&corp, RecordType::A, upstream);
+        assert_eq!(addresses(&rewritten), ["10.20.0.10", "203.0.113.11"]);
+
+        let upstream = response(RecordType::AAAA, ResponseCode::NoError, &["2001:db8::1"]);
+        let rewritten = apply(&corp, RecordType::AAAA, upstream);
+        assert_eq!(rewritten.response_code(), ResponseCode::NoError);
+        assert!(rewritten.answers().is_empty());
+    }
+
+    #[test]
+    fn drops_private_addresses() {
+        let upstream = response(
+            RecordType::A,
+            ResponseCode::NoError,
+            &["192.168.1.10", "203.0.113.10", "127.0.0.1", "169.254.1.1"],
+        );
+        let (filtered, dropped) = drop_private(upstream);
+        assert_eq!(addresses(&filtered), ["203.0.113.10"]);
+        assert_eq!(dropped, 3);
+
+        for ip in ["fd00::1", "fe80::1", "::1", "::ffff:10.0.0.1"] {
+            assert!(is_private(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["2001:db8::1", "100.64.0.1", "8.8.8.8"] {
+            assert!(!is_private(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn answers_nxdomain_with_fallback_address() {
+        let corp = zone("nxdomain_address = \"10.20.0.1\"");
+        let rewritten = apply(
+            &corp,
+            RecordType::A,
+            response(RecordType::A, ResponseCode::NXDomain, &[]),
+        );
+        assert_eq!(rewritten.response_code(), ResponseCode::NoError);
+        assert_eq!(addresses(&rewritten), ["10.20.0.1"]);
+        assert_eq!(rewritten.answers()[0].ttl(), NXDOMAIN_TTL);
+
+        // The name exists now, just without IPv6 addresses
+        let rewritten = apply(
+            &corp,
+            RecordType::AAAA,
+            response(RecordType::AAAA, ResponseCode::NXDomain, &[]),
+        );
+        assert_eq!(rewritten.response_code(), ResponseCode::NoError);
+        assert!(rewritten.answers().is_empty());
+
+        // Without the rule NXDOMAIN stays
+        let rewritten = apply(
+            &zone(""),
+            RecordType::A,
+            response(RecordType::A, ResponseCode::NXDomain, &[]),
+        );
+        assert_eq!(rewritten.response_code(), ResponseCode::NXDomain);
+    }
+}
@@ -0,0 +1,118 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// TTL of reverse answers for routed addresses: the name behind an address
+/// can change with the next resolution
+pub const ROUTED_PTR_TTL: u32 = 60;
+
+/// Addresses remembered at most; beyond that the least recently resolved
+/// are forgotten.
+const MAX_NAMES: usize = 65536;
+
+/// The name whose answer got each address routed, kept across reloads, so
+/// reverse queries and the admin API tell what put an address in the
+/// routing table.
+#[derive(Default)]
+pub struct RoutedNames {
+    inner: Mutex<Inner>,
+}
+
+/// One routed address and the name it was resolved for, for the admin API.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RoutedName {
+    pub ip: IpAddr,
+    pub name: String,
+    pub zone: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    by_ip: HashMap<IpAddr, (RoutedName, u64)>,
+    /// Addresses by when they were last resolved
+    recency: BTreeMap<u64, IpAddr>,
+    clock: u64,
+}
+
+impl RoutedNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember that resolving `name` routed `ip` through `zone`.
+    pub fn record(&self, ip: IpAddr, name: &str, zone: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let now = inner.clock;
+        let entry = RoutedName {
+            ip,
+            name: name.trim_end_matches('.').to_string(),
+            zone: zone.to_string(),
+        };
+        if let Some((_, last)) = inner.by_ip.insert(ip, (entry, now)) {
+            inner.recency.remove(&last);
+        }
+        inner.recency.insert(now, ip);
+        while inner.by_ip.len() > MAX_NAMES {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.by_ip.remove(&oldest);
+        }
+    }
+
+    /// The name that last got `ip` routed.
+    pub fn get(&self, ip: IpAddr) -> Option<RoutedName> {
+        let inner = self.inner.lock().unwrap();
+        inner.by_ip.get(&ip).map(|(entry, _)| entry.clone())
+    }
+
+    /// Forget the addresses routed through `zone`, once its routes are gone.
+    pub fn forget_zone(&self, zone: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { by_ip, recency, .. } = &mut *inner;
+        by_ip.retain(|_, (entry, last)| {
+            let keep = entry.zone != zone;
+            if !keep {
+                recency.remove(last);
+            }
+            keep
+        });
+    }
+
+    /// Every remembered address, in address order.
+    pub fn entries(&self) -> Vec<RoutedName> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries: Vec<RoutedName> = inner
+            .by_ip
+            .values()
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        entries.sort_by_key(|entry| entry.ip);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_latest_name_per_address() {
+        let names = RoutedNames::new();
+        let ip: IpAddr = "104.16.1.1".parse().unwrap();
+        names.record(ip, "www.example.com.", "vpn");
+        names.record(ip, "cdn.example.com.", "vpn");
+        names.record("10.0.0.5".parse().unwrap(), "git.corp.example.", "corp");
+
+        let entry = names.get(ip).unwrap();
+        assert_eq!(entry.name, "cdn.example.com");
+        assert_eq!(entry.zone, "vpn");
+        assert_eq!(names.entries().len(), 2);
+
+        names.forget_zone("vpn");
+        assert_eq!(names.get(ip), None);
+        assert_eq!(names.entries()[0].name, "git.corp.example");
+    }
+}
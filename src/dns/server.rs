@@ -1,19 +1,79 @@
-use crate::dns::handler::DnsHandler;
+use crate::config::ServerConfig;
+use crate::dns::handler::SharedHandler;
+use crate::error::{self, LeshyError};
+use hickory_proto::op::ResponseCode;
+use hickory_server::authority::MessageResponseBuilder;
 use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
 use hickory_server::ServerFuture;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Notify;
 
-/// Wrapper around DnsHandler that allows Arc<RwLock<>> access
+/// How long a TCP connection may sit without sending a query.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Queries being answered, so shutdown can wait for them.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    /// Set on shutdown: new queries are refused
+    draining: AtomicBool,
+    /// Notified when the last query in flight finishes
+    idle: Notify,
+}
+
+/// Counts a query as in flight until dropped.
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl InFlight {
+    fn start(&self) -> Option<InFlightGuard<'_>> {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        let guard = InFlightGuard(self);
+        // Checked after counting, so drain() can't miss this query
+        (!self.draining.load(Ordering::Acquire)).then_some(guard)
+    }
+
+    /// Refuse new queries and wait up to `timeout` for those in flight.
+    /// Returns how many were still unanswered.
+    async fn drain(&self, timeout: Duration) -> usize {
+        self.draining.store(true, Ordering::Release);
+        let wait = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.count.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait).await;
+        self.count.load(Ordering::Acquire)
+    }
+}
+
+/// Wrapper around DnsHandler that serves queries with its current snapshot
 pub struct ReloadableHandler {
-    handler: Arc<RwLock<DnsHandler>>,
+    handler: SharedHandler,
+    in_flight: Arc<InFlight>,
 }
 
 impl ReloadableHandler {
-    pub fn new(handler: Arc<RwLock<DnsHandler>>) -> Self {
-        Self { handler }
+    pub fn new(handler: SharedHandler) -> Self {
+        Self {
+            handler,
+            in_flight: Arc::default(),
+        }
     }
 }
 
@@ -22,35 +82,495 @@ impl RequestHandler for ReloadableHandler {
     async fn handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
-        response_handle: R,
+        mut response_handle: R,
     ) -> ResponseInfo {
-        let handler = self.handler.read().await;
+        let Some(_in_flight) = self.in_flight.start() else {
+            // Shutting down: let the client move on to its next server
+            let builder = MessageResponseBuilder::from_message_request(request);
+            let response = builder.error_msg(request.header(), ResponseCode::Refused);
+            return response_handle.send_response(response).await.unwrap();
+        };
+        // Reloads swap in a new snapshot; this query finishes on the one
+        // it started with
+        let handler = self.handler.load_full();
         handler.handle_request(request, response_handle).await
     }
 }
 
+/// Where the server listens: `[server]` `listen_address`, `udp_workers`,
+/// `listen_interface` and, with `docker_dns`, `docker_bridge`. Changing any
+/// of them rebinds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub address: SocketAddr,
+    pub workers: usize,
+    pub interface: Option<String>,
+    pub docker_bridge: Option<String>,
+}
+
+impl Listen {
+    pub fn from_config(server: &ServerConfig) -> Self {
+        Self {
+            address: server.listen_address,
+            workers: server.udp_workers,
+            interface: server.listen_interface.clone(),
+            docker_bridge: server.docker_dns.then(|| server.docker_bridge.clone()),
+        }
+    }
+}
+
+/// Sockets bound for a `Listen` by [`DnsServer::bind`].
+pub struct Sockets {
+    udp: Vec<std::net::UdpSocket>,
+    /// For queries retried over TCP after a truncated answer
+    tcp: Vec<std::net::TcpListener>,
+}
+
 pub struct DnsServer {
     server: ServerFuture<ReloadableHandler>,
+    in_flight: Arc<InFlight>,
+    listen: Listen,
+    handler: SharedHandler,
 }
 
 impl DnsServer {
-    pub async fn new(
-        listen_addr: SocketAddr,
-        handler: Arc<RwLock<DnsHandler>>,
-    ) -> anyhow::Result<Self> {
-        let reloadable_handler = ReloadableHandler::new(handler);
+    /// Bind `workers` UDP sockets to `address` (0 = one per CPU); more than
+    /// one share the address with SO_REUSEPORT. A TCP listener takes the
+    /// same address. With `interface`, only queries arriving on that
+    /// interface are answered. With a Docker bridge, one more pair listens
+    /// on its address, unless the others already do. Needs no runtime, so
+    /// it can run before privileges are dropped.
+    pub fn bind(listen: &Listen) -> error::Result<Sockets> {
+        let workers = match listen.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let interface = listen.interface.as_deref();
+        let bind_error = |e| LeshyError::io(format!("failed to bind {}", listen.address), e);
+        let mut udp = (0..workers)
+            .map(|_| bind_udp(listen.address, workers > 1, interface).map_err(bind_error))
+            .collect::<error::Result<Vec<_>>>()?;
+        // On the port the UDP sockets got, should the config leave it to the kernel
+        let address = udp[0].local_addr().map_err(bind_error)?;
+        let mut tcp = vec![bind_tcp(address, interface).map_err(bind_error)?];
+        tracing::info!(
+            addr = %listen.address,
+            sockets = workers,
+            interface = interface.unwrap_or("any"),
+            "DNS server listening on UDP and TCP"
+        );
+        if let Some(bridge) = &listen.docker_bridge {
+            if let Some((bridge_udp, bridge_tcp)) = bind_docker_bridge(listen, bridge)? {
+                udp.push(bridge_udp);
+                tcp.push(bridge_tcp);
+            }
+        }
+        Ok(Sockets { udp, tcp })
+    }
+
+    /// Serve on `sockets`, as bound by [`DnsServer::bind`] for `listen`.
+    pub fn new(listen: Listen, sockets: Sockets, handler: SharedHandler) -> error::Result<Self> {
+        let reloadable_handler = ReloadableHandler::new(handler.clone());
+        let in_flight = reloadable_handler.in_flight.clone();
         let mut server = ServerFuture::new(reloadable_handler);
+        for socket in sockets.udp {
+            let socket = UdpSocket::from_std(socket)
+                .map_err(|e| LeshyError::io("failed to register UDP socket", e))?;
+            server.register_socket(socket);
+        }
+        for listener in sockets.tcp {
+            let listener = TcpListener::from_std(listener)
+                .map_err(|e| LeshyError::io("failed to register TCP listener", e))?;
+            server.register_listener(listener, TCP_TIMEOUT);
+        }
+        Ok(Self {
+            server,
+            in_flight,
+            listen,
+            handler,
+        })
+    }
 
-        // Bind UDP socket
-        let socket = UdpSocket::bind(listen_addr).await?;
-        tracing::info!(addr = %listen_addr, "DNS server listening on UDP");
-        server.register_socket(socket);
+    /// Serve on `listen` instead, with the same handler (and so the same
+    /// routes and cache). The new sockets are bound before the old ones
+    /// close, unless they can't share an address: then the old ones go
+    /// first, and come back if the new ones still can't be bound.
+    pub async fn rebind(&mut self, listen: Listen) -> error::Result<()> {
+        let sockets = match Self::bind(&listen) {
+            Ok(sockets) => sockets,
+            Err(LeshyError::Io { source, .. })
+                if source.kind() == std::io::ErrorKind::AddrInUse
+                    && (listen.address == self.listen.address
+                        || (listen.docker_bridge.is_some()
+                            && listen.docker_bridge == self.listen.docker_bridge)) =>
+            {
+                self.server.shutdown_gracefully().await.ok();
+                match Self::bind(&listen) {
+                    Ok(sockets) => sockets,
+                    Err(e) => {
+                        let sockets = Self::bind(&self.listen)?;
+                        *self = Self::new(self.listen.clone(), sockets, self.handler.clone())?;
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        let next = Self::new(listen, sockets, self.handler.clone())?;
+        let mut old = std::mem::replace(self, next);
+        // Stops reading the old sockets and accepting on the old listeners;
+        // queries already read are answered
+        tokio::spawn(async move {
+            if let Err(e) = old.server.shutdown_gracefully().await {
+                tracing::warn!(error = %e, "Old DNS sockets did not close cleanly");
+            }
+        });
+        Ok(())
+    }
 
-        Ok(Self { server })
+    pub async fn run(&mut self) -> error::Result<()> {
+        self.server
+            .block_until_done()
+            .await
+            .map_err(|e| LeshyError::Dns(format!("DNS server failed: {e}")))
     }
 
-    pub async fn run(mut self) -> anyhow::Result<()> {
-        self.server.block_until_done().await?;
-        Ok(())
+    /// Stop serving: refuse new queries, give those in flight up to
+    /// `timeout` to be answered, then close the sockets.
+    pub async fn shutdown(mut self, timeout: Duration) {
+        let unanswered = self.in_flight.drain(timeout).await;
+        if unanswered > 0 {
+            tracing::warn!(
+                queries = unanswered,
+                "Shutdown timeout reached, dropping queries in flight"
+            );
+        }
+        if let Err(e) = self.server.shutdown_gracefully().await {
+            tracing::warn!(error = %e, "DNS server did not stop cleanly");
+        }
+    }
+}
+
+/// A UDP socket and a TCP listener on the Docker `bridge`'s address at the
+/// listen port, or none if the bridge is down or the wildcard listener
+/// already covers it.
+fn bind_docker_bridge(
+    listen: &Listen,
+    bridge: &str,
+) -> error::Result<Option<(std::net::UdpSocket, std::net::TcpListener)>> {
+    let Some(ip) = crate::dns::docker::bridge_address(bridge) else {
+        tracing::warn!(
+            bridge,
+            "Docker bridge not found, containers can't reach leshy on it"
+        );
+        return Ok(None);
+    };
+    let covered =
+        listen.address.ip() == std::net::Ipv4Addr::UNSPECIFIED && listen.interface.is_none();
+    let socket = if covered {
+        None
+    } else {
+        let addr = SocketAddr::new(ip.into(), listen.address.port());
+        let bind_error = |e| LeshyError::io(format!("failed to bind {addr} on {bridge}"), e);
+        let udp = bind_udp(addr, false, None).map_err(bind_error)?;
+        let addr = udp.local_addr().map_err(bind_error)?;
+        Some((udp, bind_tcp(addr, None).map_err(bind_error)?))
+    };
+    if listen.address.port() == 53 {
+        tracing::info!(
+            bridge,
+            "Containers can use leshy with `--dns {ip}`, or \"dns\": [\"{ip}\"] in {}",
+            crate::dns::docker::DAEMON_JSON
+        );
+    } else {
+        tracing::warn!(
+            bridge,
+            port = listen.address.port(),
+            "Docker only sends container DNS to port 53"
+        );
+    }
+    Ok(socket)
+}
+
+/// A non-blocking UDP socket bound to `addr`. With `reuse_port`
+/// (SO_REUSEPORT) several can share it and the kernel balances queries
+/// between them; with `interface` (SO_BINDTODEVICE) it only sees traffic
+/// on that interface, whatever addresses it has.
+fn bind_udp(
+    addr: SocketAddr,
+    reuse_port: bool,
+    interface: Option<&str>,
+) -> std::io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// A non-blocking TCP listener on `addr`, on `interface` only if given.
+/// SO_REUSEADDR lets a rebind take the address back while connections to
+/// the old listener linger.
+fn bind_tcp(addr: SocketAddr, interface: Option<&str>) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &socket2::Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("cannot bind to interface '{interface}': {e}"),
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &socket2::Socket, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "listen_interface is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::dns::DnsHandler;
+    use crate::zones::ZoneMatcher;
+    use arc_swap::ArcSwap;
+    use hickory_proto::op::{Message, MessageType, Query};
+    use hickory_proto::rr::{Name, RecordType};
+
+    #[tokio::test]
+    async fn drain_waits_for_queries_in_flight() {
+        let in_flight = Arc::new(InFlight::default());
+        let guard_owner = in_flight.clone();
+        let query = tokio::spawn(async move {
+            let _guard = guard_owner.start().unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let started = std::time::Instant::now();
+        assert_eq!(in_flight.drain(Duration::from_secs(5)).await, 0);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        query.await.unwrap();
+        // Draining: new queries are turned away
+        assert!(in_flight.start().is_none());
+        assert_eq!(in_flight.count.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn reuseport_sockets_share_the_address() {
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), true, None).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_udp(addr, true, None).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        // A socket without it still can't take the address
+        assert!(std::net::UdpSocket::bind(addr).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binds_to_interface() {
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), false, Some("lo")).unwrap();
+        assert!(socket.local_addr().unwrap().port() > 0);
+        let error = bind_udp("127.0.0.1:0".parse().unwrap(), false, Some("nosuchif0")).unwrap_err();
+        assert!(error.to_string().contains("nosuchif0"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binds_on_docker_bridge() {
+        let mut listen = Listen {
+            address: "127.0.0.2:0".parse().unwrap(),
+            workers: 1,
+            interface: None,
+            docker_bridge: Some("lo".to_string()),
+        };
+        let sockets = DnsServer::bind(&listen).unwrap();
+        assert_eq!((sockets.udp.len(), sockets.tcp.len()), (2, 2));
+        assert_eq!(
+            sockets.udp[1].local_addr().unwrap().ip(),
+            std::net::Ipv4Addr::LOCALHOST
+        );
+        assert_eq!(
+            sockets.tcp[1].local_addr().unwrap(),
+            sockets.udp[1].local_addr().unwrap()
+        );
+
+        // Covered by the wildcard listener, or no such bridge
+        listen.address = "0.0.0.0:0".parse().unwrap();
+        assert_eq!(DnsServer::bind(&listen).unwrap().udp.len(), 1);
+        listen.docker_bridge = Some("nosuchif0".to_string());
+        assert_eq!(DnsServer::bind(&listen).unwrap().tcp.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_timeout() {
+        let in_flight = InFlight::default();
+        let _stuck = in_flight.start().unwrap();
+        assert_eq!(in_flight.drain(Duration::from_millis(50)).await, 1);
+    }
+
+    /// A handler whose upstream echoes each query back as an answer.
+    async fn echoing_handler() -> SharedHandler {
+        echoing_handler_with("").await
+    }
+
+    /// Same, with more `[server]` settings.
+    async fn echoing_handler_with(server: &str) -> SharedHandler {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = upstream.recv_from(&mut buf).await {
+                let mut response = Message::from_vec(&buf[..len]).unwrap();
+                response.set_message_type(MessageType::Response);
+                let _ = upstream.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        let config: Config = toml::from_str(&format!(
+            "[server]\nlisten_address = \"127.0.0.1:53\"\n\
+             default_upstream = [\"{upstream_addr}\"]\n{server}"
+        ))
+        .unwrap();
+        let handler = DnsHandler::new(config, ZoneMatcher::new(Vec::new()).unwrap()).unwrap();
+        Arc::new(ArcSwap::from_pointee(handler))
+    }
+
+    fn query() -> Message {
+        let mut query = Message::new();
+        query.set_id(0x4242).set_recursion_desired(true);
+        query.add_query(Query::query(
+            Name::from_ascii("rebind.example.").unwrap(),
+            RecordType::A,
+        ));
+        query
+    }
+
+    /// The answer to a query sent over UDP, if one comes.
+    async fn ask(server: SocketAddr) -> Option<Message> {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&query().to_vec().unwrap(), server)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 512];
+        match tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf)).await {
+            Ok(Ok(len)) => Some(Message::from_vec(&buf[..len]).unwrap()),
+            _ => None,
+        }
+    }
+
+    /// The answer to a query sent over TCP, if one comes.
+    async fn ask_tcp(server: SocketAddr) -> Option<Message> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let exchange = async {
+            let mut stream = tokio::net::TcpStream::connect(server).await.ok()?;
+            let query = query().to_vec().unwrap();
+            stream.write_u16(query.len() as u16).await.ok()?;
+            stream.write_all(&query).await.ok()?;
+            let mut buf = vec![0u8; stream.read_u16().await.ok()? as usize];
+            stream.read_exact(&mut buf).await.ok()?;
+            Message::from_vec(&buf).ok()
+        };
+        tokio::time::timeout(Duration::from_secs(2), exchange)
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn answers(server: SocketAddr) -> bool {
+        ask(server)
+            .await
+            .is_some_and(|answer| answer.id() == 0x4242)
+    }
+
+    async fn answers_tcp(server: SocketAddr) -> bool {
+        ask_tcp(server)
+            .await
+            .is_some_and(|answer| answer.id() == 0x4242)
+    }
+
+    #[tokio::test]
+    async fn rebinds_to_new_listen_settings() {
+        let handler = echoing_handler().await;
+        let first = Listen {
+            address: "127.0.0.1:0".parse().unwrap(),
+            workers: 1,
+            interface: None,
+            docker_bridge: None,
+        };
+        let sockets = DnsServer::bind(&first).unwrap();
+        let address = sockets.udp[0].local_addr().unwrap();
+        let listen = Listen { address, ..first };
+        let mut server = DnsServer::new(listen.clone(), sockets, handler).unwrap();
+        assert!(answers(address).await);
+        assert!(answers_tcp(address).await);
+
+        // Same address, now shared by two sockets: the old one closes first
+        let shared = Listen {
+            workers: 2,
+            ..listen.clone()
+        };
+        server.rebind(shared).await.unwrap();
+        assert!(answers(address).await);
+        assert!(answers_tcp(address).await);
+
+        // Moved: the old address is let go
+        let moved = Listen {
+            address: "127.0.0.1:0".parse().unwrap(),
+            ..listen
+        };
+        server.rebind(moved).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(std::net::UdpSocket::bind(address).is_ok());
+        assert!(std::net::TcpListener::bind(address).is_ok());
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_retried_over_tcp() {
+        let handler = echoing_handler_with(
+            "rate_limit = 1\nrate_limit_burst = 1\nrate_limit_action = \"truncate\"\n",
+        )
+        .await;
+        let first = Listen {
+            address: "127.0.0.1:0".parse().unwrap(),
+            workers: 1,
+            interface: None,
+            docker_bridge: None,
+        };
+        let sockets = DnsServer::bind(&first).unwrap();
+        let address = sockets.udp[0].local_addr().unwrap();
+        let server = DnsServer::new(Listen { address, ..first }, sockets, handler).unwrap();
+
+        assert!(!ask(address).await.unwrap().truncated());
+        assert!(ask(address).await.unwrap().truncated());
+        // Over the limit still, but a TCP retry is answered in full
+        let answer = ask_tcp(address).await.unwrap();
+        assert!(!answer.truncated());
+        assert_eq!(answer.queries().len(), 1);
+        server.shutdown(Duration::from_secs(1)).await;
     }
 }
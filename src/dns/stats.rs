@@ -0,0 +1,382 @@
+use crate::config::DnsProtocol;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Name counters of queries that matched no zone are kept under.
+pub const DEFAULT_ZONE: &str = "(default)";
+
+/// Upper bounds (milliseconds) of the upstream round-trip time buckets;
+/// slower responses land in a last, unbounded bucket.
+pub const RTT_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// An upstream is queried over UDP or TCP, and each is tracked apart.
+type UpstreamKey = (SocketAddr, DnsProtocol);
+
+/// Per-zone query counters and per-upstream health, shared by every
+/// request and kept across config reloads.
+#[derive(Debug, Default)]
+pub struct QueryStats {
+    zones: RwLock<BTreeMap<String, Arc<ZoneCounters>>>,
+    upstreams: RwLock<BTreeMap<UpstreamKey, Arc<UpstreamCounters>>>,
+}
+
+#[derive(Debug, Default)]
+struct ZoneCounters {
+    queries: AtomicU64,
+    cache_hits: AtomicU64,
+    routes_installed: AtomicU64,
+    upstream_failures: AtomicU64,
+    rate_limited: AtomicU64,
+    overloaded: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct UpstreamCounters {
+    answered: AtomicU64,
+    failures: AtomicU64,
+    consecutive_failures: AtomicU64,
+    servfail: AtomicU64,
+    refused: AtomicU64,
+    unreachable: AtomicU64,
+    /// Responses per `RTT_BUCKETS_MS` bucket, plus the unbounded one
+    rtt_buckets: [AtomicU64; RTT_BUCKETS_MS.len() + 1],
+    rtt_sum_us: AtomicU64,
+    rtt_max_us: AtomicU64,
+}
+
+/// Why an upstream attempt failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    /// Answered SERVFAIL
+    ServFail,
+    /// Answered REFUSED
+    Refused,
+    /// No usable response: connection error, timeout or garbage
+    Unreachable,
+}
+
+/// A point-in-time copy of one zone's counters.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct ZoneStats {
+    /// Queries the zone matched (or, for the default, that no zone matched)
+    pub queries: u64,
+    /// Of those, answered from the cache
+    pub cache_hits: u64,
+    /// New routes installed for IPs the zone's queries resolved to
+    pub routes_installed: u64,
+    /// Upstream attempts that failed (errors, timeouts, SERVFAIL/REFUSED)
+    pub upstream_failures: u64,
+    /// Of `queries`, turned away by the client rate limit
+    pub rate_limited: u64,
+    /// Of `queries`, answered SERVFAIL because too many were being
+    /// forwarded already
+    pub overloaded: u64,
+}
+
+/// A point-in-time copy of one upstream server's counters.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct UpstreamStats {
+    /// Queries it answered
+    pub answered: u64,
+    /// Attempts that failed (errors, timeouts, SERVFAIL/REFUSED)
+    pub failures: u64,
+    /// Failures since it last answered; 0 = healthy
+    pub consecutive_failures: u64,
+    /// Of `failures`, SERVFAIL answers
+    pub servfail: u64,
+    /// Of `failures`, REFUSED answers
+    pub refused: u64,
+    /// Of `failures`, errors and timeouts
+    pub unreachable: u64,
+    /// Round-trip times of its responses, failures answered included
+    pub rtt: RttStats,
+}
+
+/// Round-trip time distribution of an upstream's responses. Percentiles
+/// are estimated as the upper bound of the bucket they fall in.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct RttStats {
+    pub responses: u64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    pub buckets: Vec<RttBucket>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RttBucket {
+    /// Upper bound; None for the last, unbounded bucket
+    pub le_ms: Option<u64>,
+    pub responses: u64,
+}
+
+impl QueryStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_query(&self, zone: Option<&str>) {
+        self.counters(zone).queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_hit(&self, zone: Option<&str>) {
+        self.counters(zone)
+            .cache_hits
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self, zone: Option<&str>) {
+        self.counters(zone)
+            .rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_overloaded(&self, zone: Option<&str>) {
+        self.counters(zone)
+            .overloaded
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_route_installed(&self, zone: &str) {
+        self.counters(Some(zone))
+            .routes_installed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upstream_answer(&self, upstream: SocketAddr, protocol: DnsProtocol) {
+        let counters = self.upstream_counters((upstream, protocol));
+        counters.answered.fetch_add(1, Ordering::Relaxed);
+        counters.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Count a response from `upstream` that took `rtt`.
+    pub fn record_upstream_rtt(&self, upstream: SocketAddr, protocol: DnsProtocol, rtt: Duration) {
+        let counters = self.upstream_counters((upstream, protocol));
+        let bucket = RTT_BUCKETS_MS
+            .iter()
+            .position(|&bound| rtt <= Duration::from_millis(bound))
+            .unwrap_or(RTT_BUCKETS_MS.len());
+        counters.rtt_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX);
+        counters.rtt_sum_us.fetch_add(micros, Ordering::Relaxed);
+        counters.rtt_max_us.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Returns the upstream's failures in a row, this one included.
+    pub fn record_upstream_failure(
+        &self,
+        zone: Option<&str>,
+        upstream: SocketAddr,
+        protocol: DnsProtocol,
+        failure: UpstreamFailure,
+    ) -> u64 {
+        self.counters(zone)
+            .upstream_failures
+            .fetch_add(1, Ordering::Relaxed);
+        let counters = self.upstream_counters((upstream, protocol));
+        counters.failures.fetch_add(1, Ordering::Relaxed);
+        match failure {
+            UpstreamFailure::ServFail => &counters.servfail,
+            UpstreamFailure::Refused => &counters.refused,
+            UpstreamFailure::Unreachable => &counters.unreachable,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        counters
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1
+    }
+
+    /// Current counters of every zone seen so far, by zone name.
+    pub fn snapshot(&self) -> BTreeMap<String, ZoneStats> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, counters)| {
+                let stats = ZoneStats {
+                    queries: counters.queries.load(Ordering::Relaxed),
+                    cache_hits: counters.cache_hits.load(Ordering::Relaxed),
+                    routes_installed: counters.routes_installed.load(Ordering::Relaxed),
+                    upstream_failures: counters.upstream_failures.load(Ordering::Relaxed),
+                    rate_limited: counters.rate_limited.load(Ordering::Relaxed),
+                    overloaded: counters.overloaded.load(Ordering::Relaxed),
+                };
+                (name.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// Current counters of every upstream queried so far, by address and
+    /// protocol.
+    pub fn upstream_snapshot(&self) -> BTreeMap<(SocketAddr, DnsProtocol), UpstreamStats> {
+        self.upstreams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&upstream, counters)| {
+                let stats = UpstreamStats {
+                    answered: counters.answered.load(Ordering::Relaxed),
+                    failures: counters.failures.load(Ordering::Relaxed),
+                    consecutive_failures: counters.consecutive_failures.load(Ordering::Relaxed),
+                    servfail: counters.servfail.load(Ordering::Relaxed),
+                    refused: counters.refused.load(Ordering::Relaxed),
+                    unreachable: counters.unreachable.load(Ordering::Relaxed),
+                    rtt: counters.rtt_stats(),
+                };
+                (upstream, stats)
+            })
+            .collect()
+    }
+
+    fn upstream_counters(&self, upstream: UpstreamKey) -> Arc<UpstreamCounters> {
+        if let Some(counters) = self.upstreams.read().unwrap().get(&upstream) {
+            return Arc::clone(counters);
+        }
+        Arc::clone(self.upstreams.write().unwrap().entry(upstream).or_default())
+    }
+
+    fn counters(&self, zone: Option<&str>) -> Arc<ZoneCounters> {
+        let name = zone.unwrap_or(DEFAULT_ZONE);
+        if let Some(counters) = self.zones.read().unwrap().get(name) {
+            return Arc::clone(counters);
+        }
+        Arc::clone(
+            self.zones
+                .write()
+                .unwrap()
+                .entry(name.to_string())
+                .or_default(),
+        )
+    }
+}
+
+impl UpstreamCounters {
+    fn rtt_stats(&self) -> RttStats {
+        let counts: Vec<u64> = self
+            .rtt_buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let responses: u64 = counts.iter().sum();
+        if responses == 0 {
+            return RttStats::default();
+        }
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        let max_ms = ms(self.rtt_max_us.load(Ordering::Relaxed));
+        // Upper bound of the bucket holding the q-th response, at most the
+        // slowest one seen
+        let percentile = |q: f64| {
+            let rank = (q * responses as f64).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            for (bucket, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    return RTT_BUCKETS_MS
+                        .get(bucket)
+                        .map_or(max_ms, |&bound| (bound as f64).min(max_ms));
+                }
+            }
+            max_ms
+        };
+        RttStats {
+            responses,
+            mean_ms: ms(self.rtt_sum_us.load(Ordering::Relaxed)) / responses as f64,
+            p50_ms: percentile(0.5),
+            p90_ms: percentile(0.9),
+            p99_ms: percentile(0.99),
+            max_ms,
+            buckets: counts
+                .iter()
+                .enumerate()
+                .map(|(bucket, &responses)| RttBucket {
+                    le_ms: RTT_BUCKETS_MS.get(bucket).copied(),
+                    responses,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_zone() {
+        let stats = QueryStats::new();
+        stats.record_query(Some("corp"));
+        stats.record_query(Some("corp"));
+        stats.record_cache_hit(Some("corp"));
+        stats.record_route_installed("corp");
+        stats.record_query(None);
+        stats.record_query(Some("corp"));
+        stats.record_rate_limited(Some("corp"));
+        stats.record_overloaded(None);
+        let upstream: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let udp = DnsProtocol::Udp;
+        stats.record_upstream_failure(None, upstream, udp, UpstreamFailure::Unreachable);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot["corp"],
+            ZoneStats {
+                queries: 3,
+                cache_hits: 1,
+                routes_installed: 1,
+                upstream_failures: 0,
+                rate_limited: 1,
+                overloaded: 0,
+            }
+        );
+        assert_eq!(snapshot[DEFAULT_ZONE].overloaded, 1);
+        assert_eq!(snapshot[DEFAULT_ZONE].queries, 1);
+        assert_eq!(snapshot[DEFAULT_ZONE].upstream_failures, 1);
+
+        assert_eq!(
+            stats.record_upstream_failure(None, upstream, udp, UpstreamFailure::ServFail),
+            2
+        );
+        let key = (upstream, udp);
+        assert_eq!(stats.upstream_snapshot()[&key].consecutive_failures, 2);
+        stats.record_upstream_answer(upstream, udp);
+        let snapshot = stats.upstream_snapshot();
+        assert_eq!(
+            (
+                snapshot[&key].answered,
+                snapshot[&key].failures,
+                snapshot[&key].consecutive_failures,
+                snapshot[&key].servfail,
+                snapshot[&key].unreachable,
+            ),
+            (1, 2, 0, 1, 1)
+        );
+        // TCP to the same server is tracked apart
+        assert!(!snapshot.contains_key(&(upstream, DnsProtocol::Tcp)));
+    }
+
+    #[test]
+    fn upstream_rtt_distribution() {
+        let stats = QueryStats::new();
+        let upstream: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let tcp = DnsProtocol::Tcp;
+        for ms in [3, 4, 4, 4, 8, 9, 9, 15, 40, 7500] {
+            stats.record_upstream_rtt(upstream, tcp, Duration::from_millis(ms));
+        }
+
+        let rtt = &stats.upstream_snapshot()[&(upstream, tcp)].rtt;
+        assert_eq!(rtt.responses, 10);
+        assert_eq!(rtt.mean_ms, 759.6);
+        assert_eq!((rtt.p50_ms, rtt.p90_ms, rtt.p99_ms), (10.0, 50.0, 7500.0));
+        assert_eq!(rtt.max_ms, 7500.0);
+        let counts: Vec<u64> = rtt.buckets.iter().map(|b| b.responses).collect();
+        assert_eq!(counts, [0, 0, 4, 3, 1, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(rtt.buckets.last().unwrap().le_ms, None);
+    }
+}
@@ -0,0 +1,288 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long one generation of counters lasts. Reports cover the current
+/// and the previous one, so the last 30 to 60 minutes.
+const GENERATION: Duration = Duration::from_secs(30 * 60);
+
+/// Names counted per generation; queries for further names are not.
+const MAX_NAMES: usize = 10_000;
+
+/// Slowest resolutions kept per generation.
+const SLOWEST_KEPT: usize = 50;
+
+/// Rolling per-name statistics: the most queried names, the slowest
+/// upstream resolutions and the names whose answers installed the most
+/// routes.
+pub struct TopDomains {
+    generation: Duration,
+    windows: Mutex<Windows>,
+}
+
+struct Windows {
+    current: Generation,
+    previous: Generation,
+}
+
+struct Generation {
+    started: Instant,
+    names: HashMap<String, NameCounters>,
+    /// Slowest first
+    slowest: Vec<SlowQuery>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct NameCounters {
+    queries: u64,
+    routes: u64,
+}
+
+/// One upstream resolution, for the slow-query report.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SlowQuery {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: String,
+    pub zone: Option<String>,
+    pub upstream: Option<SocketAddr>,
+    pub latency_ms: f64,
+    /// When it was received (RFC 3339, local time)
+    pub at: String,
+}
+
+/// The top entries of each statistic, for the admin API.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TopReport {
+    /// Seconds the report covers
+    pub window_secs: u64,
+    pub queried: Vec<NameCount>,
+    pub slowest: Vec<SlowQuery>,
+    /// Names by routes their answers installed
+    pub routing: Vec<NameCount>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct NameCount {
+    pub name: String,
+    pub count: u64,
+}
+
+impl Generation {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            names: HashMap::new(),
+            slowest: Vec::new(),
+        }
+    }
+
+    fn counters(&mut self, name: &str) -> Option<&mut NameCounters> {
+        if !self.names.contains_key(name) && self.names.len() >= MAX_NAMES {
+            return None;
+        }
+        Some(self.names.entry(name.to_string()).or_default())
+    }
+}
+
+impl Default for TopDomains {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TopDomains {
+    pub fn new() -> Self {
+        Self::with_generation(GENERATION)
+    }
+
+    fn with_generation(generation: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            generation,
+            windows: Mutex::new(Windows {
+                current: Generation::new(now),
+                previous: Generation::new(now),
+            }),
+        }
+    }
+
+    pub fn record_query(&self, name: &str) {
+        let name = normalize(name);
+        if let Some(counters) = self.current().current.counters(&name) {
+            counters.queries += 1;
+        }
+    }
+
+    /// Count `routes` newly installed for an answer to `name`.
+    pub fn record_routes(&self, name: &str, routes: u64) {
+        let name = normalize(name);
+        if let Some(counters) = self.current().current.counters(&name) {
+            counters.routes += routes;
+        }
+    }
+
+    /// Keep `query` if it is among the slowest of the generation.
+    pub fn record_resolution(&self, mut query: SlowQuery) {
+        query.name = normalize(&query.name);
+        let mut windows = self.current();
+        let slowest = &mut windows.current.slowest;
+        if slowest.len() >= SLOWEST_KEPT
+            && slowest
+                .last()
+                .is_some_and(|fastest| fastest.latency_ms >= query.latency_ms)
+        {
+            return;
+        }
+        let at = slowest.partition_point(|kept| kept.latency_ms >= query.latency_ms);
+        slowest.insert(at, query);
+        slowest.truncate(SLOWEST_KEPT);
+    }
+
+    /// The `limit` top entries of each statistic over the window.
+    pub fn report(&self, limit: usize) -> TopReport {
+        let windows = self.current();
+        let mut names: HashMap<&str, NameCounters> = HashMap::new();
+        for generation in [&windows.previous, &windows.current] {
+            for (name, counters) in &generation.names {
+                let total = names.entry(name).or_default();
+                total.queries += counters.queries;
+                total.routes += counters.routes;
+            }
+        }
+        let top = |count: fn(&NameCounters) -> u64| {
+            let mut counts: Vec<NameCount> = names
+                .iter()
+                .filter(|(_, counters)| count(counters) > 0)
+                .map(|(name, counters)| NameCount {
+                    name: name.to_string(),
+                    count: count(counters),
+                })
+                .collect();
+            counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+            counts.truncate(limit);
+            counts
+        };
+
+        let mut slowest: Vec<SlowQuery> = windows
+            .previous
+            .slowest
+            .iter()
+            .chain(&windows.current.slowest)
+            .cloned()
+            .collect();
+        slowest.sort_by(|a, b| b.latency_ms.total_cmp(&a.latency_ms));
+        slowest.truncate(limit);
+
+        TopReport {
+            window_secs: windows.previous.started.elapsed().as_secs(),
+            queried: top(|counters| counters.queries),
+            slowest,
+            routing: top(|counters| counters.routes),
+        }
+    }
+
+    /// The windows, with the current generation started less than a
+    /// generation ago.
+    fn current(&self) -> std::sync::MutexGuard<'_, Windows> {
+        let mut windows = self.windows.lock().unwrap();
+        let now = Instant::now();
+        let age = now.duration_since(windows.current.started);
+        if age >= self.generation * 2 {
+            // Idle for over a whole window: both generations are stale
+            windows.previous = Generation::new(now);
+            windows.current = Generation::new(now);
+        } else if age >= self.generation {
+            let current = std::mem::replace(&mut windows.current, Generation::new(now));
+            windows.previous = current;
+        }
+        windows
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow(name: &str, latency_ms: f64) -> SlowQuery {
+        SlowQuery {
+            name: name.to_string(),
+            qtype: "A".to_string(),
+            zone: None,
+            upstream: None,
+            latency_ms,
+            at: String::new(),
+        }
+    }
+
+    #[test]
+    fn reports_top_names_slowest_and_routing() {
+        let top = TopDomains::new();
+        for name in ["a.example.", "b.example.", "A.example.", "c.example."] {
+            top.record_query(name);
+        }
+        top.record_routes("c.example.", 3);
+        top.record_routes("a.example.", 1);
+        for (name, latency_ms) in [
+            ("a.example", 5.0),
+            ("b.example", 120.5),
+            ("c.example", 40.0),
+        ] {
+            top.record_resolution(slow(name, latency_ms));
+        }
+
+        let report = top.report(2);
+        let names = |counts: &[NameCount]| -> Vec<(String, u64)> {
+            counts.iter().map(|c| (c.name.clone(), c.count)).collect()
+        };
+        assert_eq!(
+            names(&report.queried),
+            [("a.example".to_string(), 2), ("b.example".to_string(), 1)]
+        );
+        assert_eq!(
+            names(&report.routing),
+            [("c.example".to_string(), 3), ("a.example".to_string(), 1)]
+        );
+        let slowest: Vec<f64> = report.slowest.iter().map(|q| q.latency_ms).collect();
+        assert_eq!(slowest, [120.5, 40.0]);
+    }
+
+    #[test]
+    fn keeps_only_the_slowest() {
+        let top = TopDomains::new();
+        for i in 0..SLOWEST_KEPT + 10 {
+            top.record_resolution(slow("x.example", i as f64));
+        }
+        let report = top.report(SLOWEST_KEPT + 10);
+        assert_eq!(report.slowest.len(), SLOWEST_KEPT);
+        assert_eq!(report.slowest[0].latency_ms, (SLOWEST_KEPT + 9) as f64);
+        assert_eq!(report.slowest.last().unwrap().latency_ms, 10.0);
+    }
+
+    #[test]
+    fn old_generations_roll_off() {
+        let top = TopDomains::with_generation(Duration::from_millis(100));
+        top.record_query("old.example");
+        std::thread::sleep(Duration::from_millis(120));
+        top.record_query("new.example");
+        // The previous generation still counts
+        assert_eq!(top.report(10).queried.len(), 2);
+
+        std::thread::sleep(Duration::from_millis(120));
+        top.record_query("new.example");
+        let report = top.report(10);
+        assert_eq!(
+            report.queried,
+            [NameCount {
+                name: "new.example".to_string(),
+                count: 2,
+            }]
+        );
+    }
+}
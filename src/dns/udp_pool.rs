@@ -0,0 +1,294 @@
+use hickory_proto::op::Message;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Sockets kept open per upstream; queries take them in turn.
+const SOCKETS_PER_UPSTREAM: usize = 4;
+
+/// Queries sent over one socket before it is replaced, so the source port
+/// keeps changing and stays hard to guess.
+const QUERIES_PER_SOCKET: u64 = 1000;
+
+/// Datagrams buffered per pending query; more are dropped.
+const RESPONSES_QUEUED: usize = 4;
+
+type Pending = Arc<Mutex<HashMap<u16, mpsc::Sender<io::Result<Vec<u8>>>>>>;
+
+/// Long-lived UDP sockets to each upstream, shared by the queries to it:
+/// responses are handed to the query waiting under their ID.
+pub struct UdpPool {
+    sockets_per_upstream: usize,
+    queries_per_socket: u64,
+    upstreams: Mutex<HashMap<SocketAddr, Vec<Arc<PooledSocket>>>>,
+    next: AtomicUsize,
+}
+
+/// One connected socket and the queries waiting on it. Its receive task
+/// stops once it is dropped, i.e. replaced and no query waits on it.
+struct PooledSocket {
+    socket: Arc<UdpSocket>,
+    pending: Pending,
+    queries: AtomicU64,
+    closed: CancellationToken,
+}
+
+impl Drop for PooledSocket {
+    fn drop(&mut self) {
+        self.closed.cancel();
+    }
+}
+
+/// A query sent through the pool; receives the datagrams carrying its ID
+/// until dropped.
+pub struct PendingQuery {
+    socket: Arc<PooledSocket>,
+    id: u16,
+    responses: mpsc::Receiver<io::Result<Vec<u8>>>,
+}
+
+impl PendingQuery {
+    /// The next datagram for this query, or the error the socket got.
+    pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.responses.recv().await.unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "upstream socket closed",
+            ))
+        })
+    }
+}
+
+impl Drop for PendingQuery {
+    fn drop(&mut self) {
+        self.socket.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Default for UdpPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UdpPool {
+    pub fn new() -> Self {
+        Self::with_limits(SOCKETS_PER_UPSTREAM, QUERIES_PER_SOCKET)
+    }
+
+    fn with_limits(sockets_per_upstream: usize, queries_per_socket: u64) -> Self {
+        Self {
+            sockets_per_upstream,
+            queries_per_socket,
+            upstreams: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Send `query` to `upstream` under a random ID no other query on the
+    /// socket uses (set on `query`).
+    pub async fn send(
+        &self,
+        upstream: SocketAddr,
+        query: &mut Message,
+    ) -> io::Result<PendingQuery> {
+        let socket = self.socket(upstream)?;
+        let (sender, responses) = mpsc::channel(RESPONSES_QUEUED);
+        let id = {
+            let mut pending = socket.pending.lock().unwrap();
+            if pending.len() > usize::from(u16::MAX) / 2 {
+                return Err(io::Error::other(
+                    "too many queries pending on upstream socket",
+                ));
+            }
+            let mut id = query.id();
+            while pending.contains_key(&id) {
+                id = rand::random();
+            }
+            pending.insert(id, sender);
+            id
+        };
+        let pending = PendingQuery {
+            socket: Arc::clone(&socket),
+            id,
+            responses,
+        };
+        query.set_id(id);
+        let bytes = query.to_vec().map_err(io::Error::other)?;
+        socket.socket.send(&bytes).await?;
+        Ok(pending)
+    }
+
+    /// Close the sockets of upstreams `keep` rejects (queries in flight on
+    /// them still complete).
+    pub fn retain(&self, keep: impl Fn(SocketAddr) -> bool) {
+        self.upstreams
+            .lock()
+            .unwrap()
+            .retain(|&upstream, _| keep(upstream));
+    }
+
+    /// The next of `upstream`'s sockets, opened (or replaced, once worn
+    /// out) as needed.
+    fn socket(&self, upstream: SocketAddr) -> io::Result<Arc<PooledSocket>> {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let sockets = upstreams.entry(upstream).or_default();
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.sockets_per_upstream;
+        if let Some(socket) = sockets.get(slot) {
+            if socket.queries.fetch_add(1, Ordering::Relaxed) < self.queries_per_socket {
+                return Ok(Arc::clone(socket));
+            }
+        }
+        let socket = Arc::new(open(upstream)?);
+        socket.queries.store(1, Ordering::Relaxed);
+        if slot < sockets.len() {
+            sockets[slot] = Arc::clone(&socket);
+        } else {
+            sockets.push(Arc::clone(&socket));
+        }
+        Ok(socket)
+    }
+}
+
+/// A socket connected to `upstream` from a random ephemeral port, with a
+/// task handing its datagrams to the pending queries.
+fn open(upstream: SocketAddr) -> io::Result<PooledSocket> {
+    let local: SocketAddr = if upstream.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = std::net::UdpSocket::bind(local)?;
+    socket.connect(upstream)?;
+    socket.set_nonblocking(true)?;
+    let socket = Arc::new(UdpSocket::from_std(socket)?);
+    let pending: Pending = Arc::default();
+    let closed = CancellationToken::new();
+
+    let receiver = Arc::clone(&socket);
+    let waiting = Arc::clone(&pending);
+    let stop = closed.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let received = tokio::select! {
+                _ = stop.cancelled() => break,
+                received = receiver.recv(&mut buf) => received,
+            };
+            match received {
+                Ok(len) if len >= 2 => {
+                    let id = u16::from_be_bytes([buf[0], buf[1]]);
+                    if let Some(query) = waiting.lock().unwrap().get(&id) {
+                        let _ = query.try_send(Ok(buf[..len].to_vec()));
+                    }
+                }
+                Ok(_) => {}
+                // E.g. ICMP port unreachable: every query on it fails
+                Err(e) => {
+                    for query in waiting.lock().unwrap().values() {
+                        let _ = query.try_send(Err(io::Error::new(e.kind(), e.to_string())));
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(PooledSocket {
+        socket,
+        pending,
+        queries: AtomicU64::new(0),
+        closed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{MessageType, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    /// Echo each query back as a response, the second one first.
+    async fn reordering_upstream() -> (SocketAddr, Arc<Mutex<HashSet<SocketAddr>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let peers = Arc::new(Mutex::new(HashSet::new()));
+        let seen = Arc::clone(&peers);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let mut held = None;
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                seen.lock().unwrap().insert(peer);
+                let mut response = Message::from_vec(&buf[..len]).unwrap();
+                response.set_message_type(MessageType::Response);
+                let bytes = response.to_vec().unwrap();
+                match held.take() {
+                    None => held = Some((bytes, peer)),
+                    Some((first, first_peer)) => {
+                        socket.send_to(&bytes, peer).await.unwrap();
+                        socket.send_to(&first, first_peer).await.unwrap();
+                    }
+                }
+            }
+        });
+        (addr, peers)
+    }
+
+    fn query(name: &str) -> Message {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        message.set_id(7);
+        message
+    }
+
+    async fn name_of(pending: &mut PendingQuery) -> String {
+        let bytes = tokio::time::timeout(Duration::from_secs(2), pending.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        Message::from_vec(&bytes).unwrap().queries()[0]
+            .name()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn demultiplexes_responses_by_id() {
+        let (upstream, peers) = reordering_upstream().await;
+        let pool = UdpPool::with_limits(1, 1000);
+
+        let mut first_query = query("one.example.");
+        let mut second_query = query("two.example.");
+        let mut first = pool.send(upstream, &mut first_query).await.unwrap();
+        let mut second = pool.send(upstream, &mut second_query).await.unwrap();
+        // Both asked under ID 7: the second got another
+        assert_eq!(first_query.id(), 7);
+        assert_ne!(second_query.id(), 7);
+        assert_eq!(name_of(&mut first).await, "one.example.");
+        assert_eq!(name_of(&mut second).await, "two.example.");
+        // Over one socket
+        assert_eq!(peers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replaces_worn_out_sockets() {
+        let (upstream, peers) = reordering_upstream().await;
+        let pool = UdpPool::with_limits(1, 2);
+        let mut pending = Vec::new();
+        for name in ["a.example.", "b.example.", "c.example.", "d.example."] {
+            pending.push(pool.send(upstream, &mut query(name)).await.unwrap());
+        }
+        for query in &mut pending {
+            name_of(query).await;
+        }
+        assert_eq!(peers.lock().unwrap().len(), 2);
+
+        pool.retain(|_| false);
+        assert!(pool.upstreams.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,586 @@
+use crate::config::{Config, DnsProtocol, RouteType};
+use crate::dns::{docker, DnsHandler};
+use crate::routing;
+use crate::zones::ZoneMatcher;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::RecordType;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How long a gateway gets to answer a ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Resolvers that commonly hold port 53 or rewrite /etc/resolv.conf,
+/// by process name (as in /proc/<pid>/comm, at most 15 characters).
+#[cfg(target_os = "linux")]
+const RESOLVERS: [(&str, &str); 3] = [
+    ("systemd-resolve", "systemd-resolved"),
+    ("dnsmasq", "dnsmasq"),
+    ("unbound", "unbound"),
+];
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// The outcome of one `leshy doctor` check.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Finding {
+    /// What was checked, e.g. "upstream corp 10.0.0.53:53"
+    pub check: String,
+    pub severity: Severity,
+    pub message: String,
+    /// What to do about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            check: check.into(),
+            severity: Severity::Ok,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warning(check: impl Into<String>, message: impl Into<String>, hint: &str) -> Self {
+        Self {
+            severity: Severity::Warning,
+            hint: Some(hint.to_string()),
+            ..Self::ok(check, message)
+        }
+    }
+
+    fn error(check: impl Into<String>, message: impl Into<String>, hint: &str) -> Self {
+        Self {
+            severity: Severity::Error,
+            ..Self::warning(check, message, hint)
+        }
+    }
+}
+
+/// Check that this host can run `config`: privileges, the listen port,
+/// upstreams, dev zones' devices and via zones' gateways (unless routing
+/// is disabled), other resolvers and, with `docker_dns`, the Docker bridge.
+pub async fn run(config: &Config) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = check_capabilities(config);
+    findings.push(check_port(config.server.listen_address));
+    findings.extend(check_upstreams(config).await?);
+    if !config.routing.is_disabled() {
+        findings.extend(check_devices(config));
+        findings.extend(check_gateways(config).await);
+    }
+    findings.extend(check_resolvers(config));
+    findings.extend(check_docker(config));
+    Ok(findings)
+}
+
+/// Findings as lines of `[status] check: message`, hints indented below,
+/// and a summary.
+pub fn render(findings: &[Finding]) -> String {
+    let mut out = String::new();
+    for finding in findings {
+        let status = match finding.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warn",
+            Severity::Error => "FAIL",
+        };
+        out.push_str(&format!(
+            "[{status:>4}] {}: {}\n",
+            finding.check, finding.message
+        ));
+        if let Some(hint) = &finding.hint {
+            out.push_str(&format!("       -> {hint}\n"));
+        }
+    }
+    let count = |severity| findings.iter().filter(|f| f.severity == severity).count();
+    out.push_str(&format!(
+        "\n{} checks: {} failed, {} warnings\n",
+        findings.len(),
+        count(Severity::Error),
+        count(Severity::Warning)
+    ));
+    out
+}
+
+#[cfg(target_os = "linux")]
+fn check_capabilities(config: &Config) -> Vec<Finding> {
+    const CAP_NET_BIND_SERVICE: u32 = 10;
+    const CAP_NET_ADMIN: u32 = 12;
+
+    let effective = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| effective_capabilities(&status));
+    let Some(effective) = effective else {
+        return vec![Finding::warning(
+            "capabilities",
+            "could not read the effective capabilities",
+            "run as root, or grant CAP_NET_ADMIN and CAP_NET_BIND_SERVICE",
+        )];
+    };
+    let has = |cap: u32| effective & (1 << cap) != 0;
+
+    let mut findings = Vec::new();
+    if has(CAP_NET_ADMIN) {
+        findings.push(Finding::ok("CAP_NET_ADMIN", "effective"));
+    } else if config.routing.is_disabled() {
+        findings.push(Finding::ok(
+            "CAP_NET_ADMIN",
+            "missing, but routing is disabled",
+        ));
+    } else if config.routing.dry_run {
+        findings.push(Finding::ok(
+            "CAP_NET_ADMIN",
+            "missing, but routing is in dry-run mode",
+        ));
+    } else {
+        findings.push(Finding::error(
+            "CAP_NET_ADMIN",
+            "missing: routes can't be installed",
+            "run as root, install with `leshy service install`, or \
+             `sudo setcap cap_net_admin,cap_net_bind_service+ep $(command -v leshy)`",
+        ));
+    }
+    if config.server.listen_address.port() < 1024 {
+        if has(CAP_NET_BIND_SERVICE) {
+            findings.push(Finding::ok("CAP_NET_BIND_SERVICE", "effective"));
+        } else {
+            findings.push(Finding::error(
+                "CAP_NET_BIND_SERVICE",
+                format!(
+                    "missing: can't listen on privileged port {}",
+                    config.server.listen_address.port()
+                ),
+                "run as root or grant CAP_NET_BIND_SERVICE, or listen on a port above 1023",
+            ));
+        }
+    }
+    findings
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_capabilities(config: &Config) -> Vec<Finding> {
+    let root = std::process::Command::new("id")
+        .arg("-u")
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "0");
+    if root || config.routing.dry_run || config.routing.is_disabled() {
+        vec![Finding::ok("privileges", "sufficient")]
+    } else {
+        vec![Finding::error(
+            "privileges",
+            "not running as root: routes can't be installed",
+            "run with sudo, or install with `leshy service install`",
+        )]
+    }
+}
+
+/// The `CapEff` mask of a /proc/<pid>/status file.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn effective_capabilities(status: &str) -> Option<u64> {
+    let mask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+    u64::from_str_radix(mask, 16).ok()
+}
+
+fn check_port(listen: SocketAddr) -> Finding {
+    let check = format!("listen {listen}");
+    match std::net::UdpSocket::bind(listen) {
+        Ok(_) => Finding::ok(check, "available"),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Finding::error(
+            check,
+            "already in use",
+            "stop the other DNS server holding it (see the resolver checks; \
+             `ss -ulpn` shows which), or change `listen_address`. \
+             Expected if leshy is already running",
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Finding::error(
+            check,
+            "permission denied",
+            "run as root or grant CAP_NET_BIND_SERVICE",
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrNotAvailable => Finding::error(
+            check,
+            "no local interface has this address",
+            "listen on an address of this host, or 0.0.0.0",
+        ),
+        Err(e) => Finding::error(check, e.to_string(), "check `listen_address`"),
+    }
+}
+
+/// Query each upstream once: zone servers for one of the zone's domains,
+/// the default upstreams for the root.
+async fn check_upstreams(config: &Config) -> anyhow::Result<Vec<Finding>> {
+    let mut config = config.clone();
+    config.routing.dry_run = true;
+    config.server.state_file = None;
+    config.server.query_log = None;
+    config.hooks.clear();
+    let matcher = ZoneMatcher::new(config.zones.clone())?;
+    let handler = DnsHandler::new(config.clone(), matcher)?;
+
+    let mut probes = Vec::new();
+    for &upstream in &config.server.default_upstream {
+        probes.push((
+            "default".to_string(),
+            upstream,
+            DnsProtocol::Udp,
+            ".".to_string(),
+        ));
+    }
+    for zone in &config.zones {
+        let name = zone
+            .domains
+            .iter()
+            .map(|domain| domain.trim_start_matches("*."))
+            .next()
+            .unwrap_or(".")
+            .to_string();
+        let mut servers: Vec<SocketAddr> = Vec::new();
+        for server in zone
+            .dns_servers
+            .iter()
+            .chain(zone.type_dns_servers.values().flatten())
+        {
+            if servers.contains(&server.address) {
+                continue;
+            }
+            servers.push(server.address);
+            probes.push((
+                zone.name.clone(),
+                server.address,
+                zone.dns_protocol,
+                name.clone(),
+            ));
+        }
+    }
+
+    let handler = &handler;
+    let probes = probes.into_iter().map(|(zone, upstream, protocol, name)| async move {
+        let check = format!("upstream {zone} {upstream}");
+        let started = Instant::now();
+        let qtype = if name == "." { RecordType::NS } else { RecordType::A };
+        let rcode = match handler.probe_upstream(upstream, protocol, &name, qtype).await {
+            Ok(rcode) => rcode,
+            Err(e) => return Finding::error(check, e.to_string(), "fix the zone's domains"),
+        };
+        let elapsed = started.elapsed().as_millis();
+        match rcode {
+            Some(ResponseCode::NoError | ResponseCode::NXDomain) => {
+                Finding::ok(check, format!("answered {name} in {elapsed} ms"))
+            }
+            Some(rcode) => Finding::warning(
+                check,
+                format!("answered {name} with {rcode}"),
+                "the server is reachable but won't resolve for us; check it allows recursion from this host",
+            ),
+            None => Finding::error(
+                check,
+                format!("no answer over {protocol:?}"),
+                if zone == "default" {
+                    "check `default_upstream` and this host's internet access"
+                } else {
+                    "check the address, and that the VPN or tunnel it's reached through is up"
+                },
+            ),
+        }
+    });
+    Ok(futures::future::join_all(probes).await)
+}
+
+/// Dev zones' targets: the device file (if any) names an existing interface.
+fn check_devices(config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for zone in config
+        .zones
+        .iter()
+        .filter(|z| z.route_type.kernel_type() == RouteType::Dev)
+    {
+        let target = &zone.route_target;
+        let check = format!("device {}", zone.name);
+        let device = if routing::is_device_file(target) {
+            match std::fs::read_to_string(target) {
+                Ok(content) if content.trim().is_empty() => {
+                    findings.push(Finding::error(
+                        check,
+                        format!("device file '{target}' is empty"),
+                        "write the interface name into it (your VPN's up script usually does)",
+                    ));
+                    continue;
+                }
+                Ok(content) => content.trim().to_string(),
+                Err(e) => {
+                    let hint = if e.kind() == std::io::ErrorKind::NotFound {
+                        "connect the VPN, or check the path; routes wait until the file appears"
+                    } else {
+                        "make the file readable by leshy"
+                    };
+                    findings.push(Finding::error(
+                        check,
+                        format!("device file '{target}': {e}"),
+                        hint,
+                    ));
+                    continue;
+                }
+            }
+        } else {
+            target.clone()
+        };
+        findings.push(match interface_exists(&device) {
+            Some(false) => Finding::error(
+                check,
+                format!("interface '{device}' does not exist"),
+                "bring the tunnel up, or fix the interface name",
+            ),
+            _ => Finding::ok(check, format!("interface '{device}'")),
+        });
+    }
+    findings
+}
+
+/// Whether the interface exists; None if that can't be told here.
+fn interface_exists(name: &str) -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        Some(std::path::Path::new("/sys/class/net").join(name).exists())
+    } else {
+        None
+    }
+}
+
+/// Via zones' gateways: answering pings, or at least resolved on the link.
+async fn check_gateways(config: &Config) -> Vec<Finding> {
+    let gateways = config
+        .zones
+        .iter()
+        .filter(|z| z.route_type == RouteType::Via)
+        .map(|zone| async move {
+            let check = format!("gateway {} {}", zone.name, zone.route_target);
+            let gateway = match routing::resolve_gateway(&zone.route_target).await {
+                Ok(gateway) => gateway,
+                Err(e) => {
+                    return Finding::error(check, e.to_string(), "check the uplink is connected")
+                }
+            };
+            let Ok(ip) = gateway.parse::<IpAddr>() else {
+                return Finding::error(
+                    check,
+                    "not an IP address",
+                    "set `route_target` to the gateway's IP, \"auto\" or \"iface:<name>\"",
+                );
+            };
+            let alive = match routing::ping(&gateway, PING_TIMEOUT).await {
+                Ok(alive) => alive,
+                Err(e) => {
+                    return Finding::warning(check, format!("can't probe: {e:#}"), "install ping")
+                }
+            };
+            if alive {
+                Finding::ok(check, "answers ping")
+            } else if neighbor_resolved(ip) {
+                Finding::warning(
+                    check,
+                    "has an ARP entry but doesn't answer ping",
+                    "fine if it filters ICMP, but a `health_check` would consider it down",
+                )
+            } else {
+                Finding::error(
+                    check,
+                    "unreachable: no ping reply and no ARP entry",
+                    "check the tunnel is up and the gateway is on a directly connected network",
+                )
+            }
+        });
+    futures::future::join_all(gateways).await
+}
+
+/// Whether the kernel has a complete ARP entry for `ip` (IPv4, Linux).
+fn neighbor_resolved(ip: IpAddr) -> bool {
+    std::fs::read_to_string("/proc/net/arp").is_ok_and(|arp| arp_resolved(&arp, ip))
+}
+
+/// Whether /proc/net/arp content has a complete entry for `ip`.
+fn arp_resolved(arp: &str, ip: IpAddr) -> bool {
+    const ATF_COM: u32 = 0x2;
+    arp.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 3
+            && fields[0].parse() == Ok(ip)
+            && u32::from_str_radix(fields[2].trim_start_matches("0x"), 16)
+                .is_ok_and(|flags| flags & ATF_COM != 0)
+    })
+}
+
+/// Other resolvers running, and whether /etc/resolv.conf points at leshy.
+fn check_resolvers(config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    #[cfg(target_os = "linux")]
+    {
+        let running = running_processes();
+        for (comm, name) in RESOLVERS {
+            if !running.iter().any(|p| p == comm) {
+                continue;
+            }
+            let hint = match name {
+                "systemd-resolved" => {
+                    "it listens on 127.0.0.53:53; if leshy should answer there or on \
+                     0.0.0.0:53 set DNSStubListener=no in /etc/systemd/resolved.conf, \
+                     or forward to leshy with DNS= and Domains=~."
+                }
+                _ => {
+                    "it may hold port 53 or own /etc/resolv.conf; disable it, bind it to \
+                     other interfaces, or forward it to leshy"
+                }
+            };
+            findings.push(Finding::warning(
+                format!("resolver {name}"),
+                "running",
+                hint,
+            ));
+        }
+    }
+
+    let listen = config.server.listen_address;
+    if listen.port() == 53 {
+        if let Ok(resolv) = std::fs::read_to_string("/etc/resolv.conf") {
+            let nameservers = nameservers(&resolv);
+            let local = |ip: &IpAddr| {
+                *ip == listen.ip() || (listen.ip().is_unspecified() && ip.is_loopback())
+            };
+            findings.push(if nameservers.iter().any(local) {
+                Finding::ok("resolv.conf", "points at leshy")
+            } else {
+                Finding::warning(
+                    "resolv.conf",
+                    format!(
+                        "nameservers {} don't include leshy",
+                        nameservers
+                            .iter()
+                            .map(IpAddr::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    "this host's own lookups bypass leshy; point resolv.conf (or your network \
+                     manager) at it, unless only other devices use it",
+                )
+            });
+        }
+    }
+    findings
+}
+
+/// With `docker_dns`: the bridge is up, and Docker hands its address to
+/// containers as their DNS server.
+fn check_docker(config: &Config) -> Vec<Finding> {
+    if !config.server.docker_dns {
+        return Vec::new();
+    }
+    let bridge = &config.server.docker_bridge;
+    let check = format!("docker bridge {bridge}");
+    let Some(ip) = docker::bridge_address(bridge) else {
+        return vec![Finding::error(
+            check,
+            "no IPv4 address",
+            "start Docker, or set docker_bridge to the bridge containers use",
+        )];
+    };
+    let mut findings = vec![Finding::ok(check, ip.to_string())];
+    let servers = docker::daemon_dns().unwrap_or_default();
+    findings.push(if servers.contains(&IpAddr::V4(ip)) {
+        Finding::ok("docker daemon.json", format!("gives containers {ip}"))
+    } else {
+        Finding::warning(
+            "docker daemon.json",
+            format!("\"dns\" doesn't include {ip}"),
+            &format!(
+                "add \"dns\": [\"{ip}\"] to {} and restart Docker, or run containers \
+                 with --dns {ip}",
+                docker::DAEMON_JSON
+            ),
+        )
+    });
+    findings
+}
+
+/// Process names (/proc/<pid>/comm) of everything running.
+#[cfg(target_os = "linux")]
+fn running_processes() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok())
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .collect()
+}
+
+/// The `nameserver` addresses of a resolv.conf.
+fn nameservers(resolv: &str) -> Vec<IpAddr> {
+    resolv
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_effective_capabilities() {
+        let status = "Name:\tleshy\nCapInh:\t0000000000000000\nCapEff:\t0000000000001400\n";
+        let effective = effective_capabilities(status).unwrap();
+        assert_ne!(effective & (1 << 12), 0);
+        assert_ne!(effective & (1 << 10), 0);
+        assert_eq!(effective_capabilities("Name:\tleshy\n"), None);
+    }
+
+    #[test]
+    fn reads_arp_and_resolv_conf() {
+        let arp = "\
+IP address       HW type     Flags       HW address            Mask     Device
+198.51.100.1     0x1         0x2         52:54:00:12:34:56     *        wg0
+198.51.100.2     0x1         0x0         00:00:00:00:00:00     *        wg0
+";
+        assert!(arp_resolved(arp, "198.51.100.1".parse().unwrap()));
+        assert!(!arp_resolved(arp, "198.51.100.2".parse().unwrap()));
+        assert!(!arp_resolved(arp, "198.51.100.3".parse().unwrap()));
+
+        let resolv = "# generated\nnameserver 127.0.0.53\noptions edns0\nnameserver ::1\n";
+        assert_eq!(
+            nameservers(resolv),
+            [
+                "127.0.0.53".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_findings_with_hints_and_summary() {
+        let findings = vec![
+            Finding::ok("listen 127.0.0.1:5353", "available"),
+            Finding::error(
+                "gateway corp 198.51.100.1",
+                "unreachable",
+                "bring the tunnel up",
+            ),
+        ];
+        let out = render(&findings);
+        assert!(out.contains("[  ok] listen 127.0.0.1:5353: available\n"));
+        assert!(out.contains("[FAIL] gateway corp 198.51.100.1: unreachable\n"));
+        assert!(out.contains("       -> bring the tunnel up\n"));
+        assert!(out.ends_with("2 checks: 1 failed, 0 warnings\n"));
+    }
+}
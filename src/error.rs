@@ -1,23 +1,141 @@
 use thiserror::Error;
 
+/// Errors of the `config`, `routing` and `dns` APIs. `code()` tells the
+/// kinds apart for library users and admin API clients; the message is
+/// for people.
 #[derive(Error, Debug)]
-#[allow(dead_code)]
 pub enum LeshyError {
-    #[error("Configuration error: {0}")]
+    /// The config is malformed or fails validation
+    #[error("{0}")]
     Config(String),
 
-    #[error("DNS error: {0}")]
+    /// An operation named a zone that isn't configured
+    #[error("Zone '{0}' is not configured")]
+    UnknownZone(String),
+
+    /// Serving or forwarding DNS failed
+    #[error("{0}")]
     Dns(String),
 
-    #[error("Routing error: {0}")]
+    /// Installing or removing routes or rules failed
+    #[error("{0}")]
     Routing(String),
 
-    #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
+    /// The kernel refused a routing change: leshy lacks CAP_NET_ADMIN
+    #[error("{0}")]
+    PermissionDenied(String),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
 
-    #[error("Parse error: {0}")]
+    /// A name, address, CIDR or glob given to the API doesn't parse
+    #[error("{0}")]
     Parse(String),
 }
 
-#[allow(dead_code)]
 pub type Result<T> = std::result::Result<T, LeshyError>;
+
+impl LeshyError {
+    /// Stable identifier of the error kind, e.g. "config_invalid".
+    pub fn code(&self) -> &'static str {
+        match self {
+            LeshyError::Config(_) => "config_invalid",
+            LeshyError::UnknownZone(_) => "unknown_zone",
+            LeshyError::Dns(_) => "dns_failed",
+            LeshyError::Routing(_) => "routing_failed",
+            LeshyError::PermissionDenied(_) => "permission_denied",
+            LeshyError::Io { .. } => "io_error",
+            LeshyError::Parse(_) => "invalid_input",
+        }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        LeshyError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// A config loading or validation failure.
+    pub fn config(error: anyhow::Error) -> Self {
+        classify(error, LeshyError::Config)
+    }
+
+    /// A route or rule change failure; the kernel refusing it for lack of
+    /// privileges becomes `PermissionDenied`.
+    pub fn routing(error: anyhow::Error) -> Self {
+        if permission_denied(&error) {
+            return LeshyError::PermissionDenied(format!("{error:#}"));
+        }
+        classify(error, LeshyError::Routing)
+    }
+}
+
+/// `error` itself if it already is a `LeshyError`, else `kind` of its
+/// message (with causes).
+fn classify(error: anyhow::Error, kind: fn(String) -> LeshyError) -> LeshyError {
+    match error.downcast::<LeshyError>() {
+        Ok(error) => error,
+        Err(error) => kind(format!("{error:#}")),
+    }
+}
+
+/// Whether any cause is an EPERM/EACCES from the kernel, or `ip`/`route`
+/// reporting one.
+fn permission_denied(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return io.kind() == std::io::ErrorKind::PermissionDenied;
+        }
+        #[cfg(all(target_os = "linux", feature = "netlink"))]
+        if let Some(rtnetlink::Error::NetlinkError(message)) =
+            cause.downcast_ref::<rtnetlink::Error>()
+        {
+            return message.to_io().kind() == std::io::ErrorKind::PermissionDenied;
+        }
+        let message = cause.to_string();
+        message.contains("Operation not permitted") || message.contains("must be root")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn routing_errors_tell_permission_apart() {
+        let denied = Err::<(), _>(std::io::Error::from_raw_os_error(1))
+            .context("Failed to add route 198.51.100.0/24")
+            .unwrap_err();
+        let error = LeshyError::routing(denied);
+        assert_eq!(error.code(), "permission_denied");
+        assert!(error
+            .to_string()
+            .starts_with("Failed to add route 198.51.100.0/24: "));
+
+        let iproute =
+            anyhow::anyhow!("ip route add failed: RTNETLINK answers: Operation not permitted");
+        assert_eq!(LeshyError::routing(iproute).code(), "permission_denied");
+
+        let other = anyhow::anyhow!("Device 'wg0' not found");
+        let error = LeshyError::routing(other);
+        assert_eq!(error.code(), "routing_failed");
+        assert_eq!(error.to_string(), "Device 'wg0' not found");
+    }
+
+    #[test]
+    fn typed_errors_keep_their_kind() {
+        let wrapped = anyhow::Error::new(LeshyError::UnknownZone("corp".to_string()));
+        let error = LeshyError::config(wrapped);
+        assert_eq!(error.code(), "unknown_zone");
+        assert_eq!(error.to_string(), "Zone 'corp' is not configured");
+
+        let error = LeshyError::config(anyhow::anyhow!("bad listen_address"));
+        assert_eq!(error.code(), "config_invalid");
+    }
+}
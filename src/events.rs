@@ -0,0 +1,298 @@
+use crate::config::{EventKind, HookConfig};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+/// How long one hook may run (or a webhook take) before it is abandoned.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events waiting for their hooks; more are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Something hooks can react to. Serialized as the JSON hooks receive,
+/// e.g. `{"event":"route_installed","zone":"corp","prefix":"198.51.100.0/24"}`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RouteInstalled {
+        zone: String,
+        prefix: String,
+    },
+    RouteRemoved {
+        zone: String,
+        prefix: String,
+    },
+    ZonesReloaded {
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    UpstreamUnhealthy {
+        upstream: SocketAddr,
+        /// Zone whose query failed; None = default upstream
+        zone: Option<String>,
+    },
+    StaticRoutesFailed {
+        /// Static routes still not applied
+        pending: usize,
+        attempts: u32,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::RouteInstalled { .. } => EventKind::RouteInstalled,
+            Event::RouteRemoved { .. } => EventKind::RouteRemoved,
+            Event::ZonesReloaded { .. } => EventKind::ZonesReloaded,
+            Event::UpstreamUnhealthy { .. } => EventKind::UpstreamUnhealthy,
+            Event::StaticRoutesFailed { .. } => EventKind::StaticRoutesFailed,
+        }
+    }
+}
+
+/// Runs the configured `[[hooks]]` for emitted events, one at a time and
+/// in order, on a background task, so emitting never waits on a hook.
+pub struct Events {
+    hooks: Arc<RwLock<Vec<HookConfig>>>,
+    /// Started on the first event a hook wants
+    sender: OnceLock<mpsc::Sender<Event>>,
+}
+
+impl Events {
+    pub fn new(hooks: Vec<HookConfig>) -> Self {
+        Self {
+            hooks: Arc::new(RwLock::new(hooks)),
+            sender: OnceLock::new(),
+        }
+    }
+
+    /// Replace the hooks (on config reload). Queued events use the new ones.
+    pub fn set_hooks(&self, hooks: Vec<HookConfig>) {
+        *self.hooks.write().unwrap() = hooks;
+    }
+
+    pub fn emit(&self, event: Event) {
+        let kind = event.kind();
+        if !self.hooks.read().unwrap().iter().any(|h| wants(h, kind)) {
+            return;
+        }
+        let sender = self.sender.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(dispatch(Arc::clone(&self.hooks), receiver));
+            sender
+        });
+        if sender.try_send(event).is_err() {
+            tracing::warn!(event = ?kind, "Event hook queue full, dropping event");
+        }
+    }
+}
+
+fn wants(hook: &HookConfig, kind: EventKind) -> bool {
+    hook.events.is_empty() || hook.events.contains(&kind)
+}
+
+async fn dispatch(hooks: Arc<RwLock<Vec<HookConfig>>>, mut receiver: mpsc::Receiver<Event>) {
+    let client = match webhook_client() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(error = %e, "Event hooks disabled");
+            return;
+        }
+    };
+    while let Some(event) = receiver.recv().await {
+        let kind = event.kind();
+        let payload = payload(&event);
+        let matching: Vec<HookConfig> = hooks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|h| wants(h, kind))
+            .cloned()
+            .collect();
+        for hook in matching {
+            let result = match (&hook.command, &hook.url) {
+                (Some(command), _) => run_command(command, &payload).await,
+                (None, Some(url)) => post_webhook(&client, url, &payload).await,
+                (None, None) => Ok(()),
+            };
+            if let Err(e) = result {
+                tracing::warn!(event = ?kind, error = %format!("{e:#}"), "Event hook failed");
+            }
+        }
+    }
+}
+
+/// The event's JSON with the time it is dispatched at.
+fn payload(event: &Event) -> Value {
+    let mut payload = serde_json::to_value(event).unwrap_or_default();
+    if let Value::Object(fields) = &mut payload {
+        let at = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false);
+        fields.insert("at".to_string(), Value::String(at));
+    }
+    payload
+}
+
+#[cfg(feature = "https")]
+type Client = reqwest::Client;
+
+/// Stand-in for the HTTP client in builds without the https feature, where
+/// webhooks fail and command hooks still run.
+#[cfg(not(feature = "https"))]
+struct Client;
+
+#[cfg(not(feature = "https"))]
+fn webhook_client() -> Result<Client> {
+    Ok(Client)
+}
+
+#[cfg(feature = "https")]
+fn webhook_client() -> Result<Client> {
+    reqwest::Client::builder()
+        .timeout(HOOK_TIMEOUT)
+        .user_agent(concat!("leshy/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .context("Failed to build HTTP client")
+}
+
+/// Run `command` with `sh -c`, the event in its environment.
+async fn run_command(command: &str, payload: &Value) -> Result<()> {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command])
+        .env("LESHY_EVENT_JSON", payload.to_string())
+        .kill_on_drop(true);
+    for (field, value) in payload.as_object().into_iter().flatten() {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            Value::Array(values) => values
+                .iter()
+                .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        };
+        cmd.env(format!("LESHY_{}", field.to_uppercase()), value);
+    }
+
+    let output = tokio::time::timeout(HOOK_TIMEOUT, cmd.output())
+        .await
+        .with_context(|| format!("Hook '{command}' timed out"))?
+        .with_context(|| format!("Failed to run hook '{command}'"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "hook '{command}' failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "https"))]
+async fn post_webhook(_client: &Client, url: &str, _payload: &Value) -> Result<()> {
+    anyhow::bail!("Can't post to '{url}': leshy was built without the https feature")
+}
+
+#[cfg(feature = "https")]
+async fn post_webhook(client: &Client, url: &str, payload: &Value) -> Result<()> {
+    client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to post to '{url}'"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route_installed() -> Event {
+        Event::RouteInstalled {
+            zone: "corp".to_string(),
+            prefix: "198.51.100.0/24".to_string(),
+        }
+    }
+
+    /// `path`'s content once it is `expected`, or after 2 seconds.
+    async fn settled(path: &std::path::Path, expected: &str) -> String {
+        for _ in 0..100 {
+            let content = std::fs::read_to_string(path).unwrap_or_default();
+            if content == expected {
+                return content;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn commands_get_matching_events_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("events");
+        let events = Events::new(vec![HookConfig {
+            events: vec![EventKind::RouteInstalled, EventKind::ZonesReloaded],
+            command: Some(format!(
+                "echo \"$LESHY_EVENT $LESHY_ZONE$LESHY_PREFIX$LESHY_ADDED\" >> {}",
+                log.display()
+            )),
+            url: None,
+        }]);
+
+        events.emit(route_installed());
+        events.emit(Event::RouteRemoved {
+            zone: "corp".to_string(),
+            prefix: "198.51.100.0/24".to_string(),
+        });
+        events.emit(Event::ZonesReloaded {
+            added: vec!["lab".to_string(), "home".to_string()],
+            removed: vec![],
+        });
+
+        let expected = "route_installed corp198.51.100.0/24\nzones_reloaded lab home\n";
+        assert_eq!(settled(&log, expected).await, expected);
+    }
+
+    #[cfg(feature = "https")]
+    #[tokio::test]
+    async fn webhooks_get_event_json() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let events = Events::new(vec![HookConfig {
+            events: vec![],
+            command: None,
+            url: Some(url),
+        }]);
+        events.emit(route_installed());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&request).ends_with('}') {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed early");
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["event"], "route_installed");
+        assert_eq!(body["zone"], "corp");
+        assert!(body["at"].is_string());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::admin::{self, AdminListen};
+use crate::config::{Config, RouteType, ZoneConfig};
+use crate::routing::{is_device_file, is_dynamic_gateway};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// `leshy hook up|down`, called from a VPN client's up and down scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Action {
+    /// The tunnel came up
+    Up,
+    /// The tunnel is going away
+    Down,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Up => "up",
+            Self::Down => "down",
+        }
+    }
+}
+
+/// What the VPN client reports about its tunnel.
+#[derive(Debug, Default)]
+pub struct Tunnel {
+    pub device: Option<String>,
+    pub gateway: Option<IpAddr>,
+}
+
+/// Update the zone's device file for `action` and tell the running daemon
+/// over its admin API (`admin`, else the config's `admin_listen`), so the
+/// zone's routes are moved onto the tunnel (up) or taken out (down) before
+/// the script returns. Returns the line to print.
+pub async fn run(
+    config: &Config,
+    zone_name: &str,
+    action: Action,
+    tunnel: &Tunnel,
+    admin: Option<String>,
+) -> anyhow::Result<String> {
+    let zone = config
+        .zones
+        .iter()
+        .find(|zone| zone.name == zone_name)
+        .ok_or_else(|| anyhow::anyhow!("Zone '{zone_name}' is not configured"))?;
+    let Some(admin) = admin.or_else(|| config.server.admin_listen.clone()) else {
+        anyhow::bail!("admin_listen is not set (set it and restart leshy, or pass --admin)");
+    };
+    let admin = AdminListen::parse(&admin)?;
+
+    if let Some(path) = device_file(zone, tunnel)? {
+        match (action, &tunnel.device) {
+            (Action::Up, Some(device)) => write_device_file(&path, device)?,
+            (Action::Up, None) => anyhow::bail!(
+                "Zone '{zone_name}' reads its device from '{}'; pass --device",
+                path.display()
+            ),
+            (Action::Down, _) => remove_device_file(&path)?,
+        }
+    }
+
+    let path = format!("/zones/{zone_name}/{}", action.as_str());
+    let response = admin::request(&admin, "POST", &path).await?;
+    let routes = &response["routes"];
+    Ok(match (action, &tunnel.device) {
+        (Action::Up, Some(device)) => {
+            format!("Zone '{zone_name}' up on {device}: {routes} routes reinstalled")
+        }
+        (Action::Up, None) => format!("Zone '{zone_name}' up: {routes} routes reinstalled"),
+        (Action::Down, _) => format!("Zone '{zone_name}' down: {routes} routes withdrawn"),
+    })
+}
+
+/// The device file the hook writes for `zone`, after checking the tunnel
+/// matches what the zone routes through.
+fn device_file(zone: &ZoneConfig, tunnel: &Tunnel) -> anyhow::Result<Option<PathBuf>> {
+    match zone.route_type.kernel_type() {
+        RouteType::Dev => {
+            if tunnel.gateway.is_some() {
+                anyhow::bail!(
+                    "Zone '{}' is a dev zone; --gateway applies to via zones",
+                    zone.name
+                );
+            }
+            if is_device_file(&zone.route_target) {
+                return Ok(Some(PathBuf::from(&zone.route_target)));
+            }
+            match &tunnel.device {
+                Some(device) if *device != zone.route_target => anyhow::bail!(
+                    "Zone '{}' routes through '{}', not '{device}' \
+                     (point route_target at a device file to follow the tunnel)",
+                    zone.name,
+                    zone.route_target
+                ),
+                _ => Ok(None),
+            }
+        }
+        RouteType::Via => {
+            // "auto" and "iface:" zones look their gateway up themselves
+            if let Some(gateway) = tunnel
+                .gateway
+                .filter(|_| !is_dynamic_gateway(&zone.route_target))
+            {
+                if zone.route_target.parse::<IpAddr>().ok() != Some(gateway) {
+                    anyhow::bail!(
+                        "Zone '{}' routes via {}, not {gateway}",
+                        zone.name,
+                        zone.route_target
+                    );
+                }
+            }
+            if let (Some(device), Some(route_device)) = (&tunnel.device, &zone.route_device) {
+                if device != route_device {
+                    anyhow::bail!(
+                        "Zone '{}' routes on '{route_device}', not '{device}'",
+                        zone.name
+                    );
+                }
+            }
+            Ok(None)
+        }
+        other => anyhow::bail!(
+            "Zone '{}' is a {} zone; hooks apply to via and dev zones",
+            zone.name,
+            format!("{other:?}").to_lowercase()
+        ),
+    }
+}
+
+/// Replace the device file in one step, so the daemon never reads it half
+/// written.
+pub(crate) fn write_device_file(path: &Path, device: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("leshy-tmp");
+    std::fs::write(&tmp, format!("{device}\n"))
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| anyhow::anyhow!("failed to write device file '{}': {e}", path.display()))
+}
+
+pub(crate) fn remove_device_file(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(anyhow::anyhow!(
+            "failed to remove device file '{}': {e}",
+            path.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(route_type: &str, route_target: &str) -> ZoneConfig {
+        toml::from_str(&format!(
+            "name = \"corp\"\nroute_type = \"{route_type}\"\nroute_target = \"{route_target}\"\n\
+             domains = [\"corp.example\"]"
+        ))
+        .unwrap()
+    }
+
+    fn tunnel(device: Option<&str>, gateway: Option<&str>) -> Tunnel {
+        Tunnel {
+            device: device.map(str::to_string),
+            gateway: gateway.map(|gateway| gateway.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn checks_tunnel_against_zone() {
+        let file = zone("dev", "/run/vpn/corp.dev");
+        assert_eq!(
+            device_file(&file, &tunnel(Some("tun1"), None)).unwrap(),
+            Some(PathBuf::from("/run/vpn/corp.dev"))
+        );
+        assert!(device_file(&file, &tunnel(None, Some("198.51.100.1"))).is_err());
+
+        let literal = zone("dev", "wg0");
+        assert_eq!(
+            device_file(&literal, &tunnel(Some("wg0"), None)).unwrap(),
+            None
+        );
+        assert!(device_file(&literal, &tunnel(Some("wg1"), None)).is_err());
+
+        let via = zone("via", "198.51.100.1");
+        assert_eq!(
+            device_file(&via, &tunnel(Some("tun0"), Some("198.51.100.1"))).unwrap(),
+            None
+        );
+        assert!(device_file(&via, &tunnel(None, Some("198.51.100.2"))).is_err());
+        assert!(device_file(&zone("blackhole", ""), &Tunnel::default()).is_err());
+    }
+
+    #[test]
+    fn replaces_and_removes_device_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corp.dev");
+        std::fs::write(&path, "tun0\n").unwrap();
+
+        write_device_file(&path, "tun1").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "tun1\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        remove_device_file(&path).unwrap();
+        assert!(!path.exists());
+        remove_device_file(&path).unwrap();
+    }
+}
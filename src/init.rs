@@ -0,0 +1,78 @@
+use std::path::Path;
+
+/// Starter configs written by `leshy init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Template {
+    /// One "via" zone to adapt
+    #[default]
+    Basic,
+    /// Company domains through a VPN tunnel, resolved by corporate DNS
+    CorporateVpn,
+    /// Everything through a tunnel gateway except listed domains
+    ExclusiveTunnel,
+    /// Listed domains straight onto a WireGuard interface
+    WireguardDev,
+    /// Node-local DNS in a Kubernetes DaemonSet: cluster names to kube-dns,
+    /// corporate zones through the node's VPN, with health checks and metrics
+    KubernetesNodeLocal,
+}
+
+impl Template {
+    pub fn content(self) -> &'static str {
+        match self {
+            Self::Basic => include_str!("../templates/basic.toml"),
+            Self::CorporateVpn => include_str!("../templates/corporate-vpn.toml"),
+            Self::ExclusiveTunnel => include_str!("../templates/exclusive-tunnel.toml"),
+            Self::WireguardDev => include_str!("../templates/wireguard-dev.toml"),
+            Self::KubernetesNodeLocal => include_str!("../templates/kubernetes-node-local.toml"),
+        }
+    }
+}
+
+/// Write `template` to `path` and create the config.d directory next to
+/// it. An existing file is only replaced with `force`.
+pub fn write_config(path: &Path, template: Template, force: bool) -> anyhow::Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!(
+            "'{}' already exists (use --force to overwrite)",
+            path.display()
+        );
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir.join("config.d"))
+        .map_err(|e| anyhow::anyhow!("failed to create '{}': {}", dir.display(), e))?;
+    std::fs::write(path, template.content())
+        .map_err(|e| anyhow::anyhow!("failed to write '{}': {}", path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::ValueEnum;
+
+    #[test]
+    fn templates_are_valid_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        for &template in Template::value_variants() {
+            let path = dir.path().join(format!("{template:?}/leshy.toml"));
+            write_config(&path, template, false).unwrap();
+            assert!(path.parent().unwrap().join("config.d").is_dir());
+
+            // Loads without unknown keys
+            let content = std::fs::read_to_string(&path).unwrap();
+            std::fs::write(
+                &path,
+                content.replacen("[server]\n", "[server]\nstrict = true\n", 1),
+            )
+            .unwrap();
+            let config = Config::from_file_with_includes(&path).unwrap();
+            assert!(!config.zones.is_empty(), "{template:?}");
+        }
+
+        let path = dir.path().join("Basic/leshy.toml");
+        assert!(write_config(&path, Template::Basic, false).is_err());
+        write_config(&path, Template::WireguardDev, true).unwrap();
+    }
+}
@@ -1,8 +1,19 @@
 // Public API for testing
+pub mod admin;
 pub mod config;
 pub mod dns;
+pub mod doctor;
 pub mod error;
+pub mod events;
+pub mod hook;
+pub mod init;
+pub mod metrics;
 pub mod reload;
+pub mod resolv;
 pub mod routing;
 pub mod service;
+pub mod status;
+pub mod tunnel;
+#[cfg(feature = "ubus")]
+pub mod ubus;
 pub mod zones;
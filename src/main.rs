@@ -1,19 +1,42 @@
+mod admin;
 mod config;
 mod dns;
+mod doctor;
 mod error;
+mod events;
+mod hook;
+mod init;
+mod metrics;
 mod reload;
+mod resolv;
 mod routing;
 mod service;
+mod status;
+mod tunnel;
+#[cfg(feature = "ubus")]
+mod ubus;
 mod zones;
 
-use clap::{Parser, Subcommand};
+use admin::{AdminApi, AdminListen};
+use arc_swap::ArcSwap;
+use clap::{Args, Parser, Subcommand};
 use config::Config;
-use dns::{DnsHandler, DnsServer};
-use reload::{get_new_zones, get_zones_to_cleanup, ConfigWatcher};
-use std::path::PathBuf;
+use config::{ZoneConfig, ZoneMode};
+use dns::{DnsHandler, DnsServer, Listen, SharedHandler, Sockets};
+use events::Event;
+use metrics::MetricsServer;
+use reload::{
+    get_changed_zones, get_new_zones, get_zones_to_cleanup, ConfigWatcher, ReloadHistory,
+};
+use routing::endpoints::{self, EndpointRoutes, Uplink};
+use routing::{DeviceWatcher, GatewayMonitor, UplinkWatcher};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tracing_subscriber::EnvFilter;
+use tunnel::TunnelSupervisor;
 use zones::ZoneMatcher;
 
 #[derive(Parser)]
@@ -23,6 +46,14 @@ struct Cli {
     #[arg(global = true)]
     config: Option<PathBuf>,
 
+    /// Log route changes instead of applying them to the kernel
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Config profile to apply (`[profiles.<name>]`). Default: $LESHY_PROFILE
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -34,48 +65,680 @@ enum Command {
         #[command(subcommand)]
         action: ServiceAction,
     },
+    /// Write a commented starter config and create config.d next to it
+    Init {
+        /// Where to write the config
+        #[arg(default_value = service::default_config())]
+        path: PathBuf,
+
+        /// Setup to start from
+        #[arg(long, value_enum, default_value_t)]
+        template: init::Template,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the JSON Schema of the configuration file
+    Schema,
+    /// Show the running daemon's zones, cache, upstreams and recent reloads
+    Status {
+        /// Print the admin API's JSON instead
+        #[arg(long)]
+        json: bool,
+
+        /// Admin API address (ip:port or socket path). Default: the
+        /// config's `admin_listen`
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    /// Show the running daemon's most queried names, slowest resolutions
+    /// and the names that installed the most routes, over the last 30-60
+    /// minutes
+    Top {
+        /// Entries per list
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+
+        /// Print the admin API's JSON instead
+        #[arg(long)]
+        json: bool,
+
+        /// Admin API address (ip:port or socket path). Default: the
+        /// config's `admin_listen`
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    /// Show the routes the running daemon tracks, per zone
+    Routes {
+        /// Only this zone
+        #[arg(long)]
+        zone: Option<String>,
+
+        /// Print the admin API's JSON instead
+        #[arg(long)]
+        json: bool,
+
+        /// Admin API address (ip:port or socket path). Default: the
+        /// config's `admin_listen`
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    /// Show which zone a name matches, the upstream used, the addresses it
+    /// resolves to and the routes they get
+    Resolve {
+        /// Domain name to resolve
+        name: String,
+
+        /// Resolve as this client would (for client-scoped zones)
+        #[arg(long)]
+        client: Option<std::net::IpAddr>,
+
+        /// Resolve locally from the config file instead of asking the
+        /// running daemon
+        #[arg(long)]
+        offline: bool,
+
+        /// Print JSON instead
+        #[arg(long)]
+        json: bool,
+
+        /// Admin API address (ip:port or socket path). Default: the
+        /// config's `admin_listen`
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    /// Inspect or purge the running daemon's response cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+
+        /// Print the admin API's JSON instead
+        #[arg(long, global = true)]
+        json: bool,
+
+        /// Admin API address (ip:port or socket path). Default: the
+        /// config's `admin_listen`
+        #[arg(long, global = true)]
+        admin: Option<String>,
+    },
+    /// Check this host can run the config: privileges, the listen port,
+    /// upstreams, devices, gateways and conflicting resolvers
+    Doctor {
+        /// Print the findings as JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Tell the running daemon a zone's tunnel came up or is going down,
+    /// from an OpenVPN up/down script or wg-quick PostUp/PreDown
+    Hook {
+        action: hook::Action,
+
+        /// Zone routed through the tunnel
+        #[arg(long)]
+        zone: String,
+
+        /// Tunnel interface, written to the zone's device file on `up`
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Tunnel gateway, checked against a via zone's route_target
+        #[arg(long)]
+        gateway: Option<std::net::IpAddr>,
+
+        /// Admin API address (ip:port or socket path). Default: the
+        /// config's `admin_listen`
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    /// Inspect configuration files
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// rpcd exec plugin exposing the running daemon on ubus (OpenWrt)
+    #[cfg(feature = "ubus")]
+    Ubus {
+        #[command(subcommand)]
+        action: UbusAction,
+    },
+}
+
+#[cfg(feature = "ubus")]
+#[derive(Subcommand)]
+enum UbusAction {
+    /// Print the methods and their arguments
+    List,
+    /// Answer a method call, its arguments read as JSON from stdin
+    Call { method: String },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Entry count, capacity and hit rate
+    Stats,
+    /// List cached answers with their remaining TTL and hit count
+    Dump {
+        /// Only names matching this glob, e.g. "*.example.com"
+        pattern: Option<String>,
+    },
+    /// Drop cached answers for a name or glob. Default: everything
+    Purge {
+        /// Name or glob, e.g. "*.example.com"
+        name: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Preview what reloading into a new config file would change
+    Diff {
+        /// Config to compare against. Default: the daemon's config file
+        #[arg(long)]
+        old: Option<PathBuf>,
+
+        /// New config file
+        new: PathBuf,
+    },
 }
 
 #[derive(Subcommand)]
 enum ServiceAction {
-    /// Install as a system service (systemd on Linux, launchd on macOS)
+    /// Install as a system service (systemd, OpenRC or runit on Linux,
+    /// launchd on macOS)
     Install {
-        /// Path to configuration file for the service
-        #[arg(long, default_value = service::default_config())]
-        config: PathBuf,
+        /// Path to configuration file for the service. Default:
+        /// /etc/leshy/config.toml, or ~/.config/leshy/config.toml with --user
+        #[arg(long)]
+        config: Option<PathBuf>,
 
         /// Service name (allows running multiple instances)
         #[arg(long, default_value = service::default_name())]
         name: String,
+
+        /// Service manager to install into. Default: the one running
+        #[arg(long, value_enum)]
+        init: Option<service::InitSystem>,
+
+        /// Install a launchd agent running as the current user, without
+        /// root (macOS)
+        #[arg(long)]
+        user: bool,
+
+        /// Environment variable of the service, e.g. RUST_LOG=leshy=debug
+        /// (repeatable)
+        #[arg(long = "env", value_name = "KEY=VALUE", value_parser = service::parse_env)]
+        env: Vec<(String, String)>,
+
+        /// Add systemd sandboxing (ProtectSystem=strict, NoNewPrivileges,
+        /// RuntimeDirectory, ...) to the unit
+        #[arg(long)]
+        hardened: bool,
+
+        /// Print the files that would be written instead of installing
+        #[arg(long)]
+        print: bool,
+
+        /// Extra leshy arguments after the config path, e.g. `-- --profile
+        /// office`
+        #[arg(last = true)]
+        args: Vec<String>,
     },
     /// Remove the system service
     Uninstall {
         /// Service name to uninstall
         #[arg(long, default_value = service::default_name())]
         name: String,
+
+        /// Service manager it was installed into. Default: the one running
+        #[arg(long, value_enum)]
+        init: Option<service::InitSystem>,
+
+        /// Remove the current user's launchd agent (macOS)
+        #[arg(long)]
+        user: bool,
+
+        /// Also remove the config, logs, state and query log, and restore
+        /// resolver files leshy changed
+        #[arg(long)]
+        purge: bool,
+
+        /// Config of the service, for --purge. Default: as for install
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Show whether the service is running
+    Status(ServiceTarget),
+    /// Start the service
+    Start(ServiceTarget),
+    /// Stop the service
+    Stop(ServiceTarget),
+    /// Restart the service
+    Restart(ServiceTarget),
+    /// Print the service's log (journalctl, or its log files)
+    Logs {
+        #[command(flatten)]
+        target: ServiceTarget,
+
+        /// Lines of history to print
+        #[arg(short = 'n', long, default_value_t = 50)]
+        lines: usize,
+
+        /// Keep printing new lines
+        #[arg(short, long)]
+        follow: bool,
     },
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// The installed service a `leshy service` action applies to.
+#[derive(Args)]
+struct ServiceTarget {
+    /// Service name
+    #[arg(long, default_value = service::default_name())]
+    name: String,
+
+    /// Service manager it was installed into. Default: the one running
+    #[arg(long, value_enum)]
+    init: Option<service::InitSystem>,
+
+    /// The current user's launchd agent (macOS)
+    #[arg(long)]
+    user: bool,
+}
+
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let profile = cli.profile.or_else(|| std::env::var("LESHY_PROFILE").ok());
+
+    let Some(command) = cli.command else {
+        // Bind and drop privileges before the runtime starts its threads:
+        // capabilities are per thread, and new threads inherit them
+        let startup = start_server(cli.config, profile, cli.dry_run)?;
+        return runtime()?.block_on(run_server(startup));
+    };
+    runtime()?.block_on(run_command(command, cli.config, profile.as_deref()))
+}
+
+fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+}
 
-    match cli.command {
-        Some(Command::Service { action }) => match action {
-            ServiceAction::Install { config, name } => {
-                service::install(Some(&name), Some(&config))?;
+async fn run_command(
+    command: Command,
+    config_arg: Option<PathBuf>,
+    profile: Option<&str>,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Service { action } => match action {
+            ServiceAction::Install {
+                config,
+                name,
+                init,
+                user,
+                env,
+                hardened,
+                print,
+                args,
+            } => {
+                let options = service::InstallOptions {
+                    env,
+                    args,
+                    hardened,
+                };
+                service::install(Some(&name), config.as_deref(), init, user, &options, print)?;
             }
-            ServiceAction::Uninstall { name } => {
-                service::uninstall(Some(&name))?;
+            ServiceAction::Uninstall {
+                name,
+                init,
+                user,
+                purge,
+                config,
+            } => {
+                service::uninstall(Some(&name), config.as_deref(), init, user, purge)?;
+            }
+            ServiceAction::Status(target) => control(target, service::Control::Status)?,
+            ServiceAction::Start(target) => control(target, service::Control::Start)?,
+            ServiceAction::Stop(target) => control(target, service::Control::Stop)?,
+            ServiceAction::Restart(target) => control(target, service::Control::Restart)?,
+            ServiceAction::Logs {
+                target,
+                lines,
+                follow,
+            } => {
+                service::logs(Some(&target.name), target.init, target.user, lines, follow)?;
             }
         },
-        None => run_server(cli.config).await?,
+        Command::Init {
+            path,
+            template,
+            force,
+        } => {
+            init::write_config(&path, template, force)?;
+            println!("Wrote {}", path.display());
+        }
+        Command::Schema => {
+            println!("{}", serde_json::to_string_pretty(&Config::json_schema())?);
+        }
+        Command::Status { json, admin } => {
+            let admin = admin_address(admin, config_arg, profile)?;
+            let status = admin::request(&admin, "GET", "/status").await?;
+            let zones = admin::request(&admin, "GET", "/zones").await?;
+            let cache = admin::request(&admin, "GET", "/cache").await?;
+            let upstreams = admin::request(&admin, "GET", "/upstreams").await?;
+            if json {
+                let all = serde_json::json!({
+                    "status": status,
+                    "zones": zones,
+                    "cache": cache,
+                    "upstreams": upstreams,
+                });
+                println!("{}", serde_json::to_string_pretty(&all)?);
+            } else {
+                print!(
+                    "{}",
+                    status::render_status(&status, &zones, &cache, &upstreams)
+                );
+            }
+        }
+        Command::Top { limit, json, admin } => {
+            let admin = admin_address(admin, config_arg, profile)?;
+            let top = admin::request(&admin, "GET", &format!("/top?limit={limit}")).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&top)?);
+            } else {
+                print!("{}", status::render_top(&top));
+            }
+        }
+        Command::Routes { zone, json, admin } => {
+            let admin = admin_address(admin, config_arg, profile)?;
+            let mut routes = admin::request(&admin, "GET", "/routes").await?;
+            if let (Some(zone), Some(zones)) = (&zone, routes.as_object_mut()) {
+                if !zones.contains_key(zone) {
+                    anyhow::bail!("Zone '{zone}' is neither configured nor tracked");
+                }
+                zones.retain(|name, _| name == zone);
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&routes)?);
+            } else {
+                print!("{}", status::render_routes(&routes));
+            }
+        }
+        Command::Resolve {
+            name,
+            client,
+            offline,
+            json,
+            admin,
+        } => {
+            let resolution = resolve(&name, client, offline, admin, config_arg, profile).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&resolution)?);
+            } else {
+                print!("{}", status::render_resolution(&resolution));
+            }
+        }
+        Command::Cache {
+            action,
+            json,
+            admin,
+        } => {
+            let admin = admin_address(admin, config_arg, profile)?;
+            let (response, text) = match action {
+                CacheAction::Stats => {
+                    let stats = admin::request(&admin, "GET", "/cache").await?;
+                    let text = status::render_cache_stats(&stats);
+                    (stats, text)
+                }
+                CacheAction::Dump { pattern } => {
+                    let mut path = "/cache/entries".to_string();
+                    if let Some(pattern) = pattern {
+                        path.push_str(&format!("?pattern={}", admin::encode_query_value(&pattern)));
+                    }
+                    let entries = admin::request(&admin, "GET", &path).await?;
+                    let text = status::render_cache_entries(&entries);
+                    (entries, text)
+                }
+                CacheAction::Purge { name: Some(name) } => {
+                    let path = format!("/cache/purge?name={}", admin::encode_query_value(&name));
+                    let purged = admin::request(&admin, "POST", &path).await?;
+                    let text = format!("Purged {} entries matching {name}\n", purged["purged"]);
+                    (purged, text)
+                }
+                CacheAction::Purge { name: None } => {
+                    let flushed = admin::request(&admin, "POST", "/cache/flush").await?;
+                    let text = format!("Purged all {} entries\n", flushed["flushed"]);
+                    (flushed, text)
+                }
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&response)?);
+            } else {
+                print!("{text}");
+            }
+        }
+        Command::Doctor { json } => {
+            let config = Config::from_file_with_includes_and_profile(
+                &resolve_config_path(config_arg),
+                profile,
+            )?;
+            let findings = doctor::run(&config).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&findings)?);
+            } else {
+                print!("{}", doctor::render(&findings));
+            }
+            let failed = findings
+                .iter()
+                .filter(|f| f.severity == doctor::Severity::Error)
+                .count();
+            if failed > 0 {
+                anyhow::bail!("{failed} checks failed");
+            }
+        }
+        Command::Hook {
+            action,
+            zone,
+            device,
+            gateway,
+            admin,
+        } => {
+            let config = Config::from_file_with_includes_and_profile(
+                &resolve_config_path(config_arg),
+                profile,
+            )?;
+            let tunnel = hook::Tunnel { device, gateway };
+            println!(
+                "{}",
+                hook::run(&config, &zone, action, &tunnel, admin).await?
+            );
+        }
+        Command::Config { action } => match action {
+            ConfigAction::Diff { old, new } => {
+                let old = old.unwrap_or_else(|| resolve_config_path(config_arg));
+                config_diff(&old, &new, profile)?;
+            }
+        },
+        #[cfg(feature = "ubus")]
+        Command::Ubus { action } => {
+            let response = match action {
+                UbusAction::List => ubus::methods(),
+                UbusAction::Call { method } => {
+                    let mut input = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)?;
+                    let args = match input.trim() {
+                        "" => serde_json::Value::Null,
+                        input => serde_json::from_str(input)?,
+                    };
+                    let admin = admin_address(None, config_arg, profile)?;
+                    // rpcd hands the reply to the ubus caller as is, so
+                    // failures are reported in it too
+                    ubus::call(&admin, &method, &args)
+                        .await
+                        .unwrap_or_else(|e| serde_json::json!({ "error": format!("{e:#}") }))
+                }
+            };
+            println!("{response}");
+        }
     }
 
     Ok(())
 }
 
-async fn run_server(config_arg: Option<PathBuf>) -> anyhow::Result<()> {
+fn control(target: ServiceTarget, action: service::Control) -> anyhow::Result<()> {
+    service::control(Some(&target.name), target.init, target.user, action)
+}
+
+/// The config file given on the command line, else the first one found in
+/// the usual locations.
+fn resolve_config_path(config_arg: Option<PathBuf>) -> PathBuf {
+    if let Some(path) = config_arg {
+        return path;
+    }
+    // Try common locations
+    let home = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
+    let candidates = vec![
+        PathBuf::from("leshy.toml"),  // Current directory
+        PathBuf::from("config.toml"), // Current directory
+        PathBuf::from(format!("{home}/.config/leshy/config.toml")),
+        PathBuf::from("/etc/leshy/config.toml"),
+    ];
+
+    candidates
+        .into_iter()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| PathBuf::from("/etc/leshy/config.toml"))
+}
+
+/// The admin API to query: `--admin` if given, else the `admin_listen` of
+/// the config file.
+fn admin_address(
+    admin: Option<String>,
+    config_arg: Option<PathBuf>,
+    profile: Option<&str>,
+) -> anyhow::Result<AdminListen> {
+    if let Some(admin) = admin {
+        return AdminListen::parse(&admin);
+    }
+    let config_path = resolve_config_path(config_arg);
+    let config = Config::from_file_with_includes_and_profile(&config_path, profile)?;
+    match &config.server.admin_listen {
+        Some(listen) => AdminListen::parse(listen),
+        None => anyhow::bail!(
+            "admin_listen is not set in '{}' (set it and restart leshy, or pass --admin)",
+            config_path.display()
+        ),
+    }
+}
+
+/// `leshy resolve`: ask the running daemon when its admin API is known
+/// (unless `offline`), else resolve from the config file with routing in
+/// dry-run mode.
+async fn resolve(
+    name: &str,
+    client: Option<std::net::IpAddr>,
+    offline: bool,
+    admin: Option<String>,
+    config_arg: Option<PathBuf>,
+    profile: Option<&str>,
+) -> anyhow::Result<serde_json::Value> {
+    let config_path = resolve_config_path(config_arg);
+    let mut config = Config::from_file_with_includes_and_profile(&config_path, profile)?;
+    let listen = match admin {
+        Some(admin) => Some(admin),
+        None => config.server.admin_listen.clone(),
+    };
+    if let (false, Some(listen)) = (offline, listen) {
+        let mut path = format!("/resolve/{name}");
+        if let Some(client) = client {
+            path.push_str(&format!("?client={client}"));
+        }
+        return admin::request(&AdminListen::parse(&listen)?, "GET", &path).await;
+    }
+
+    config.routing.dry_run = true;
+    config.server.state_file = None;
+    let matcher = ZoneMatcher::new(config.zones.clone())?;
+    let handler = DnsHandler::new(config, matcher)?;
+    Ok(serde_json::to_value(handler.explain(name, client).await?)?)
+}
+
+/// Print what reloading from `old` into `new` would do: zones added,
+/// removed and changed, and the static routes applied for them. Fails if
+/// `new` doesn't load or validate, as a reload would. `profile` applies to
+/// both.
+fn config_diff(old: &Path, new: &Path, profile: Option<&str>) -> anyhow::Result<()> {
+    let old_config = Config::from_file_with_includes_and_profile(old, profile)?;
+    let new_config = Config::from_file_with_includes_and_profile(new, profile)
+        .and_then(|config| ZoneMatcher::new(config.zones.clone()).map(|_| config))
+        .map_err(|e| anyhow::anyhow!("{e:#}\n(a reload would keep the old config)"))?;
+
+    let added = get_new_zones(&old_config.zones, &new_config.zones);
+    let mut removed = get_zones_to_cleanup(&old_config.zones, &new_config.zones);
+    removed.sort();
+    let changed = get_changed_zones(&old_config.zones, &new_config.zones);
+
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        println!("No zone changes");
+    }
+    for zone in &added {
+        let route_type = format!("{:?}", zone.route_type).to_lowercase();
+        println!("+ {} ({route_type} {})", zone.name, zone.route_target);
+    }
+    for name in &removed {
+        let zone = old_config.zones.iter().find(|z| &z.name == name);
+        let cleanup = zone.map(|z| z.cleanup_mode).unwrap_or_default();
+        println!(
+            "- {name} (routes: {})",
+            format!("{cleanup:?}").to_lowercase()
+        );
+    }
+    for (name, options) in &changed {
+        println!("~ {name}: {}", options.join(", "));
+    }
+
+    // Static routes of added zones, and those new to changed zones
+    for zone in &new_config.zones {
+        if zone.mode == ZoneMode::Exclusive {
+            continue;
+        }
+        let old_routes: &[String] = old_config
+            .zones
+            .iter()
+            .find(|z| z.name == zone.name)
+            .map(|z| z.static_routes.as_slice())
+            .unwrap_or_default();
+        let routes: Vec<&str> = zone
+            .static_routes
+            .iter()
+            .filter(|route| !old_routes.contains(route))
+            .map(String::as_str)
+            .collect();
+        if !routes.is_empty() {
+            println!("Static routes for {}: {}", zone.name, routes.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// The loaded config and the listener bound for it, set up before the
+/// runtime starts.
+struct Startup {
+    config_path: PathBuf,
+    /// Applied again on every reload
+    profile: Option<String>,
+    config: Config,
+    sockets: Sockets,
+    resolv_conf: Option<resolv::ResolvConf>,
+}
+
+fn start_server(
+    config_arg: Option<PathBuf>,
+    profile: Option<String>,
+    dry_run: bool,
+) -> anyhow::Result<Startup> {
     // Initialize logging
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -83,46 +746,101 @@ async fn run_server(config_arg: Option<PathBuf>) -> anyhow::Result<()> {
         )
         .init();
 
-    let config_path = if let Some(path) = config_arg {
-        path
-    } else {
-        // Try common locations
-        let home = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
-        let candidates = vec![
-            PathBuf::from("leshy.toml"),  // Current directory
-            PathBuf::from("config.toml"), // Current directory
-            PathBuf::from(format!("{home}/.config/leshy/config.toml")),
-            PathBuf::from("/etc/leshy/config.toml"),
-        ];
-
-        candidates
-            .into_iter()
-            .find(|p| p.exists())
-            .unwrap_or_else(|| PathBuf::from("/etc/leshy/config.toml"))
-    };
+    let config_path = resolve_config_path(config_arg);
 
-    tracing::info!(config_path = ?config_path, "Loading configuration");
+    tracing::info!(config_path = ?config_path, profile = ?profile, "Loading configuration");
 
     // Load configuration (includes config.d directory if present)
-    let config = Config::from_file_with_includes(&config_path)?;
-    let auto_reload = config.server.auto_reload;
+    let mut config = Config::from_file_with_includes_and_profile(&config_path, profile.as_deref())?;
+    config.routing.dry_run |= dry_run;
 
     tracing::info!(
         listen = %config.server.listen_address,
         zones = config.zones.len(),
-        auto_reload = auto_reload,
+        auto_reload = config.server.auto_reload,
         "Configuration loaded"
     );
 
+    let sockets = DnsServer::bind(&Listen::from_config(&config.server))?;
+
+    // Point the system resolver at leshy now that it listens; the
+    // original is restored when this is dropped
+    let resolv_conf = config
+        .server
+        .manage_resolv_conf
+        .then(|| {
+            resolv::ResolvConf::take_over(
+                Path::new(resolv::RESOLV_CONF),
+                config.server.listen_address.ip(),
+            )
+        })
+        .transpose()?;
+
+    // Everything that needs root beyond CAP_NET_ADMIN is done
+    if let Some(user) = &config.server.user {
+        service::privileges::drop_to(user, config.server.group.as_deref())?;
+    }
+
+    Ok(Startup {
+        config_path,
+        profile,
+        config,
+        sockets,
+        resolv_conf,
+    })
+}
+
+async fn run_server(startup: Startup) -> anyhow::Result<()> {
+    let Startup {
+        config_path,
+        profile,
+        config,
+        sockets,
+        resolv_conf,
+    } = startup;
+    let auto_reload = config.server.auto_reload;
+
     // Create zone matcher
     let matcher = ZoneMatcher::new(config.zones.clone())?;
 
-    // Create DNS handler (wrapped in Arc for reload)
-    let handler = Arc::new(RwLock::new(DnsHandler::new(config.clone(), matcher)?));
+    sync_resolver_files(&config);
+
+    // Create DNS handler (snapshots swapped on reload)
+    let handler: SharedHandler = Arc::new(ArcSwap::from_pointee(DnsHandler::new(
+        config.clone(),
+        matcher,
+    )?));
+
+    // Fixed at startup: with routing disabled, nothing below touches routes
+    let routes_enabled = handler.load_full().routes_enabled();
+    if !routes_enabled {
+        tracing::info!("Routing disabled, only forwarding and caching DNS");
+    }
+
+    // Resume route tracking from the previous run before installing anything
+    if let Some(state_file) = config.server.state_file.as_ref().filter(|_| routes_enabled) {
+        if let Err(e) = handler.load_full().restore_state(state_file).await {
+            tracing::warn!(error = %e, "Failed to restore route state, starting fresh");
+        }
+        let handler_state = handler.clone();
+        tokio::spawn(async move {
+            persist_state_loop(handler_state).await;
+        });
+    }
+
+    // Adopt routes a previous run left in the kernel, so aggregates aren't
+    // re-installed over their carve-outs
+    if let Err(e) = handler.load_full().reconcile_routes().await {
+        tracing::warn!(error = %e, "Failed to reconcile with kernel routing table");
+    }
+
+    // Route the tunnels' endpoints around them before any zone's routes
+    let mut uplink = protect_endpoints(&handler.load_full(), &config, routes_enabled, None).await;
 
     // Apply static routes (and spawn retry loop for dev zones where VPN may not be up yet)
     {
-        let handler_guard = handler.read().await;
+        let handler_guard = handler.load_full();
+        handler_guard.apply_ip_rules().await;
         let failures = handler_guard.apply_static_routes().await;
         if failures > 0 && handler_guard.has_static_routes() {
             let handler_retry = handler.clone();
@@ -133,15 +851,59 @@ async fn run_server(config_arg: Option<PathBuf>) -> anyhow::Result<()> {
     }
 
     // Create and start DNS server
-    let server = DnsServer::new(config.server.listen_address, handler.clone()).await?;
+    let mut server = DnsServer::new(
+        Listen::from_config(&config.server),
+        sockets,
+        handler.clone(),
+    )?;
 
     tracing::info!("Leshy DNS server started");
 
+    // Move dev zones' routes to the new interface when a device file
+    // changes, and auto via zones' to a new default gateway
+    let mut device_watch =
+        spawn_device_watcher(handler.clone(), routed(&config.zones, routes_enabled));
+
+    // Fetch and periodically refresh zones' static_routes_url lists
+    let mut remote_refresh =
+        spawn_remote_routes(handler.clone(), routed(&config.zones, routes_enabled));
+
+    // Reinstall routes deleted from the kernel behind our back
+    if routes_enabled {
+        let handler_audit = handler.clone();
+        tokio::spawn(async move {
+            audit_routes_loop(handler_audit).await;
+        });
+    }
+
+    // Resolve preresolve zones' domains ahead of the first query
+    let mut preresolver = spawn_preresolver(handler.clone(), routed(&config.zones, routes_enabled));
+
+    // Withdraw via zones' routes while their gateway is unreachable
+    let mut gateway_monitor =
+        spawn_gateway_monitor(handler.clone(), routed(&config.zones, routes_enabled));
+
+    // Run zones' socks_tunnel processes, moving their routes onto them
+    let mut tunnels = TunnelSupervisor::new(handler.clone());
+    tunnels.sync(routed(&config.zones, routes_enabled));
+
+    // Reloaded configs, from the config watcher and the admin API
+    let (reload_tx, mut reload_rx) = mpsc::unbounded_channel::<Config>();
+    // Listen settings changed by a reload, for the server to rebind
+    let (rebind_tx, mut rebind_rx) = mpsc::unbounded_channel::<Listen>();
+    let history = ReloadHistory::new();
+
     // Spawn config watcher if auto_reload is enabled
     if auto_reload {
-        let handler_clone = handler.clone();
         let config_dir = config.server.config_dir.as_ref().map(PathBuf::from);
-        let (watcher, mut reload_rx) = ConfigWatcher::new(config_path.clone(), config_dir);
+        let watcher = ConfigWatcher::new(
+            config_path.clone(),
+            config_dir,
+            config.watched_files(),
+            reload_tx.clone(),
+        )
+        .with_history(history.clone())
+        .with_profile(profile.clone());
 
         // Spawn watcher task
         tokio::spawn(async move {
@@ -149,25 +911,55 @@ async fn run_server(config_arg: Option<PathBuf>) -> anyhow::Result<()> {
                 tracing::error!("Config watcher error: {}", e);
             }
         });
+    }
+
+    // Serve the admin API if configured (not re-bound on reload)
+    if let Some(listen) = &config.server.admin_listen {
+        let api = AdminApi::new(
+            handler.clone(),
+            config_path.clone(),
+            reload_tx.clone(),
+            history.clone(),
+        )
+        .with_profile(profile.clone());
+        api.spawn(&AdminListen::parse(listen)?).await?;
+    }
+    // Health checks and metrics, likewise bound once
+    if let Some(addr) = config.server.metrics_listen {
+        MetricsServer::new(handler.clone()).spawn(addr).await?;
+    }
+
+    // Sockets are bound and static routes attempted: tell systemd we're up,
+    // and keep its watchdog fed while the handler stays responsive
+    service::notify::ready();
+    if let Some(interval) = service::notify::watchdog_interval() {
+        let handler_watchdog = handler.clone();
+        tokio::spawn(async move {
+            feed_watchdog(handler_watchdog, interval).await;
+        });
+    }
 
-        // Spawn reload handler task
+    // Spawn reload handler task
+    {
+        let handler_clone = handler.clone();
         let handler_for_reload = handler.clone();
+        let history = history.clone();
         tokio::spawn(async move {
             while let Some(new_config) = reload_rx.recv().await {
                 tracing::info!("Applying new configuration");
 
                 // Get current handler
-                let mut handler_guard = handler_clone.write().await;
-                let old_config = handler_guard.config().clone();
+                let current = handler_clone.load_full();
+                let old_config = current.config().clone();
 
                 // Determine zones to cleanup and new zones
                 let zones_to_cleanup = get_zones_to_cleanup(&old_config.zones, &new_config.zones);
                 let new_zones = get_new_zones(&old_config.zones, &new_config.zones);
 
                 // Cleanup routes for removed zones
-                for zone_name in zones_to_cleanup {
+                for zone_name in zones_to_cleanup.clone() {
                     tracing::info!(zone = zone_name, "Removing zone and cleaning up routes");
-                    if let Err(e) = handler_guard.cleanup_zone(&zone_name).await {
+                    if let Err(e) = current.cleanup_zone(&zone_name).await {
                         tracing::error!(zone = zone_name, error = %e, "Failed to cleanup zone");
                     }
                 }
@@ -175,52 +967,445 @@ async fn run_server(config_arg: Option<PathBuf>) -> anyhow::Result<()> {
                 // Create new matcher with updated zones
                 match ZoneMatcher::new(new_config.zones.clone()) {
                     Ok(new_matcher) => {
-                        // Update handler with new config and matcher
-                        if let Err(e) = handler_guard
-                            .update_config(new_config.clone(), new_matcher)
-                            .await
-                        {
-                            tracing::error!(error = %e, "Failed to update handler config");
-                        } else {
-                            let failures = handler_guard.apply_static_routes().await;
-                            if failures > 0 && handler_guard.has_static_routes() {
-                                let handler_retry = handler_for_reload.clone();
-                                tokio::spawn(async move {
-                                    retry_static_routes(handler_retry).await;
+                        // Build the next snapshot; queries keep being served
+                        // by the current one until it is swapped in
+                        match current.reconfigure(new_config.clone(), new_matcher).await {
+                            Err(e) => {
+                                tracing::error!(error = %e, "Failed to update handler config");
+                                history.failed(&e);
+                            }
+                            Ok(next) => {
+                                handler_clone.store(Arc::new(next));
+                                let handler_guard = handler_clone.load_full();
+                                device_watch.abort();
+                                let zones = routed(&new_config.zones, routes_enabled);
+                                device_watch =
+                                    spawn_device_watcher(handler_for_reload.clone(), zones);
+                                remote_refresh.abort();
+                                handler_guard.prune_remote_routes().await;
+                                remote_refresh =
+                                    spawn_remote_routes(handler_for_reload.clone(), zones);
+                                gateway_monitor.abort();
+                                gateway_monitor =
+                                    spawn_gateway_monitor(handler_for_reload.clone(), zones);
+                                preresolver.abort();
+                                preresolver = spawn_preresolver(handler_for_reload.clone(), zones);
+                                tunnels.sync(zones);
+                                uplink = protect_endpoints(
+                                    &handler_guard,
+                                    &new_config,
+                                    routes_enabled,
+                                    uplink,
+                                )
+                                .await;
+                                handler_guard.apply_ip_rules().await;
+                                let failures = handler_guard.apply_static_routes().await;
+                                if failures > 0 && handler_guard.has_static_routes() {
+                                    let handler_retry = handler_for_reload.clone();
+                                    tokio::spawn(async move {
+                                        retry_static_routes(handler_retry).await;
+                                    });
+                                }
+                                sync_resolver_files(&new_config);
+                                let listen = Listen::from_config(&new_config.server);
+                                if listen != Listen::from_config(&old_config.server) {
+                                    let _ = rebind_tx.send(listen);
+                                }
+                                tracing::info!(
+                                    zones_added = new_zones.len(),
+                                    total_zones = new_config.zones.len(),
+                                    "Configuration applied successfully"
+                                );
+                                let added: Vec<String> =
+                                    new_zones.iter().map(|z| z.name.clone()).collect();
+                                handler_guard.events().emit(Event::ZonesReloaded {
+                                    added: added.clone(),
+                                    removed: zones_to_cleanup.clone(),
                                 });
+                                history.applied(added, zones_to_cleanup);
                             }
-                            tracing::info!(
-                                zones_added = new_zones.len(),
-                                total_zones = new_config.zones.len(),
-                                "Configuration applied successfully"
-                            );
                         }
                     }
                     Err(e) => {
                         tracing::error!(error = %e, "Failed to create zone matcher, keeping old config");
+                        history.failed(&e);
                     }
                 }
             }
         });
     }
 
-    // Run server
-    server.run().await?;
+    // Log per-zone query counters on SIGUSR1
+    let handler_stats = handler.clone();
+    tokio::spawn(async move {
+        log_stats_on_signal(handler_stats).await;
+    });
+
+    // Run server until it stops or a shutdown signal arrives, rebinding
+    // when a reload moves it
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = server.run() => return Ok(result?),
+            Some(listen) = rebind_rx.recv() => {
+                let address = listen.address;
+                match server.rebind(listen).await {
+                    Ok(()) => tracing::info!(addr = %address, "Rebound DNS server"),
+                    Err(e) => tracing::error!(
+                        addr = %address,
+                        error = %e,
+                        "Failed to rebind DNS server"
+                    ),
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    tracing::info!("Shutdown signal received, draining queries in flight");
+    service::notify::stopping();
+    let timeout = handler.load_full().config().server.shutdown_timeout;
+    server
+        .shutdown(std::time::Duration::from_secs(timeout))
+        .await;
+
+    tracing::info!("Cleaning up");
+    let handler_guard = handler.load_full();
+    handler_guard.cleanup_on_shutdown().await;
+    let state_file = handler_guard.config().server.state_file.as_ref();
+    if let Some(state_file) = state_file.filter(|_| routes_enabled) {
+        if let Err(e) = handler_guard.save_state(state_file).await {
+            tracing::error!(error = %e, "Failed to save route state");
+        }
+    }
+    drop(resolv_conf);
 
     Ok(())
 }
 
-/// Retry applying static routes every 10 seconds until all succeed.
-/// Handles the case where VPN device files don't exist yet at startup.
-async fn retry_static_routes(handler: Arc<RwLock<DnsHandler>>) {
+/// Route the tunnels' endpoints via the default gateway with
+/// `routing.protect_endpoints`, or take their routes away without it. A
+/// default route through a tunnel leaves `uplink`, the one used before, in
+/// place. Returns the gateway now used.
+async fn protect_endpoints(
+    handler: &DnsHandler,
+    config: &Config,
+    routes_enabled: bool,
+    uplink: Option<Uplink>,
+) -> Option<Uplink> {
+    if !routes_enabled {
+        return uplink;
+    }
+    if !config.routing.protect_endpoints {
+        handler.protect_endpoints(EndpointRoutes::default()).await;
+        return uplink;
+    }
+    let uplink = endpoints::current_uplink(&config.zones, uplink);
+    handler
+        .protect_endpoints(EndpointRoutes::for_zones(&config.zones, uplink.as_ref()))
+        .await;
+    uplink
+}
+
+/// The zones whose routes leshy manages: none with routing disabled.
+fn routed(zones: &[ZoneConfig], routes_enabled: bool) -> &[ZoneConfig] {
+    if routes_enabled {
+        zones
+    } else {
+        &[]
+    }
+}
+
+/// Ping the systemd watchdog twice per `interval`, skipping pings while
+/// the route manager lock can't be taken, so a hung server gets restarted.
+async fn feed_watchdog(handler: SharedHandler, interval: std::time::Duration) {
+    let period = interval / 2;
+    loop {
+        tokio::time::sleep(period).await;
+        let current = handler.load_full();
+        match tokio::time::timeout(period, current.state_generation()).await {
+            Ok(_) => service::notify::watchdog(),
+            Err(_) => tracing::warn!("DNS handler unresponsive, skipping watchdog ping"),
+        }
+    }
+}
+
+/// Point `/etc/resolver` files for the inclusive zones' domains at leshy
+/// with `macos_resolvers`, removing those for domains (or, with it off,
+/// all) that are no longer wanted.
+fn sync_resolver_files(config: &Config) {
+    let zones: &[ZoneConfig] = if config.server.macos_resolvers {
+        &config.zones
+    } else {
+        &[]
+    };
+    match resolv::sync_resolver_files(
+        Path::new(resolv::RESOLVER_DIR),
+        zones,
+        config.server.listen_address,
+    ) {
+        Ok((0, 0)) => {}
+        Ok((written, removed)) => {
+            tracing::info!(written, removed, "Updated /etc/resolver files");
+        }
+        Err(e) => tracing::warn!(error = %e, "Failed to update /etc/resolver files"),
+    }
+}
+
+/// Resolve when SIGINT or SIGTERM is received.
+async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to install SIGTERM handler");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Log every zone's query counters and every upstream's counters and
+/// round-trip times each time SIGUSR1 is received.
+async fn log_stats_on_signal(handler: SharedHandler) {
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to install SIGUSR1 handler");
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        for (zone, stats) in handler.load_full().zone_stats() {
+            tracing::info!(
+                zone = zone,
+                queries = stats.queries,
+                cache_hits = stats.cache_hits,
+                routes_installed = stats.routes_installed,
+                upstream_failures = stats.upstream_failures,
+                rate_limited = stats.rate_limited,
+                overloaded = stats.overloaded,
+                "Zone stats"
+            );
+        }
+        for ((upstream, protocol), stats) in handler.load_full().upstream_stats() {
+            tracing::info!(
+                upstream = %upstream,
+                protocol = ?protocol,
+                answered = stats.answered,
+                failures = stats.failures,
+                servfail = stats.servfail,
+                refused = stats.refused,
+                unreachable = stats.unreachable,
+                rtt_p50_ms = stats.rtt.p50_ms,
+                rtt_p99_ms = stats.rtt.p99_ms,
+                "Upstream stats"
+            );
+        }
+    }
+}
+
+/// Watch dev zones' device files, and the gateways of "auto" and "iface:"
+/// via zones, and re-apply a zone's routes whenever its device or gateway
+/// changes. Abort the returned task to stop watching.
+fn spawn_device_watcher(handler: SharedHandler, zones: &[ZoneConfig]) -> JoinHandle<()> {
+    let (watcher, mut changed_rx) = DeviceWatcher::new(zones);
+    let uplink = UplinkWatcher::new(zones, watcher.sender());
+    tokio::spawn(async move {
+        let reapply = async {
+            while let Some(zone_name) = changed_rx.recv().await {
+                let handler_guard = handler.load_full();
+                if let Err(e) = handler_guard.reapply_zone(&zone_name).await {
+                    tracing::warn!(zone = zone_name, error = %e, "Failed to re-apply zone routes");
+                }
+            }
+        };
+        let (result, (), ()) = tokio::join!(watcher.watch(), uplink.watch(), reapply);
+        if let Err(e) = result {
+            tracing::error!(error = %e, "Device watcher error");
+        }
+    })
+}
+
+/// Ping via zones' gateways, withdrawing a zone's routes while its gateway
+/// is down and restoring them when it answers again. Abort the returned
+/// task to stop monitoring.
+fn spawn_gateway_monitor(handler: SharedHandler, zones: &[ZoneConfig]) -> JoinHandle<()> {
+    let (monitor, mut event_rx) = GatewayMonitor::new(zones);
+    tokio::spawn(async move {
+        let apply = async {
+            while let Some(event) = event_rx.recv().await {
+                let handler_guard = handler.load_full();
+                let result = if event.up {
+                    handler_guard.restore_zone(&event.zone).await
+                } else {
+                    handler_guard.withdraw_zone(&event.zone).await
+                };
+                if let Err(e) = result {
+                    tracing::warn!(zone = event.zone, up = event.up, error = %e, "Failed to apply gateway state");
+                }
+            }
+        };
+        tokio::join!(monitor.run(), apply);
+    })
+}
+
+/// Fetch every zone's `static_routes_url` now and then on its refresh
+/// interval, applying only the prefixes that changed. A failed fetch keeps
+/// the current routes and is retried within a minute.
+/// Abort the returned task to stop refreshing.
+fn spawn_remote_routes(handler: SharedHandler, zones: &[ZoneConfig]) -> JoinHandle<()> {
+    let sources: Vec<(String, String, u64)> = zones
+        .iter()
+        .filter_map(|zone| {
+            let url = zone.static_routes_url.clone()?;
+            Some((zone.name.clone(), url, zone.static_routes_refresh))
+        })
+        .collect();
+
+    tokio::spawn(async move {
+        if sources.is_empty() {
+            return;
+        }
+        let client = match routing::remote::client() {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::error!(error = %e, "Remote static routes disabled");
+                return;
+            }
+        };
+
+        let refreshers = sources.into_iter().map(|(zone_name, url, refresh)| {
+            let handler = handler.clone();
+            let client = client.clone();
+            async move {
+                let interval = std::time::Duration::from_secs(refresh);
+                let retry = interval.min(std::time::Duration::from_secs(60));
+                loop {
+                    let delay = match routing::remote::fetch_prefixes(&client, &url).await {
+                        Ok(prefixes) => {
+                            let handler_guard = handler.load_full();
+                            if let Err(e) = handler_guard
+                                .sync_remote_routes(&zone_name, prefixes)
+                                .await
+                            {
+                                tracing::warn!(zone = zone_name, error = %e, "Failed to sync remote static routes");
+                            }
+                            interval
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                zone = zone_name,
+                                error = format!("{e:#}"),
+                                "Failed to fetch remote static routes, keeping current routes"
+                            );
+                            retry
+                        }
+                    };
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        });
+        futures::future::join_all(refreshers).await;
+    })
+}
+
+/// Resolve `preresolve` zones' domains now and then every
+/// `preresolve_interval`, routing their addresses ahead of clients.
+fn spawn_preresolver(handler: SharedHandler, zones: &[ZoneConfig]) -> JoinHandle<()> {
+    let zones: Vec<(String, u64)> = zones
+        .iter()
+        .filter(|zone| zone.preresolve)
+        .map(|zone| (zone.name.clone(), zone.preresolve_interval))
+        .collect();
+
+    tokio::spawn(async move {
+        let resolvers = zones.into_iter().map(|(zone_name, interval)| {
+            let handler = handler.clone();
+            async move {
+                let interval = std::time::Duration::from_secs(interval);
+                loop {
+                    if let Err(e) = handler.load_full().preresolve(&zone_name).await {
+                        tracing::warn!(zone = zone_name, error = %e, "Failed to preresolve zone");
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        });
+        futures::future::join_all(resolvers).await;
+    })
+}
+
+/// Audit tracked routes against the kernel table every
+/// `routing.audit_interval` of the current config; without one, check
+/// again for it in a minute.
+async fn audit_routes_loop(handler: SharedHandler) {
+    loop {
+        let interval = handler.load_full().config().routing.audit_interval;
+        let Some(interval) = interval else {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            continue;
+        };
+        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+        if let Err(e) = handler.load_full().audit_routes().await {
+            tracing::warn!(error = %e, "Route audit failed");
+        }
+    }
+}
+
+/// Save route state every 30 seconds when it changed since the last save.
+/// Uses the state file of the current config, so reloads are picked up.
+async fn persist_state_loop(handler: SharedHandler) {
+    let mut saved_generation = None;
     loop {
+        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+        let handler_guard = handler.load_full();
+        let Some(state_file) = handler_guard.config().server.state_file.clone() else {
+            continue;
+        };
+        let generation = handler_guard.state_generation().await;
+        if saved_generation == Some(generation) {
+            continue;
+        }
+        match handler_guard.save_state(&state_file).await {
+            Ok(()) => {
+                tracing::debug!(path = %state_file.display(), "Route state saved");
+                saved_generation = Some(generation);
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to save route state"),
+        }
+    }
+}
+
+/// Retry applying static routes every 10 seconds until all succeed, or
+/// `static_routes_retries` attempts failed. Handles the case where VPN
+/// device files don't exist yet at startup.
+async fn retry_static_routes(handler: SharedHandler) {
+    for attempt in 1.. {
         tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-        let handler_guard = handler.read().await;
+        let handler_guard = handler.load_full();
         let failures = handler_guard.apply_static_routes().await;
         if failures == 0 {
             tracing::info!("All static routes applied successfully");
             break;
         }
+        let limit = handler_guard.config().routing.static_routes_retries;
+        if limit.is_some_and(|limit| attempt >= limit) {
+            tracing::error!(
+                pending = failures,
+                attempts = attempt,
+                "Giving up on static routes that still fail to apply"
+            );
+            handler_guard.events().emit(Event::StaticRoutesFailed {
+                pending: failures,
+                attempts: attempt,
+            });
+            break;
+        }
         tracing::debug!(
             pending = failures,
             "Some static routes still pending, will retry"
@@ -0,0 +1,390 @@
+use crate::admin::{read_head, reason};
+use crate::config::DnsProtocol;
+use crate::dns::SharedHandler;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the handler may take to show it isn't stuck
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Failures in a row after which a default upstream counts as down; not
+/// ready once all of them are
+const UNREADY_AFTER_FAILURES: u64 = 3;
+
+/// Read-only HTTP endpoints for orchestrators and scrapers, on
+/// `server.metrics_listen`: liveness (`/healthz`), readiness (`/readyz`)
+/// and Prometheus metrics (`/metrics`). Unlike the admin API, nothing here
+/// changes state, so it can face the network.
+pub struct MetricsServer {
+    handler: SharedHandler,
+    started: Instant,
+}
+
+impl MetricsServer {
+    pub fn new(handler: SharedHandler) -> Self {
+        Self {
+            handler,
+            started: Instant::now(),
+        }
+    }
+
+    /// Bind `addr` and serve requests in the background.
+    pub async fn spawn(self, addr: SocketAddr) -> anyhow::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to bind metrics on {addr}: {e}"))?;
+        tracing::info!(addr = %addr, "Health and metrics endpoints listening");
+        let server = Arc::new(self);
+        Ok(tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let server = server.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = server.serve_connection(stream).await {
+                                tracing::debug!(error = %e, "Metrics connection failed");
+                            }
+                        });
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Metrics accept failed"),
+                }
+            }
+        }))
+    }
+
+    async fn serve_connection(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+            .await
+            .map_err(|_| anyhow::anyhow!("request timed out"))??;
+        let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => self.respond(method, target).await,
+            _ => (400, "malformed request\n".to_string()),
+        };
+        let response = format!(
+            "HTTP/1.1 {status} {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            reason(status),
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Status code and plain-text body for a request.
+    pub async fn respond(&self, method: &str, target: &str) -> (u16, String) {
+        let path = target.split_once('?').map_or(target, |(path, _)| path);
+        match (method, path.trim_end_matches('/')) {
+            ("GET", "/healthz") => match self.live().await {
+                Ok(()) => (200, "ok\n".to_string()),
+                Err(e) => (503, format!("{e}\n")),
+            },
+            ("GET", "/readyz") => match self.ready().await {
+                Ok(()) => (200, "ok\n".to_string()),
+                Err(e) => (503, format!("{e}\n")),
+            },
+            ("GET", "/metrics") => (200, self.metrics().await),
+            _ => (404, format!("no such endpoint: {method} {path}\n")),
+        }
+    }
+
+    /// The handler still gets hold of the route state (the lock every
+    /// query that installs routes takes).
+    async fn live(&self) -> Result<(), String> {
+        let handler = self.handler.load_full();
+        tokio::time::timeout(LIVENESS_TIMEOUT, handler.state_generation())
+            .await
+            .map(|_| ())
+            .map_err(|_| "handler is not responding".to_string())
+    }
+
+    /// Live, and some default upstream answers (or hasn't been asked yet).
+    async fn ready(&self) -> Result<(), String> {
+        self.live().await?;
+        let handler = self.handler.load_full();
+        let stats = handler.upstream_stats();
+        let upstreams = &handler.config().server.default_upstream;
+        let up = upstreams.iter().any(|&upstream| {
+            stats
+                .get(&(upstream, DnsProtocol::Udp))
+                .is_none_or(|s| s.consecutive_failures < UNREADY_AFTER_FAILURES)
+        });
+        if up {
+            Ok(())
+        } else {
+            Err("no default upstream is answering".to_string())
+        }
+    }
+
+    /// Counters and gauges in the Prometheus text format.
+    async fn metrics(&self) -> String {
+        let handler = self.handler.load_full();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+            let _ = writeln!(
+                out,
+                "# HELP leshy_{name} {help}\n# TYPE leshy_{name} {kind}"
+            );
+            for (labels, value) in samples {
+                let _ = writeln!(out, "leshy_{name}{labels} {value}");
+            }
+        };
+
+        metric(
+            "build_info",
+            "gauge",
+            "Version of the running leshy.",
+            vec![(labels(&[("version", env!("CARGO_PKG_VERSION"))]), 1.0)],
+        );
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the server started.",
+            vec![(String::new(), self.started.elapsed().as_secs_f64())],
+        );
+
+        let zones = handler.zone_stats();
+        let per_zone = |value: fn(&crate::dns::stats::ZoneStats) -> u64| {
+            zones
+                .iter()
+                .map(|(zone, stats)| (labels(&[("zone", zone)]), value(stats) as f64))
+                .collect()
+        };
+        metric(
+            "queries_total",
+            "counter",
+            "Queries received, by the zone they matched.",
+            per_zone(|s| s.queries),
+        );
+        metric(
+            "cache_hits_total",
+            "counter",
+            "Queries answered from the cache.",
+            per_zone(|s| s.cache_hits),
+        );
+        metric(
+            "routes_installed_total",
+            "counter",
+            "Routes installed for resolved addresses.",
+            per_zone(|s| s.routes_installed),
+        );
+        metric(
+            "zone_upstream_failures_total",
+            "counter",
+            "Failed upstream attempts for the zone's queries.",
+            per_zone(|s| s.upstream_failures),
+        );
+        metric(
+            "rate_limited_total",
+            "counter",
+            "Queries turned away by the client rate limit.",
+            per_zone(|s| s.rate_limited),
+        );
+        metric(
+            "overloaded_total",
+            "counter",
+            "Queries answered SERVFAIL because too many were being forwarded.",
+            per_zone(|s| s.overloaded),
+        );
+
+        let routes = handler.zone_routes().await;
+        metric(
+            "routes",
+            "gauge",
+            "Kernel routes tracked, by zone.",
+            routes
+                .iter()
+                .map(|(zone, r)| (labels(&[("zone", zone)]), r.routes.len() as f64))
+                .collect(),
+        );
+        metric(
+            "route_ips",
+            "gauge",
+            "Resolved addresses the tracked routes cover, by zone.",
+            routes
+                .iter()
+                .map(|(zone, r)| (labels(&[("zone", zone)]), r.ips as f64))
+                .collect(),
+        );
+        metric(
+            "route_install_queue",
+            "gauge",
+            "Route installs waiting under routing.max_installs_per_second.",
+            vec![(String::new(), handler.queued_route_installs().await as f64)],
+        );
+
+        let cache = handler.cache_stats();
+        metric(
+            "cache_entries",
+            "gauge",
+            "Cached responses.",
+            vec![(String::new(), cache.entries as f64)],
+        );
+
+        let upstreams = handler.upstream_stats();
+        let upstream_labels =
+            |address: &SocketAddr, protocol: &DnsProtocol, extra: &[(&str, &str)]| {
+                let address = address.to_string();
+                let protocol = format!("{protocol:?}").to_lowercase();
+                let mut pairs = vec![
+                    ("upstream", address.as_str()),
+                    ("protocol", protocol.as_str()),
+                ];
+                pairs.extend_from_slice(extra);
+                labels(&pairs)
+            };
+        metric(
+            "upstream_answered_total",
+            "counter",
+            "Queries the upstream answered.",
+            upstreams
+                .iter()
+                .map(|((address, protocol), s)| {
+                    (upstream_labels(address, protocol, &[]), s.answered as f64)
+                })
+                .collect(),
+        );
+        metric(
+            "upstream_failures_total",
+            "counter",
+            "Failed attempts, by reason.",
+            upstreams
+                .iter()
+                .flat_map(|((address, protocol), s)| {
+                    [
+                        ("servfail", s.servfail),
+                        ("refused", s.refused),
+                        ("unreachable", s.unreachable),
+                    ]
+                    .map(|(reason, count)| {
+                        let labels = upstream_labels(address, protocol, &[("reason", reason)]);
+                        (labels, count as f64)
+                    })
+                })
+                .collect(),
+        );
+        metric(
+            "upstream_healthy",
+            "gauge",
+            "1 if the upstream answered its last query.",
+            upstreams
+                .iter()
+                .map(|((address, protocol), s)| {
+                    let healthy = u8::from(s.consecutive_failures == 0);
+                    (upstream_labels(address, protocol, &[]), f64::from(healthy))
+                })
+                .collect(),
+        );
+        metric(
+            "upstream_rtt_seconds",
+            "histogram",
+            "Round-trip times of upstream responses.",
+            upstreams
+                .iter()
+                .flat_map(|((address, protocol), s)| {
+                    let mut cumulative = 0;
+                    let mut samples: Vec<(String, f64)> = s
+                        .rtt
+                        .buckets
+                        .iter()
+                        .map(|bucket| {
+                            cumulative += bucket.responses;
+                            let le = bucket
+                                .le_ms
+                                .map_or("+Inf".to_string(), |ms| (ms as f64 / 1000.0).to_string());
+                            let labels = upstream_labels(address, protocol, &[("le", &le)]);
+                            (format!("_bucket{labels}"), cumulative as f64)
+                        })
+                        .collect();
+                    let labels = upstream_labels(address, protocol, &[]);
+                    let sum = s.rtt.mean_ms * s.rtt.responses as f64 / 1000.0;
+                    samples.push((format!("_sum{labels}"), sum));
+                    samples.push((format!("_count{labels}"), s.rtt.responses as f64));
+                    samples
+                })
+                .collect(),
+        );
+        out
+    }
+}
+
+/// `{a="1",b="2"}`, values escaped as the text format wants.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::dns::DnsHandler;
+    use crate::zones::ZoneMatcher;
+    use arc_swap::ArcSwap;
+
+    const CONFIG: &str = r#"
+        [server]
+        listen_address = "127.0.0.1:15390"
+        default_upstream = ["198.51.100.53:53"]
+
+        [routing]
+        dry_run = true
+
+        [[zones]]
+        name = "corp"
+        route_type = "via"
+        route_target = "198.51.100.1"
+        domains = ["corp.example"]
+    "#;
+
+    fn metrics_server() -> MetricsServer {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let matcher = ZoneMatcher::new(config.zones.clone()).unwrap();
+        let handler = DnsHandler::new(config, matcher).unwrap();
+        MetricsServer::new(Arc::new(ArcSwap::from_pointee(handler)))
+    }
+
+    #[tokio::test]
+    async fn serves_health_and_metrics() {
+        let server = metrics_server();
+        assert_eq!(server.respond("GET", "/healthz").await.0, 200);
+        // Not asked yet counts as answering
+        assert_eq!(server.respond("GET", "/readyz").await.0, 200);
+        assert_eq!(server.respond("POST", "/healthz").await.0, 404);
+
+        let (status, body) = server.respond("GET", "/metrics").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("# TYPE leshy_queries_total counter\n"));
+        assert!(body.contains("leshy_cache_entries 0\n"));
+        assert!(body.contains(&format!(
+            "leshy_build_info{{version=\"{}\"}} 1\n",
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(
+            labels(&[("zone", "a\"b\\c"), ("le", "+Inf")]),
+            r#"{zone="a\"b\\c",le="+Inf"}"#
+        );
+    }
+}
@@ -1,43 +1,157 @@
 use crate::config::{Config, ZoneConfig};
+use crate::error::LeshyError;
 use anyhow::Result;
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::collections::HashSet;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+
+/// Quiet period after a change before reloading, so the bursts of events
+/// editors and `cp` produce for one save cause a single reload.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reload attempts kept in `ReloadHistory`
+const RELOAD_HISTORY: usize = 20;
+
+/// Outcome of one reload attempt.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReloadRecord {
+    /// Local time, RFC 3339
+    pub at: String,
+    pub applied: bool,
+    #[serde(default)]
+    pub added: Vec<String>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// `LeshyError::code` of the failure, e.g. "config_invalid"
+    #[serde(default)]
+    pub error_code: Option<String>,
+}
+
+/// The latest reload attempts, shared by the config watcher, the reload
+/// task and the admin API.
+#[derive(Debug, Clone, Default)]
+pub struct ReloadHistory(Arc<Mutex<VecDeque<ReloadRecord>>>);
+
+impl ReloadHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn applied(&self, mut added: Vec<String>, mut removed: Vec<String>) {
+        added.sort();
+        removed.sort();
+        self.push(true, added, removed, None);
+    }
+
+    pub fn failed(&self, error: &LeshyError) {
+        self.push(false, Vec::new(), Vec::new(), Some(error));
+    }
+
+    /// Recorded attempts, newest first.
+    pub fn recent(&self) -> Vec<ReloadRecord> {
+        self.0.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    fn push(
+        &self,
+        applied: bool,
+        added: Vec<String>,
+        removed: Vec<String>,
+        error: Option<&LeshyError>,
+    ) {
+        let mut records = self.0.lock().unwrap();
+        if records.len() == RELOAD_HISTORY {
+            records.pop_front();
+        }
+        records.push_back(ReloadRecord {
+            at: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+            applied,
+            added,
+            removed,
+            error: error.map(LeshyError::to_string),
+            error_code: error.map(|e| e.code().to_string()),
+        });
+    }
+}
 
 /// Watches config file for changes and sends reload signals
 pub struct ConfigWatcher {
     config_path: PathBuf,
     config_dir: Option<PathBuf>,
+    /// Files the config references (`Config::watched_files`)
+    watched_files: Vec<PathBuf>,
     reload_tx: mpsc::UnboundedSender<Config>,
+    history: ReloadHistory,
+    /// Profile applied to each reloaded config (`--profile`)
+    profile: Option<String>,
 }
 
 impl ConfigWatcher {
+    /// Reloaded configs are sent to `reload_tx` (shared with the admin
+    /// API's forced reloads).
     pub fn new(
         config_path: PathBuf,
         config_dir: Option<PathBuf>,
-    ) -> (Self, mpsc::UnboundedReceiver<Config>) {
-        let (reload_tx, reload_rx) = mpsc::unbounded_channel();
-        (
-            Self {
-                config_path,
-                config_dir,
-                reload_tx,
-            },
-            reload_rx,
-        )
+        watched_files: Vec<PathBuf>,
+        reload_tx: mpsc::UnboundedSender<Config>,
+    ) -> Self {
+        Self {
+            config_path,
+            config_dir,
+            watched_files,
+            reload_tx,
+            history: ReloadHistory::new(),
+            profile: None,
+        }
     }
 
-    /// Start watching the config file and config.d directory for changes
+    /// Record configs that fail to load in `history`.
+    pub fn with_history(mut self, history: ReloadHistory) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Apply `profile` to reloaded configs, as at startup.
+    pub fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Start watching the config file, config.d directory and the files
+    /// the config references (zone files, the GeoIP database) for changes.
+    /// Referenced files are watched through their directories, so files
+    /// replaced by an editor's rename, or created after a failed load, are
+    /// still picked up.
     pub async fn watch(self) -> Result<()> {
         let (tx, mut rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
         let config_path = self.config_path.clone();
         let reload_tx = self.reload_tx.clone();
 
+        // Try explicit config_dir first, then look next to config file
+        let config_dir = [
+            self.config_dir.clone(),
+            config_path.parent().map(|p| p.join("config.d")),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|dir| dir.is_dir());
+
+        // Referenced files, sent again after every reload so the watcher
+        // follows files the new config adds
+        let mut files: HashSet<PathBuf> = self.watched_files.iter().cloned().collect();
+        let (files_tx, files_rx) = std::sync::mpsc::channel::<Vec<PathBuf>>();
+        let _ = files_tx.send(self.watched_files.clone());
+
         // Spawn file watcher in blocking task
         let watch_path = config_path.clone();
-        let explicit_config_dir = self.config_dir.clone();
+        let watch_config_dir = config_dir.clone();
         tokio::task::spawn_blocking(move || {
             let mut watcher = RecommendedWatcher::new(
                 move |res: notify::Result<Event>| {
@@ -56,59 +170,85 @@ impl ConfigWatcher {
             info!("Watching config file for changes: {}", watch_path.display());
 
             // Watch config.d directory if it exists
-            // Try explicit config_dir first, then look next to config file
-            let candidates: Vec<std::path::PathBuf> = vec![
-                explicit_config_dir.clone(),
-                watch_path.parent().map(|p| p.join("config.d")),
-            ]
-            .into_iter()
-            .flatten()
-            .collect();
-
-            for config_dir in candidates {
-                if config_dir.exists() && config_dir.is_dir() {
-                    if let Err(e) = watcher.watch(&config_dir, RecursiveMode::Recursive) {
-                        warn!("Failed to watch config.d directory: {}", e);
-                    } else {
-                        info!("Watching config.d directory: {}", config_dir.display());
-                    }
-                    break;
+            if let Some(config_dir) = &watch_config_dir {
+                if let Err(e) = watcher.watch(config_dir, RecursiveMode::Recursive) {
+                    warn!("Failed to watch config.d directory: {}", e);
+                } else {
+                    info!("Watching config.d directory: {}", config_dir.display());
                 }
             }
 
-            // Keep watcher alive
-            loop {
-                std::thread::sleep(std::time::Duration::from_secs(1));
+            // Keep watcher alive until the event loop below stops. The
+            // config file's directory is watched too, for editors that
+            // save by replacing the file.
+            let mut watched_dirs: HashSet<PathBuf> = HashSet::new();
+            while let Ok(files) = files_rx.recv() {
+                let dirs: HashSet<PathBuf> = files
+                    .iter()
+                    .chain([&watch_path])
+                    .filter_map(|file| file.parent())
+                    .map(|dir| {
+                        if dir.as_os_str().is_empty() {
+                            PathBuf::from(".")
+                        } else {
+                            dir.to_path_buf()
+                        }
+                    })
+                    .collect();
+                for dir in watched_dirs.difference(&dirs) {
+                    let _ = watcher.unwatch(dir);
+                }
+                for dir in dirs.difference(&watched_dirs) {
+                    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                        warn!("Failed to watch directory {}: {}", dir.display(), e);
+                    } else {
+                        debug!("Watching referenced files in {}", dir.display());
+                    }
+                }
+                watched_dirs = dirs;
             }
         });
 
         // Process file change events
         while let Some(event_result) = rx.recv().await {
-            match event_result {
-                Ok(event) => {
-                    if matches!(
-                        event.kind,
-                        notify::EventKind::Modify(_)
-                            | notify::EventKind::Create(_)
-                            | notify::EventKind::Remove(_)
-                    ) {
-                        info!("Config changed, reloading...");
-                        match Config::from_file_with_includes(&config_path) {
-                            Ok(new_config) => {
-                                info!("Config reloaded successfully");
-                                if let Err(e) = reload_tx.send(new_config) {
-                                    error!("Failed to send reload signal: {}", e);
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Failed to reload config, keeping old config: {}", e);
-                            }
-                        }
+            let relevant = |event_result| {
+                is_change(event_result, |path| {
+                    path == config_path
+                        || config_dir.as_ref().is_some_and(|dir| path.starts_with(dir))
+                        || files.contains(path)
+                })
+            };
+            if !relevant(event_result) {
+                continue;
+            }
+
+            // Coalesce the rest of the burst into this reload
+            let mut coalesced = 0;
+            while let Ok(Some(event_result)) =
+                tokio::time::timeout(RELOAD_DEBOUNCE, rx.recv()).await
+            {
+                if relevant(event_result) {
+                    coalesced += 1;
+                }
+            }
+            debug!(coalesced = coalesced, "Coalesced config change events");
+
+            info!("Config changed, reloading...");
+            match Config::from_file_with_includes_and_profile(&config_path, self.profile.as_deref())
+            {
+                Ok(new_config) => {
+                    info!("Config reloaded successfully");
+                    let watched = new_config.watched_files();
+                    files = watched.iter().cloned().collect();
+                    let _ = files_tx.send(watched);
+                    if let Err(e) = reload_tx.send(new_config) {
+                        error!("Failed to send reload signal: {}", e);
+                        break;
                     }
                 }
                 Err(e) => {
-                    error!("Watch error: {}", e);
+                    warn!("Failed to reload config, keeping old config: {}", e);
+                    self.history.failed(&e);
                 }
             }
         }
@@ -117,6 +257,25 @@ impl ConfigWatcher {
     }
 }
 
+/// Whether a watcher event changes a path `watched` accepts, warranting a
+/// reload.
+fn is_change(event_result: notify::Result<Event>, watched: impl Fn(&Path) -> bool) -> bool {
+    match event_result {
+        Ok(event) => {
+            matches!(
+                event.kind,
+                notify::EventKind::Modify(_)
+                    | notify::EventKind::Create(_)
+                    | notify::EventKind::Remove(_)
+            ) && event.paths.iter().any(|path| watched(path))
+        }
+        Err(e) => {
+            error!("Watch error: {}", e);
+            false
+        }
+    }
+}
+
 /// Compares two zone configurations and returns zones that need cleanup
 pub fn get_zones_to_cleanup(old_zones: &[ZoneConfig], new_zones: &[ZoneConfig]) -> Vec<String> {
     let old_zone_names: HashSet<String> = old_zones.iter().map(|z| z.name.clone()).collect();
@@ -140,26 +299,42 @@ pub fn get_new_zones(old_zones: &[ZoneConfig], new_zones: &[ZoneConfig]) -> Vec<
         .collect()
 }
 
+/// Zones present in both configurations whose definitions differ, with the
+/// names of the options that changed (sorted)
+pub fn get_changed_zones(
+    old_zones: &[ZoneConfig],
+    new_zones: &[ZoneConfig],
+) -> Vec<(String, Vec<String>)> {
+    new_zones
+        .iter()
+        .filter_map(|new| {
+            let old = old_zones.iter().find(|z| z.name == new.name)?;
+            if old == new {
+                return None;
+            }
+            let old = serde_json::to_value(old).ok()?;
+            let new_value = serde_json::to_value(new).ok()?;
+            let (Some(old), Some(new_value)) = (old.as_object(), new_value.as_object()) else {
+                return None;
+            };
+            let mut options: Vec<String> = new_value
+                .iter()
+                .filter(|(key, value)| old.get(*key) != Some(*value))
+                .map(|(key, _)| key.clone())
+                .collect();
+            options.sort();
+            Some((new.name.clone(), options))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{RouteType, ZoneConfig};
 
     fn test_zone(name: &str, route_type: RouteType, route_target: &str) -> ZoneConfig {
-        ZoneConfig {
-            name: name.to_string(),
-            mode: Default::default(),
-            dns_servers: vec![],
-            route_type,
-            route_target: route_target.to_string(),
-            domains: vec![],
-            patterns: vec![],
-            static_routes: vec![],
-            dns_protocol: Default::default(),
-            cache_min_ttl: None,
-            cache_max_ttl: None,
-            cache_negative_ttl: None,
-        }
+        ZoneConfig::new(name, route_type, route_target)
     }
 
     #[test]
@@ -189,4 +364,111 @@ mod tests {
         assert_eq!(new.len(), 1);
         assert_eq!(new[0].name, "zone2");
     }
+
+    #[test]
+    fn reload_history_keeps_latest_attempts() {
+        let history = ReloadHistory::new();
+        for i in 0..RELOAD_HISTORY {
+            history.failed(&LeshyError::Config(format!("attempt {i}")));
+        }
+        history.applied(vec!["b".into(), "a".into()], vec![]);
+
+        let recent = history.recent();
+        assert_eq!(recent.len(), RELOAD_HISTORY);
+        assert!(recent[0].applied);
+        assert_eq!(recent[0].added, vec!["a", "b"]);
+        assert_eq!(recent[1].error.as_deref(), Some("attempt 19"));
+        assert_eq!(recent[1].error_code.as_deref(), Some("config_invalid"));
+        assert_eq!(recent.last().unwrap().error.as_deref(), Some("attempt 1"));
+    }
+
+    #[test]
+    fn test_get_changed_zones() {
+        let old_zones = vec![
+            test_zone("zone1", RouteType::Via, "192.168.1.1"),
+            test_zone("zone2", RouteType::Via, "192.168.1.1"),
+        ];
+
+        let mut changed = test_zone("zone2", RouteType::Via, "192.168.1.2");
+        changed.domains = vec!["example.com".to_string()];
+        let new_zones = vec![
+            test_zone("zone1", RouteType::Via, "192.168.1.1"),
+            changed,
+            test_zone("zone3", RouteType::Via, "192.168.1.1"),
+        ];
+
+        assert_eq!(
+            get_changed_zones(&old_zones, &new_zones),
+            vec![(
+                "zone2".to_string(),
+                vec!["domains".to_string(), "route_target".to_string()]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_burst_of_changes_reloads_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("leshy.toml");
+        let config = "[server]\nlisten_address = \"127.0.0.1:15399\"\n\
+                      default_upstream = [\"8.8.8.8:53\"]\n";
+        std::fs::write(&config_path, config).unwrap();
+
+        let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+        let watcher = ConfigWatcher::new(config_path.clone(), None, vec![], reload_tx);
+        let watch = tokio::spawn(watcher.watch());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // An editor-style save: several writes in quick succession
+        for _ in 0..3 {
+            std::fs::write(&config_path, config).unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let reload = tokio::time::timeout(Duration::from_secs(5), reload_rx.recv()).await;
+        assert!(reload.unwrap().is_some());
+        let extra = tokio::time::timeout(RELOAD_DEBOUNCE * 2, reload_rx.recv()).await;
+        assert!(extra.is_err(), "burst caused more than one reload");
+        watch.abort();
+    }
+
+    #[tokio::test]
+    async fn test_replaced_referenced_file_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("leshy.toml");
+        let domains = dir.path().join("corp.txt");
+        std::fs::write(
+            &config_path,
+            "[server]\nlisten_address = \"127.0.0.1:15387\"\n\
+             default_upstream = [\"8.8.8.8:53\"]\n\n\
+             [[zones]]\nname = \"corp\"\nroute_type = \"blackhole\"\n\
+             domains_file = [\"corp.txt\"]\n",
+        )
+        .unwrap();
+        std::fs::write(&domains, "corp.example\n").unwrap();
+        let config = Config::from_file_with_includes(&config_path).unwrap();
+
+        let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+        let watcher =
+            ConfigWatcher::new(config_path.clone(), None, config.watched_files(), reload_tx);
+        let watch = tokio::spawn(watcher.watch());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Unrelated files next to the config don't reload it
+        std::fs::write(dir.path().join("state.json"), "{}").unwrap();
+        let unrelated = tokio::time::timeout(RELOAD_DEBOUNCE * 2, reload_rx.recv()).await;
+        assert!(unrelated.is_err(), "unrelated file caused a reload");
+
+        // An editor-style save replaces the file by renaming over it
+        let tmp = dir.path().join(".corp.txt.swp");
+        std::fs::write(&tmp, "corp.example\nlab.example\n").unwrap();
+        std::fs::rename(&tmp, &domains).unwrap();
+        let reload = tokio::time::timeout(Duration::from_secs(5), reload_rx.recv()).await;
+        let new_config = reload.unwrap().unwrap();
+        assert_eq!(
+            new_config.zones[0].domains,
+            vec!["corp.example", "lab.example"]
+        );
+        watch.abort();
+    }
 }
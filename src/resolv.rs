@@ -0,0 +1,348 @@
+use crate::config::{ZoneConfig, ZoneMode};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// The system resolver config taken over with `manage_resolv_conf`.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// macOS per-domain resolver files, written with `macos_resolvers`.
+pub const RESOLVER_DIR: &str = "/etc/resolver";
+
+/// First line of the resolver files leshy writes; others are left alone.
+const RESOLVER_MARKER: &str = "# Managed by leshy";
+
+/// resolv.conf pointed at leshy, with the original kept beside it (as
+/// `resolv.conf.leshy-orig`) and put back when dropped. A copy left by a
+/// run that didn't shut down cleanly is taken as the original.
+pub struct ResolvConf {
+    path: PathBuf,
+    backup: PathBuf,
+}
+
+impl ResolvConf {
+    /// Point `path` at leshy on `listen` (loopback for a wildcard address),
+    /// keeping the original's `search`, `domain` and `options` lines. A
+    /// symlink, as left by systemd-resolved or resolvconf, is replaced by a
+    /// file and restored as the same link.
+    pub fn take_over(path: &Path, listen: IpAddr) -> Result<Self> {
+        let backup = backup_path(path);
+        let original = fs::symlink_metadata(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if fs::symlink_metadata(&backup).is_ok() {
+            tracing::warn!(
+                backup = %backup.display(),
+                "Found the original resolv.conf of a previous run, keeping it"
+            );
+        } else if original.file_type().is_symlink() {
+            let target = fs::read_link(path)?;
+            std::os::unix::fs::symlink(&target, &backup)
+                .with_context(|| format!("failed to back up {}", path.display()))?;
+        } else {
+            fs::copy(path, &backup)
+                .with_context(|| format!("failed to back up {}", path.display()))?;
+        }
+
+        // Through the link, if the original is one
+        let kept = fs::read_to_string(&backup).unwrap_or_default();
+        let content = render(&kept, nameserver(listen));
+        if original.file_type().is_symlink() {
+            let tmp = path.with_extension("leshy-tmp");
+            fs::write(&tmp, content)?;
+            fs::rename(&tmp, path)
+        } else {
+            // In place: in containers resolv.conf is a bind mount that
+            // can't be renamed over
+            fs::write(path, content)
+        }
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+        tracing::info!(path = %path.display(), "Pointed the system resolver at leshy");
+        Ok(Self {
+            path: path.to_path_buf(),
+            backup,
+        })
+    }
+
+    fn restore(&self) -> Result<()> {
+        restore_backup(&self.path, &self.backup)
+    }
+}
+
+/// Put back the original `path` left by a run that didn't shut down
+/// cleanly. Returns whether there was one.
+pub fn restore_leftover(path: &Path) -> Result<bool> {
+    let backup = backup_path(path);
+    if fs::symlink_metadata(&backup).is_err() {
+        return Ok(false);
+    }
+    restore_backup(path, &backup)
+        .with_context(|| format!("failed to restore {}", path.display()))?;
+    Ok(true)
+}
+
+fn restore_backup(path: &Path, backup: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(backup)?;
+    if metadata.file_type().is_symlink() {
+        fs::rename(backup, path)?;
+    } else {
+        fs::write(path, fs::read(backup)?)?;
+        fs::remove_file(backup)?;
+    }
+    Ok(())
+}
+
+impl Drop for ResolvConf {
+    fn drop(&mut self) {
+        match self.restore() {
+            Ok(()) => tracing::info!(path = %self.path.display(), "Restored resolv.conf"),
+            Err(e) => tracing::error!(
+                path = %self.path.display(),
+                backup = %self.backup.display(),
+                error = %e,
+                "Failed to restore resolv.conf"
+            ),
+        }
+    }
+}
+
+/// Write a resolver file in `dir` for the domains of every inclusive zone,
+/// sending their queries to leshy on `listen`, and remove the files an
+/// earlier run for the same address wrote for domains no longer there.
+/// Returns how many files were written and removed.
+pub fn sync_resolver_files(
+    dir: &Path,
+    zones: &[ZoneConfig],
+    listen: SocketAddr,
+) -> Result<(usize, usize)> {
+    let owner = format!("{RESOLVER_MARKER} ({listen})");
+    let content = format!(
+        "{owner}\nnameserver {}\nport {}\n",
+        nameserver(listen.ip()),
+        listen.port()
+    );
+    let wanted: BTreeSet<String> = zones
+        .iter()
+        .filter(|zone| zone.mode == ZoneMode::Inclusive)
+        .flat_map(|zone| &zone.domains)
+        .filter_map(|domain| resolver_name(domain))
+        .collect();
+
+    let mut removed = 0;
+    for (path, first_line) in managed_files(dir)? {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if first_line == owner && !wanted.contains(name) {
+            fs::remove_file(&path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+
+    if wanted.is_empty() {
+        return Ok((0, removed));
+    }
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let mut written = 0;
+    for name in &wanted {
+        let path = dir.join(name);
+        match fs::read_to_string(&path) {
+            Ok(existing) if existing == content => continue,
+            Ok(existing) if !existing.starts_with(RESOLVER_MARKER) => {
+                tracing::warn!(path = %path.display(), "Resolver file not written by leshy, leaving it");
+                continue;
+            }
+            _ => {}
+        }
+        fs::write(&path, &content)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        written += 1;
+    }
+    Ok((written, removed))
+}
+
+/// Remove every resolver file leshy wrote in `dir`, for any address.
+/// Returns how many were removed.
+pub fn remove_resolver_files(dir: &Path) -> Result<usize> {
+    let files = managed_files(dir)?;
+    for (path, _) in &files {
+        fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+    Ok(files.len())
+}
+
+/// Resolver files in `dir` written by leshy, with their first line.
+fn managed_files(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", dir.display())),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let first_line = content.lines().next().unwrap_or_default();
+        if first_line.starts_with(RESOLVER_MARKER) {
+            files.push((path, first_line.to_string()));
+        }
+    }
+    Ok(files)
+}
+
+/// The resolver file name for a zone domain: `*.example.com` and
+/// `example.com.` both become `example.com`.
+fn resolver_name(domain: &str) -> Option<String> {
+    let name = domain
+        .trim()
+        .trim_start_matches("*.")
+        .trim_end_matches('.')
+        .to_lowercase();
+    (!name.is_empty() && !name.starts_with('.') && !name.contains(['/', '*'])).then_some(name)
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".leshy-orig");
+    PathBuf::from(backup)
+}
+
+fn nameserver(listen: IpAddr) -> IpAddr {
+    match listen {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    }
+}
+
+fn render(original: &str, nameserver: IpAddr) -> String {
+    let mut content = format!(
+        "# Generated by leshy, which restores the original on shutdown\nnameserver {nameserver}\n"
+    );
+    for line in original.lines() {
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+        if matches!(keyword, "search" | "domain" | "options") {
+            content.push_str(line);
+            content.push('\n');
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "# from DHCP\nnameserver 198.51.100.53\nsearch corp.example\n";
+
+    #[test]
+    fn takes_over_and_restores_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        fs::write(&path, ORIGINAL).unwrap();
+
+        let takeover = ResolvConf::take_over(&path, "0.0.0.0".parse().unwrap()).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("nameserver 127.0.0.1\n"));
+        assert!(!content.contains("198.51.100.53"));
+        assert!(content.contains("search corp.example\n"));
+
+        drop(takeover);
+        assert_eq!(fs::read_to_string(&path).unwrap(), ORIGINAL);
+        assert!(!backup_path(&path).exists());
+    }
+
+    #[test]
+    fn restores_symlink_and_keeps_stale_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("stub-resolv.conf");
+        let path = dir.path().join("resolv.conf");
+        fs::write(&target, ORIGINAL).unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        // A crashed run: the link is in the backup, resolv.conf is ours
+        let crashed = ResolvConf::take_over(&path, "::".parse().unwrap()).unwrap();
+        std::mem::forget(crashed);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("nameserver ::1\n"));
+
+        let takeover = ResolvConf::take_over(&path, "127.0.0.53".parse().unwrap()).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("nameserver 127.0.0.53\n"));
+        drop(takeover);
+        assert_eq!(fs::read_link(&path).unwrap(), target);
+        assert_eq!(fs::read_to_string(&target).unwrap(), ORIGINAL);
+    }
+
+    #[test]
+    fn restores_leftover_of_a_crashed_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        fs::write(&path, ORIGINAL).unwrap();
+        assert!(!restore_leftover(&path).unwrap());
+
+        std::mem::forget(ResolvConf::take_over(&path, "127.0.0.1".parse().unwrap()).unwrap());
+        assert!(restore_leftover(&path).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), ORIGINAL);
+        assert!(!backup_path(&path).exists());
+    }
+
+    fn zone(name: &str, mode: &str, domains: &[&str]) -> ZoneConfig {
+        toml::from_str(&format!(
+            "name = \"{name}\"\nmode = \"{mode}\"\nroute_type = \"via\"\n\
+             route_target = \"198.51.100.1\"\ndomains = {domains:?}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn syncs_resolver_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = dir.path().join("resolver");
+        let listen: SocketAddr = "0.0.0.0:5353".parse().unwrap();
+        let zones = [
+            zone("corp", "inclusive", &["corp.example", "*.Media.example."]),
+            zone("direct", "exclusive", &["bank.example"]),
+        ];
+
+        assert_eq!(
+            sync_resolver_files(&resolver, &zones, listen).unwrap(),
+            (2, 0)
+        );
+        let content = fs::read_to_string(resolver.join("media.example")).unwrap();
+        assert!(content.contains("nameserver 127.0.0.1\nport 5353\n"));
+        assert!(!resolver.join("bank.example").exists());
+        // Unchanged files aren't rewritten
+        assert_eq!(
+            sync_resolver_files(&resolver, &zones, listen).unwrap(),
+            (0, 0)
+        );
+
+        // A file someone else wrote is left alone
+        fs::write(resolver.join("other.example"), "nameserver 203.0.113.53\n").unwrap();
+        let zones = [zone(
+            "corp",
+            "inclusive",
+            &["corp.example", "other.example"],
+        )];
+        assert_eq!(
+            sync_resolver_files(&resolver, &zones, listen).unwrap(),
+            (0, 1)
+        );
+        assert!(!resolver.join("media.example").exists());
+        assert!(fs::read_to_string(resolver.join("other.example"))
+            .unwrap()
+            .contains("203.0.113.53"));
+
+        assert_eq!(remove_resolver_files(&resolver).unwrap(), 1);
+        assert!(resolver.join("other.example").exists());
+    }
+}
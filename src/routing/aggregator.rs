@@ -1,23 +1,44 @@
 use crate::config::RouteType;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 
-/// Describes a kernel route action the caller must execute.
+/// Describes a kernel route action the caller must execute, on behalf of
+/// the zone that owns the prefix.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RouteAction {
     Add {
+        zone: String,
         network: Ipv4Addr,
         prefix_len: u8,
         route_type: RouteType,
         route_target: String,
     },
     Remove {
+        zone: String,
         network: Ipv4Addr,
         prefix_len: u8,
     },
 }
 
-#[derive(Debug, Clone)]
+/// An installed aggregator prefix, as persisted in the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledPrefix {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    pub zone: String,
+    pub route_type: RouteType,
+    pub route_target: String,
+}
+
+/// Serializable snapshot of the aggregator's ownership state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregatorState {
+    pub installed: Vec<InstalledPrefix>,
+    pub known_ips: Vec<(Ipv4Addr, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct RouteOwner {
     zone_name: String,
     route_type: RouteType,
@@ -75,6 +96,7 @@ impl RouteAggregator {
                 },
             );
             return vec![RouteAction::Add {
+                zone: zone_name.to_string(),
                 network: ip,
                 prefix_len: 32,
                 route_type,
@@ -96,6 +118,7 @@ impl RouteAggregator {
             self.installed.remove(&(old_net, old_prefix));
 
             let mut actions = vec![RouteAction::Remove {
+                zone: old_owner.zone_name.clone(),
                 network: Ipv4Addr::from(old_net),
                 prefix_len: old_prefix,
             }];
@@ -126,6 +149,7 @@ impl RouteAggregator {
                     },
                 );
                 actions.push(RouteAction::Add {
+                    zone: old_owner.zone_name.clone(),
                     network: Ipv4Addr::from(sibling),
                     prefix_len: child_prefix,
                     route_type: old_owner.route_type,
@@ -146,6 +170,7 @@ impl RouteAggregator {
                 },
             );
             actions.push(RouteAction::Add {
+                zone: zone_name.to_string(),
                 network: ip,
                 prefix_len: 32,
                 route_type,
@@ -180,6 +205,7 @@ impl RouteAggregator {
                 },
             );
             return vec![RouteAction::Add {
+                zone: zone_name.to_string(),
                 network: Ipv4Addr::from(agg_net),
                 prefix_len: self.prefix_len,
                 route_type,
@@ -197,6 +223,7 @@ impl RouteAggregator {
             },
         );
         let mut actions = vec![RouteAction::Add {
+            zone: zone_name.to_string(),
             network: Ipv4Addr::from(agg_net),
             prefix_len: self.prefix_len,
             route_type,
@@ -215,6 +242,7 @@ impl RouteAggregator {
                     self.installed.remove(&(cov_net, cov_prefix));
 
                     actions.push(RouteAction::Remove {
+                        zone: cov_owner.zone_name.clone(),
                         network: Ipv4Addr::from(cov_net),
                         prefix_len: cov_prefix,
                     });
@@ -243,6 +271,7 @@ impl RouteAggregator {
                             },
                         );
                         actions.push(RouteAction::Add {
+                            zone: cov_owner.zone_name.clone(),
                             network: Ipv4Addr::from(sibling),
                             prefix_len: child_prefix,
                             route_type: cov_owner.route_type,
@@ -270,11 +299,276 @@ impl RouteAggregator {
         self.known_ips.insert(ip, zone_name.to_string());
     }
 
+    /// Forget a static route IP registered by `register_static_ip`.
+    pub fn unregister_static_ip(&mut self, ip: Ipv4Addr, zone_name: &str) {
+        if self
+            .known_ips
+            .get(&ip)
+            .is_some_and(|zone| zone == zone_name)
+        {
+            self.known_ips.remove(&ip);
+        }
+    }
+
+    /// Record `ip` as the zone's unless some zone already owns it, when
+    /// rebuilding ownership from tracked routes. Returns whether it was
+    /// missing.
+    pub fn claim_ip(&mut self, ip: Ipv4Addr, zone_name: &str) -> bool {
+        match self.known_ips.entry(ip) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(zone_name.to_string());
+                true
+            }
+        }
+    }
+
+    /// Take ownership of a prefix found in the kernel at startup.
+    /// Returns false if the prefix is already tracked.
+    pub fn adopt(
+        &mut self,
+        network: Ipv4Addr,
+        prefix_len: u8,
+        zone_name: &str,
+        route_type: RouteType,
+        route_target: &str,
+    ) -> bool {
+        let key = (network_address(u32::from(network), prefix_len), prefix_len);
+        if self.installed.contains_key(&key) {
+            return false;
+        }
+        self.installed.insert(
+            key,
+            RouteOwner {
+                zone_name: zone_name.to_string(),
+                route_type,
+                route_target: route_target.to_string(),
+            },
+        );
+        if prefix_len == 32 {
+            self.known_ips.insert(network, zone_name.to_string());
+        }
+        true
+    }
+
     /// Remove all tracking for a zone.
-    pub fn cleanup_zone(&mut self, zone_name: &str) {
-        self.installed
-            .retain(|_, owner| owner.zone_name != zone_name);
+    /// Returns Remove actions for the zone's installed routes; the caller
+    /// decides whether to execute them.
+    pub fn cleanup_zone(&mut self, zone_name: &str) -> Vec<RouteAction> {
+        let mut actions = Vec::new();
+        self.installed.retain(|&(network, prefix_len), owner| {
+            if owner.zone_name == zone_name {
+                actions.push(RouteAction::Remove {
+                    zone: zone_name.to_string(),
+                    network: Ipv4Addr::from(network),
+                    prefix_len,
+                });
+                false
+            } else {
+                true
+            }
+        });
         self.known_ips.retain(|_, zone| zone != zone_name);
+        actions
+    }
+
+    /// Drop one installed prefix of a zone along with the zone's IPs inside
+    /// it. Returns the Remove action, or None if the zone doesn't own it.
+    pub fn evict(
+        &mut self,
+        network: Ipv4Addr,
+        prefix_len: u8,
+        zone_name: &str,
+    ) -> Option<RouteAction> {
+        let key = (u32::from(network), prefix_len);
+        if self.installed.get(&key)?.zone_name != zone_name {
+            return None;
+        }
+        self.installed.remove(&key);
+        self.known_ips.retain(|ip, zone| {
+            zone != zone_name || !ip_in_network(u32::from(*ip), key.0, prefix_len)
+        });
+        Some(RouteAction::Remove {
+            zone: zone_name.to_string(),
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Merge split prefixes back toward the aggregate once the conflicts
+    /// that split them are gone (e.g. after `cleanup_zone`).
+    ///
+    /// Two sibling prefixes with the same owner merge into their parent, as
+    /// does a prefix whose sibling is free: no installed route overlaps it and
+    /// no other zone's known IP falls in it. Merging stops at the aggregation
+    /// prefix length. Returns Add actions for the merged prefixes before the
+    /// Remove actions for the prefixes they replace, so traffic keeps a route.
+    pub fn rebalance(&mut self) -> Vec<RouteAction> {
+        if self.prefix_len >= 32 {
+            return vec![];
+        }
+
+        let before = self.installed.clone();
+        loop {
+            // Most specific first, so a merged parent can merge again in the
+            // same pass
+            let mut keys: Vec<(u32, u8)> = self
+                .installed
+                .keys()
+                .copied()
+                .filter(|&(_, prefix_len)| prefix_len > self.prefix_len)
+                .collect();
+            keys.sort_by_key(|&(network, prefix_len)| (std::cmp::Reverse(prefix_len), network));
+
+            let mut merged = false;
+            for key in keys {
+                merged |= self.try_merge(key);
+            }
+            if !merged {
+                break;
+            }
+        }
+
+        let mut added: Vec<(u32, u8)> = self
+            .installed
+            .keys()
+            .filter(|key| !before.contains_key(key))
+            .copied()
+            .collect();
+        added.sort_by_key(|&(network, prefix_len)| (prefix_len, network));
+        let mut removed: Vec<(u32, u8)> = before
+            .keys()
+            .filter(|key| !self.installed.contains_key(key))
+            .copied()
+            .collect();
+        removed.sort_by_key(|&(network, prefix_len)| (prefix_len, network));
+
+        let mut actions = Vec::with_capacity(added.len() + removed.len());
+        for (network, prefix_len) in added {
+            let owner = &self.installed[&(network, prefix_len)];
+            actions.push(RouteAction::Add {
+                zone: owner.zone_name.clone(),
+                network: Ipv4Addr::from(network),
+                prefix_len,
+                route_type: owner.route_type,
+                route_target: owner.route_target.clone(),
+            });
+        }
+        for (network, prefix_len) in removed {
+            actions.push(RouteAction::Remove {
+                zone: before[&(network, prefix_len)].zone_name.clone(),
+                network: Ipv4Addr::from(network),
+                prefix_len,
+            });
+        }
+        actions
+    }
+
+    /// Merge an installed prefix with its sibling into their parent, if the
+    /// sibling has the same owner or is free.
+    fn try_merge(&mut self, (network, prefix_len): (u32, u8)) -> bool {
+        // Already merged away earlier in this pass
+        let Some(owner) = self.installed.get(&(network, prefix_len)).cloned() else {
+            return false;
+        };
+        let sibling = network ^ (1u32 << (32 - prefix_len));
+        match self.installed.get(&(sibling, prefix_len)) {
+            Some(sibling_owner) if *sibling_owner == owner => {}
+            Some(_) => return false,
+            None if self.is_free(sibling, prefix_len, &owner.zone_name) => {}
+            None => return false,
+        }
+
+        self.installed.remove(&(network, prefix_len));
+        self.installed.remove(&(sibling, prefix_len));
+        self.installed.insert(
+            (network_address(network, prefix_len - 1), prefix_len - 1),
+            owner,
+        );
+        true
+    }
+
+    /// Whether a prefix can be taken over by `zone_name`: no installed route
+    /// overlaps it and no other zone's known IP falls in it.
+    fn is_free(&self, network: u32, prefix_len: u8, zone_name: &str) -> bool {
+        let overlaps = self.installed.keys().any(|&(net, len)| {
+            let common = len.min(prefix_len);
+            network_address(net, common) == network_address(network, common)
+        });
+        let claimed = self.known_ips.iter().any(|(ip, zone)| {
+            zone != zone_name && ip_in_network(u32::from(*ip), network, prefix_len)
+        });
+        !overlaps && !claimed
+    }
+
+    /// Point a zone's installed prefixes at a new route type/target (after
+    /// a config change), so later splits install siblings with it.
+    pub fn retarget_zone(&mut self, zone_name: &str, route_type: RouteType, route_target: &str) {
+        for owner in self.installed.values_mut() {
+            if owner.zone_name == zone_name {
+                owner.route_type = route_type;
+                owner.route_target = route_target.to_string();
+            }
+        }
+    }
+
+    /// Installed prefixes owned by a zone.
+    pub fn zone_prefixes(&self, zone_name: &str) -> Vec<(Ipv4Addr, u8)> {
+        let mut prefixes: Vec<(Ipv4Addr, u8)> = self
+            .installed
+            .iter()
+            .filter(|(_, owner)| owner.zone_name == zone_name)
+            .map(|(&(network, prefix_len), _)| (Ipv4Addr::from(network), prefix_len))
+            .collect();
+        prefixes.sort();
+        prefixes
+    }
+
+    /// Export installed prefixes and IP ownership for persistence.
+    pub fn snapshot(&self) -> AggregatorState {
+        let mut installed: Vec<InstalledPrefix> = self
+            .installed
+            .iter()
+            .map(|(&(network, prefix_len), owner)| InstalledPrefix {
+                network: Ipv4Addr::from(network),
+                prefix_len,
+                zone: owner.zone_name.clone(),
+                route_type: owner.route_type,
+                route_target: owner.route_target.clone(),
+            })
+            .collect();
+        installed.sort_by_key(|p| (p.network, p.prefix_len));
+
+        let mut known_ips: Vec<(Ipv4Addr, String)> = self
+            .known_ips
+            .iter()
+            .map(|(ip, zone)| (*ip, zone.clone()))
+            .collect();
+        known_ips.sort();
+
+        AggregatorState {
+            installed,
+            known_ips,
+        }
+    }
+
+    /// Merge a persisted snapshot into the current state. Routes listed in
+    /// the snapshot are assumed to already exist in the kernel.
+    pub fn restore(&mut self, state: AggregatorState) {
+        for prefix in state.installed {
+            self.installed.insert(
+                (
+                    network_address(u32::from(prefix.network), prefix.prefix_len),
+                    prefix.prefix_len,
+                ),
+                RouteOwner {
+                    zone_name: prefix.zone,
+                    route_type: prefix.route_type,
+                    route_target: prefix.route_target,
+                },
+            );
+        }
+        self.known_ips.extend(state.known_ips);
     }
 
     /// Find an installed route that covers the given IP.
@@ -331,6 +625,7 @@ mod tests {
         assert_eq!(
             actions[0],
             RouteAction::Add {
+                zone: "zone1".to_string(),
                 network: Ipv4Addr::new(10, 0, 0, 0),
                 prefix_len: 24,
                 route_type: RouteType::Via,
@@ -391,6 +686,7 @@ mod tests {
         assert_eq!(
             removes[0],
             &RouteAction::Remove {
+                zone: "zone1".to_string(),
                 network: Ipv4Addr::new(10, 0, 0, 0),
                 prefix_len: 24,
             }
@@ -403,6 +699,7 @@ mod tests {
         assert_eq!(
             *adds.last().unwrap(),
             &RouteAction::Add {
+                zone: "zone2".to_string(),
                 network: Ipv4Addr::new(10, 0, 0, 200),
                 prefix_len: 32,
                 route_type: RouteType::Via,
@@ -472,6 +769,7 @@ mod tests {
         assert_eq!(
             actions[0],
             RouteAction::Add {
+                zone: "zone1".to_string(),
                 network: Ipv4Addr::new(10, 0, 0, 5),
                 prefix_len: 32,
                 route_type: RouteType::Via,
@@ -503,6 +801,7 @@ mod tests {
         assert_eq!(
             actions[0],
             RouteAction::Add {
+                zone: "zone1".to_string(),
                 network: Ipv4Addr::new(10, 0, 0, 5),
                 prefix_len: 32,
                 route_type: RouteType::Via,
@@ -527,7 +826,15 @@ mod tests {
             "192.168.2.1",
         );
 
-        agg.cleanup_zone("zone1");
+        let removals = agg.cleanup_zone("zone1");
+        assert_eq!(
+            removals,
+            vec![RouteAction::Remove {
+                zone: "zone1".to_string(),
+                network: Ipv4Addr::new(10, 0, 0, 0),
+                prefix_len: 24,
+            }]
+        );
 
         // zone1's aggregate should be gone from installed
         assert!(!agg.installed.values().any(|o| o.zone_name == "zone1"));
@@ -587,4 +894,155 @@ mod tests {
         assert_eq!(left, u32::from(Ipv4Addr::new(10, 0, 0, 0)));
         assert_eq!(right, u32::from(Ipv4Addr::new(10, 0, 0, 128)));
     }
+
+    #[test]
+    fn snapshot_restore_roundtrip() {
+        let mut agg = RouteAggregator::new(Some(24));
+        agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 5),
+            "zone1",
+            RouteType::Via,
+            "192.168.1.1",
+        );
+        agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 200),
+            "zone2",
+            RouteType::Via,
+            "192.168.2.1",
+        );
+        let state = agg.snapshot();
+
+        let mut restored = RouteAggregator::new(Some(24));
+        restored.restore(state.clone());
+        assert_eq!(restored.snapshot(), state);
+
+        // Restored ownership drives decisions: same-zone IP is already covered
+        let actions = restored.process_ip(
+            Ipv4Addr::new(10, 0, 0, 1),
+            "zone1",
+            RouteType::Via,
+            "192.168.1.1",
+        );
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn adopted_carve_out_is_not_reinstalled() {
+        let mut agg = RouteAggregator::new(Some(24));
+        // A previous run left zone1's /24 split around zone2's /32
+        assert!(agg.adopt(
+            Ipv4Addr::new(10, 0, 0, 0),
+            25,
+            "zone1",
+            RouteType::Via,
+            "192.168.1.1"
+        ));
+        assert!(agg.adopt(
+            Ipv4Addr::new(10, 0, 0, 200),
+            32,
+            "zone2",
+            RouteType::Via,
+            "192.168.2.1"
+        ));
+        assert!(!agg.adopt(
+            Ipv4Addr::new(10, 0, 0, 200),
+            32,
+            "zone2",
+            RouteType::Via,
+            "192.168.2.1"
+        ));
+
+        let actions = agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 5),
+            "zone1",
+            RouteType::Via,
+            "192.168.1.1",
+        );
+        assert!(actions.is_empty());
+        let actions = agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 200),
+            "zone2",
+            RouteType::Via,
+            "192.168.2.1",
+        );
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn rebalance_restores_aggregate_after_conflict_cleanup() {
+        let mut agg = RouteAggregator::new(Some(24));
+        agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 5),
+            "zone1",
+            RouteType::Via,
+            "192.168.1.1",
+        );
+        agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 200),
+            "zone2",
+            RouteType::Via,
+            "192.168.2.1",
+        );
+        // Nothing to merge while the conflict is still there
+        assert!(agg.rebalance().is_empty());
+
+        agg.cleanup_zone("zone2");
+        let actions = agg.rebalance();
+
+        assert_eq!(
+            actions[0],
+            RouteAction::Add {
+                zone: "zone1".to_string(),
+                network: Ipv4Addr::new(10, 0, 0, 0),
+                prefix_len: 24,
+                route_type: RouteType::Via,
+                route_target: "192.168.1.1".to_string(),
+            }
+        );
+        // The 8 split siblings are removed after the aggregate is back
+        assert_eq!(actions.len(), 9);
+        assert!(actions[1..]
+            .iter()
+            .all(|a| matches!(a, RouteAction::Remove { zone, .. } if zone == "zone1")));
+        assert_eq!(
+            agg.zone_prefixes("zone1"),
+            vec![(Ipv4Addr::new(10, 0, 0, 0), 24)]
+        );
+        assert!(agg.rebalance().is_empty());
+    }
+
+    #[test]
+    fn rebalance_keeps_holes_claimed_by_other_zones() {
+        let mut agg = RouteAggregator::new(Some(24));
+        agg.register_static_ip(Ipv4Addr::new(10, 0, 0, 50), "zone2");
+        agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 5),
+            "zone1",
+            RouteType::Via,
+            "192.168.1.1",
+        );
+        agg.process_ip(
+            Ipv4Addr::new(10, 0, 0, 200),
+            "zone3",
+            RouteType::Via,
+            "192.168.3.1",
+        );
+
+        // zone3's /32 is gone, but zone2's static IP still keeps its hole
+        agg.cleanup_zone("zone3");
+        let actions = agg.rebalance();
+
+        assert!(!actions.is_empty());
+        assert!(!agg
+            .zone_prefixes("zone1")
+            .contains(&(Ipv4Addr::new(10, 0, 0, 0), 24)));
+        assert_eq!(
+            agg.find_covering_route(Ipv4Addr::new(10, 0, 0, 200))
+                .map(|(key, _)| key.1),
+            Some(25)
+        );
+        assert!(agg
+            .find_covering_route(Ipv4Addr::new(10, 0, 0, 50))
+            .is_none());
+    }
 }
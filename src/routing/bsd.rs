@@ -0,0 +1,224 @@
+use super::{KernelRoute, KernelRouteAdder, RouteOptions};
+use crate::config::IpRule;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::net::IpAddr;
+use tokio::process::Command;
+
+#[cfg(target_os = "macos")]
+const PLATFORM: &str = "macOS";
+#[cfg(target_os = "freebsd")]
+const PLATFORM: &str = "FreeBSD";
+#[cfg(target_os = "openbsd")]
+const PLATFORM: &str = "OpenBSD";
+
+/// Routes via route(8), shared by macOS, FreeBSD (incl. pfSense/OPNsense)
+/// and OpenBSD; they differ only in how an alternate table is selected.
+pub struct BsdRouteAdder;
+
+impl BsdRouteAdder {
+    pub fn new() -> Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl BsdRouteAdder {
+    /// Add a route to the loopback address carrying `flag` (-blackhole or
+    /// -reject); BSD requires a gateway even for routes that drop traffic.
+    async fn add_drop_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        flag: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, flag = flag, "Adding drop route");
+
+        let loopback = if ip.is_ipv6() { "::1" } else { "127.0.0.1" };
+        let output = route_command("add", ip, prefix_len, options)?
+            .args([loopback, flag])
+            .output()
+            .await?;
+
+        if output.status.success() {
+            tracing::debug!(ip = %ip, flag = flag, "Route added successfully");
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // "File exists" = route already present, not an error
+            if stderr.contains("File exists") {
+                tracing::debug!(ip = %ip, "Route already exists");
+                Ok(())
+            } else {
+                tracing::error!(ip = %ip, stderr = %stderr, "Failed to add route");
+                anyhow::bail!("route add failed: {stderr}")
+            }
+        }
+    }
+}
+
+/// Build `route -n <action> [-inet6] -host|-net <dest>` in the zone's table.
+/// Callers append the gateway/interface and flags.
+fn route_command(
+    action: &str,
+    ip: IpAddr,
+    prefix_len: u8,
+    options: &RouteOptions,
+) -> Result<Command> {
+    if let Some(vrf) = &options.vrf {
+        anyhow::bail!("vrf '{vrf}' is not supported on {PLATFORM}");
+    }
+
+    let mut command = Command::new("/sbin/route");
+    command.arg("-n");
+    // OpenBSD selects the routing table (rtable) with a global option
+    #[cfg(target_os = "openbsd")]
+    if let Some(table) = options.table {
+        command.args(["-T", &table.to_string()]);
+    }
+    command.arg(action);
+    // FreeBSD selects the table (FIB) with a command modifier
+    #[cfg(target_os = "freebsd")]
+    if let Some(table) = options.table {
+        command.args(["-fib", &table.to_string()]);
+    }
+    // macOS has a single routing table, so per-zone tables can't be honoured
+    #[cfg(target_os = "macos")]
+    if let Some(table) = options.table {
+        anyhow::bail!("route_table {table} is not supported on {PLATFORM}");
+    }
+
+    if action == "add" && (options.device.is_some() || options.onlink || options.source.is_some()) {
+        anyhow::bail!("route_device, onlink and preferred_source are not supported on {PLATFORM}");
+    }
+
+    if ip.is_ipv6() {
+        command.arg("-inet6");
+    }
+    let max_prefix = if ip.is_ipv6() { 128 } else { 32 };
+    if prefix_len == max_prefix {
+        command.args(["-host", &ip.to_string()]);
+    } else {
+        command.args(["-net", &format!("{ip}/{prefix_len}")]);
+    }
+    Ok(command)
+}
+
+#[async_trait]
+impl KernelRouteAdder for BsdRouteAdder {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, gateway = %gateway, "Adding route via gateway");
+
+        let output = route_command("add", ip, prefix_len, options)?
+            .arg(gateway)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            tracing::debug!(ip = %ip, gateway = %gateway, "Route added successfully");
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // "File exists" = route already present, not an error
+            if stderr.contains("File exists") {
+                tracing::debug!(ip = %ip, "Route already exists");
+                Ok(())
+            } else {
+                tracing::error!(ip = %ip, stderr = %stderr, "Failed to add route");
+                anyhow::bail!("route add failed: {stderr}")
+            }
+        }
+    }
+
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, device = device, "Adding route via device");
+
+        let output = route_command("add", ip, prefix_len, options)?
+            .args(["-interface", device])
+            .output()
+            .await?;
+
+        if output.status.success() {
+            tracing::debug!(ip = %ip, device = device, "Route added successfully");
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("File exists") {
+                tracing::debug!(ip = %ip, "Route already exists");
+                Ok(())
+            } else {
+                tracing::error!(ip = %ip, stderr = %stderr, "Failed to add route");
+                anyhow::bail!("route add failed: {stderr}")
+            }
+        }
+    }
+
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        self.add_drop_route(ip, prefix_len, "-blackhole", options)
+            .await
+    }
+
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        self.add_drop_route(ip, prefix_len, "-reject", options)
+            .await
+    }
+
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, "Removing route");
+
+        let output = route_command("delete", ip, prefix_len, options)?
+            .output()
+            .await?;
+
+        if output.status.success() {
+            tracing::debug!(ip = %ip, prefix_len = prefix_len, "Route removed successfully");
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("not in table") {
+                tracing::debug!(ip = %ip, "Route does not exist, nothing to remove");
+                Ok(())
+            } else {
+                tracing::error!(ip = %ip, stderr = %stderr, "Failed to remove route");
+                anyhow::bail!("route delete failed: {stderr}")
+            }
+        }
+    }
+
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        // BSD routes carry no owner marker, so leshy's routes can't be told
+        // apart from anyone else's; rely on the state file instead.
+        tracing::debug!("Kernel route listing not supported on {PLATFORM}");
+        Ok(Vec::new())
+    }
+
+    async fn add_rule(&self, _rule: &IpRule, _table: u32) -> Result<()> {
+        anyhow::bail!("ip_rules are not supported on {PLATFORM}")
+    }
+
+    async fn remove_rule(&self, _rule: &IpRule, _table: u32) -> Result<()> {
+        anyhow::bail!("ip_rules are not supported on {PLATFORM}")
+    }
+}
@@ -0,0 +1,77 @@
+use super::{KernelRoute, RouteAdder, RouteOptions};
+use crate::config::IpRule;
+use crate::error::{LeshyError, Result};
+use async_trait::async_trait;
+use std::net::IpAddr;
+
+/// Route adder of `routing = "disabled"`: nothing asks it for routes then,
+/// so any change that still gets here is refused.
+pub struct DisabledRouteAdder;
+
+fn disabled<T>() -> Result<T> {
+    Err(LeshyError::Routing(
+        "route management is disabled".to_string(),
+    ))
+}
+
+#[async_trait]
+impl RouteAdder for DisabledRouteAdder {
+    async fn add_via_route(
+        &self,
+        _ip: IpAddr,
+        _prefix_len: u8,
+        _gateway: &str,
+        _options: &RouteOptions,
+    ) -> Result<()> {
+        disabled()
+    }
+
+    async fn add_dev_route(
+        &self,
+        _ip: IpAddr,
+        _prefix_len: u8,
+        _device: &str,
+        _options: &RouteOptions,
+    ) -> Result<()> {
+        disabled()
+    }
+
+    async fn add_blackhole_route(
+        &self,
+        _ip: IpAddr,
+        _prefix_len: u8,
+        _options: &RouteOptions,
+    ) -> Result<()> {
+        disabled()
+    }
+
+    async fn add_reject_route(
+        &self,
+        _ip: IpAddr,
+        _prefix_len: u8,
+        _options: &RouteOptions,
+    ) -> Result<()> {
+        disabled()
+    }
+
+    async fn remove_route(
+        &self,
+        _ip: IpAddr,
+        _prefix_len: u8,
+        _options: &RouteOptions,
+    ) -> Result<()> {
+        disabled()
+    }
+
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        Ok(Vec::new())
+    }
+
+    async fn add_rule(&self, _rule: &IpRule, _table: u32) -> Result<()> {
+        disabled()
+    }
+
+    async fn remove_rule(&self, _rule: &IpRule, _table: u32) -> Result<()> {
+        disabled()
+    }
+}
@@ -0,0 +1,110 @@
+use super::{KernelRoute, RouteAdder, RouteOptions};
+use crate::config::IpRule;
+use crate::error::Result;
+use async_trait::async_trait;
+use std::net::IpAddr;
+
+/// Route adder that logs every kernel change it would make instead of
+/// making it. Selected by `routing.dry_run` or `--dry-run`.
+pub struct DryRunRouteAdder;
+
+#[async_trait]
+impl RouteAdder for DryRunRouteAdder {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(
+            ip = %ip,
+            prefix_len = prefix_len,
+            gateway = gateway,
+            table = ?options.table,
+            vrf = ?options.vrf,
+            device = ?options.device,
+            onlink = options.onlink,
+            source = ?options.source,
+            "[dry-run] Would add route via gateway"
+        );
+        Ok(())
+    }
+
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(
+            ip = %ip,
+            prefix_len = prefix_len,
+            device = device,
+            table = ?options.table,
+            vrf = ?options.vrf,
+            onlink = options.onlink,
+            source = ?options.source,
+            "[dry-run] Would add route via device"
+        );
+        Ok(())
+    }
+
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(
+            ip = %ip,
+            prefix_len = prefix_len,
+            table = ?options.table,
+            vrf = ?options.vrf,
+            "[dry-run] Would add blackhole route"
+        );
+        Ok(())
+    }
+
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(
+            ip = %ip,
+            prefix_len = prefix_len,
+            table = ?options.table,
+            vrf = ?options.vrf,
+            "[dry-run] Would add reject route"
+        );
+        Ok(())
+    }
+
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()> {
+        tracing::info!(
+            ip = %ip,
+            prefix_len = prefix_len,
+            table = ?options.table,
+            vrf = ?options.vrf,
+            "[dry-run] Would remove route"
+        );
+        Ok(())
+    }
+
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        Ok(Vec::new())
+    }
+
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        tracing::info!(rule = ?rule, table = table, "[dry-run] Would add ip rule");
+        Ok(())
+    }
+
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        tracing::info!(rule = ?rule, table = table, "[dry-run] Would remove ip rule");
+        Ok(())
+    }
+}
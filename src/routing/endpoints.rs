@@ -0,0 +1,398 @@
+use super::is_device_file;
+use crate::config::{RouteType, ZoneConfig};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Zone the tunnels' endpoints are routed in, via the default gateway
+pub const ENDPOINT_ZONE: &str = "vpn-endpoints";
+
+/// The default route: gateway and the interface it is reached on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uplink {
+    pub gateway: Ipv4Addr,
+    pub device: String,
+}
+
+/// The tunnels' endpoints and the routes keeping their traffic out of the
+/// tunnels (`routing.protect_endpoints`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointRoutes {
+    /// Kept out of every zone's routes
+    pub endpoints: Vec<Ipv4Addr>,
+    /// Routes them as static routes via the uplink; None without a usable one
+    pub zone: Option<ZoneConfig>,
+}
+
+impl EndpointRoutes {
+    /// Every zone's `tunnel_endpoints`, and the WireGuard peers of dev
+    /// zones' interfaces when none are listed, routed via `uplink`.
+    pub fn for_zones(zones: &[ZoneConfig], uplink: Option<&Uplink>) -> Self {
+        protect(zones, uplink, wireguard_endpoints)
+    }
+
+    pub fn protects(&self, ip: IpAddr) -> bool {
+        matches!(ip, IpAddr::V4(v4) if self.endpoints.contains(&v4))
+    }
+}
+
+/// The uplink to route endpoints via: the default route now, unless it
+/// goes through one of `zones`' tunnels (a full-tunnel VPN connected since
+/// `previous` was found), or there is none.
+pub fn current_uplink(zones: &[ZoneConfig], previous: Option<Uplink>) -> Option<Uplink> {
+    pick_uplink(zones, default_route(), previous)
+}
+
+fn pick_uplink(
+    zones: &[ZoneConfig],
+    current: Option<Uplink>,
+    previous: Option<Uplink>,
+) -> Option<Uplink> {
+    match current {
+        Some(uplink)
+            if !zones
+                .iter()
+                .any(|zone| tunnel_device(zone).as_deref() == Some(uplink.device.as_str())) =>
+        {
+            Some(uplink)
+        }
+        _ => previous,
+    }
+}
+
+fn protect(
+    zones: &[ZoneConfig],
+    uplink: Option<&Uplink>,
+    detect: impl Fn(&str) -> Vec<Ipv4Addr>,
+) -> EndpointRoutes {
+    let mut endpoints: Vec<Ipv4Addr> = Vec::new();
+    let mut tunnels: Vec<String> = Vec::new();
+    for zone in zones {
+        let device = tunnel_device(zone);
+        let found = match (&device, zone.tunnel_endpoints.is_empty()) {
+            (Some(device), true) if zone.route_type == RouteType::Dev => detect(device),
+            _ => zone
+                .tunnel_endpoints
+                .iter()
+                .filter_map(|ip| ip.parse().ok())
+                .collect(),
+        };
+        for ip in found {
+            if !endpoints.contains(&ip) {
+                endpoints.push(ip);
+            }
+        }
+        tunnels.extend(device);
+    }
+    let mut routes = EndpointRoutes {
+        endpoints,
+        zone: None,
+    };
+    if routes.endpoints.is_empty() {
+        return routes;
+    }
+
+    let Some(uplink) = uplink else {
+        tracing::warn!(
+            endpoints = ?routes.endpoints,
+            "No default gateway found; VPN endpoints are only kept out of zone routes"
+        );
+        return routes;
+    };
+    if tunnels.contains(&uplink.device) {
+        tracing::warn!(
+            device = uplink.device,
+            "Default route goes through a tunnel; not routing VPN endpoints via it"
+        );
+        return routes;
+    }
+    if zones.iter().any(|zone| zone.name == ENDPOINT_ZONE) {
+        tracing::warn!(
+            zone = ENDPOINT_ZONE,
+            "A configured zone has the name of the VPN endpoint zone; not routing VPN endpoints"
+        );
+        return routes;
+    }
+    tracing::debug!(
+        gateway = %uplink.gateway,
+        endpoints = ?routes.endpoints,
+        "Routing VPN endpoints via the default gateway"
+    );
+    let static_routes = routes
+        .endpoints
+        .iter()
+        .map(|ip| format!("{ip}/32"))
+        .collect();
+    routes.zone = Some(endpoint_zone(uplink, static_routes));
+    routes
+}
+
+/// The interface a zone's routes go through, if it is a tunnel: a dev
+/// zone's device (read from its device file, if it is one) or a via zone's
+/// `route_device`.
+fn tunnel_device(zone: &ZoneConfig) -> Option<String> {
+    match zone.route_type.kernel_type() {
+        RouteType::Dev if is_device_file(&zone.route_target) => {
+            let device = std::fs::read_to_string(&zone.route_target).ok()?;
+            Some(device.trim().to_string()).filter(|device| !device.is_empty())
+        }
+        RouteType::Dev => Some(zone.route_target.clone()),
+        RouteType::Via => zone.route_device.clone(),
+        _ => None,
+    }
+}
+
+fn endpoint_zone(uplink: &Uplink, static_routes: Vec<String>) -> ZoneConfig {
+    ZoneConfig {
+        static_routes,
+        ..ZoneConfig::new(ENDPOINT_ZONE, RouteType::Via, &uplink.gateway.to_string())
+    }
+}
+
+/// IPv4 endpoints of a WireGuard interface's peers, from `wg show`. Empty
+/// for other interfaces or without wireguard-tools.
+fn wireguard_endpoints(device: &str) -> Vec<Ipv4Addr> {
+    let output = match std::process::Command::new("wg")
+        .args(["show", device, "endpoints"])
+        .stderr(std::process::Stdio::null())
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+    parse_wg_endpoints(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// `<public key>\t<ip:port>` per peer, `(none)` for peers without one.
+fn parse_wg_endpoints(output: &str) -> Vec<Ipv4Addr> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1)?.parse().ok())
+        .filter_map(|endpoint: std::net::SocketAddr| match endpoint.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// The main table's IPv4 default route.
+#[cfg(target_os = "linux")]
+pub fn default_route() -> Option<Uplink> {
+    parse_proc_route(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+/// The main table's IPv4 default route.
+#[cfg(not(target_os = "linux"))]
+pub fn default_route() -> Option<Uplink> {
+    route_get(&["-n", "get", "default"])
+}
+
+/// The next hop the main table uses on `device`: its default route there,
+/// else its first other route through a gateway.
+#[cfg(target_os = "linux")]
+pub fn interface_gateway(device: &str) -> Option<Ipv4Addr> {
+    parse_proc_interface_gateway(&std::fs::read_to_string("/proc/net/route").ok()?, device)
+}
+
+/// The next hop the main table uses on `device`: its scoped default route.
+#[cfg(not(target_os = "linux"))]
+pub fn interface_gateway(device: &str) -> Option<Ipv4Addr> {
+    route_get(&["-n", "get", "-ifscope", device, "default"])
+        .filter(|uplink| uplink.device == device)
+        .map(|uplink| uplink.gateway)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn route_get(args: &[&str]) -> Option<Uplink> {
+    let output = std::process::Command::new("/sbin/route")
+        .args(args)
+        .output()
+        .ok()?;
+    parse_route_get(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// A route through a gateway in `/proc/net/route`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct ProcRoute {
+    uplink: Uplink,
+    default: bool,
+    metric: u32,
+}
+
+/// The routes through a gateway in `/proc/net/route`, whose addresses are
+/// little-endian hex.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn proc_gateway_routes(table: &str) -> impl Iterator<Item = ProcRoute> + '_ {
+    const RTF_UP: u16 = 0x1;
+    const RTF_GATEWAY: u16 = 0x2;
+    table.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [device, destination, gateway, flags, _, _, metric, mask, ..] = fields[..] else {
+            return None;
+        };
+        let flags = u16::from_str_radix(flags, 16).ok()?;
+        if flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+            return None;
+        }
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(ProcRoute {
+            uplink: Uplink {
+                gateway: Ipv4Addr::from(u32::from_be(gateway)),
+                device: device.to_string(),
+            },
+            default: destination == "00000000" && mask == "00000000",
+            metric: metric.parse().ok()?,
+        })
+    })
+}
+
+/// The default route with the lowest metric in `/proc/net/route`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_route(table: &str) -> Option<Uplink> {
+    proc_gateway_routes(table)
+        .filter(|route| route.default)
+        .min_by_key(|route| route.metric)
+        .map(|route| route.uplink)
+}
+
+/// The gateway `device` routes through in `/proc/net/route`, preferring
+/// its default route with the lowest metric.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_interface_gateway(table: &str, device: &str) -> Option<Ipv4Addr> {
+    proc_gateway_routes(table)
+        .filter(|route| route.uplink.device == device)
+        .min_by_key(|route| (!route.default, route.metric))
+        .map(|route| route.uplink.gateway)
+}
+
+/// `gateway:` and `interface:` of `route -n get default`.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+fn parse_route_get(output: &str) -> Option<Uplink> {
+    let field = |name: &str| {
+        output.lines().find_map(|line| {
+            let (key, value) = line.trim().split_once(':')?;
+            (key == name).then(|| value.trim().to_string())
+        })
+    };
+    Some(Uplink {
+        gateway: field("gateway")?.parse().ok()?,
+        device: field("interface")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(toml: &str) -> ZoneConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn uplink() -> Uplink {
+        Uplink {
+            gateway: "192.168.1.1".parse().unwrap(),
+            device: "eth0".to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_default_routes() {
+        let table =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+            eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+            eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n";
+        assert_eq!(
+            parse_proc_route(table),
+            Some(Uplink {
+                gateway: "192.168.2.1".parse().unwrap(),
+                device: "eth0".to_string(),
+            })
+        );
+
+        let route_get = "   route to: default\ndestination: default\n       mask: default\n    \
+            gateway: 192.168.1.1\n  interface: en0\n      flags: <UP,GATEWAY,DONE,STATIC,PRCLONING>\n";
+        assert_eq!(parse_route_get(route_get).unwrap().device, "en0");
+
+        let table = format!("{table}eth1\t0000A8C0\t0103A8C0\t0003\t0\t0\t0\t0000FFFF\t0\t0\t0\n");
+        let gateway = |device| parse_proc_interface_gateway(&table, device);
+        assert_eq!(gateway("wlan0"), Some("192.168.1.1".parse().unwrap()));
+        assert_eq!(gateway("eth1"), Some("192.168.3.1".parse().unwrap()));
+        assert_eq!(gateway("eth2"), None);
+        assert_eq!(
+            parse_route_get("route: writing to routing socket: not in table\n"),
+            None
+        );
+
+        assert_eq!(
+            parse_wg_endpoints(
+                "abc=\t203.0.113.7:51820\ndef=\t(none)\nghi=\t[2001:db8::1]:51820\n"
+            ),
+            ["203.0.113.7".parse::<Ipv4Addr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn routes_endpoints_around_tunnels() {
+        let zones = vec![
+            zone(
+                "name = \"corp\"\nroute_type = \"via\"\nroute_target = \"10.8.0.1\"\n\
+                 route_device = \"tun0\"\ntunnel_endpoints = [\"203.0.113.7\"]\n\
+                 domains = [\"corp.example\"]",
+            ),
+            zone(
+                "name = \"all\"\nmode = \"exclusive\"\nroute_type = \"dev\"\n\
+                 route_target = \"wg0\"\ndomains = [\"ru\"]",
+            ),
+        ];
+        let detect = |device: &str| match device {
+            "wg0" => vec!["198.51.100.9".parse().unwrap()],
+            _ => vec![],
+        };
+        let routes = protect(&zones, Some(&uplink()), detect);
+
+        let endpoints: [Ipv4Addr; 2] = [
+            "203.0.113.7".parse().unwrap(),
+            "198.51.100.9".parse().unwrap(),
+        ];
+        assert_eq!(routes.endpoints, endpoints);
+        assert!(routes.protects("198.51.100.9".parse().unwrap()));
+        assert!(!routes.protects("198.51.100.10".parse().unwrap()));
+        let endpoint_zone = routes.zone.unwrap();
+        assert_eq!(endpoint_zone.name, ENDPOINT_ZONE);
+        assert_eq!(endpoint_zone.route_target, "192.168.1.1");
+        assert_eq!(
+            endpoint_zone.static_routes,
+            ["203.0.113.7/32", "198.51.100.9/32"]
+        );
+
+        // A default route through the tunnel isn't used, the one before it is
+        let tunnel = Uplink {
+            device: "wg0".to_string(),
+            ..uplink()
+        };
+        let routes = protect(&zones, Some(&tunnel), detect);
+        assert_eq!(routes.endpoints, endpoints);
+        assert_eq!(routes.zone, None);
+        assert_eq!(
+            pick_uplink(&zones, Some(tunnel), Some(uplink())),
+            Some(uplink())
+        );
+        let wifi = Uplink {
+            device: "wlan0".to_string(),
+            ..uplink()
+        };
+        assert_eq!(
+            pick_uplink(&zones, Some(wifi.clone()), Some(uplink())),
+            Some(wifi)
+        );
+        assert_eq!(pick_uplink(&zones, None, Some(uplink())), Some(uplink()));
+
+        let plain = vec![zone(
+            "name = \"corp\"\nroute_type = \"via\"\nroute_target = \"10.8.0.1\"\n\
+             domains = [\"corp.example\"]",
+        )];
+        assert_eq!(
+            protect(&plain, Some(&uplink()), detect),
+            EndpointRoutes::default()
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How long a hook may run before it is killed. Route changes sit on the
+/// DNS response path, so a hanging script must not stall resolution.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    Add,
+    Remove,
+}
+
+impl HookAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            HookAction::Add => "ADD",
+            HookAction::Remove => "REMOVE",
+        }
+    }
+}
+
+/// Run an exec zone's route hook as `<script> ADD|REMOVE <cidr> <zone>`.
+/// A non-zero exit status is an error carrying the script's stderr.
+pub async fn run_hook(
+    script: &str,
+    action: HookAction,
+    ip: IpAddr,
+    prefix_len: u8,
+    zone: &str,
+) -> Result<()> {
+    let cidr = format!("{ip}/{prefix_len}");
+    tracing::info!(
+        script = script,
+        action = action.as_str(),
+        cidr = cidr,
+        zone = zone,
+        "Running route hook"
+    );
+
+    let child = Command::new(script)
+        .args([action.as_str(), &cidr, zone])
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(HOOK_TIMEOUT, child)
+        .await
+        .with_context(|| format!("Route hook '{script}' timed out"))?
+        .with_context(|| format!("Failed to run route hook '{script}'"))?;
+
+    if output.status.success() {
+        tracing::debug!(script = script, cidr = cidr, "Route hook succeeded");
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        tracing::error!(script = script, cidr = cidr, stderr = %stderr, "Route hook failed");
+        anyhow::bail!(
+            "route hook '{script}' {} {cidr} failed ({}): {}",
+            action.as_str(),
+            output.status,
+            stderr.trim()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_script(dir: &std::path::Path, body: &str) -> String {
+        let path = dir.join("hook.sh");
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn hook_receives_action_cidr_and_zone() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls");
+        let script = write_script(dir.path(), &format!("echo \"$@\" >> {}", log.display()));
+
+        let ip: IpAddr = "10.0.0.0".parse().unwrap();
+        run_hook(&script, HookAction::Add, ip, 24, "corp")
+            .await
+            .unwrap();
+        run_hook(&script, HookAction::Remove, ip, 24, "corp")
+            .await
+            .unwrap();
+
+        let calls = std::fs::read_to_string(log).unwrap();
+        assert_eq!(calls, "ADD 10.0.0.0/24 corp\nREMOVE 10.0.0.0/24 corp\n");
+    }
+
+    #[tokio::test]
+    async fn hook_failure_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = write_script(dir.path(), "echo boom >&2; exit 3");
+
+        let err = run_hook(
+            &script,
+            HookAction::Add,
+            "1.2.3.4".parse().unwrap(),
+            32,
+            "z",
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("boom"));
+    }
+}
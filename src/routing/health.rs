@@ -0,0 +1,171 @@
+use super::{is_dynamic_gateway, resolve_gateway};
+use crate::config::{HealthCheck, RouteType, ZoneConfig};
+use crate::error::{LeshyError, Result};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+/// A via zone's gateway went down or came back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayEvent {
+    pub zone: String,
+    pub up: bool,
+}
+
+/// Pings via zones' gateways and reports when one stops or starts answering,
+/// so the zone's routes can be withdrawn instead of blackholing traffic.
+pub struct GatewayMonitor {
+    /// (zone name, gateway, check settings)
+    gateways: Vec<(String, String, HealthCheck)>,
+    event_tx: mpsc::UnboundedSender<GatewayEvent>,
+}
+
+impl GatewayMonitor {
+    pub fn new(zones: &[ZoneConfig]) -> (Self, mpsc::UnboundedReceiver<GatewayEvent>) {
+        let gateways = zones
+            .iter()
+            .filter(|zone| zone.route_type == RouteType::Via)
+            .filter_map(|zone| {
+                let check = zone.health_check.clone()?;
+                Some((zone.name.clone(), zone.route_target.clone(), check))
+            })
+            .collect();
+
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        (Self { gateways, event_tx }, event_rx)
+    }
+
+    /// Probe every gateway on its interval until the receiver is dropped.
+    pub async fn run(self) {
+        let probes = self.gateways.into_iter().map(|(zone, gateway, check)| {
+            let event_tx = self.event_tx.clone();
+            async move {
+                let interval = Duration::from_secs(check.interval);
+                let timeout = Duration::from_secs(check.timeout);
+                let mut state = GatewayState::default();
+                tracing::info!(zone = zone, gateway = gateway, "Monitoring gateway");
+                loop {
+                    // An "auto" or "iface:" zone's gateway is whatever it is now
+                    let target = match is_dynamic_gateway(&gateway) {
+                        true => resolve_gateway(&gateway).await.ok(),
+                        false => Some(gateway.clone()),
+                    };
+                    let probe = match &target {
+                        Some(target) => ping(target, timeout).await,
+                        None => Ok(false),
+                    };
+                    let alive = match probe {
+                        Ok(alive) => alive,
+                        Err(e) => {
+                            // Can't probe at all: don't withdraw on a guess
+                            tracing::error!(zone = zone, error = %e, "Gateway monitoring disabled");
+                            return;
+                        }
+                    };
+                    if let Some(up) = state.record(alive, check.failures) {
+                        if up {
+                            tracing::info!(
+                                zone = zone,
+                                gateway = gateway,
+                                "Gateway is reachable again"
+                            );
+                        } else {
+                            tracing::warn!(
+                                zone = zone,
+                                gateway = gateway,
+                                failures = check.failures,
+                                "Gateway is unreachable"
+                            );
+                        }
+                        let event = GatewayEvent {
+                            zone: zone.clone(),
+                            up,
+                        };
+                        if event_tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        });
+        futures::future::join_all(probes).await;
+    }
+}
+
+/// Up/down tracking for one gateway. Starts up; goes down after `failures`
+/// consecutive failed probes and back up on the first successful one.
+#[derive(Debug, Default)]
+struct GatewayState {
+    down: bool,
+    failed: u32,
+}
+
+impl GatewayState {
+    /// Record a probe result; returns the new state (true = up) on a change.
+    fn record(&mut self, alive: bool, failures: u32) -> Option<bool> {
+        if alive {
+            self.failed = 0;
+            if self.down {
+                self.down = false;
+                return Some(true);
+            }
+            return None;
+        }
+
+        self.failed = self.failed.saturating_add(1);
+        if !self.down && self.failed >= failures {
+            self.down = true;
+            return Some(false);
+        }
+        None
+    }
+}
+
+/// Send one ICMP echo to `gateway` with the system `ping`. Errors only when
+/// `ping` can't be run.
+pub async fn ping(gateway: &str, timeout: Duration) -> Result<bool> {
+    let secs = timeout.as_secs().max(1).to_string();
+    let mut command = Command::new("ping");
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    command.args(["-c", "1", "-t", &secs, gateway]);
+    #[cfg(target_os = "openbsd")]
+    command.args(["-c", "1", "-w", &secs, gateway]);
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd")))]
+    command.args(["-c", "1", "-W", &secs, gateway]);
+
+    let status = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status();
+    // ping enforces its own timeout; this only guards against a hang
+    match tokio::time::timeout(timeout + Duration::from_secs(1), status).await {
+        Ok(status) => Ok(status
+            .map_err(|e| LeshyError::io("Failed to run ping", e))?
+            .success()),
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_down_after_consecutive_failures_and_up_on_success() {
+        let mut state = GatewayState::default();
+        assert_eq!(state.record(true, 3), None);
+        assert_eq!(state.record(false, 3), None);
+        assert_eq!(state.record(false, 3), None);
+        // A success in between resets the count
+        assert_eq!(state.record(true, 3), None);
+        assert_eq!(state.record(false, 3), None);
+        assert_eq!(state.record(false, 3), None);
+        assert_eq!(state.record(false, 3), Some(false));
+        assert_eq!(state.record(false, 3), None);
+        assert_eq!(state.record(true, 3), Some(true));
+        assert_eq!(state.record(true, 3), None);
+    }
+}
@@ -0,0 +1,72 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Every address of every interface that is up, as (interface, address).
+pub fn addresses() -> Vec<(String, IpAddr)> {
+    let mut addrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills `addrs`, freed below
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Vec::new();
+    }
+    let mut found = Vec::new();
+    let mut cursor = addrs;
+    while !cursor.is_null() {
+        // SAFETY: a node of the list getifaddrs returned
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: ifa_name is a NUL-terminated string, ifa_addr non-null
+        // and read as the family it names
+        let (name, ip) = unsafe {
+            let name = std::ffi::CStr::from_ptr(entry.ifa_name).to_string_lossy();
+            let ip = match i32::from((*entry.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            (name.into_owned(), ip)
+        };
+        found.push((name, ip));
+    }
+    // SAFETY: allocated by getifaddrs above
+    unsafe { libc::freeifaddrs(addrs) };
+    found
+}
+
+/// The interface holding `ip`.
+pub fn with_address(ip: IpAddr) -> Option<String> {
+    addresses()
+        .into_iter()
+        .find(|&(_, address)| address == ip)
+        .map(|(name, _)| name)
+}
+
+/// Whether an interface named `name` exists, up or not.
+pub fn exists(name: &str) -> bool {
+    let Ok(name) = std::ffi::CString::new(name) else {
+        return false;
+    };
+    // SAFETY: a NUL-terminated string
+    unsafe { libc::if_nametoindex(name.as_ptr()) != 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_loopback() {
+        let loopback = with_address(Ipv4Addr::LOCALHOST.into()).unwrap();
+        assert!(exists(&loopback));
+        assert!(addresses().contains(&(loopback, Ipv4Addr::LOCALHOST.into())));
+        assert_eq!(with_address("198.51.100.254".parse().unwrap()), None);
+        assert!(!exists("leshy-none0"));
+    }
+}
@@ -0,0 +1,403 @@
+use super::{parse_cidr, KernelRoute, KernelRouteAdder, RouteOptions, MAIN_TABLE};
+use crate::config::{IpRule, RouteType};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::process::Output;
+use tokio::process::Command;
+
+/// Route protocol stamped on every route and rule, as with netlink (0x6c).
+const LESHY_PROTO: &str = "108";
+
+/// Routes via the iproute2 `ip` command, for hosts where leshy can't use
+/// netlink itself, e.g. unprivileged containers where only `sudo ip` is
+/// allowed. Selected with `routing.backend = "ip"`.
+pub struct IpRouteAdder {
+    /// Program and leading arguments, e.g. ["sudo", "-n", "ip"]
+    command: Vec<String>,
+}
+
+impl IpRouteAdder {
+    pub fn new(command: &str) -> Result<Self> {
+        let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+        if command.is_empty() {
+            anyhow::bail!("routing.ip_command is empty");
+        }
+        Ok(Self { command })
+    }
+
+    async fn run(&self, args: &[String]) -> Result<Output> {
+        tracing::debug!(command = ?self.command, args = ?args, "Running ip command");
+        Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .args(args)
+            .output()
+            .await
+            .with_context(|| format!("Failed to run '{}'", self.command.join(" ")))
+    }
+
+    /// Run `ip route add`; an already present route is not an error.
+    async fn add(&self, ip: IpAddr, args: Vec<String>) -> Result<()> {
+        let output = self.run(&args).await?;
+        if output.status.success() {
+            tracing::debug!(ip = %ip, "Route added successfully");
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // "File exists" = route already present, not an error
+            if stderr.contains("File exists") {
+                tracing::debug!(ip = %ip, "Route already exists");
+                Ok(())
+            } else {
+                tracing::error!(ip = %ip, stderr = %stderr, "Failed to add route");
+                anyhow::bail!("ip route add failed: {}", stderr.trim())
+            }
+        }
+    }
+
+    /// Leshy routes of one address family, from `ip -j route show`.
+    async fn list_family(&self, family: &str) -> Result<Vec<KernelRoute>> {
+        let args = [
+            family,
+            "-j",
+            "route",
+            "show",
+            "table",
+            "all",
+            "proto",
+            LESHY_PROTO,
+        ]
+        .map(String::from);
+        let output = self.run(&args).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "ip route show failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let entries: Vec<JsonRoute> =
+            serde_json::from_slice(&output.stdout).context("Failed to parse ip route output")?;
+        let vrfs = self.vrf_tables().await.unwrap_or_else(|e| {
+            tracing::debug!(error = %e, "Failed to list VRF devices");
+            HashMap::new()
+        });
+        Ok(entries
+            .iter()
+            .filter_map(|entry| entry.to_kernel_route(&vrfs))
+            .collect())
+    }
+
+    /// Routing table -> VRF device name, from `ip -j -d link show type vrf`.
+    async fn vrf_tables(&self) -> Result<HashMap<u32, String>> {
+        let args = ["-j", "-d", "link", "show", "type", "vrf"].map(String::from);
+        let output = self.run(&args).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "ip link show failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let links: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+        Ok(links
+            .iter()
+            .filter_map(|link| {
+                let name = link["ifname"].as_str()?;
+                let table = link["linkinfo"]["info_data"]["table"].as_u64()?;
+                Some((u32::try_from(table).ok()?, name.to_string()))
+            })
+            .collect())
+    }
+
+    /// Run `ip rule add|del` for every address family the rule applies to.
+    async fn rule(&self, action: &str, rule: &IpRule, table: u32) -> Result<()> {
+        let source = rule.from.as_deref().map(parse_cidr).transpose()?;
+        let families = match source {
+            Some((IpAddr::V4(_), _)) => vec!["-4"],
+            Some((IpAddr::V6(_), _)) => vec!["-6"],
+            None => vec!["-4", "-6"],
+        };
+
+        for family in families {
+            let mut args = vec![family.to_string(), "rule".into(), action.into()];
+            if let Some(mark) = rule.fwmark {
+                args.extend(["fwmark".into(), mark.to_string()]);
+            }
+            if let Some(from) = &rule.from {
+                args.extend(["from".into(), from.clone()]);
+            }
+            if let Some(priority) = rule.priority {
+                args.extend(["priority".into(), priority.to_string()]);
+            }
+            args.extend([
+                "table".into(),
+                table.to_string(),
+                "protocol".into(),
+                LESHY_PROTO.into(),
+            ]);
+
+            let output = self.run(&args).await?;
+            if output.status.success() {
+                continue;
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            match action {
+                "add" if stderr.contains("File exists") => {
+                    tracing::debug!(rule = ?rule, "Ip rule already exists");
+                }
+                // ENOENT = no such rule, not an error
+                "del" if stderr.contains("No such file or directory") => {
+                    tracing::debug!(rule = ?rule, "Ip rule does not exist, nothing to remove");
+                }
+                _ => {
+                    tracing::error!(rule = ?rule, stderr = %stderr, "ip rule {action} failed");
+                    anyhow::bail!("ip rule {action} failed: {}", stderr.trim());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Leading `ip route <action> [type] <dest>` arguments for a route.
+fn route_args(action: &str, kind: Option<&str>, ip: IpAddr, prefix_len: u8) -> Vec<String> {
+    let family = if ip.is_ipv6() { "-6" } else { "-4" };
+    let mut args = vec![family.to_string(), "route".into(), action.into()];
+    if let Some(kind) = kind {
+        args.push(kind.into());
+    }
+    args.push(format!("{ip}/{prefix_len}"));
+    args
+}
+
+/// Trailing arguments selecting the zone's table and leshy's protocol.
+fn table_args(options: &RouteOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(vrf) = &options.vrf {
+        args.extend(["vrf".into(), vrf.clone()]);
+    } else if let Some(table) = options.table {
+        args.extend(["table".into(), table.to_string()]);
+    }
+    args.extend(["proto".into(), LESHY_PROTO.into()]);
+    args
+}
+
+/// Onlink flag and preferred source for via/dev routes. A source of the
+/// other address family than the route is left out.
+fn next_hop_args(ip: IpAddr, options: &RouteOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    if options.onlink {
+        args.push("onlink".into());
+    }
+    if let Some(source) = options.source {
+        if source.is_ipv6() == ip.is_ipv6() {
+            args.extend(["src".into(), source.to_string()]);
+        }
+    }
+    args
+}
+
+/// One entry of `ip -j route show`.
+#[derive(Debug, Deserialize)]
+struct JsonRoute {
+    dst: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    gateway: Option<IpAddr>,
+    dev: Option<String>,
+    table: Option<String>,
+}
+
+impl JsonRoute {
+    fn to_kernel_route(&self, vrfs: &HashMap<u32, String>) -> Option<KernelRoute> {
+        let (network, prefix_len) = parse_cidr(&self.dst).ok()?;
+        let table = match self.table.as_deref() {
+            None | Some("main") => MAIN_TABLE,
+            Some("default") => 253,
+            Some("local") => 255,
+            Some(table) => match table.parse() {
+                Ok(table) => table,
+                Err(_) => {
+                    tracing::debug!(table = table, "Skipping route in a named table");
+                    return None;
+                }
+            },
+        };
+        let route_type = match self.kind.as_deref() {
+            Some("blackhole") => RouteType::Blackhole,
+            Some("unreachable") => RouteType::Reject,
+            _ if self.gateway.is_some() => RouteType::Via,
+            _ => RouteType::Dev,
+        };
+        Some(KernelRoute {
+            network,
+            prefix_len,
+            table,
+            route_type,
+            gateway: self.gateway,
+            device: self.dev.clone(),
+            vrf: vrfs.get(&table).cloned(),
+        })
+    }
+}
+
+#[async_trait]
+impl KernelRouteAdder for IpRouteAdder {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, gateway = %gateway, "Adding route via gateway");
+
+        let mut args = route_args("add", None, ip, prefix_len);
+        args.extend(["via".into(), gateway.into()]);
+        if let Some(device) = &options.device {
+            args.extend(["dev".into(), device.clone()]);
+        }
+        args.extend(next_hop_args(ip, options));
+        args.extend(table_args(options));
+        self.add(ip, args).await
+    }
+
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, device = device, "Adding route via device");
+
+        let mut args = route_args("add", None, ip, prefix_len);
+        args.extend(["dev".into(), device.into(), "scope".into(), "link".into()]);
+        args.extend(next_hop_args(ip, options));
+        args.extend(table_args(options));
+        self.add(ip, args).await
+    }
+
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, kind = "blackhole", "Adding drop route");
+
+        let mut args = route_args("add", Some("blackhole"), ip, prefix_len);
+        args.extend(table_args(options));
+        self.add(ip, args).await
+    }
+
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, kind = "unreachable", "Adding drop route");
+
+        let mut args = route_args("add", Some("unreachable"), ip, prefix_len);
+        args.extend(table_args(options));
+        self.add(ip, args).await
+    }
+
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, "Removing route");
+
+        // Matching leshy's protocol means routes with the same prefix
+        // installed by anyone else are never touched
+        let mut args = route_args("del", None, ip, prefix_len);
+        args.extend(table_args(options));
+        let output = self.run(&args).await?;
+
+        if output.status.success() {
+            tracing::debug!(ip = %ip, prefix_len = prefix_len, "Route removed successfully");
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // ESRCH = no such route, not an error
+            if stderr.contains("No such process") {
+                tracing::debug!(ip = %ip, "Route does not exist, nothing to remove");
+                Ok(())
+            } else {
+                tracing::error!(ip = %ip, stderr = %stderr, "Failed to remove route");
+                anyhow::bail!("ip route del failed: {}", stderr.trim())
+            }
+        }
+    }
+
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        let mut routes = self.list_family("-4").await?;
+        routes.extend(self.list_family("-6").await?);
+        tracing::debug!(count = routes.len(), "Listed leshy routes in kernel table");
+        Ok(routes)
+    }
+
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        tracing::info!(rule = ?rule, table = table, "Adding ip rule");
+        self.rule("add", rule, table).await
+    }
+
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        tracing::info!(rule = ?rule, table = table, "Removing ip rule");
+        self.rule("del", rule, table).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ip_route_json() {
+        let json = r#"[
+            {"dst":"198.51.100.0/24","gateway":"10.8.0.1","dev":"eth0","table":"100","flags":[]},
+            {"type":"blackhole","dst":"203.0.113.0/24","flags":[]},
+            {"type":"unreachable","dst":"2001:db8::5","dev":"lo","metric":1024,"flags":[],"pref":"medium"},
+            {"dst":"192.0.2.0/24","dev":"wg0","table":"corp","flags":[]}
+        ]"#;
+        let entries: Vec<JsonRoute> = serde_json::from_str(json).unwrap();
+        let vrfs = HashMap::from([(100, "vrf-corp".to_string())]);
+        let routes: Vec<KernelRoute> = entries
+            .iter()
+            .filter_map(|entry| entry.to_kernel_route(&vrfs))
+            .collect();
+
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0].table, 100);
+        assert_eq!(routes[0].route_type, RouteType::Via);
+        assert_eq!(routes[0].vrf.as_deref(), Some("vrf-corp"));
+        assert_eq!(routes[1].table, MAIN_TABLE);
+        assert_eq!(routes[1].route_type, RouteType::Blackhole);
+        assert_eq!(routes[2].prefix_len, 128);
+        assert_eq!(routes[2].route_type, RouteType::Reject);
+    }
+
+    #[test]
+    fn builds_route_arguments() {
+        let options = RouteOptions {
+            table: Some(100),
+            onlink: true,
+            source: Some("10.66.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+        let ip: IpAddr = "198.51.100.0".parse().unwrap();
+        let mut args = route_args("add", None, ip, 24);
+        args.extend(next_hop_args(ip, &options));
+        args.extend(table_args(&options));
+        assert_eq!(
+            args.join(" "),
+            "-4 route add 198.51.100.0/24 onlink src 10.66.0.2 table 100 proto 108"
+        );
+
+        // A v4 source hint doesn't apply to v6 routes
+        assert_eq!(
+            next_hop_args("2001:db8::1".parse().unwrap(), &options),
+            vec!["onlink"]
+        );
+    }
+}
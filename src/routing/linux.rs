@@ -1,10 +1,23 @@
-use super::RouteAdder;
+use super::{parse_cidr, KernelRoute, KernelRouteAdder, RouteOptions, MAIN_TABLE};
+use crate::config::{IpRule, RouteType};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use futures::TryStreamExt;
-use netlink_packet_route::route::{RouteAddress, RouteProtocol, RouteScope};
-use rtnetlink::{new_connection, Handle};
-use std::net::IpAddr;
+use netlink_packet_route::link::{InfoData, InfoVrf, LinkAttribute, LinkInfo};
+use netlink_packet_route::route::{
+    RouteAddress, RouteAttribute, RouteFlag, RouteHeader, RouteMessage, RouteProtocol, RouteScope,
+    RouteType as KernelRouteType,
+};
+use netlink_packet_route::rule::{RuleAction, RuleAttribute, RuleMessage};
+use netlink_packet_route::AddressFamily;
+use rtnetlink::{new_connection, Handle, IpVersion};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Route protocol stamped on every route leshy installs (0x6c = 'l'), so
+/// `ip route show proto 108` lists exactly our routes and deletes can never
+/// match routes owned by the kernel, DHCP clients or the administrator.
+pub const LESHY_ROUTE_PROTOCOL: RouteProtocol = RouteProtocol::Other(0x6c);
 
 pub struct LinuxRouteAdder {
     handle: Handle,
@@ -16,14 +29,144 @@ impl LinuxRouteAdder {
         tokio::spawn(connection);
         Ok(Self { handle })
     }
+
+    /// Add a route with no next hop whose type decides what happens to the
+    /// traffic (blackhole, unreachable).
+    async fn add_typed_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        kind: KernelRouteType,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        tracing::info!(ip = %ip, prefix_len = prefix_len, kind = ?kind, "Adding drop route");
+        let options = &self.resolve_vrf(options).await?;
+
+        let route = match ip {
+            IpAddr::V4(addr) => {
+                let mut route = self.handle.route().add().v4();
+                route.message_mut().header.destination_prefix_length = prefix_len;
+                route
+                    .message_mut()
+                    .attributes
+                    .push(RouteAttribute::Destination(RouteAddress::Inet(addr)));
+                route.message_mut().header.kind = kind;
+                route.message_mut().header.protocol = LESHY_ROUTE_PROTOCOL;
+                set_table(route.message_mut(), options);
+                route.execute().await
+            }
+            IpAddr::V6(addr) => {
+                let mut route = self.handle.route().add().v6();
+                route.message_mut().header.destination_prefix_length = prefix_len;
+                route
+                    .message_mut()
+                    .attributes
+                    .push(RouteAttribute::Destination(RouteAddress::Inet6(addr)));
+                route.message_mut().header.kind = kind;
+                route.message_mut().header.protocol = LESHY_ROUTE_PROTOCOL;
+                set_table(route.message_mut(), options);
+                route.execute().await
+            }
+        };
+
+        match route {
+            Ok(_) => {
+                tracing::debug!(ip = %ip, kind = ?kind, "Route added successfully");
+                Ok(())
+            }
+            Err(rtnetlink::Error::NetlinkError(err)) if matches!(err.code, Some(code) if code.get() == -17) =>
+            {
+                tracing::debug!(ip = %ip, "Route already exists");
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!(ip = %ip, error = %e, "Failed to add route");
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Options with `vrf` turned into the VRF device's routing table, which
+    /// is where routes bound to the VRF live.
+    async fn resolve_vrf(&self, options: &RouteOptions) -> Result<RouteOptions> {
+        let mut options = options.clone();
+        if let Some(vrf) = &options.vrf {
+            let mut links = self.handle.link().get().match_name(vrf.clone()).execute();
+            let link = links
+                .try_next()
+                .await?
+                .context(format!("VRF device '{vrf}' not found"))?;
+            let table = vrf_table(&link.attributes)
+                .with_context(|| format!("Device '{vrf}' is not a VRF"))?;
+            options.table = Some(table);
+        }
+        Ok(options)
+    }
+
+    /// Routing table -> VRF device name, for every VRF on the system.
+    async fn vrf_tables(&self) -> Result<HashMap<u32, String>> {
+        let mut tables = HashMap::new();
+        let mut links = self.handle.link().get().execute();
+        while let Some(link) = links.try_next().await? {
+            let Some(table) = vrf_table(&link.attributes) else {
+                continue;
+            };
+            let name = link.attributes.iter().find_map(|attr| match attr {
+                LinkAttribute::IfName(name) => Some(name.clone()),
+                _ => None,
+            });
+            if let Some(name) = name {
+                tables.insert(table, name);
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Resolve an interface name to its index.
+    async fn link_index(&self, device: &str) -> Result<u32> {
+        let mut links = self
+            .handle
+            .link()
+            .get()
+            .match_name(device.to_string())
+            .execute();
+        let link = links
+            .try_next()
+            .await?
+            .context(format!("Device '{device}' not found"))?;
+        Ok(link.header.index)
+    }
+
+    /// Resolve an interface index to its name.
+    async fn link_name(&self, index: u32) -> Result<Option<String>> {
+        let mut links = self.handle.link().get().match_index(index).execute();
+        let Some(link) = links.try_next().await? else {
+            return Ok(None);
+        };
+        Ok(link.attributes.into_iter().find_map(|attr| match attr {
+            LinkAttribute::IfName(name) => Some(name),
+            _ => None,
+        }))
+    }
 }
 
 #[async_trait]
-impl RouteAdder for LinuxRouteAdder {
-    async fn add_via_route(&self, ip: IpAddr, prefix_len: u8, gateway: &str) -> Result<()> {
+impl KernelRouteAdder for LinuxRouteAdder {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
         let gateway_ip: IpAddr = gateway.parse().context("Failed to parse gateway IP")?;
+        let oif = match &options.device {
+            Some(device) => Some(self.link_index(device).await?),
+            None => None,
+        };
 
         tracing::info!(ip = %ip, prefix_len = prefix_len, gateway = %gateway, "Adding route via gateway");
+        let options = &self.resolve_vrf(options).await?;
 
         let route = match ip {
             IpAddr::V4(addr) => {
@@ -42,8 +185,17 @@ impl RouteAdder for LinuxRouteAdder {
                         )),
                     );
                 }
+                if let Some(index) = oif {
+                    route
+                        .message_mut()
+                        .attributes
+                        .push(RouteAttribute::Oif(index));
+                }
 
                 route.message_mut().header.scope = RouteScope::Universe;
+                route.message_mut().header.protocol = LESHY_ROUTE_PROTOCOL;
+                set_table(route.message_mut(), options);
+                set_next_hop_options(route.message_mut(), options);
                 route.execute().await
             }
             IpAddr::V6(addr) => {
@@ -62,8 +214,17 @@ impl RouteAdder for LinuxRouteAdder {
                         )),
                     );
                 }
+                if let Some(index) = oif {
+                    route
+                        .message_mut()
+                        .attributes
+                        .push(RouteAttribute::Oif(index));
+                }
 
                 route.message_mut().header.scope = RouteScope::Universe;
+                route.message_mut().header.protocol = LESHY_ROUTE_PROTOCOL;
+                set_table(route.message_mut(), options);
+                set_next_hop_options(route.message_mut(), options);
                 route.execute().await
             }
         };
@@ -85,19 +246,17 @@ impl RouteAdder for LinuxRouteAdder {
         }
     }
 
-    async fn add_dev_route(&self, ip: IpAddr, prefix_len: u8, device: &str) -> Result<()> {
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
         tracing::info!(ip = %ip, prefix_len = prefix_len, device = device, "Adding route via device");
+        let options = &self.resolve_vrf(options).await?;
 
-        let mut links = self
-            .handle
-            .link()
-            .get()
-            .match_name(device.to_string())
-            .execute();
-        let link = links
-            .try_next()
-            .await?
-            .context(format!("Device '{device}' not found"))?;
+        let index = self.link_index(device).await?;
 
         let route = match ip {
             IpAddr::V4(addr) => {
@@ -108,10 +267,14 @@ impl RouteAdder for LinuxRouteAdder {
                         addr,
                     )),
                 );
-                route.message_mut().attributes.push(
-                    netlink_packet_route::route::RouteAttribute::Oif(link.header.index),
-                );
+                route
+                    .message_mut()
+                    .attributes
+                    .push(netlink_packet_route::route::RouteAttribute::Oif(index));
                 route.message_mut().header.scope = RouteScope::Link;
+                route.message_mut().header.protocol = LESHY_ROUTE_PROTOCOL;
+                set_table(route.message_mut(), options);
+                set_next_hop_options(route.message_mut(), options);
                 route.execute().await
             }
             IpAddr::V6(addr) => {
@@ -122,10 +285,14 @@ impl RouteAdder for LinuxRouteAdder {
                         addr,
                     )),
                 );
-                route.message_mut().attributes.push(
-                    netlink_packet_route::route::RouteAttribute::Oif(link.header.index),
-                );
+                route
+                    .message_mut()
+                    .attributes
+                    .push(netlink_packet_route::route::RouteAttribute::Oif(index));
                 route.message_mut().header.scope = RouteScope::Link;
+                route.message_mut().header.protocol = LESHY_ROUTE_PROTOCOL;
+                set_table(route.message_mut(), options);
+                set_next_hop_options(route.message_mut(), options);
                 route.execute().await
             }
         };
@@ -147,14 +314,34 @@ impl RouteAdder for LinuxRouteAdder {
         }
     }
 
-    async fn remove_route(&self, ip: IpAddr, prefix_len: u8) -> Result<()> {
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        self.add_typed_route(ip, prefix_len, KernelRouteType::BlackHole, options)
+            .await
+    }
+
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        self.add_typed_route(ip, prefix_len, KernelRouteType::Unreachable, options)
+            .await
+    }
+
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()> {
         tracing::info!(ip = %ip, prefix_len = prefix_len, "Removing route");
+        let options = &self.resolve_vrf(options).await?;
 
         let result = match ip {
             IpAddr::V4(addr) => {
-                let mut msg = netlink_packet_route::route::RouteMessage::default();
-                msg.header.destination_prefix_length = prefix_len;
-                msg.header.protocol = RouteProtocol::Boot;
+                let mut msg = delete_message(prefix_len, options);
+                msg.header.address_family = AddressFamily::Inet;
                 msg.attributes
                     .push(netlink_packet_route::route::RouteAttribute::Destination(
                         RouteAddress::Inet(addr),
@@ -162,10 +349,8 @@ impl RouteAdder for LinuxRouteAdder {
                 self.handle.route().del(msg).execute().await
             }
             IpAddr::V6(addr) => {
-                let mut msg = netlink_packet_route::route::RouteMessage::default();
-                msg.header.destination_prefix_length = prefix_len;
-                msg.header.protocol = RouteProtocol::Boot;
-                msg.header.address_family = netlink_packet_route::AddressFamily::Inet6;
+                let mut msg = delete_message(prefix_len, options);
+                msg.header.address_family = AddressFamily::Inet6;
                 msg.attributes
                     .push(netlink_packet_route::route::RouteAttribute::Destination(
                         RouteAddress::Inet6(addr),
@@ -191,4 +376,209 @@ impl RouteAdder for LinuxRouteAdder {
             }
         }
     }
+
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        let mut routes = Vec::new();
+        let mut names: HashMap<u32, Option<String>> = HashMap::new();
+        let vrfs = self.vrf_tables().await?;
+
+        for version in [IpVersion::V4, IpVersion::V6] {
+            let mut dump = self.handle.route().get(version).execute();
+            while let Some(msg) = dump.try_next().await? {
+                if msg.header.protocol != LESHY_ROUTE_PROTOCOL {
+                    continue;
+                }
+
+                let (network, table, gateway, oif) = parse_route(&msg);
+                let route_type = match msg.header.kind {
+                    KernelRouteType::BlackHole => RouteType::Blackhole,
+                    KernelRouteType::Unreachable => RouteType::Reject,
+                    _ if gateway.is_some() => RouteType::Via,
+                    _ => RouteType::Dev,
+                };
+                let device = match oif {
+                    Some(index) => match names.get(&index) {
+                        Some(name) => name.clone(),
+                        None => {
+                            let name = self.link_name(index).await?;
+                            names.insert(index, name.clone());
+                            name
+                        }
+                    },
+                    None => None,
+                };
+                routes.push(KernelRoute {
+                    network,
+                    prefix_len: msg.header.destination_prefix_length,
+                    table,
+                    route_type,
+                    gateway,
+                    device,
+                    vrf: vrfs.get(&table).cloned(),
+                });
+            }
+        }
+
+        tracing::debug!(count = routes.len(), "Listed leshy routes in kernel table");
+        Ok(routes)
+    }
+
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        tracing::info!(rule = ?rule, table = table, "Adding ip rule");
+
+        for msg in rule_messages(rule, table)? {
+            let mut request = self.handle.rule().add();
+            *request.message_mut() = msg;
+            match request.execute().await {
+                Ok(()) => {}
+                Err(rtnetlink::Error::NetlinkError(err)) if matches!(err.code, Some(code) if code.get() == -17) =>
+                {
+                    tracing::debug!(rule = ?rule, "Ip rule already exists");
+                }
+                Err(e) => {
+                    tracing::error!(rule = ?rule, error = %e, "Failed to add ip rule");
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        tracing::info!(rule = ?rule, table = table, "Removing ip rule");
+
+        for msg in rule_messages(rule, table)? {
+            match self.handle.rule().del(msg).execute().await {
+                Ok(()) => {}
+                Err(rtnetlink::Error::NetlinkError(err)) if matches!(err.code, Some(code) if code.get() == -2) =>
+                {
+                    // ENOENT = no such rule, not an error
+                    tracing::debug!(rule = ?rule, "Ip rule does not exist, nothing to remove");
+                }
+                Err(e) => {
+                    tracing::error!(rule = ?rule, error = %e, "Failed to remove ip rule");
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Route fields read from a dump message (network, table, gateway, output
+/// interface index).
+fn parse_route(msg: &RouteMessage) -> (IpAddr, u32, Option<IpAddr>, Option<u32>) {
+    let mut network = match msg.header.address_family {
+        AddressFamily::Inet6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let mut table = u32::from(msg.header.table);
+    let mut gateway = None;
+    let mut oif = None;
+    for attr in &msg.attributes {
+        match attr {
+            RouteAttribute::Destination(RouteAddress::Inet(addr)) => network = IpAddr::V4(*addr),
+            RouteAttribute::Destination(RouteAddress::Inet6(addr)) => network = IpAddr::V6(*addr),
+            RouteAttribute::Gateway(RouteAddress::Inet(addr)) => gateway = Some(IpAddr::V4(*addr)),
+            RouteAttribute::Gateway(RouteAddress::Inet6(addr)) => gateway = Some(IpAddr::V6(*addr)),
+            RouteAttribute::Oif(index) => oif = Some(*index),
+            RouteAttribute::Table(id) => table = *id,
+            _ => {}
+        }
+    }
+    (network, table, gateway, oif)
+}
+
+/// Table id of a VRF device, from its link attributes.
+fn vrf_table(attributes: &[LinkAttribute]) -> Option<u32> {
+    attributes.iter().find_map(|attr| match attr {
+        LinkAttribute::LinkInfo(infos) => infos.iter().find_map(|info| match info {
+            LinkInfo::Data(InfoData::Vrf(vrf)) => vrf.iter().find_map(|nla| match nla {
+                InfoVrf::TableId(table) => Some(*table),
+                _ => None,
+            }),
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+/// Point a route message at the zone's table (main table when unset).
+/// Ids above 255 only fit in the RTA_TABLE attribute.
+fn set_table(msg: &mut RouteMessage, options: &RouteOptions) {
+    let table = options.table.unwrap_or(MAIN_TABLE);
+    msg.header.table = u8::try_from(table).unwrap_or(RouteHeader::RT_TABLE_UNSPEC);
+    msg.attributes.push(RouteAttribute::Table(table));
+}
+
+/// Apply the zone's onlink flag and preferred source to a via/dev route.
+/// A source of the other address family than the route is left out.
+fn set_next_hop_options(msg: &mut RouteMessage, options: &RouteOptions) {
+    if options.onlink {
+        msg.header.flags.push(RouteFlag::Onlink);
+    }
+    match (msg.header.address_family, options.source) {
+        (AddressFamily::Inet, Some(IpAddr::V4(src))) => msg
+            .attributes
+            .push(RouteAttribute::PrefSource(RouteAddress::Inet(src))),
+        (AddressFamily::Inet6, Some(IpAddr::V6(src))) => msg
+            .attributes
+            .push(RouteAttribute::PrefSource(RouteAddress::Inet6(src))),
+        _ => {}
+    }
+}
+
+/// Build an RTM_NEWRULE/RTM_DELRULE message for one address family of a
+/// zone rule. Rules carry leshy's protocol so deletes only match our own.
+fn rule_message(
+    rule: &IpRule,
+    table: u32,
+    family: AddressFamily,
+    source: Option<(IpAddr, u8)>,
+) -> RuleMessage {
+    let mut msg = RuleMessage::default();
+    msg.header.family = family;
+    msg.header.action = RuleAction::ToTable;
+    msg.header.table = u8::try_from(table).unwrap_or(RouteHeader::RT_TABLE_UNSPEC);
+    msg.attributes.push(RuleAttribute::Table(table));
+    if let Some(mark) = rule.fwmark {
+        msg.attributes.push(RuleAttribute::FwMark(mark));
+    }
+    if let Some((ip, prefix_len)) = source {
+        msg.header.src_len = prefix_len;
+        msg.attributes.push(RuleAttribute::Source(ip));
+    }
+    if let Some(priority) = rule.priority {
+        msg.attributes.push(RuleAttribute::Priority(priority));
+    }
+    msg.attributes
+        .push(RuleAttribute::Protocol(LESHY_ROUTE_PROTOCOL));
+    msg
+}
+
+/// Rule messages for every address family the rule applies to: a source
+/// prefix pins the family, a bare fwmark rule is installed for both.
+fn rule_messages(rule: &IpRule, table: u32) -> Result<Vec<RuleMessage>> {
+    let source = rule.from.as_deref().map(parse_cidr).transpose()?;
+    let families = match source {
+        Some((IpAddr::V4(_), _)) => vec![AddressFamily::Inet],
+        Some((IpAddr::V6(_), _)) => vec![AddressFamily::Inet6],
+        None => vec![AddressFamily::Inet, AddressFamily::Inet6],
+    };
+    Ok(families
+        .into_iter()
+        .map(|family| rule_message(rule, table, family, source))
+        .collect())
+}
+
+/// Build an RTM_DELROUTE message for the zone's table matching any scope but
+/// only leshy's route protocol, so routes with the same prefix installed by
+/// anyone else are never touched.
+fn delete_message(prefix_len: u8, options: &RouteOptions) -> RouteMessage {
+    let mut msg = RouteMessage::default();
+    msg.header.destination_prefix_length = prefix_len;
+    set_table(&mut msg, options);
+    msg.header.protocol = LESHY_ROUTE_PROTOCOL;
+    msg.header.scope = RouteScope::NoWhere;
+    msg
 }
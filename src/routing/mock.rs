@@ -0,0 +1,296 @@
+use super::{KernelRoute, RouteAdder, RouteOptions, MAIN_TABLE};
+use crate::config::{IpRule, RouteType};
+use crate::error::{LeshyError, Result};
+use async_trait::async_trait;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// A kernel change `MockRouteAdder` was asked to make.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteCall {
+    AddVia {
+        prefix: (IpAddr, u8),
+        gateway: String,
+        options: RouteOptions,
+    },
+    AddDev {
+        prefix: (IpAddr, u8),
+        device: String,
+        options: RouteOptions,
+    },
+    AddBlackhole {
+        prefix: (IpAddr, u8),
+        options: RouteOptions,
+    },
+    AddReject {
+        prefix: (IpAddr, u8),
+        options: RouteOptions,
+    },
+    Remove {
+        prefix: (IpAddr, u8),
+        options: RouteOptions,
+    },
+    AddRule {
+        rule: IpRule,
+        table: u32,
+    },
+    RemoveRule {
+        rule: IpRule,
+        table: u32,
+    },
+}
+
+/// `add 10.0.0.0/24 via 10.8.0.1`, `remove 10.0.0.0/24`, ...; route options
+/// are left out.
+impl fmt::Display for RouteCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddVia {
+                prefix: (ip, len),
+                gateway,
+                ..
+            } => write!(f, "add {ip}/{len} via {gateway}"),
+            Self::AddDev {
+                prefix: (ip, len),
+                device,
+                ..
+            } => write!(f, "add {ip}/{len} dev {device}"),
+            Self::AddBlackhole {
+                prefix: (ip, len), ..
+            } => write!(f, "add blackhole {ip}/{len}"),
+            Self::AddReject {
+                prefix: (ip, len), ..
+            } => write!(f, "add unreachable {ip}/{len}"),
+            Self::Remove {
+                prefix: (ip, len), ..
+            } => write!(f, "remove {ip}/{len}"),
+            Self::AddRule { rule, table } => write!(f, "add rule {rule:?} table {table}"),
+            Self::RemoveRule { rule, table } => write!(f, "remove rule {rule:?} table {table}"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<RouteCall>,
+    routes: Vec<KernelRoute>,
+    failing: bool,
+}
+
+/// Route adder that records every change it is asked to make and keeps the
+/// routes it added as its kernel table, for `list_routes`. Clones share
+/// the recording, so a test keeps one while `RouteManager::with_adder`
+/// owns another.
+#[derive(Clone, Default)]
+pub struct MockRouteAdder {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockRouteAdder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every change asked for so far, in order, including failed ones.
+    pub fn calls(&self) -> Vec<RouteCall> {
+        self.state().calls.clone()
+    }
+
+    /// Forget the recorded calls, keeping the routes.
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    /// The routes currently in the mock kernel table.
+    pub fn routes(&self) -> Vec<KernelRoute> {
+        self.state().routes.clone()
+    }
+
+    /// Put a route in the mock kernel table without recording a call, as a
+    /// previous run would have left it.
+    pub fn insert_route(&self, route: KernelRoute) {
+        self.state().routes.push(route);
+    }
+
+    /// Empty the mock kernel table without recording a call, as a
+    /// NetworkManager restart flushing it would.
+    pub fn flush_routes(&self) {
+        self.state().routes.clear();
+    }
+
+    /// Make every following change fail (after being recorded), or succeed
+    /// again.
+    pub fn set_failing(&self, failing: bool) {
+        self.state().failing = failing;
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record `call`, then apply `change` to the table unless failing.
+    fn apply(&self, call: RouteCall, change: impl FnOnce(&mut Vec<KernelRoute>)) -> Result<()> {
+        let mut state = self.state();
+        let failed = state
+            .failing
+            .then(|| LeshyError::Routing(format!("mock failure: {call}")));
+        state.calls.push(call);
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        change(&mut state.routes);
+        Ok(())
+    }
+
+    fn add(
+        &self,
+        call: RouteCall,
+        (network, prefix_len): (IpAddr, u8),
+        route_type: RouteType,
+        gateway: Option<IpAddr>,
+        device: Option<String>,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let route = KernelRoute {
+            network,
+            prefix_len,
+            table: options.table.unwrap_or(MAIN_TABLE),
+            route_type,
+            gateway,
+            device,
+            vrf: options.vrf.clone(),
+        };
+        self.apply(call, |routes| {
+            routes.retain(|r| !same_prefix(r, &route));
+            routes.push(route);
+        })
+    }
+}
+
+fn same_prefix(a: &KernelRoute, b: &KernelRoute) -> bool {
+    (a.network, a.prefix_len, a.table, &a.vrf) == (b.network, b.prefix_len, b.table, &b.vrf)
+}
+
+#[async_trait]
+impl RouteAdder for MockRouteAdder {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let call = RouteCall::AddVia {
+            prefix: (ip, prefix_len),
+            gateway: gateway.to_string(),
+            options: options.clone(),
+        };
+        let gateway = gateway.parse().ok();
+        let device = options.device.clone();
+        self.add(
+            call,
+            (ip, prefix_len),
+            RouteType::Via,
+            gateway,
+            device,
+            options,
+        )
+    }
+
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let call = RouteCall::AddDev {
+            prefix: (ip, prefix_len),
+            device: device.to_string(),
+            options: options.clone(),
+        };
+        let device = Some(device.to_string());
+        self.add(
+            call,
+            (ip, prefix_len),
+            RouteType::Dev,
+            None,
+            device,
+            options,
+        )
+    }
+
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let call = RouteCall::AddBlackhole {
+            prefix: (ip, prefix_len),
+            options: options.clone(),
+        };
+        self.add(
+            call,
+            (ip, prefix_len),
+            RouteType::Blackhole,
+            None,
+            None,
+            options,
+        )
+    }
+
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let call = RouteCall::AddReject {
+            prefix: (ip, prefix_len),
+            options: options.clone(),
+        };
+        self.add(
+            call,
+            (ip, prefix_len),
+            RouteType::Reject,
+            None,
+            None,
+            options,
+        )
+    }
+
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()> {
+        let call = RouteCall::Remove {
+            prefix: (ip, prefix_len),
+            options: options.clone(),
+        };
+        let table = options.table.unwrap_or(MAIN_TABLE);
+        self.apply(call, |routes| {
+            routes.retain(|r| {
+                (r.network, r.prefix_len, r.table, &r.vrf) != (ip, prefix_len, table, &options.vrf)
+            })
+        })
+    }
+
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        Ok(self.routes())
+    }
+
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        let call = RouteCall::AddRule {
+            rule: rule.clone(),
+            table,
+        };
+        self.apply(call, |_| {})
+    }
+
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        let call = RouteCall::RemoveRule {
+            rule: rule.clone(),
+            table,
+        };
+        self.apply(call, |_| {})
+    }
+}
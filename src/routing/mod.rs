@@ -1,69 +1,519 @@
 mod aggregator;
-#[cfg(target_os = "linux")]
+#[cfg(all(
+    any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"),
+    feature = "routing"
+))]
+mod bsd;
+mod disabled;
+mod dry_run;
+pub mod endpoints;
+mod exec;
+mod health;
+pub mod interfaces;
+#[cfg(all(target_os = "linux", feature = "routing"))]
+mod iproute;
+#[cfg(all(target_os = "linux", feature = "netlink"))]
 mod linux;
-#[cfg(target_os = "macos")]
-mod macos;
+// For tests and simulations through the library; the binary never uses it
+#[allow(dead_code)]
+pub mod mock;
+mod pacer;
+pub mod remote;
+mod state;
+mod watch;
 
-use crate::config::{RouteType, ZoneConfig};
+use crate::config::{
+    CleanupMode, IpRule, RouteBackend, RouteType, RoutingConfig, ZoneConfig, ZoneMode,
+};
+use crate::error::{LeshyError, Result};
+use crate::events::{Event, Events};
 use aggregator::{RouteAction, RouteAggregator};
-use anyhow::{Context, Result};
 use async_trait::async_trait;
+use exec::HookAction;
+use pacer::InstallPacer;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
 
-#[cfg(target_os = "linux")]
-use linux::LinuxRouteAdder as PlatformRouteAdder;
-#[cfg(target_os = "macos")]
-use macos::MacosRouteAdder as PlatformRouteAdder;
+pub use health::{ping, GatewayMonitor};
+pub use state::RouteState;
+pub use watch::{DeviceWatcher, UplinkWatcher};
+
+/// `route_target` of via zones routed through whatever the default gateway
+/// is when their routes are installed.
+pub const AUTO_GATEWAY: &str = "auto";
+
+/// Prefix of a via zone's `route_target` naming an interface, routed
+/// through the next hop currently used on it (e.g. "iface:eth1").
+pub const IFACE_GATEWAY_PREFIX: &str = "iface:";
+
+/// Whether a via zone's `route_target` is looked up when routes are
+/// installed rather than being the gateway itself.
+pub fn is_dynamic_gateway(target: &str) -> bool {
+    target == AUTO_GATEWAY || target.starts_with(IFACE_GATEWAY_PREFIX)
+}
+
+#[async_trait]
+/// Where `RouteManager` makes its kernel changes: the platform backend,
+/// the dry-run logger or `mock::MockRouteAdder`. Failures are typed, e.g.
+/// `LeshyError::PermissionDenied` without CAP_NET_ADMIN.
+pub trait RouteAdder: Send + Sync {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()>;
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()>;
+    /// Install a route that silently drops matching traffic.
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()>;
+    /// Install a route that rejects matching traffic as unreachable.
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()>;
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()>;
+    /// List routes in the kernel tables that were installed by leshy.
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>>;
+    /// Install a policy rule directing matching traffic to `table`.
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> Result<()>;
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> Result<()>;
+}
 
 #[async_trait]
-pub(crate) trait RouteAdder: Send + Sync {
-    async fn add_via_route(&self, ip: IpAddr, prefix_len: u8, gateway: &str) -> Result<()>;
-    async fn add_dev_route(&self, ip: IpAddr, prefix_len: u8, device: &str) -> Result<()>;
-    async fn remove_route(&self, ip: IpAddr, prefix_len: u8) -> Result<()>;
+/// A platform backend changing the kernel tables, failing with the
+/// errors of the netlink socket or command it goes through. It is a
+/// `RouteAdder` whose failures `LeshyError::routing` classifies.
+#[cfg_attr(not(feature = "routing"), allow(dead_code))]
+trait KernelRouteAdder: Send + Sync {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> anyhow::Result<()>;
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> anyhow::Result<()>;
+    /// Install a route that silently drops matching traffic.
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> anyhow::Result<()>;
+    /// Install a route that rejects matching traffic as unreachable.
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> anyhow::Result<()>;
+    async fn remove_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> anyhow::Result<()>;
+    /// List routes in the kernel tables that were installed by leshy.
+    async fn list_routes(&self) -> anyhow::Result<Vec<KernelRoute>>;
+    /// Install a policy rule directing matching traffic to `table`.
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> anyhow::Result<()>;
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl<T: KernelRouteAdder> RouteAdder for T {
+    async fn add_via_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        gateway: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        KernelRouteAdder::add_via_route(self, ip, prefix_len, gateway, options)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn add_dev_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        device: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        KernelRouteAdder::add_dev_route(self, ip, prefix_len, device, options)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn add_blackhole_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        KernelRouteAdder::add_blackhole_route(self, ip, prefix_len, options)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn add_reject_route(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        KernelRouteAdder::add_reject_route(self, ip, prefix_len, options)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn remove_route(&self, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> Result<()> {
+        KernelRouteAdder::remove_route(self, ip, prefix_len, options)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn list_routes(&self) -> Result<Vec<KernelRoute>> {
+        KernelRouteAdder::list_routes(self)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn add_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        KernelRouteAdder::add_rule(self, rule, table)
+            .await
+            .map_err(LeshyError::routing)
+    }
+    async fn remove_rule(&self, rule: &IpRule, table: u32) -> Result<()> {
+        KernelRouteAdder::remove_rule(self, rule, table)
+            .await
+            .map_err(LeshyError::routing)
+    }
+}
+
+/// Id of the main routing table, used when a zone sets no `route_table`.
+pub(crate) const MAIN_TABLE: u32 = 254;
+
+/// Per-zone attributes applied to every route of the zone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteOptions {
+    /// Routing table; None = main table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<u32>,
+    /// VRF device whose table the routes go into (overrides `table`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vrf: Option<String>,
+    /// Script that applies the zone's routes instead of the kernel (exec zones)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+    /// Output interface for via routes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Treat the gateway as directly reachable on the interface
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub onlink: bool,
+    /// Preferred source address for traffic using the route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<IpAddr>,
+}
+
+impl RouteOptions {
+    pub fn for_zone(zone: &ZoneConfig) -> Self {
+        Self {
+            table: zone.route_table,
+            vrf: zone.vrf.clone(),
+            hook: (zone.route_type == RouteType::Exec).then(|| zone.route_target.clone()),
+            device: zone.route_device.clone(),
+            onlink: zone.onlink,
+            source: zone.preferred_source,
+        }
+    }
+}
+
+/// A leshy-owned route found in the kernel routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelRoute {
+    pub network: IpAddr,
+    pub prefix_len: u8,
+    pub table: u32,
+    pub route_type: RouteType,
+    pub gateway: Option<IpAddr>,
+    pub device: Option<String>,
+    /// VRF device owning the route's table, if any
+    pub vrf: Option<String>,
 }
 
+/// Installed kernel prefixes (network, prefix length) per zone
+type ZonePrefixes = HashMap<String, HashSet<(IpAddr, u8)>>;
+
+/// Installed policy rules (rule, table) per zone
+type ZoneRules = HashMap<String, Vec<(IpRule, u32)>>;
+
+/// Sequence number of the last resolution of each IP, per zone
+type ZoneLastSeen = HashMap<String, HashMap<IpAddr, u64>>;
+
 pub struct RouteManager {
-    adder: PlatformRouteAdder,
+    adder: Box<dyn RouteAdder>,
     zone_routes: Arc<RwLock<HashMap<String, HashSet<IpAddr>>>>,
+    /// Kernel prefixes installed outside the aggregator (static routes, IPv6)
+    direct_routes: Arc<RwLock<ZonePrefixes>>,
     aggregator: Mutex<RouteAggregator>,
+    /// Route options each zone's routes were installed with, so removals
+    /// (including splits of another zone's aggregate) target the right table
+    zone_options: RwLock<HashMap<String, RouteOptions>>,
+    zone_rules: RwLock<ZoneRules>,
+    /// Zones whose gateway is down: routes are tracked but not installed
+    withdrawn: RwLock<HashSet<String>>,
+    /// When each resolved IP was last seen, for `max_routes` eviction
+    last_seen: RwLock<ZoneLastSeen>,
+    /// Source of `last_seen` sequence numbers
+    resolve_seq: AtomicU64,
+    dry_run: bool,
+    /// Check each installed route is in the kernel (`verify_routes`)
+    verify: bool,
+    /// Spaces installs out (`max_installs_per_second`)
+    pacer: Option<InstallPacer>,
+    /// Bumped on every tracking change, so persistence can skip unchanged state
+    generation: AtomicU64,
+    /// Where route installs and removals are reported for `[[hooks]]`
+    events: Option<Arc<Events>>,
 }
 
 impl RouteManager {
-    pub fn new(aggregation_prefix: Option<u8>) -> Result<Self> {
-        let adder = PlatformRouteAdder::new()?;
+    /// With `routing.dry_run`, route changes are only logged and the kernel
+    /// is never touched; tracking still behaves as if they had succeeded.
+    pub fn new(aggregation_prefix: Option<u8>, routing: &RoutingConfig) -> Result<Self> {
+        let dry_run = routing.dry_run;
+        let adder: Box<dyn RouteAdder> = if routing.is_disabled() {
+            if routing.backend != RouteBackend::Disabled {
+                tracing::warn!("Built without route management, only forwarding DNS");
+            }
+            Box::new(disabled::DisabledRouteAdder)
+        } else if dry_run {
+            tracing::warn!("Routing dry-run enabled, kernel routes will not be changed");
+            Box::new(dry_run::DryRunRouteAdder)
+        } else {
+            platform_adder(routing).map_err(LeshyError::routing)?
+        };
+
+        let mut manager = Self::with_adder(adder, aggregation_prefix);
+        manager.dry_run = dry_run;
+        manager.verify = routing.verify_routes && !dry_run;
+        manager.pacer = InstallPacer::from_config(routing);
+        Ok(manager)
+    }
 
-        Ok(Self {
+    /// Make every kernel change through `adder`, e.g. a `MockRouteAdder`
+    /// in tests. Exec zones still run their hooks.
+    pub fn with_adder(adder: Box<dyn RouteAdder>, aggregation_prefix: Option<u8>) -> Self {
+        Self {
             adder,
             zone_routes: Arc::new(RwLock::new(HashMap::new())),
+            direct_routes: Arc::new(RwLock::new(HashMap::new())),
             aggregator: Mutex::new(RouteAggregator::new(aggregation_prefix)),
-        })
+            zone_options: RwLock::new(HashMap::new()),
+            zone_rules: RwLock::new(HashMap::new()),
+            withdrawn: RwLock::new(HashSet::new()),
+            last_seen: RwLock::new(HashMap::new()),
+            resolve_seq: AtomicU64::new(0),
+            dry_run: false,
+            verify: false,
+            pacer: None,
+            generation: AtomicU64::new(0),
+            events: None,
+        }
+    }
+
+    /// Report every route installed or removed to `events`.
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+    }
+
+    /// Report a route newly put in the kernel; one tracked for a withdrawn
+    /// zone isn't.
+    async fn report_installed(&self, zone_name: &str, ip: IpAddr, prefix_len: u8) {
+        if self.events.is_some() && !self.withdrawn.read().await.contains(zone_name) {
+            self.emit(Event::RouteInstalled {
+                zone: zone_name.to_string(),
+                prefix: format!("{ip}/{prefix_len}"),
+            });
+        }
     }
 
     /// Add a route for the given IP based on zone configuration.
     /// For IPv4 with aggregation enabled, installs a wider CIDR prefix.
     /// For IPv6, always uses /128 (no aggregation).
-    pub async fn add_route(&self, ip: IpAddr, zone: &ZoneConfig) -> Result<()> {
-        match ip {
-            IpAddr::V4(v4) => self.add_route_v4(v4, zone).await,
-            IpAddr::V6(_) => self.add_route_simple(ip, 128, zone).await,
+    /// With `max_routes` set, evicts the zone's least recently resolved
+    /// routes once the cap is exceeded.
+    /// Returns whether a new route was installed for the IP.
+    pub async fn add_route(&self, ip: IpAddr, zone: &ZoneConfig) -> Result<bool> {
+        let seq = self.resolve_seq.fetch_add(1, Ordering::Relaxed);
+        self.last_seen
+            .write()
+            .await
+            .entry(zone.name.clone())
+            .or_default()
+            .insert(ip, seq);
+
+        let installed = match ip {
+            IpAddr::V4(v4) => self.add_route_v4(v4, zone).await?,
+            IpAddr::V6(_) => self.add_route_simple(ip, 128, zone).await?,
+        };
+
+        if let Some(max_routes) = zone.max_routes {
+            self.enforce_max_routes(&zone.name, max_routes).await?;
+        }
+        Ok(installed)
+    }
+
+    /// Evict the zone's least recently resolved routes until at most
+    /// `max_routes` remain. A prefix is as recent as the latest resolution
+    /// of any IP inside it; prefixes adopted from the kernel or restored
+    /// from the state file and not resolved since go first.
+    async fn enforce_max_routes(&self, zone_name: &str, max_routes: usize) -> Result<()> {
+        loop {
+            let mut prefixes: Vec<(IpAddr, u8)> = self
+                .aggregator
+                .lock()
+                .await
+                .zone_prefixes(zone_name)
+                .into_iter()
+                .map(|(network, prefix_len)| (IpAddr::V4(network), prefix_len))
+                .collect();
+            let last_seen = self
+                .last_seen
+                .read()
+                .await
+                .get(zone_name)
+                .cloned()
+                .unwrap_or_default();
+            // Direct routes only count when resolved (IPv6), not static routes
+            if let Some(direct) = self.direct_routes.read().await.get(zone_name) {
+                prefixes.extend(
+                    direct
+                        .iter()
+                        .filter(|(ip, prefix_len)| *prefix_len == 128 && last_seen.contains_key(ip))
+                        .copied(),
+                );
+            }
+            if prefixes.len() <= max_routes {
+                return Ok(());
+            }
+
+            let recency = |&(network, prefix_len): &(IpAddr, u8)| {
+                last_seen
+                    .iter()
+                    .filter(|(ip, _)| prefix_contains(network, prefix_len, **ip))
+                    .map(|(_, seq)| *seq)
+                    .max()
+            };
+            let Some(victim) = prefixes
+                .into_iter()
+                .min_by_key(|prefix| (recency(prefix), *prefix))
+            else {
+                return Ok(());
+            };
+            self.evict_route(zone_name, victim).await?;
+        }
+    }
+
+    /// Remove one resolved prefix of a zone from the kernel and tracking.
+    async fn evict_route(&self, zone_name: &str, (ip, prefix_len): (IpAddr, u8)) -> Result<()> {
+        let tracked = match ip {
+            IpAddr::V4(v4) => self
+                .aggregator
+                .lock()
+                .await
+                .evict(v4, prefix_len, zone_name)
+                .is_some(),
+            IpAddr::V6(_) => self
+                .direct_routes
+                .write()
+                .await
+                .get_mut(zone_name)
+                .is_some_and(|direct| direct.remove(&(ip, prefix_len))),
+        };
+        if !tracked {
+            return Ok(());
         }
+
+        if let Some(routes) = self.zone_routes.write().await.get_mut(zone_name) {
+            routes.retain(|routed| !prefix_contains(ip, prefix_len, *routed));
+        }
+        if let Some(seen) = self.last_seen.write().await.get_mut(zone_name) {
+            seen.retain(|seen_ip, _| !prefix_contains(ip, prefix_len, *seen_ip));
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+
+        tracing::info!(
+            zone = zone_name,
+            ip = %ip,
+            prefix_len = prefix_len,
+            "Evicting least recently resolved route (max_routes reached)"
+        );
+        // A withdrawn zone's routes aren't in the kernel
+        if self.withdrawn.read().await.contains(zone_name) {
+            return Ok(());
+        }
+        let options = self.options_for(zone_name).await;
+        self.uninstall_route(zone_name, ip, prefix_len, &options)
+            .await
     }
 
-    async fn add_route_v4(&self, ip: Ipv4Addr, zone: &ZoneConfig) -> Result<()> {
+    async fn add_route_v4(&self, ip: Ipv4Addr, zone: &ZoneConfig) -> Result<bool> {
+        self.remember_options(zone).await;
         let actions = {
             let mut agg = self.aggregator.lock().await;
             agg.process_ip(ip, &zone.name, zone.route_type, &zone.route_target)
         };
 
         if actions.is_empty() {
-            return Ok(());
+            return Ok(false);
         }
 
         for action in &actions {
-            self.execute_action(action).await?;
+            if let Err(e) = self.execute_action(action).await {
+                // Forget the prefix so the next resolution tries it again
+                if let RouteAction::Add {
+                    zone,
+                    network,
+                    prefix_len,
+                    ..
+                } = action
+                {
+                    self.aggregator
+                        .lock()
+                        .await
+                        .evict(*network, *prefix_len, zone);
+                }
+                return Err(e);
+            }
         }
 
         let mut routes = self.zone_routes.write().await;
@@ -71,65 +521,207 @@ impl RouteManager {
             .entry(zone.name.clone())
             .or_default()
             .insert(IpAddr::V4(ip));
+        self.generation.fetch_add(1, Ordering::Relaxed);
 
-        Ok(())
+        Ok(actions
+            .iter()
+            .any(|action| matches!(action, RouteAction::Add { .. })))
     }
 
     /// Execute a single RouteAction against the kernel.
     async fn execute_action(&self, action: &RouteAction) -> Result<()> {
         match action {
             RouteAction::Add {
+                zone,
                 network,
                 prefix_len,
                 route_type,
                 route_target,
             } => {
+                let options = self.options_for(zone).await;
                 let ip = IpAddr::V4(*network);
-                match route_type {
-                    RouteType::Via => {
-                        self.adder
-                            .add_via_route(ip, *prefix_len, route_target)
-                            .await
-                    }
-                    RouteType::Dev => {
-                        let device = self.read_device_file(route_target).await?;
-                        self.adder.add_dev_route(ip, *prefix_len, &device).await
-                    }
-                }
+                self.install_route(zone, ip, *prefix_len, *route_type, route_target, &options)
+                    .await?;
+                self.report_installed(zone, ip, *prefix_len).await;
+                Ok(())
             }
             RouteAction::Remove {
+                zone,
                 network,
                 prefix_len,
             } => {
-                self.adder
-                    .remove_route(IpAddr::V4(*network), *prefix_len)
+                let options = self.options_for(zone).await;
+                self.uninstall_route(zone, IpAddr::V4(*network), *prefix_len, &options)
                     .await
             }
         }
     }
 
-    /// Simple route add without aggregation (used for IPv6).
-    async fn add_route_simple(&self, ip: IpAddr, prefix_len: u8, zone: &ZoneConfig) -> Result<()> {
-        let result = match zone.route_type {
+    /// Install one route of the given type for a zone.
+    async fn install_route(
+        &self,
+        zone_name: &str,
+        ip: IpAddr,
+        prefix_len: u8,
+        route_type: RouteType,
+        route_target: &str,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        if self.withdrawn.read().await.contains(zone_name) {
+            tracing::debug!(
+                zone = zone_name,
+                ip = %ip,
+                prefix_len = prefix_len,
+                "Zone is withdrawn, tracking route without installing it"
+            );
+            return Ok(());
+        }
+        if let Some(pacer) = &self.pacer {
+            pacer.wait().await;
+        }
+
+        let result = match route_type {
             RouteType::Via => {
+                let gateway = resolve_gateway(route_target).await?;
+                self.adder
+                    .add_via_route(ip, prefix_len, &gateway, options)
+                    .await
+            }
+            RouteType::Dev | RouteType::Tailscale => {
+                let device = self.resolve_device(route_target).await?;
+                self.adder
+                    .add_dev_route(ip, prefix_len, &device, options)
+                    .await
+            }
+            RouteType::Blackhole => {
                 self.adder
-                    .add_via_route(ip, prefix_len, &zone.route_target)
+                    .add_blackhole_route(ip, prefix_len, options)
                     .await
             }
-            RouteType::Dev => {
-                let device = self.read_device_file(&zone.route_target).await?;
-                self.adder.add_dev_route(ip, prefix_len, &device).await
+            RouteType::Reject => self.adder.add_reject_route(ip, prefix_len, options).await,
+            RouteType::Exec => {
+                return self
+                    .run_hook(route_target, HookAction::Add, ip, prefix_len, zone_name)
+                    .await
             }
         };
+        if result.is_ok() && self.verify {
+            self.verify_installed(ip, prefix_len, options).await?;
+        }
+        result
+    }
 
-        if result.is_ok() {
-            let mut routes = self.zone_routes.write().await;
-            routes.entry(zone.name.clone()).or_default().insert(ip);
+    /// Fail unless the route just installed is in the kernel table.
+    async fn verify_installed(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let kernel_routes = self.adder.list_routes().await?;
+        if !kernel_routes
+            .iter()
+            .any(|route| route_matches(route, ip, prefix_len, options))
+        {
+            return Err(LeshyError::Routing(format!(
+                "Route {ip}/{prefix_len} is missing from the kernel table after installing it"
+            )));
         }
+        Ok(())
+    }
 
+    /// Remove one route of a zone, through its hook for exec zones.
+    async fn uninstall_route(
+        &self,
+        zone_name: &str,
+        ip: IpAddr,
+        prefix_len: u8,
+        options: &RouteOptions,
+    ) -> Result<()> {
+        let result = match &options.hook {
+            Some(script) => {
+                self.run_hook(script, HookAction::Remove, ip, prefix_len, zone_name)
+                    .await
+            }
+            None => self.adder.remove_route(ip, prefix_len, options).await,
+        };
+        if result.is_ok() {
+            self.emit(Event::RouteRemoved {
+                zone: zone_name.to_string(),
+                prefix: format!("{ip}/{prefix_len}"),
+            });
+        }
         result
     }
 
+    async fn run_hook(
+        &self,
+        script: &str,
+        action: HookAction,
+        ip: IpAddr,
+        prefix_len: u8,
+        zone_name: &str,
+    ) -> Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                script = script,
+                action = ?action,
+                ip = %ip,
+                prefix_len = prefix_len,
+                zone = zone_name,
+                "[dry-run] Would run route hook"
+            );
+            return Ok(());
+        }
+        exec::run_hook(script, action, ip, prefix_len, zone_name)
+            .await
+            .map_err(LeshyError::routing)
+    }
+
+    /// Simple route add without aggregation (used for IPv6).
+    async fn add_route_simple(
+        &self,
+        ip: IpAddr,
+        prefix_len: u8,
+        zone: &ZoneConfig,
+    ) -> Result<bool> {
+        let options = self.remember_options(zone).await;
+        // Already in the kernel: don't spend a pacer slot installing it again
+        if self
+            .direct_routes
+            .read()
+            .await
+            .get(&zone.name)
+            .is_some_and(|direct| direct.contains(&(ip, prefix_len)))
+        {
+            return Ok(false);
+        }
+        let result = self
+            .install_route(
+                &zone.name,
+                ip,
+                prefix_len,
+                zone.route_type,
+                &zone.route_target,
+                &options,
+            )
+            .await;
+
+        result?;
+        let mut routes = self.zone_routes.write().await;
+        routes.entry(zone.name.clone()).or_default().insert(ip);
+        let mut direct = self.direct_routes.write().await;
+        let installed = direct
+            .entry(zone.name.clone())
+            .or_default()
+            .insert((ip, prefix_len));
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        if installed {
+            self.report_installed(&zone.name, ip, prefix_len).await;
+        }
+        Ok(installed)
+    }
+
     /// Add a static route from a CIDR string (e.g. "149.154.160.0/20" or "1.2.3.4").
     /// Static routes bypass aggregation but register their IPs so aggregates don't overlap.
     pub async fn add_static_route(&self, cidr: &str, zone: &ZoneConfig) -> Result<()> {
@@ -144,96 +736,886 @@ impl RouteManager {
             agg.register_static_ip(v4, &zone.name);
         }
 
-        let result = match zone.route_type {
-            RouteType::Via => {
-                self.adder
-                    .add_via_route(ip, prefix_len, &zone.route_target)
-                    .await
-            }
-            RouteType::Dev => {
-                let device = self.read_device_file(&zone.route_target).await?;
-                self.adder.add_dev_route(ip, prefix_len, &device).await
-            }
-        };
+        let options = self.remember_options(zone).await;
+        let result = self
+            .install_route(
+                &zone.name,
+                ip,
+                prefix_len,
+                zone.route_type,
+                &zone.route_target,
+                &options,
+            )
+            .await;
 
         if result.is_ok() {
             let mut routes = self.zone_routes.write().await;
             routes.entry(zone.name.clone()).or_default().insert(ip);
+            let mut direct = self.direct_routes.write().await;
+            let new = direct
+                .entry(zone.name.clone())
+                .or_default()
+                .insert((ip, prefix_len));
+            drop((routes, direct));
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            if new {
+                self.report_installed(&zone.name, ip, prefix_len).await;
+            }
+        }
+
+        result
+    }
+
+    /// Remove a route added by `add_static_route` from the kernel and from
+    /// tracking.
+    pub async fn remove_static_route(&self, cidr: &str, zone: &ZoneConfig) -> Result<()> {
+        let (ip, prefix_len) = parse_cidr(cidr)?;
+
+        tracing::info!(cidr = cidr, zone = zone.name, "Removing static route");
+
+        let options = self.options_for(&zone.name).await;
+        self.uninstall_route(&zone.name, ip, prefix_len, &options)
+            .await?;
+
+        if let IpAddr::V4(v4) = ip {
+            let mut agg = self.aggregator.lock().await;
+            agg.unregister_static_ip(v4, &zone.name);
         }
+        if let Some(direct) = self.direct_routes.write().await.get_mut(&zone.name) {
+            direct.remove(&(ip, prefix_len));
+        }
+        if let Some(routes) = self.zone_routes.write().await.get_mut(&zone.name) {
+            routes.remove(&ip);
+        }
+        // The address has a single owner: another zone routing it too (e.g.
+        // a remote list dropped by a reload overlapping a static route) takes
+        // it back
+        self.restore_ownership().await;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Record the zone's current route options and return them.
+    async fn remember_options(&self, zone: &ZoneConfig) -> RouteOptions {
+        let options = RouteOptions::for_zone(zone);
+        self.zone_options
+            .write()
+            .await
+            .insert(zone.name.clone(), options.clone());
+        options
+    }
 
+    async fn options_for(&self, zone_name: &str) -> RouteOptions {
+        self.zone_options
+            .read()
+            .await
+            .get(zone_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Install the zone's `ip_rules` pointing at its routing table, and
+    /// remove rules installed earlier that the zone no longer lists.
+    pub async fn apply_rules(&self, zone: &ZoneConfig) -> Result<()> {
+        let wanted: Vec<(IpRule, u32)> = match zone.route_table {
+            Some(table) => zone
+                .ip_rules
+                .iter()
+                .map(|rule| (rule.clone(), table))
+                .collect(),
+            None => Vec::new(),
+        };
+        let installed = self
+            .zone_rules
+            .read()
+            .await
+            .get(&zone.name)
+            .cloned()
+            .unwrap_or_default();
+        if wanted == installed {
+            return Ok(());
+        }
+
+        for (rule, table) in installed.iter().filter(|r| !wanted.contains(r)) {
+            if let Err(e) = self.adder.remove_rule(rule, *table).await {
+                tracing::warn!(zone = zone.name, error = %e, "Failed to remove stale ip rule");
+            }
+        }
+
+        let mut applied = Vec::new();
+        let mut result = Ok(());
+        for (rule, table) in &wanted {
+            match self.adder.add_rule(rule, *table).await {
+                Ok(()) => applied.push((rule.clone(), *table)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        let mut rules = self.zone_rules.write().await;
+        if applied.is_empty() {
+            rules.remove(&zone.name);
+        } else {
+            rules.insert(zone.name.clone(), applied);
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
         result
     }
 
+    /// Resolve a dev zone's `route_target` to an interface name: a path is
+    /// read as a device file, anything else is the interface name itself.
+    async fn resolve_device(&self, target: &str) -> Result<String> {
+        if is_device_file(target) {
+            self.read_device_file(target).await
+        } else {
+            Ok(target.to_string())
+        }
+    }
+
     async fn read_device_file(&self, path: &str) -> Result<String> {
         match tokio::fs::read_to_string(path).await {
             Ok(content) => {
                 let device = content.trim().to_string();
                 if device.is_empty() {
-                    anyhow::bail!("Device file '{path}' is empty");
+                    return Err(LeshyError::Routing(format!(
+                        "Device file '{path}' is empty"
+                    )));
                 }
                 Ok(device)
             }
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                anyhow::bail!("Device file '{path}' not found (VPN not connected?)");
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(LeshyError::Routing(
+                format!("Device file '{path}' not found (VPN not connected?)"),
+            )),
+            Err(e) => Err(LeshyError::io(
+                format!("Failed to read device file '{path}'"),
+                e,
+            )),
+        }
+    }
+
+    /// Reinstall every tracked route of a zone, e.g. after its device file
+    /// names a new interface or a reload changed its route target or table.
+    /// Routes are removed with the zone's previous options and installed
+    /// with the current ones. Returns the number of routes reinstalled.
+    pub async fn reapply_zone(&self, zone: &ZoneConfig) -> Result<usize> {
+        let old_options = self.options_for(&zone.name).await;
+        let options = self.remember_options(zone).await;
+        self.aggregator
+            .lock()
+            .await
+            .retarget_zone(&zone.name, zone.route_type, &zone.route_target);
+        let prefixes = self.tracked_prefixes(&zone.name).await;
+
+        let mut failures = 0;
+        for &(ip, prefix_len) in &prefixes {
+            // Remove first: adding over the stale route would report EEXIST
+            let result = match self
+                .uninstall_route(&zone.name, ip, prefix_len, &old_options)
+                .await
+            {
+                Ok(()) => {
+                    let result = self
+                        .install_route(
+                            &zone.name,
+                            ip,
+                            prefix_len,
+                            zone.route_type,
+                            &zone.route_target,
+                            &options,
+                        )
+                        .await;
+                    if result.is_ok() {
+                        self.report_installed(&zone.name, ip, prefix_len).await;
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::warn!(zone = zone.name, ip = %ip, error = %e, "Failed to reinstall route");
+                failures += 1;
+            }
+        }
+
+        tracing::info!(
+            zone = zone.name,
+            reinstalled = prefixes.len() - failures,
+            failed = failures,
+            "Re-applied zone routes"
+        );
+        if failures > 0 {
+            return Err(LeshyError::Routing(format!(
+                "Failed to reinstall {failures} route(s) for zone '{}'",
+                zone.name
+            )));
+        }
+        Ok(prefixes.len())
+    }
+
+    /// Give zones back ownership of the tracked IPv4 addresses the
+    /// aggregator has no owner for, so another zone's aggregate can't
+    /// silently cover them. Resolved IPs and static routes' network
+    /// addresses count, as when they were added. Returns how many were
+    /// restored.
+    async fn restore_ownership(&self) -> usize {
+        let routes = self.zone_routes.read().await;
+        let direct = self.direct_routes.read().await;
+        let mut agg = self.aggregator.lock().await;
+        let tracked = routes
+            .iter()
+            .flat_map(|(zone, ips)| ips.iter().map(move |ip| (zone, *ip)))
+            .chain(
+                direct
+                    .iter()
+                    .flat_map(|(zone, prefixes)| prefixes.iter().map(move |(ip, _)| (zone, *ip))),
+            );
+        let mut restored = 0;
+        for (zone, ip) in tracked {
+            if let IpAddr::V4(v4) = ip {
+                if agg.claim_ip(v4, zone) {
+                    restored += 1;
+                }
+            }
+        }
+        if restored > 0 {
+            tracing::info!(
+                ips = restored,
+                "Restored aggregator ownership of tracked IPs"
+            );
+        }
+        restored
+    }
+
+    /// Route installs waiting for their turn under `max_installs_per_second`.
+    pub fn queued_installs(&self) -> usize {
+        self.pacer.as_ref().map_or(0, InstallPacer::queued)
+    }
+
+    /// Kernel prefixes tracked for a zone, aggregated and direct.
+    pub async fn tracked_prefixes(&self, zone_name: &str) -> Vec<(IpAddr, u8)> {
+        let mut prefixes: Vec<(IpAddr, u8)> = self
+            .aggregator
+            .lock()
+            .await
+            .zone_prefixes(zone_name)
+            .into_iter()
+            .map(|(network, prefix_len)| (IpAddr::V4(network), prefix_len))
+            .collect();
+        if let Some(direct) = self.direct_routes.read().await.get(zone_name) {
+            prefixes.extend(direct.iter().copied());
+        }
+        prefixes
+    }
+
+    /// Take a zone's routes out of the kernel while keeping them tracked,
+    /// e.g. because its gateway stopped answering. Routes added for the zone
+    /// meanwhile are tracked only. Returns the number of removed routes.
+    pub async fn withdraw_zone(&self, zone_name: &str) -> Result<usize> {
+        if !self.withdrawn.write().await.insert(zone_name.to_string()) {
+            return Ok(0);
+        }
+
+        let options = self.options_for(zone_name).await;
+        let prefixes = self.tracked_prefixes(zone_name).await;
+        let mut failures = 0;
+        for &(ip, prefix_len) in &prefixes {
+            if let Err(e) = self
+                .uninstall_route(zone_name, ip, prefix_len, &options)
+                .await
+            {
+                tracing::warn!(zone = zone_name, ip = %ip, error = %e, "Failed to withdraw route");
+                failures += 1;
             }
-            Err(e) => Err(e.into()),
         }
+
+        tracing::warn!(
+            zone = zone_name,
+            withdrawn = prefixes.len() - failures,
+            failed = failures,
+            "Withdrew zone routes"
+        );
+        if failures > 0 {
+            return Err(LeshyError::Routing(format!(
+                "Failed to withdraw {failures} route(s) for zone '{zone_name}'"
+            )));
+        }
+        Ok(prefixes.len())
+    }
+
+    /// Whether the zone's routes are out of the kernel (`withdraw_zone`).
+    pub async fn is_withdrawn(&self, zone_name: &str) -> bool {
+        self.withdrawn.read().await.contains(zone_name)
+    }
+
+    /// Reinstall the routes of a zone taken out by `withdraw_zone`.
+    pub async fn restore_zone(&self, zone: &ZoneConfig) -> Result<usize> {
+        if !self.withdrawn.write().await.remove(&zone.name) {
+            return Ok(0);
+        }
+        self.reapply_zone(zone).await
     }
 
     /// Clean up routes for a specific zone
     ///
-    /// Removes the zone from tracking but does NOT delete routes from the
-    /// kernel routing table. Routes will naturally expire or be replaced.
-    pub async fn cleanup_zone(&self, zone_name: &str) -> Result<()> {
-        let mut routes = self.zone_routes.write().await;
+    /// Always removes the zone from tracking. With `CleanupMode::Keep` the
+    /// routes stay in the kernel routing table; with `CleanupMode::Delete`
+    /// every prefix installed for the zone is removed from the kernel.
+    /// Either way, other zones' aggregates that were split around this
+    /// zone's IPs are merged back.
+    pub async fn cleanup_zone(&self, zone_name: &str, mode: CleanupMode) -> Result<()> {
+        let ips = self.zone_routes.write().await.remove(zone_name);
+        let direct = self
+            .direct_routes
+            .write()
+            .await
+            .remove(zone_name)
+            .unwrap_or_default();
+        let (removals, merges) = {
+            let mut agg = self.aggregator.lock().await;
+            let removals = agg.cleanup_zone(zone_name);
+            (removals, agg.rebalance())
+        };
+        let rules = self
+            .zone_rules
+            .write()
+            .await
+            .remove(zone_name)
+            .unwrap_or_default();
+        self.withdrawn.write().await.remove(zone_name);
+        self.last_seen.write().await.remove(zone_name);
+        let options = self
+            .zone_options
+            .write()
+            .await
+            .remove(zone_name)
+            .unwrap_or_default();
+        self.generation.fetch_add(1, Ordering::Relaxed);
 
-        if let Some(ips) = routes.remove(zone_name) {
-            tracing::info!(
-                zone = zone_name,
-                route_count = ips.len(),
-                "Removed zone from tracking (routes remain in kernel table)"
-            );
-            tracing::debug!(
+        match &ips {
+            Some(ips) => tracing::debug!(
                 zone = zone_name,
                 ips = ?ips,
                 "Routes that were tracked for this zone"
+            ),
+            None => tracing::debug!(zone = zone_name, "Zone has no tracked routes"),
+        }
+
+        // Other zones' prefixes split around this zone's IPs merge back
+        for action in &merges {
+            if let Err(e) = self.execute_action(action).await {
+                tracing::warn!(action = ?action, error = %e, "Failed to consolidate aggregate");
+            }
+        }
+        if !merges.is_empty() {
+            tracing::info!(
+                zone = zone_name,
+                actions = merges.len(),
+                "Consolidated aggregates split around removed zone"
             );
-        } else {
-            tracing::debug!(zone = zone_name, "Zone has no tracked routes");
         }
 
-        // Also clean up aggregator state
-        let mut agg = self.aggregator.lock().await;
-        agg.cleanup_zone(zone_name);
+        if mode == CleanupMode::Keep {
+            if let Some(ips) = ips {
+                tracing::info!(
+                    zone = zone_name,
+                    route_count = ips.len(),
+                    "Removed zone from tracking (routes remain in kernel table)"
+                );
+            }
+            return Ok(());
+        }
+
+        let mut failures = 0;
+        for action in &removals {
+            let RouteAction::Remove {
+                network,
+                prefix_len,
+                ..
+            } = action
+            else {
+                continue;
+            };
+            if let Err(e) = self
+                .uninstall_route(zone_name, IpAddr::V4(*network), *prefix_len, &options)
+                .await
+            {
+                tracing::warn!(zone = zone_name, error = %e, "Failed to delete route");
+                failures += 1;
+            }
+        }
+        for (ip, prefix_len) in &direct {
+            if let Err(e) = self
+                .uninstall_route(zone_name, *ip, *prefix_len, &options)
+                .await
+            {
+                tracing::warn!(zone = zone_name, ip = %ip, error = %e, "Failed to delete route");
+                failures += 1;
+            }
+        }
+        for (rule, table) in &rules {
+            if let Err(e) = self.adder.remove_rule(rule, *table).await {
+                tracing::warn!(zone = zone_name, error = %e, "Failed to delete ip rule");
+                failures += 1;
+            }
+        }
+
+        let deleted = removals.len() + direct.len() + rules.len() - failures;
+        tracing::info!(
+            zone = zone_name,
+            deleted = deleted,
+            failed = failures,
+            "Removed zone and deleted its routes from kernel table"
+        );
 
+        if failures > 0 {
+            return Err(LeshyError::Routing(format!(
+                "Failed to delete {failures} route(s) for zone '{zone_name}'"
+            )));
+        }
         Ok(())
     }
 
+    /// Adopt leshy routes left in the kernel by a previous run into tracking,
+    /// so a restart doesn't re-install aggregates over their carve-outs.
+    ///
+    /// Routes are matched to zones by gateway or device. Routes that are
+    /// already tracked (e.g. restored from the state file) are skipped, and
+    /// routes no configured zone claims are left in place untouched.
+    /// Returns the number of adopted routes.
+    pub async fn reconcile(&self, zones: &[ZoneConfig]) -> Result<usize> {
+        let kernel_routes = self.adder.list_routes().await?;
+
+        // What dev zones' device files and auto via zones' gateway name now
+        let mut targets = HashMap::new();
+        for zone in zones {
+            let target = match zone.route_type.kernel_type() {
+                RouteType::Dev => self.resolve_device(&zone.route_target).await,
+                RouteType::Via => resolve_gateway(&zone.route_target).await,
+                _ => continue,
+            };
+            if let Ok(target) = target {
+                targets.insert(zone.name.clone(), target);
+            }
+        }
+
+        let mut adopted = 0;
+        for route in &kernel_routes {
+            let Some(zone) = route_owner(route, zones, &targets) else {
+                tracing::warn!(
+                    ip = %route.network,
+                    prefix_len = route.prefix_len,
+                    "Kernel route matches no configured zone, leaving it in place"
+                );
+                continue;
+            };
+            if self.adopt_route(route, zone).await {
+                tracing::debug!(
+                    ip = %route.network,
+                    prefix_len = route.prefix_len,
+                    zone = zone.name,
+                    "Adopted existing kernel route"
+                );
+                adopted += 1;
+            }
+        }
+
+        if adopted > 0 {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        tracing::info!(
+            found = kernel_routes.len(),
+            adopted = adopted,
+            "Reconciled with kernel routing table"
+        );
+        Ok(adopted)
+    }
+
+    /// Reinstall the tracked routes of `zones` missing from the kernel table,
+    /// e.g. deleted by a NetworkManager restart. Exec zones keep no routes
+    /// there, and withdrawn zones none on purpose. Returns the number of
+    /// routes reinstalled.
+    pub async fn audit(&self, zones: &[ZoneConfig]) -> Result<usize> {
+        if self.dry_run {
+            return Ok(0);
+        }
+        let kernel_routes = self.adder.list_routes().await?;
+
+        let mut repaired = 0;
+        let mut failures = 0;
+        for zone in zones.iter().filter(|z| z.route_type != RouteType::Exec) {
+            if self.is_withdrawn(&zone.name).await {
+                continue;
+            }
+            let options = self.options_for(&zone.name).await;
+            for (ip, prefix_len) in self.tracked_prefixes(&zone.name).await {
+                if kernel_routes
+                    .iter()
+                    .any(|route| route_matches(route, ip, prefix_len, &options))
+                {
+                    continue;
+                }
+                tracing::warn!(
+                    zone = zone.name,
+                    ip = %ip,
+                    prefix_len = prefix_len,
+                    "Tracked route missing from kernel table, reinstalling"
+                );
+                let result = self
+                    .install_route(
+                        &zone.name,
+                        ip,
+                        prefix_len,
+                        zone.route_type,
+                        &zone.route_target,
+                        &options,
+                    )
+                    .await;
+                match result {
+                    Ok(()) => {
+                        self.report_installed(&zone.name, ip, prefix_len).await;
+                        repaired += 1;
+                    }
+                    Err(e) => {
+                        tracing::warn!(zone = zone.name, ip = %ip, error = %e, "Failed to reinstall route");
+                        failures += 1;
+                    }
+                }
+            }
+        }
+
+        if repaired > 0 || failures > 0 {
+            tracing::info!(
+                repaired = repaired,
+                failed = failures,
+                "Audited routes against kernel table"
+            );
+        }
+        if failures > 0 {
+            return Err(LeshyError::Routing(format!(
+                "Failed to reinstall {failures} missing route(s)"
+            )));
+        }
+        Ok(repaired)
+    }
+
+    /// Track a kernel route for a zone. Returns false if already tracked.
+    async fn adopt_route(&self, route: &KernelRoute, zone: &ZoneConfig) -> bool {
+        self.remember_options(zone).await;
+        let key = (route.network, route.prefix_len);
+        {
+            let mut direct = self.direct_routes.write().await;
+            if direct.values().any(|prefixes| prefixes.contains(&key)) {
+                return false;
+            }
+
+            let mut agg = self.aggregator.lock().await;
+            match route.network {
+                IpAddr::V4(v4) if !is_static_route(zone, key) => {
+                    if !agg.adopt(
+                        v4,
+                        route.prefix_len,
+                        &zone.name,
+                        zone.route_type,
+                        &zone.route_target,
+                    ) {
+                        return false;
+                    }
+                }
+                ip => {
+                    if let IpAddr::V4(v4) = ip {
+                        agg.register_static_ip(v4, &zone.name);
+                    }
+                    direct.entry(zone.name.clone()).or_default().insert(key);
+                }
+            }
+        }
+
+        let host_len = if route.network.is_ipv4() { 32 } else { 128 };
+        if route.prefix_len == host_len {
+            let mut routes = self.zone_routes.write().await;
+            routes
+                .entry(zone.name.clone())
+                .or_default()
+                .insert(route.network);
+        }
+        true
+    }
+
+    /// Names of all zones with tracked routes or rules, including zones
+    /// restored from the state file that are no longer configured.
+    pub async fn tracked_zones(&self) -> Vec<String> {
+        let mut zones: HashSet<String> = self.zone_options.read().await.keys().cloned().collect();
+        zones.extend(self.zone_routes.read().await.keys().cloned());
+        zones.extend(self.zone_rules.read().await.keys().cloned());
+        let mut zones: Vec<String> = zones.into_iter().collect();
+        zones.sort();
+        zones
+    }
+
+    /// Whether route changes are only logged (see `new`).
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Counter that changes whenever tracked state changes.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Export tracked routes and aggregator state for persistence.
+    /// Zone cleanup modes are left at their default for the caller to fill in.
+    pub async fn snapshot(&self) -> RouteState {
+        let mut state = RouteState::default();
+        for (zone, ips) in self.zone_routes.read().await.iter() {
+            let mut ips: Vec<IpAddr> = ips.iter().copied().collect();
+            ips.sort();
+            state.zones.entry(zone.clone()).or_default().ips = ips;
+        }
+        for (zone, prefixes) in self.direct_routes.read().await.iter() {
+            let mut prefixes: Vec<(IpAddr, u8)> = prefixes.iter().copied().collect();
+            prefixes.sort();
+            state.zones.entry(zone.clone()).or_default().direct_routes = prefixes;
+        }
+        for (zone, options) in self.zone_options.read().await.iter() {
+            state.zones.entry(zone.clone()).or_default().options = options.clone();
+        }
+        for (zone, rules) in self.zone_rules.read().await.iter() {
+            state.zones.entry(zone.clone()).or_default().rules = rules.clone();
+        }
+        state.aggregator = self.aggregator.lock().await.snapshot();
+        state
+    }
+
+    /// Import previously persisted state. The routes are assumed to still be
+    /// present in the kernel and are only added to tracking.
+    pub async fn restore(&self, state: RouteState) {
+        {
+            let mut routes = self.zone_routes.write().await;
+            let mut direct = self.direct_routes.write().await;
+            let mut options = self.zone_options.write().await;
+            let mut rules = self.zone_rules.write().await;
+            for (zone, zone_state) in state.zones {
+                routes
+                    .entry(zone.clone())
+                    .or_default()
+                    .extend(zone_state.ips);
+                direct
+                    .entry(zone.clone())
+                    .or_default()
+                    .extend(zone_state.direct_routes);
+                options.insert(zone.clone(), zone_state.options);
+                if !zone_state.rules.is_empty() {
+                    rules.insert(zone, zone_state.rules);
+                }
+            }
+        }
+        self.aggregator.lock().await.restore(state.aggregator);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
     /// Get count of tracked routes for a zone
-    #[allow(dead_code)]
     pub async fn get_zone_route_count(&self, zone_name: &str) -> usize {
         let routes = self.zone_routes.read().await;
         routes.get(zone_name).map(|set| set.len()).unwrap_or(0)
     }
 }
 
+/// The kernel route backend selected by `routing.backend`. Netlink falls
+/// back to the `ip` command when a netlink socket can't be opened, or
+/// leshy was built without the netlink feature.
+#[cfg(all(target_os = "linux", feature = "routing"))]
+fn platform_adder(routing: &RoutingConfig) -> anyhow::Result<Box<dyn RouteAdder>> {
+    match routing.backend {
+        #[cfg(feature = "netlink")]
+        RouteBackend::Netlink => match linux::LinuxRouteAdder::new() {
+            Ok(adder) => Ok(Box::new(adder)),
+            Err(e) => {
+                tracing::warn!(error = %e, "Netlink unavailable, falling back to the ip command");
+                Ok(Box::new(iproute::IpRouteAdder::new(&routing.ip_command)?))
+            }
+        },
+        #[cfg(not(feature = "netlink"))]
+        RouteBackend::Netlink => {
+            tracing::info!(
+                command = routing.ip_command,
+                "Built without netlink, using the ip command for routes"
+            );
+            Ok(Box::new(iproute::IpRouteAdder::new(&routing.ip_command)?))
+        }
+        RouteBackend::Ip => {
+            tracing::info!(
+                command = routing.ip_command,
+                "Using the ip command for routes"
+            );
+            Ok(Box::new(iproute::IpRouteAdder::new(&routing.ip_command)?))
+        }
+        RouteBackend::Disabled => anyhow::bail!("routing is disabled"),
+    }
+}
+
+#[cfg(all(
+    any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"),
+    feature = "routing"
+))]
+fn platform_adder(routing: &RoutingConfig) -> anyhow::Result<Box<dyn RouteAdder>> {
+    if routing.backend == RouteBackend::Ip {
+        anyhow::bail!("routing backend \"ip\" is only supported on Linux");
+    }
+    Ok(Box::new(bsd::BsdRouteAdder::new()?))
+}
+
+/// Platforms without a route backend can only run with `routing =
+/// "disabled"` (which builds without the `routing` feature always do).
+#[cfg(not(all(
+    any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "openbsd"
+    ),
+    feature = "routing"
+)))]
+fn platform_adder(_routing: &RoutingConfig) -> anyhow::Result<Box<dyn RouteAdder>> {
+    anyhow::bail!("no route backend on this platform; set routing = \"disabled\"")
+}
+
+/// Find the zone a kernel route belongs to: via zones by gateway, dev zones
+/// by their current device (`targets` maps zone name to device, or to the
+/// resolved gateway of "auto" and "iface:" via zones), blackhole
+/// and reject zones by route type alone, all within the zone's routing table
+/// (or VRF).
+/// When several zones match, the one listing the prefix as a static route wins.
+fn route_owner<'a>(
+    route: &KernelRoute,
+    zones: &'a [ZoneConfig],
+    targets: &HashMap<String, String>,
+) -> Option<&'a ZoneConfig> {
+    let candidates: Vec<&ZoneConfig> = zones
+        .iter()
+        .filter(|zone| {
+            let same_table = match &zone.vrf {
+                Some(vrf) => route.vrf.as_ref() == Some(vrf),
+                None => zone.route_table.unwrap_or(MAIN_TABLE) == route.table,
+            };
+            same_table && zone.route_type.kernel_type() == route.route_type
+        })
+        .filter(|zone| match zone.route_type {
+            RouteType::Via => {
+                let gateway = targets.get(&zone.name).unwrap_or(&zone.route_target);
+                route.gateway.is_some() && gateway.parse::<IpAddr>().ok() == route.gateway
+            }
+            RouteType::Dev | RouteType::Tailscale => {
+                route.device.is_some() && targets.get(&zone.name) == route.device.as_ref()
+            }
+            RouteType::Blackhole | RouteType::Reject => true,
+            RouteType::Exec => false,
+        })
+        .collect();
+
+    candidates
+        .iter()
+        .find(|zone| is_static_route(zone, (route.network, route.prefix_len)))
+        .or(candidates.first())
+        .copied()
+}
+
+/// A via zone's gateway: its `route_target`, the current default gateway
+/// for "auto", or the next hop on the interface for "iface:<name>".
+pub async fn resolve_gateway(target: &str) -> Result<String> {
+    if !is_dynamic_gateway(target) {
+        return Ok(target.to_string());
+    }
+    let device = target
+        .strip_prefix(IFACE_GATEWAY_PREFIX)
+        .map(str::to_string);
+    let gateway = tokio::task::spawn_blocking({
+        let device = device.clone();
+        move || match device {
+            Some(device) => endpoints::interface_gateway(&device),
+            None => endpoints::default_route().map(|uplink| uplink.gateway),
+        }
+    })
+    .await
+    .ok()
+    .flatten();
+    match (gateway, device) {
+        (Some(gateway), _) => Ok(gateway.to_string()),
+        (None, Some(device)) => Err(LeshyError::Routing(format!(
+            "No gateway on interface '{device}' for route_target = \"{target}\" (down?)"
+        ))),
+        (None, None) => Err(LeshyError::Routing(
+            "No default gateway for route_target = \"auto\" (offline?)".to_string(),
+        )),
+    }
+}
+
+/// Whether `route` is the kernel route for `ip`/`prefix_len` in the
+/// table (or VRF) of `options`.
+fn route_matches(route: &KernelRoute, ip: IpAddr, prefix_len: u8, options: &RouteOptions) -> bool {
+    let table_matches = match &options.vrf {
+        Some(vrf) => route.vrf.as_ref() == Some(vrf),
+        None => route.table == options.table.unwrap_or(MAIN_TABLE),
+    };
+    route.network == ip && route.prefix_len == prefix_len && table_matches
+}
+
+/// Interface names can't contain '/', so any target with one is a device
+/// file path. A bare name is still a file if one exists (relative path).
+pub fn is_device_file(target: &str) -> bool {
+    target.contains('/') || std::path::Path::new(target).is_file()
+}
+
+/// Whether the prefix is one of the zone's installed static routes (or its
+/// fake-IP pool).
+/// Exclusive zones use `static_routes` as exclusions, so never match.
+fn is_static_route(zone: &ZoneConfig, prefix: (IpAddr, u8)) -> bool {
+    zone.mode != ZoneMode::Exclusive
+        && zone
+            .static_routes
+            .iter()
+            .chain(&zone.fake_ip_pool)
+            .any(|cidr| parse_cidr(cidr).ok() == Some(prefix))
+}
+
+/// Whether `ip` falls inside `network/prefix_len` (same address family).
+pub(crate) fn prefix_contains(network: IpAddr, prefix_len: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
 /// Parse a CIDR string like "149.154.160.0/20" or plain IP "1.2.3.4"
-fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
+pub(crate) fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
+    let invalid = |what: &str| LeshyError::Parse(format!("Failed to parse {what} in '{cidr}'"));
     if let Some((ip_str, prefix_str)) = cidr.split_once('/') {
-        let ip: IpAddr = ip_str.parse().context("Failed to parse IP in CIDR")?;
-        let prefix_len: u8 = prefix_str
-            .parse()
-            .context("Failed to parse prefix length")?;
+        let ip: IpAddr = ip_str.parse().map_err(|_| invalid("IP"))?;
+        let prefix_len: u8 = prefix_str.parse().map_err(|_| invalid("prefix length"))?;
         let max = match ip {
             IpAddr::V4(_) => 32,
             IpAddr::V6(_) => 128,
         };
         if prefix_len > max {
-            anyhow::bail!("Prefix length {prefix_len} exceeds maximum {max} for {ip}");
+            return Err(LeshyError::Parse(format!(
+                "Prefix length {prefix_len} exceeds maximum {max} for {ip}"
+            )));
         }
         Ok((ip, prefix_len))
     } else {
-        let ip: IpAddr = cidr.parse().context("Failed to parse IP")?;
+        let ip: IpAddr = cidr.parse().map_err(|_| invalid("IP"))?;
         let prefix_len = match ip {
             IpAddr::V4(_) => 32,
             IpAddr::V6(_) => 128,
@@ -244,6 +1626,7 @@ fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
 
 #[cfg(test)]
 mod tests {
+    use super::mock::{MockRouteAdder, RouteCall};
     use super::*;
 
     #[test]
@@ -264,4 +1647,558 @@ mod tests {
     fn parse_cidr_invalid_prefix() {
         assert!(parse_cidr("10.0.0.0/33").is_err());
     }
+
+    fn routing(dry_run: bool) -> RoutingConfig {
+        RoutingConfig {
+            dry_run,
+            ..Default::default()
+        }
+    }
+
+    fn zone(name: &str, route_type: RouteType, route_target: &str) -> ZoneConfig {
+        ZoneConfig::new(name, route_type, route_target)
+    }
+
+    fn kernel_route(cidr: &str, gateway: Option<&str>, device: Option<&str>) -> KernelRoute {
+        let (network, prefix_len) = parse_cidr(cidr).unwrap();
+        KernelRoute {
+            network,
+            prefix_len,
+            table: MAIN_TABLE,
+            route_type: if gateway.is_some() {
+                RouteType::Via
+            } else {
+                RouteType::Dev
+            },
+            gateway: gateway.map(|gw| gw.parse().unwrap()),
+            device: device.map(String::from),
+            vrf: None,
+        }
+    }
+
+    #[test]
+    fn route_owner_matches_gateway_and_device() {
+        let zones = vec![
+            zone("eu", RouteType::Via, "192.168.169.1"),
+            zone("corp", RouteType::Dev, "/run/vpn/corp.dev"),
+            zone("tailnet", RouteType::Tailscale, "tailscale0"),
+        ];
+        let devices = HashMap::from([
+            ("corp".to_string(), "tun0".to_string()),
+            ("tailnet".to_string(), "tailscale0".to_string()),
+        ]);
+
+        let via = kernel_route("10.0.0.0/24", Some("192.168.169.1"), Some("eth0"));
+        assert_eq!(route_owner(&via, &zones, &devices).unwrap().name, "eu");
+
+        let dev = kernel_route("10.1.0.5", None, Some("tun0"));
+        assert_eq!(route_owner(&dev, &zones, &devices).unwrap().name, "corp");
+        let tailscale = kernel_route("10.3.0.0/24", None, Some("tailscale0"));
+        assert_eq!(
+            route_owner(&tailscale, &zones, &devices).unwrap().name,
+            "tailnet"
+        );
+
+        let unknown = kernel_route("10.2.0.0/24", Some("192.168.1.1"), Some("eth0"));
+        assert!(route_owner(&unknown, &zones, &devices).is_none());
+    }
+
+    #[test]
+    fn route_owner_prefers_static_route_zone() {
+        let mut telegram = zone("telegram", RouteType::Via, "10.8.0.1");
+        telegram.static_routes = vec!["149.154.160.0/20".to_string()];
+        let zones = vec![zone("eu", RouteType::Via, "10.8.0.1"), telegram];
+
+        let route = kernel_route("149.154.160.0/20", Some("10.8.0.1"), None);
+        let owner = route_owner(&route, &zones, &HashMap::new()).unwrap();
+        assert_eq!(owner.name, "telegram");
+    }
+
+    #[test]
+    fn route_owner_respects_table() {
+        let mut vpn = zone("vpn", RouteType::Via, "10.8.0.1");
+        vpn.route_table = Some(100);
+        let zones = vec![zone("eu", RouteType::Via, "10.8.0.1"), vpn];
+
+        let mut route = kernel_route("10.0.0.0/24", Some("10.8.0.1"), None);
+        assert_eq!(
+            route_owner(&route, &zones, &HashMap::new()).unwrap().name,
+            "eu"
+        );
+
+        route.table = 100;
+        assert_eq!(
+            route_owner(&route, &zones, &HashMap::new()).unwrap().name,
+            "vpn"
+        );
+
+        route.table = 200;
+        assert!(route_owner(&route, &zones, &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn route_owner_matches_vrf() {
+        let mut corp = zone("corp", RouteType::Via, "10.8.0.1");
+        corp.vrf = Some("vrf-corp".to_string());
+        let zones = vec![zone("eu", RouteType::Via, "10.8.0.1"), corp];
+
+        let mut route = kernel_route("10.0.0.0/24", Some("10.8.0.1"), None);
+        route.table = 10;
+        assert!(route_owner(&route, &zones, &HashMap::new()).is_none());
+
+        route.vrf = Some("vrf-corp".to_string());
+        assert_eq!(
+            route_owner(&route, &zones, &HashMap::new()).unwrap().name,
+            "corp"
+        );
+    }
+
+    #[test]
+    fn route_owner_matches_blackhole_by_type() {
+        let zones = vec![
+            zone("eu", RouteType::Via, "10.8.0.1"),
+            zone("kill", RouteType::Blackhole, ""),
+        ];
+
+        let mut route = kernel_route("10.0.0.0/24", None, None);
+        route.route_type = RouteType::Blackhole;
+        assert_eq!(
+            route_owner(&route, &zones, &HashMap::new()).unwrap().name,
+            "kill"
+        );
+
+        route.route_type = RouteType::Reject;
+        assert!(route_owner(&route, &zones, &HashMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn dry_run_tracks_without_kernel_changes() {
+        let manager = RouteManager::new(Some(24), &routing(true)).unwrap();
+        let mut vpn = zone("vpn", RouteType::Via, "10.8.0.1");
+        vpn.static_routes = vec!["198.51.100.0/24".to_string()];
+
+        manager
+            .add_static_route("198.51.100.0/24", &vpn)
+            .await
+            .unwrap();
+        manager
+            .add_route("203.0.113.5".parse().unwrap(), &vpn)
+            .await
+            .unwrap();
+        assert_eq!(manager.get_zone_route_count("vpn").await, 2);
+
+        manager
+            .cleanup_zone("vpn", CleanupMode::Delete)
+            .await
+            .unwrap();
+        assert_eq!(manager.get_zone_route_count("vpn").await, 0);
+    }
+
+    #[tokio::test]
+    async fn new_and_removed_routes_are_reported() {
+        use crate::config::HookConfig;
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("events");
+        let events = Arc::new(Events::new(vec![HookConfig {
+            events: vec![],
+            command: Some(format!(
+                "echo \"$LESHY_EVENT $LESHY_PREFIX\" >> {}",
+                log.display()
+            )),
+            url: None,
+        }]));
+        let manager = RouteManager::new(Some(24), &routing(true))
+            .unwrap()
+            .with_events(events);
+        let vpn = zone("vpn", RouteType::Via, "10.8.0.1");
+
+        for _ in 0..2 {
+            manager
+                .add_static_route("198.51.100.0/24", &vpn)
+                .await
+                .unwrap();
+        }
+        for ip in ["203.0.113.5", "203.0.113.6"] {
+            manager.add_route(ip.parse().unwrap(), &vpn).await.unwrap();
+        }
+        manager
+            .cleanup_zone("vpn", CleanupMode::Delete)
+            .await
+            .unwrap();
+
+        let expected = [
+            "route_installed 198.51.100.0/24",
+            "route_installed 203.0.113.0/24",
+            "route_removed 198.51.100.0/24",
+            "route_removed 203.0.113.0/24",
+        ];
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            let content = std::fs::read_to_string(&log).unwrap_or_default();
+            lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+            lines.sort();
+            if lines.len() >= expected.len() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(lines, expected);
+    }
+
+    #[tokio::test]
+    async fn exec_zone_routes_go_through_hook() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls");
+        let script = dir.path().join("hook.sh");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manager = RouteManager::new(Some(24), &routing(false)).unwrap();
+        let firewall = zone("fw", RouteType::Exec, &script.to_string_lossy());
+        manager
+            .add_route("198.51.100.7".parse().unwrap(), &firewall)
+            .await
+            .unwrap();
+        manager
+            .cleanup_zone("fw", CleanupMode::Delete)
+            .await
+            .unwrap();
+
+        let calls = std::fs::read_to_string(log).unwrap();
+        assert_eq!(calls, "ADD 198.51.100.0/24 fw\nREMOVE 198.51.100.0/24 fw\n");
+    }
+
+    #[tokio::test]
+    async fn withdrawn_zone_tracks_routes_until_restored() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls");
+        let script = dir.path().join("hook.sh");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manager = RouteManager::new(Some(24), &routing(false)).unwrap();
+        let firewall = zone("fw", RouteType::Exec, &script.to_string_lossy());
+        manager
+            .add_route("198.51.100.7".parse().unwrap(), &firewall)
+            .await
+            .unwrap();
+
+        assert_eq!(manager.withdraw_zone("fw").await.unwrap(), 1);
+        // Already withdrawn: nothing to do
+        assert_eq!(manager.withdraw_zone("fw").await.unwrap(), 0);
+        manager
+            .add_route("203.0.113.9".parse().unwrap(), &firewall)
+            .await
+            .unwrap();
+        assert_eq!(manager.get_zone_route_count("fw").await, 2);
+
+        assert_eq!(manager.restore_zone(&firewall).await.unwrap(), 2);
+        assert_eq!(manager.restore_zone(&firewall).await.unwrap(), 0);
+
+        let calls = std::fs::read_to_string(log).unwrap();
+        assert_eq!(
+            calls,
+            "ADD 198.51.100.0/24 fw\n\
+             REMOVE 198.51.100.0/24 fw\n\
+             REMOVE 198.51.100.0/24 fw\n\
+             ADD 198.51.100.0/24 fw\n\
+             REMOVE 203.0.113.0/24 fw\n\
+             ADD 203.0.113.0/24 fw\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn max_routes_evicts_least_recently_resolved() {
+        let manager = RouteManager::new(Some(24), &routing(true)).unwrap();
+        let mut vpn = zone("vpn", RouteType::Via, "10.8.0.1");
+        vpn.static_routes = vec!["192.0.2.0/24".to_string()];
+        vpn.max_routes = Some(2);
+
+        manager
+            .add_static_route("192.0.2.0/24", &vpn)
+            .await
+            .unwrap();
+        for ip in ["198.51.100.1", "203.0.113.1", "198.51.100.2", "2001:db8::1"] {
+            manager.add_route(ip.parse().unwrap(), &vpn).await.unwrap();
+        }
+
+        // 203.0.113.0/24 was resolved least recently; the static route stays
+        let mut prefixes = manager.tracked_prefixes("vpn").await;
+        prefixes.sort();
+        assert_eq!(
+            prefixes,
+            vec![
+                ("192.0.2.0".parse().unwrap(), 24),
+                ("198.51.100.0".parse().unwrap(), 24),
+                ("2001:db8::1".parse().unwrap(), 128),
+            ]
+        );
+
+        // Re-resolving into an installed prefix refreshes it
+        for ip in ["2001:db8::2", "198.51.100.3"] {
+            manager.add_route(ip.parse().unwrap(), &vpn).await.unwrap();
+        }
+        let mut prefixes = manager.tracked_prefixes("vpn").await;
+        prefixes.sort();
+        assert_eq!(
+            prefixes,
+            vec![
+                ("192.0.2.0".parse().unwrap(), 24),
+                ("198.51.100.0".parse().unwrap(), 24),
+                ("2001:db8::2".parse().unwrap(), 128),
+            ]
+        );
+    }
+
+    fn mock_manager(aggregation_prefix: Option<u8>) -> (RouteManager, MockRouteAdder) {
+        let adder = MockRouteAdder::new();
+        let manager = RouteManager::with_adder(Box::new(adder.clone()), aggregation_prefix);
+        (manager, adder)
+    }
+
+    fn calls(adder: &MockRouteAdder) -> Vec<String> {
+        adder.calls().iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn audit_reinstalls_flushed_routes() {
+        let (mut manager, adder) = mock_manager(Some(24));
+        manager.verify = true;
+        let eu = zone("eu", RouteType::Via, "10.8.0.1");
+        let corp = zone("corp", RouteType::Dev, "wg0");
+        manager
+            .add_route("203.0.113.5".parse().unwrap(), &eu)
+            .await
+            .unwrap();
+        manager
+            .add_route("2001:db8::2".parse().unwrap(), &corp)
+            .await
+            .unwrap();
+        let zones = [eu, corp];
+        assert_eq!(manager.audit(&zones).await.unwrap(), 0);
+
+        adder.flush_routes();
+        adder.clear_calls();
+        assert_eq!(manager.audit(&zones).await.unwrap(), 2);
+        assert_eq!(
+            calls(&adder),
+            [
+                "add 203.0.113.0/24 via 10.8.0.1",
+                "add 2001:db8::2/128 dev wg0"
+            ]
+        );
+        assert_eq!(adder.routes().len(), 2);
+
+        // Routes of withdrawn zones stay out
+        manager.withdraw_zone("eu").await.unwrap();
+        assert_eq!(manager.audit(&zones).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn removed_static_route_hands_ownership_back() {
+        let (manager, adder) = mock_manager(Some(24));
+        let eu = zone("eu", RouteType::Via, "10.8.0.1");
+        let mut list = zone("list", RouteType::Via, "10.8.0.2");
+        list.route_table = Some(100);
+        let corp = zone("corp", RouteType::Dev, "wg0");
+        // The last zone to add the address owns it; removing its route
+        // hands the address back to eu
+        manager.add_static_route("203.0.113.5", &eu).await.unwrap();
+        manager
+            .add_static_route("203.0.113.5", &list)
+            .await
+            .unwrap();
+        manager
+            .remove_static_route("203.0.113.5", &list)
+            .await
+            .unwrap();
+
+        // corp's aggregate leaves eu's static route alone
+        let ip = "203.0.113.5".parse().unwrap();
+        manager
+            .add_route("203.0.113.200".parse().unwrap(), &corp)
+            .await
+            .unwrap();
+        assert!(adder
+            .routes()
+            .iter()
+            .filter(|route| route.device.is_some())
+            .all(|route| !prefix_contains(route.network, route.prefix_len, IpAddr::V4(ip))));
+    }
+
+    #[tokio::test]
+    async fn carve_out_splits_aggregate_and_merges_back() {
+        let (manager, adder) = mock_manager(Some(24));
+        let eu = zone("eu", RouteType::Via, "10.8.0.1");
+        let corp = zone("corp", RouteType::Dev, "wg0");
+
+        for ip in ["203.0.113.5", "203.0.113.5"] {
+            manager.add_route(ip.parse().unwrap(), &eu).await.unwrap();
+        }
+        assert_eq!(calls(&adder), ["add 203.0.113.0/24 via 10.8.0.1"]);
+
+        // corp's IP is carved out of eu's /24, which is replaced by the
+        // prefixes covering the rest of it
+        manager
+            .add_route("203.0.113.200".parse().unwrap(), &corp)
+            .await
+            .unwrap();
+        let routes = adder.routes();
+        assert_eq!(routes.len(), 9);
+        let carved = routes
+            .iter()
+            .find(|route| route.device.as_deref() == Some("wg0"))
+            .unwrap();
+        assert_eq!(
+            (carved.network, carved.prefix_len),
+            ("203.0.113.200".parse().unwrap(), 32)
+        );
+        assert!(routes
+            .iter()
+            .filter(|route| route.device.is_none())
+            .all(|route| !prefix_contains(route.network, route.prefix_len, carved.network)));
+
+        manager
+            .cleanup_zone("corp", CleanupMode::Delete)
+            .await
+            .unwrap();
+        assert_eq!(
+            adder.routes(),
+            [kernel_route("203.0.113.0/24", Some("10.8.0.1"), None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn static_routes_and_rules_use_zone_table() {
+        let (manager, adder) = mock_manager(Some(24));
+        let mut vpn = zone("vpn", RouteType::Via, "10.8.0.1");
+        vpn.route_table = Some(100);
+        vpn.ip_rules = vec![IpRule {
+            fwmark: Some(1),
+            from: None,
+            priority: None,
+        }];
+
+        manager.apply_rules(&vpn).await.unwrap();
+        manager
+            .add_static_route("198.51.100.0/24", &vpn)
+            .await
+            .unwrap();
+        // Inside the static route: nothing more to install
+        manager
+            .add_route("198.51.100.7".parse().unwrap(), &vpn)
+            .await
+            .unwrap();
+        assert_eq!(adder.routes().len(), 1);
+        assert_eq!(adder.routes()[0].table, 100);
+
+        manager
+            .cleanup_zone("vpn", CleanupMode::Delete)
+            .await
+            .unwrap();
+        assert!(adder.routes().is_empty());
+        let calls = adder.calls();
+        assert!(matches!(&calls[0], RouteCall::AddRule { table: 100, .. }));
+        assert!(matches!(
+            calls.last().unwrap(),
+            RouteCall::RemoveRule { table: 100, .. }
+        ));
+        assert!(calls.iter().all(|call| match call {
+            RouteCall::AddVia { options, .. } | RouteCall::Remove { options, .. } =>
+                options.table == Some(100),
+            _ => true,
+        }));
+    }
+
+    #[tokio::test]
+    async fn cleanup_keep_leaves_kernel_routes() {
+        let (manager, adder) = mock_manager(Some(24));
+        let eu = zone("eu", RouteType::Via, "10.8.0.1");
+        manager
+            .add_route("2001:db8::1".parse().unwrap(), &eu)
+            .await
+            .unwrap();
+        manager.cleanup_zone("eu", CleanupMode::Keep).await.unwrap();
+
+        assert_eq!(calls(&adder), ["add 2001:db8::1/128 via 10.8.0.1"]);
+        assert_eq!(adder.routes().len(), 1);
+        assert_eq!(manager.get_zone_route_count("eu").await, 0);
+    }
+
+    #[tokio::test]
+    async fn reconcile_adopts_routes_left_in_kernel() {
+        let (manager, adder) = mock_manager(Some(24));
+        let eu = zone("eu", RouteType::Via, "10.8.0.1");
+        adder.insert_route(kernel_route("203.0.113.0/24", Some("10.8.0.1"), None));
+        adder.insert_route(kernel_route("192.0.2.0/24", Some("192.168.1.1"), None));
+
+        assert_eq!(
+            manager.reconcile(std::slice::from_ref(&eu)).await.unwrap(),
+            1
+        );
+        // Covered by the adopted /24, so nothing is installed again
+        manager
+            .add_route("203.0.113.9".parse().unwrap(), &eu)
+            .await
+            .unwrap();
+        assert!(adder.calls().is_empty());
+
+        manager
+            .cleanup_zone("eu", CleanupMode::Delete)
+            .await
+            .unwrap();
+        assert_eq!(calls(&adder), ["remove 203.0.113.0/24"]);
+        // Not claimed by any zone, so left alone
+        assert_eq!(adder.routes().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tracked_routes_are_not_paced_again() {
+        let (mut manager, adder) = mock_manager(Some(24));
+        manager.pacer = Some(InstallPacer::new(1, 1));
+        let corp = zone("corp", RouteType::Dev, "wg0");
+        let ip: IpAddr = "2001:db8::2".parse().unwrap();
+        assert!(manager.add_route(ip, &corp).await.unwrap());
+
+        // The one-per-second slot is spent, but re-resolving needs none
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            assert!(!manager.add_route(ip, &corp).await.unwrap());
+        }
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+        assert_eq!(calls(&adder), ["add 2001:db8::2/128 dev wg0"]);
+    }
+
+    #[test]
+    fn device_target_path_or_interface() {
+        assert!(is_device_file("/run/vpn/corp.dev"));
+        assert!(is_device_file("./corp.dev"));
+        assert!(!is_device_file("wg0"));
+        assert!(!is_device_file("tun0"));
+    }
+
+    #[tokio::test]
+    async fn resolve_device_literal_interface() {
+        let manager = RouteManager::new(None, &routing(true)).unwrap();
+        assert_eq!(manager.resolve_device("wg0").await.unwrap(), "wg0");
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("corp.dev");
+        std::fs::write(&file, "tun0\n").unwrap();
+        let path = file.to_string_lossy();
+        assert_eq!(manager.resolve_device(&path).await.unwrap(), "tun0");
+    }
 }
@@ -0,0 +1,95 @@
+use crate::config::RoutingConfig;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Spreads route installs out to `routing.max_installs_per_second`: up to
+/// `install_burst` go through at once, the rest wait their turn in order,
+/// so a response with hundreds of addresses or a blocklist-sized zone
+/// can't flood the kernel with route changes.
+pub struct InstallPacer {
+    interval: Duration,
+    /// How far ahead of now installs may be booked before they must wait
+    burst_window: Duration,
+    /// When the next install's slot comes up, with no burst to spend
+    next_slot: Mutex<Instant>,
+    waiting: AtomicUsize,
+}
+
+/// Counts an install as queued until dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl InstallPacer {
+    /// The pacer `[routing]` asks for; None when installs are unlimited.
+    pub fn from_config(routing: &RoutingConfig) -> Option<Self> {
+        let rate = routing.max_installs_per_second?;
+        Some(Self::new(rate, routing.install_burst.unwrap_or(rate)))
+    }
+
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let interval = Duration::from_secs(1) / rate.max(1);
+        Self {
+            interval,
+            burst_window: interval * burst.max(1).saturating_sub(1),
+            next_slot: Mutex::new(Instant::now()),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Installs waiting for their slot.
+    pub fn queued(&self) -> usize {
+        self.waiting.load(Ordering::Acquire)
+    }
+
+    /// Wait for this install's slot.
+    pub async fn wait(&self) {
+        let _waiting = Waiting(&self.waiting);
+        self.waiting.fetch_add(1, Ordering::AcqRel);
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            *next_slot = slot + self.interval;
+            slot.checked_sub(self.burst_window).unwrap_or(now)
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn lets_a_burst_through_then_paces() {
+        let pacer = Arc::new(InstallPacer::new(20, 3));
+        let start = Instant::now();
+        for _ in 0..3 {
+            pacer.wait().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(40));
+
+        // The rest come 50 ms apart, queued meanwhile
+        let rest: Vec<_> = (0..3)
+            .map(|_| {
+                let pacer = Arc::clone(&pacer);
+                tokio::spawn(async move { pacer.wait().await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pacer.queued() > 0);
+        for install in rest {
+            install.await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(140));
+        assert_eq!(pacer.queued(), 0);
+    }
+}
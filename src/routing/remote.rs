@@ -0,0 +1,155 @@
+use crate::config::parse_static_routes;
+use crate::error::{LeshyError, Result};
+#[cfg(feature = "https")]
+use anyhow::Context;
+use std::net::IpAddr;
+#[cfg(feature = "https")]
+use std::time::Duration;
+
+/// How long a prefix list download may take.
+#[cfg(feature = "https")]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[cfg(feature = "https")]
+pub use reqwest::Client;
+
+/// Stand-in for the HTTP client in builds without the https feature,
+/// where every fetch fails.
+#[cfg(not(feature = "https"))]
+#[derive(Clone)]
+pub struct Client;
+
+/// HTTP client for fetching remote prefix lists.
+#[cfg(feature = "https")]
+pub fn client() -> Result<Client> {
+    reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .user_agent(concat!("leshy/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .context("Failed to build HTTP client")
+        .map_err(LeshyError::routing)
+}
+
+#[cfg(not(feature = "https"))]
+pub fn client() -> Result<Client> {
+    Ok(Client)
+}
+
+/// Download a published prefix list and return its IPs/CIDRs.
+pub async fn fetch_prefixes(client: &Client, url: &str) -> Result<Vec<String>> {
+    fetch(client, url).await.map_err(LeshyError::routing)
+}
+
+#[cfg(not(feature = "https"))]
+async fn fetch(_client: &Client, url: &str) -> anyhow::Result<Vec<String>> {
+    anyhow::bail!("Can't fetch '{url}': leshy was built without the https feature")
+}
+
+#[cfg(feature = "https")]
+async fn fetch(client: &Client, url: &str) -> anyhow::Result<Vec<String>> {
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to fetch '{url}'"))?
+        .text()
+        .await
+        .with_context(|| format!("Failed to read '{url}'"))?;
+    parse_prefix_list(&body).with_context(|| format!("Invalid prefix list at '{url}'"))
+}
+
+/// Parse a prefix list: JSON (every string value that is an IP/CIDR, e.g.
+/// `ip_prefix` entries in AWS ip-ranges.json) or plain text with one
+/// IP/CIDR per line.
+#[cfg_attr(not(feature = "https"), allow(dead_code))]
+fn parse_prefix_list(body: &str) -> anyhow::Result<Vec<String>> {
+    let trimmed = body.trim_start();
+    let prefixes = if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        let value: serde_json::Value = serde_json::from_str(body)?;
+        let mut prefixes = Vec::new();
+        collect_prefixes(&value, &mut prefixes);
+        prefixes
+    } else {
+        parse_static_routes(body)?
+    };
+
+    // An empty list is far more likely a broken publisher than an intent to
+    // drop every route
+    if prefixes.is_empty() {
+        anyhow::bail!("no prefixes found");
+    }
+    Ok(prefixes)
+}
+
+fn collect_prefixes(value: &serde_json::Value, prefixes: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(s) if is_prefix(s) => prefixes.push(s.clone()),
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect_prefixes(value, prefixes);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for value in map.values() {
+                collect_prefixes(value, prefixes);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether a JSON string is a CIDR (bare addresses are not accepted, so
+/// fields like resolver IPs in metadata aren't routed by accident).
+fn is_prefix(s: &str) -> bool {
+    let Some((ip, prefix_len)) = s.split_once('/') else {
+        return false;
+    };
+    match (ip.parse::<IpAddr>(), prefix_len.parse::<u8>()) {
+        (Ok(IpAddr::V4(_)), Ok(len)) => len <= 32,
+        (Ok(IpAddr::V6(_)), Ok(len)) => len <= 128,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_aws_ip_ranges_json() {
+        let body = r#"{
+            "syncToken": "1700000000",
+            "createDate": "2024-01-01-00-00-00",
+            "prefixes": [
+                {"ip_prefix": "3.5.140.0/22", "region": "ap-northeast-2", "service": "AMAZON"},
+                {"ip_prefix": "13.34.37.64/27", "region": "ap-southeast-4", "service": "AMAZON"}
+            ],
+            "ipv6_prefixes": [
+                {"ipv6_prefix": "2600:1f14::/35", "region": "us-west-2", "service": "AMAZON"}
+            ]
+        }"#;
+        let mut prefixes = parse_prefix_list(body).unwrap();
+        prefixes.sort();
+        assert_eq!(
+            prefixes,
+            vec!["13.34.37.64/27", "2600:1f14::/35", "3.5.140.0/22"]
+        );
+    }
+
+    #[test]
+    fn parses_plain_text_list() {
+        let body = "173.245.48.0/20\n103.21.244.0/22\n";
+        assert_eq!(
+            parse_prefix_list(body).unwrap(),
+            vec!["173.245.48.0/20", "103.21.244.0/22"]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_garbage_lists() {
+        assert!(parse_prefix_list("").is_err());
+        assert!(parse_prefix_list("{\"prefixes\": []}").is_err());
+        assert!(parse_prefix_list("<html>Not Found</html>").is_err());
+    }
+}
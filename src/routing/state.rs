@@ -0,0 +1,155 @@
+use super::aggregator::AggregatorState;
+use super::RouteOptions;
+use crate::config::{CleanupMode, IpRule};
+use crate::error::{LeshyError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+const STATE_VERSION: u32 = 1;
+
+/// Route tracking persisted across restarts (see `server.state_file`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteState {
+    pub version: u32,
+    #[serde(default)]
+    pub zones: BTreeMap<String, ZoneState>,
+    #[serde(default)]
+    pub aggregator: AggregatorState,
+}
+
+/// Per-zone tracked routes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZoneState {
+    /// Cleanup mode the zone had when the state was written, used when the
+    /// zone is gone from the config on the next start
+    #[serde(default)]
+    pub cleanup_mode: CleanupMode,
+    /// Resolved IPs routed for this zone
+    #[serde(default)]
+    pub ips: Vec<IpAddr>,
+    /// Prefixes installed outside the aggregator (static routes, IPv6)
+    #[serde(default)]
+    pub direct_routes: Vec<(IpAddr, u8)>,
+    /// Route options (e.g. table) the zone's routes were installed with
+    #[serde(default)]
+    pub options: RouteOptions,
+    /// Installed policy rules and the table each points at
+    #[serde(default)]
+    pub rules: Vec<(IpRule, u32)>,
+}
+
+impl Default for RouteState {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            zones: BTreeMap::new(),
+            aggregator: AggregatorState::default(),
+        }
+    }
+}
+
+impl RouteState {
+    /// Load state from disk. Returns `None` if the file does not exist.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(LeshyError::io(
+                    format!("failed to read {}", path.display()),
+                    e,
+                ))
+            }
+        };
+        let state: RouteState = serde_json::from_str(&content)
+            .map_err(|e| LeshyError::Parse(format!("failed to parse {}: {e}", path.display())))?;
+        if state.version != STATE_VERSION {
+            return Err(LeshyError::Parse(format!(
+                "unsupported state file version {} in {}",
+                state.version,
+                path.display()
+            )));
+        }
+        Ok(Some(state))
+    }
+
+    /// Write state atomically (temp file + rename), creating the parent directory.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| LeshyError::io(format!("failed to create {}", parent.display()), e))?;
+        }
+        let tmp = path.with_extension("tmp");
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| LeshyError::io("failed to serialize route state", e.into()))?;
+        std::fs::write(&tmp, content)
+            .map_err(|e| LeshyError::io(format!("failed to write {}", tmp.display()), e))?;
+        std::fs::rename(&tmp, path).map_err(|e| {
+            LeshyError::io(
+                format!("failed to rename state file to {}", path.display()),
+                e,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouteType;
+    use crate::routing::aggregator::InstalledPrefix;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/state.json");
+
+        let mut state = RouteState::default();
+        state.zones.insert(
+            "corp".to_string(),
+            ZoneState {
+                cleanup_mode: CleanupMode::Delete,
+                ips: vec!["10.0.0.5".parse().unwrap()],
+                direct_routes: vec![("172.16.0.0".parse().unwrap(), 12)],
+                options: RouteOptions {
+                    table: Some(100),
+                    vrf: None,
+                    hook: None,
+                    device: Some("wg0".to_string()),
+                    onlink: true,
+                    source: Some("10.66.0.2".parse().unwrap()),
+                },
+                rules: vec![(
+                    IpRule {
+                        fwmark: Some(100),
+                        from: None,
+                        priority: Some(1000),
+                    },
+                    100,
+                )],
+            },
+        );
+        state.aggregator.installed.push(InstalledPrefix {
+            network: Ipv4Addr::new(10, 0, 0, 0),
+            prefix_len: 24,
+            zone: "corp".to_string(),
+            route_type: RouteType::Via,
+            route_target: "192.168.1.1".to_string(),
+        });
+
+        state.save(&path).unwrap();
+        assert_eq!(RouteState::load(&path).unwrap(), Some(state));
+    }
+
+    #[test]
+    fn load_missing_file_is_none() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(RouteState::load(&dir.path().join("missing.json"))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,243 @@
+use super::{is_device_file, is_dynamic_gateway, resolve_gateway};
+use crate::config::{RouteType, ZoneConfig};
+use crate::error::{LeshyError, Result};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Watches dev zones' device files and reports zones whose device changed,
+/// so their routes can be moved onto the new interface.
+pub struct DeviceWatcher {
+    /// Device file -> zones routed through it
+    files: HashMap<PathBuf, Vec<String>>,
+    changed_tx: mpsc::UnboundedSender<String>,
+}
+
+impl DeviceWatcher {
+    pub fn new(zones: &[ZoneConfig]) -> (Self, mpsc::UnboundedReceiver<String>) {
+        let mut files: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        for zone in zones {
+            if zone.route_type.kernel_type() == RouteType::Dev && is_device_file(&zone.route_target)
+            {
+                files
+                    .entry(PathBuf::from(&zone.route_target))
+                    .or_default()
+                    .push(zone.name.clone());
+            }
+        }
+
+        let (changed_tx, changed_rx) = mpsc::unbounded_channel();
+        (Self { files, changed_tx }, changed_rx)
+    }
+
+    /// Where changed zones are reported, to share with an `UplinkWatcher`.
+    pub fn sender(&self) -> mpsc::UnboundedSender<String> {
+        self.changed_tx.clone()
+    }
+
+    /// Watch the device files' directories until the receiver is dropped.
+    ///
+    /// A zone is reported when its file names a device different from the
+    /// last one seen, including when the file reappears after a disconnect
+    /// (the kernel drops routes of a vanished interface).
+    pub async fn watch(self) -> Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+        let mut watcher = RecommendedWatcher::new(
+            move |res: notify::Result<Event>| {
+                let _ = tx.send(res);
+            },
+            notify::Config::default(),
+        )
+        .map_err(|e| LeshyError::Routing(format!("Failed to watch device files: {e}")))?;
+
+        // Watch parent directories: VPN scripts often replace the file, and
+        // a missing file can't be watched directly
+        let dirs: HashSet<PathBuf> = self.files.keys().map(|path| parent_dir(path)).collect();
+        for dir in &dirs {
+            match watcher.watch(dir, RecursiveMode::NonRecursive) {
+                Ok(()) => tracing::info!(dir = %dir.display(), "Watching device files"),
+                Err(e) => {
+                    tracing::warn!(dir = %dir.display(), error = %e, "Failed to watch device file directory")
+                }
+            }
+        }
+
+        let mut devices: HashMap<&PathBuf, Option<String>> = self
+            .files
+            .keys()
+            .map(|path| (path, read_device(path)))
+            .collect();
+
+        while let Some(event) = rx.recv().await {
+            if let Err(e) = event {
+                tracing::error!(error = %e, "Device file watch error");
+                continue;
+            }
+
+            for (path, zones) in &self.files {
+                let device = read_device(path);
+                let last = devices.insert(path, device.clone()).flatten();
+                let Some(device) = device else {
+                    continue;
+                };
+                if last.as_ref() == Some(&device) {
+                    continue;
+                }
+
+                tracing::info!(
+                    file = %path.display(),
+                    device = device,
+                    previous = ?last,
+                    "Device changed"
+                );
+                for zone in zones {
+                    if self.changed_tx.send(zone.clone()).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How often the gateways of "auto" and "iface:" via zones are checked
+const UPLINK_POLL: Duration = Duration::from_secs(5);
+
+/// Watches the gateways of via zones with `route_target = "auto"` (the
+/// default gateway) or `"iface:<name>"` (the next hop on an interface) and
+/// reports the zones when theirs changes, e.g. after roaming to another
+/// network or a new DHCP lease, so their routes can be moved to it.
+pub struct UplinkWatcher {
+    /// Route target -> zones routed through it
+    targets: HashMap<String, Vec<String>>,
+    changed_tx: mpsc::UnboundedSender<String>,
+}
+
+impl UplinkWatcher {
+    /// Report changes on `changed_tx`, e.g. a `DeviceWatcher`'s.
+    pub fn new(zones: &[ZoneConfig], changed_tx: mpsc::UnboundedSender<String>) -> Self {
+        let mut targets: HashMap<String, Vec<String>> = HashMap::new();
+        for zone in zones {
+            if zone.route_type == RouteType::Via && is_dynamic_gateway(&zone.route_target) {
+                targets
+                    .entry(zone.route_target.clone())
+                    .or_default()
+                    .push(zone.name.clone());
+            }
+        }
+        Self {
+            targets,
+            changed_tx,
+        }
+    }
+
+    /// Poll the gateways until the receiver is dropped. A gateway coming
+    /// back after none was found is a change too: the kernel drops routes
+    /// via a gateway that went away.
+    pub async fn watch(self) {
+        if self.targets.is_empty() {
+            return;
+        }
+        let mut gateways = HashMap::new();
+        for target in self.targets.keys() {
+            let gateway = resolve_gateway(target).await.ok();
+            tracing::info!(target = target, gateway = ?gateway, "Watching gateway");
+            gateways.insert(target, gateway);
+        }
+        loop {
+            tokio::time::sleep(UPLINK_POLL).await;
+            for (target, zones) in &self.targets {
+                let gateway = resolve_gateway(target).await.ok();
+                let last = gateways.insert(target, gateway.clone()).flatten();
+                if gateway == last {
+                    continue;
+                }
+                tracing::info!(target = target, gateway = ?gateway, previous = ?last, "Gateway changed");
+                if gateway.is_none() {
+                    continue;
+                }
+                for zone in zones {
+                    if self.changed_tx.send(zone.clone()).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Current device named by a device file, if present and non-empty.
+fn read_device(path: &Path) -> Option<String> {
+    let device = std::fs::read_to_string(path).ok()?.trim().to_string();
+    (!device.is_empty()).then_some(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::AUTO_GATEWAY;
+    use std::time::Duration;
+
+    fn dev_zone(name: &str, route_target: &str) -> ZoneConfig {
+        ZoneConfig {
+            domains: vec!["example.com".to_string()],
+            ..ZoneConfig::new(name, RouteType::Dev, route_target)
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_zone_when_device_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("corp.dev");
+        std::fs::write(&file, "tun0\n").unwrap();
+
+        let zones = vec![
+            dev_zone("corp", &file.to_string_lossy()),
+            dev_zone("literal", "wg0"),
+        ];
+        let (watcher, mut changed) = DeviceWatcher::new(&zones);
+        assert_eq!(watcher.files.len(), 1);
+        tokio::spawn(watcher.watch());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Same device rewritten: no report
+        std::fs::write(&file, "tun0\n").unwrap();
+        std::fs::write(&file, "tun1\n").unwrap();
+
+        let zone = tokio::time::timeout(Duration::from_secs(5), changed.recv())
+            .await
+            .expect("device change not reported");
+        assert_eq!(zone.as_deref(), Some("corp"));
+    }
+
+    #[test]
+    fn uplink_watcher_follows_dynamic_via_zones() {
+        let mut auto = dev_zone("home", AUTO_GATEWAY);
+        auto.route_type = RouteType::Via;
+        let mut fixed = dev_zone("office", "192.168.1.1");
+        fixed.route_type = RouteType::Via;
+        let mut lan = dev_zone("lan", "iface:eth1");
+        lan.route_type = RouteType::Via;
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let zones = [auto, fixed, lan, dev_zone("vpn", AUTO_GATEWAY)];
+        let watcher = UplinkWatcher::new(&zones, tx);
+        assert_eq!(watcher.targets.len(), 2);
+        assert_eq!(watcher.targets[AUTO_GATEWAY], ["home"]);
+        assert_eq!(watcher.targets["iface:eth1"], ["lan"]);
+    }
+}
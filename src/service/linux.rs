@@ -1,3 +1,4 @@
+use super::{Change, InstallOptions, ServiceFile};
 use anyhow::{Context, Result};
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -6,9 +7,58 @@ fn unit_path(name: &str) -> PathBuf {
     PathBuf::from(format!("/etc/systemd/system/{name}.service"))
 }
 
-fn generate_unit(name: &str, binary: &Path, config: &Path) -> String {
-    let binary = binary.display();
-    let config = config.display();
+/// `word` as one word of a unit file directive: quoted when it has
+/// whitespace or quotes, with `%` escaped from specifier expansion.
+fn unit_quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && !word
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'));
+    let word = if plain {
+        word.to_string()
+    } else {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+    };
+    word.replace('%', "%%")
+}
+
+/// Sandboxing for `--hardened`: the filesystem is read-only outside the
+/// unit's own state, runtime and log directories.
+fn hardening(name: &str) -> String {
+    format!(
+        "\
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK
+RuntimeDirectory={name}
+StateDirectory={name}
+LogsDirectory={name}
+"
+    )
+}
+
+fn generate_unit(name: &str, binary: &Path, config: &Path, options: &InstallOptions) -> String {
+    let exec_start = [binary.display().to_string(), config.display().to_string()]
+        .into_iter()
+        .chain(options.args.iter().cloned())
+        // ExecStart also expands $VARIABLES
+        .map(|word| unit_quote(&word).replace('$', "$$"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let environment: String = options
+        .env
+        .iter()
+        .map(|(key, value)| format!("Environment={}\n", unit_quote(&format!("{key}={value}"))))
+        .collect();
+    let hardening = if options.hardened {
+        hardening(name)
+    } else {
+        String::new()
+    };
     format!(
         "\
 [Unit]
@@ -17,44 +67,52 @@ After=network-online.target
 Wants=network-online.target
 
 [Service]
-Type=simple
-ExecStart={binary} {config}
+Type=notify
+NotifyAccess=main
+{environment}ExecStart={exec_start}
 Restart=on-failure
 RestartSec=5
+WatchdogSec=30
 AmbientCapabilities=CAP_NET_ADMIN CAP_NET_BIND_SERVICE
 CapabilityBoundingSet=CAP_NET_ADMIN CAP_NET_BIND_SERVICE
-
+{hardening}
 [Install]
 WantedBy=multi-user.target
 "
     )
 }
 
-pub fn install(name: &str, binary: &Path, config: &Path) -> Result<()> {
-    let path = unit_path(name);
-    let unit = generate_unit(name, binary, config);
-
-    std::fs::write(&path, &unit)
-        .with_context(|| format!("failed to write unit file to {}", path.display()))?;
-    println!("Wrote {}", path.display());
-
-    let status = Command::new("systemctl")
-        .args(["daemon-reload"])
-        .status()
-        .context("failed to run systemctl daemon-reload")?;
-    if !status.success() {
-        anyhow::bail!("systemctl daemon-reload failed");
-    }
+pub fn files(
+    name: &str,
+    binary: &Path,
+    config: &Path,
+    options: &InstallOptions,
+) -> Vec<ServiceFile> {
+    vec![ServiceFile {
+        path: unit_path(name),
+        content: generate_unit(name, binary, config, options),
+        executable: false,
+    }]
+}
 
-    let status = Command::new("systemctl")
-        .args(["enable", name])
-        .status()
-        .context("failed to run systemctl enable")?;
-    if !status.success() {
-        anyhow::bail!("systemctl enable {name} failed");
+pub fn install(name: &str, binary: &Path, config: &Path, options: &InstallOptions) -> Result<()> {
+    let change = super::write_files(&files(name, binary, config, options))?;
+    if change != Change::Unchanged {
+        super::run("systemctl", ["daemon-reload"])?;
+    }
+    // Enabling again is a no-op, and repairs a unit disabled by hand
+    super::run("systemctl", ["enable", "--quiet", name])?;
+
+    match change {
+        Change::Created => {
+            println!("Service {name} enabled. Start it with: sudo systemctl start {name}")
+        }
+        Change::Updated => {
+            super::run("systemctl", ["try-restart", name])?;
+            println!("Service {name} updated (restarted if it was running)");
+        }
+        Change::Unchanged => println!("Service {name} is up to date"),
     }
-
-    println!("Service {name} enabled. Start it with: sudo systemctl start {name}");
     Ok(())
 }
 
@@ -82,6 +140,27 @@ pub fn uninstall(name: &str) -> Result<()> {
     Ok(())
 }
 
+/// Directories a `--hardened` unit gets for its state and logs.
+pub fn data_paths(name: &str) -> Vec<PathBuf> {
+    vec![
+        PathBuf::from(format!("/var/lib/{name}")),
+        PathBuf::from(format!("/var/log/{name}")),
+    ]
+}
+
+pub fn control(name: &str, action: super::Control) -> Result<()> {
+    super::run("systemctl", [action.verb(), name])
+}
+
+pub fn logs(name: &str, lines: usize, follow: bool) -> Result<()> {
+    let lines = lines.to_string();
+    let mut args = vec!["-u", name, "-n", &lines];
+    if follow {
+        args.push("-f");
+    }
+    super::run("journalctl", args)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -93,19 +172,64 @@ mod tests {
             "leshy",
             Path::new("/usr/local/bin/leshy"),
             Path::new("/etc/leshy/config.toml"),
+            &InstallOptions::default(),
         );
         assert!(unit.contains("CAP_NET_ADMIN"));
         assert!(unit.contains("CAP_NET_BIND_SERVICE"));
         assert!(unit.contains("/usr/local/bin/leshy /etc/leshy/config.toml"));
     }
 
+    #[test]
+    fn unit_file_uses_notify_and_watchdog() {
+        let unit = generate_unit(
+            "leshy",
+            Path::new("/usr/local/bin/leshy"),
+            Path::new("/etc/leshy/config.toml"),
+            &InstallOptions::default(),
+        );
+        assert!(unit.contains("Type=notify"));
+        assert!(unit.contains("WatchdogSec="));
+    }
+
     #[test]
     fn custom_name_in_unit_description() {
         let unit = generate_unit(
             "leshy-corp",
             Path::new("/usr/local/bin/leshy"),
             Path::new("/etc/leshy/corp.toml"),
+            &InstallOptions::default(),
         );
         assert!(unit.contains("Description=leshy-corp"));
     }
+
+    #[test]
+    fn unit_file_takes_env_args_and_hardening() {
+        let options = InstallOptions {
+            env: vec![("RUST_LOG".to_string(), "leshy=debug".to_string())],
+            args: vec!["--profile".to_string(), "my office".to_string()],
+            hardened: true,
+        };
+        let unit = generate_unit(
+            "leshy",
+            Path::new("/usr/local/bin/leshy"),
+            Path::new("/etc/leshy/config.toml"),
+            &options,
+        );
+        assert!(unit.contains("Environment=RUST_LOG=leshy=debug\n"));
+        assert!(unit.contains(
+            "ExecStart=/usr/local/bin/leshy /etc/leshy/config.toml --profile \"my office\"\n"
+        ));
+        assert!(unit.contains("NoNewPrivileges=yes"));
+        assert!(unit.contains("ProtectSystem=strict"));
+        assert!(unit.contains("RuntimeDirectory=leshy"));
+        assert!(!generate_unit(
+            "leshy",
+            Path::new("/usr/local/bin/leshy"),
+            Path::new("/etc/leshy/config.toml"),
+            &InstallOptions::default(),
+        )
+        .contains("ProtectSystem"));
+        assert_eq!(unit_quote("100%"), "100%%");
+        assert_eq!(unit_quote("a \"b\""), "\"a \\\"b\\\"\"");
+    }
 }
@@ -1,22 +1,84 @@
+use super::{Change, InstallOptions, ServiceFile};
 use anyhow::{Context, Result};
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 fn plist_label(name: &str) -> String {
     format!("com.{name}.server")
 }
 
-fn plist_path(name: &str) -> PathBuf {
-    PathBuf::from(format!(
-        "/Library/LaunchDaemons/{}.plist",
-        plist_label(name)
-    ))
+/// A LaunchDaemon, run as root from boot, or with `user` a LaunchAgent,
+/// run as the installing user from login.
+fn plist_dir(user: bool) -> Result<PathBuf> {
+    if user {
+        Ok(super::home_dir()?.join("Library/LaunchAgents"))
+    } else {
+        Ok(PathBuf::from("/Library/LaunchDaemons"))
+    }
+}
+
+fn plist_path(name: &str, user: bool) -> Result<PathBuf> {
+    Ok(plist_dir(user)?.join(format!("{}.plist", plist_label(name))))
 }
 
-fn generate_plist(name: &str, binary: &Path, config: &Path) -> String {
+/// The launchd domain the service is loaded in: the system's, or the
+/// current user's GUI session.
+fn domain(user: bool) -> String {
+    if user {
+        // SAFETY: getuid never fails
+        format!("gui/{}", unsafe { libc::getuid() })
+    } else {
+        "system".to_string()
+    }
+}
+
+fn log_dir(user: bool) -> Result<PathBuf> {
+    if user {
+        Ok(super::home_dir()?.join("Library/Logs"))
+    } else {
+        Ok(PathBuf::from("/var/log"))
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn generate_plist(
+    name: &str,
+    binary: &Path,
+    config: &Path,
+    log_dir: &Path,
+    options: &InstallOptions,
+) -> String {
     let label = plist_label(name);
-    let binary = binary.display();
-    let config = config.display();
+    let binary = xml_escape(&binary.display().to_string());
+    let config = xml_escape(&config.display().to_string());
+    let log_dir = xml_escape(&log_dir.display().to_string());
+    let args: String = options
+        .args
+        .iter()
+        .map(|arg| format!("\n        <string>{}</string>", xml_escape(arg)))
+        .collect();
+    let environment = if options.env.is_empty() {
+        String::new()
+    } else {
+        let variables: String = options
+            .env
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    "\n        <key>{key}</key>\n        <string>{}</string>",
+                    xml_escape(value)
+                )
+            })
+            .collect();
+        format!("\n    <key>EnvironmentVariables</key>\n    <dict>{variables}\n    </dict>")
+    };
     format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
 <!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
@@ -27,48 +89,76 @@ fn generate_plist(name: &str, binary: &Path, config: &Path) -> String {
     <key>ProgramArguments</key>
     <array>
         <string>{binary}</string>
-        <string>{config}</string>
-    </array>
+        <string>{config}</string>{args}
+    </array>{environment}
     <key>RunAtLoad</key>
     <true/>
     <key>KeepAlive</key>
     <true/>
     <key>StandardOutPath</key>
-    <string>/var/log/{name}.log</string>
+    <string>{log_dir}/{name}.log</string>
     <key>StandardErrorPath</key>
-    <string>/var/log/{name}.err</string>
+    <string>{log_dir}/{name}.err</string>
 </dict>
 </plist>
 "#
     )
 }
 
-pub fn install(name: &str, binary: &Path, config: &Path) -> Result<()> {
-    let path = plist_path(name);
-    let plist = generate_plist(name, binary, config);
-
-    std::fs::write(&path, &plist)
-        .with_context(|| format!("failed to write plist to {}", path.display()))?;
-    println!("Wrote {}", path.display());
-
-    let status = Command::new("launchctl")
-        .args(["load", "-w"])
-        .arg(&path)
-        .status()
-        .context("failed to run launchctl load")?;
-    if !status.success() {
-        anyhow::bail!("launchctl load failed");
-    }
+pub fn files(
+    name: &str,
+    binary: &Path,
+    config: &Path,
+    user: bool,
+    options: &InstallOptions,
+) -> Result<Vec<ServiceFile>> {
+    Ok(vec![ServiceFile {
+        path: plist_path(name, user)?,
+        content: generate_plist(name, binary, config, &log_dir(user)?, options),
+        executable: false,
+    }])
+}
+
+pub fn install(
+    name: &str,
+    binary: &Path,
+    config: &Path,
+    user: bool,
+    options: &InstallOptions,
+) -> Result<()> {
+    let path = plist_path(name, user)?;
+    let change = super::write_files(&files(name, binary, config, user, options)?)?;
 
-    println!(
-        "Service {} loaded. It will start automatically.",
-        plist_label(name)
-    );
+    match change {
+        Change::Created => {
+            super::run(
+                "launchctl",
+                [OsStr::new("load"), "-w".as_ref(), path.as_ref()],
+            )?;
+            println!(
+                "Service {} loaded. It will start automatically.",
+                plist_label(name)
+            );
+        }
+        Change::Updated => {
+            // launchd reads the plist when it is loaded
+            let _ = Command::new("launchctl")
+                .args(["unload"])
+                .arg(&path)
+                .status();
+            super::run(
+                "launchctl",
+                [OsStr::new("load"), "-w".as_ref(), path.as_ref()],
+            )?;
+            println!("Service {} updated and reloaded", plist_label(name));
+        }
+        Change::Unchanged => println!("Service {} is up to date", plist_label(name)),
+    }
     Ok(())
 }
 
-pub fn uninstall(name: &str) -> Result<()> {
-    let path = plist_path(name);
+pub fn uninstall(name: &str, user: bool) -> Result<()> {
+    let path = plist_path(name, user)?;
 
     if path.exists() {
         let _ = Command::new("launchctl")
@@ -87,6 +177,55 @@ pub fn uninstall(name: &str) -> Result<()> {
     Ok(())
 }
 
+/// The stdout and stderr logs the plist points launchd at.
+pub fn data_paths(name: &str, user: bool) -> Result<Vec<PathBuf>> {
+    let dir = log_dir(user)?;
+    Ok(vec![
+        dir.join(format!("{name}.log")),
+        dir.join(format!("{name}.err")),
+    ])
+}
+
+pub fn control(name: &str, action: super::Control, user: bool) -> Result<()> {
+    let domain = domain(user);
+    let target = format!("{domain}/{}", plist_label(name));
+    match action {
+        super::Control::Status => super::run("launchctl", ["print", &target]),
+        super::Control::Start => {
+            // A stopped service is no longer loaded; a loaded one is started
+            // in place
+            let loaded = Command::new("launchctl")
+                .args(["print", &target])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success());
+            if loaded {
+                super::run("launchctl", ["kickstart", &target])
+            } else {
+                let path = plist_path(name, user)?;
+                super::run(
+                    "launchctl",
+                    [OsStr::new("bootstrap"), domain.as_ref(), path.as_ref()],
+                )
+            }
+        }
+        // Unloaded until the next boot (or login) rather than disabled,
+        // since KeepAlive would restart a merely killed process
+        super::Control::Stop => super::run("launchctl", ["bootout", &target]),
+        super::Control::Restart => super::run("launchctl", ["kickstart", "-k", &target]),
+    }
+}
+
+pub fn logs(name: &str, lines: usize, follow: bool, user: bool) -> Result<()> {
+    let dir = log_dir(user)?;
+    let files = [
+        dir.join(format!("{name}.log")),
+        dir.join(format!("{name}.err")),
+    ];
+    super::tail(&files, lines, follow)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,6 +237,8 @@ mod tests {
             "leshy",
             Path::new("/usr/local/bin/leshy"),
             Path::new("/etc/leshy/config.toml"),
+            Path::new("/var/log"),
+            &InstallOptions::default(),
         );
         assert!(plist.contains("<string>/usr/local/bin/leshy</string>"));
         assert!(plist.contains("<string>/etc/leshy/config.toml</string>"));
@@ -110,7 +251,47 @@ mod tests {
             "leshy-corp",
             Path::new("/usr/local/bin/leshy"),
             Path::new("/etc/leshy/corp.toml"),
+            Path::new("/var/log"),
+            &InstallOptions::default(),
         );
         assert!(plist.contains("com.leshy-corp.server"));
     }
+
+    #[test]
+    fn plist_takes_env_and_args() {
+        let options = InstallOptions {
+            env: vec![("RUST_LOG".to_string(), "leshy=debug".to_string())],
+            args: vec!["--profile".to_string(), "r&d".to_string()],
+            hardened: false,
+        };
+        let plist = generate_plist(
+            "leshy",
+            Path::new("/usr/local/bin/leshy"),
+            Path::new("/etc/leshy/config.toml"),
+            Path::new("/var/log"),
+            &options,
+        );
+        assert!(plist.contains(
+            "<string>/etc/leshy/config.toml</string>\n        <string>--profile</string>\n        <string>r&amp;d</string>\n    </array>"
+        ));
+        assert!(plist.contains(
+            "<key>EnvironmentVariables</key>\n    <dict>\n        <key>RUST_LOG</key>\n        <string>leshy=debug</string>\n    </dict>"
+        ));
+    }
+
+    #[test]
+    fn user_agent_logs_to_the_home_directory() {
+        let plist = generate_plist(
+            "leshy",
+            Path::new("/usr/local/bin/leshy"),
+            Path::new("/Users/dev/.config/leshy/config.toml"),
+            Path::new("/Users/dev/Library/Logs"),
+            &InstallOptions::default(),
+        );
+        assert!(plist.contains("<string>/Users/dev/Library/Logs/leshy.log</string>"));
+        assert!(plist.contains("<string>/Users/dev/.config/leshy/config.toml</string>"));
+        assert!(plist_path("leshy", false)
+            .unwrap()
+            .starts_with("/Library/LaunchDaemons"));
+    }
 }
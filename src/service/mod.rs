@@ -2,11 +2,22 @@
 mod linux;
 #[cfg(target_os = "macos")]
 mod macos;
+pub mod notify;
+#[cfg(target_os = "linux")]
+mod openrc;
+pub mod privileges;
+#[cfg(target_os = "linux")]
+mod runit;
 
-use anyhow::Result;
+use crate::config::{ConfigFiles, ServerConfig};
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 const DEFAULT_CONFIG: &str = "/etc/leshy/config.toml";
+/// Default config of a `--user` service, relative to the home directory
+const USER_CONFIG: &str = ".config/leshy/config.toml";
 const DEFAULT_NAME: &str = "leshy";
 const FALLBACK_BINARY: &str = "/usr/local/bin/leshy";
 
@@ -25,42 +36,763 @@ pub fn default_name() -> &'static str {
     DEFAULT_NAME
 }
 
-pub fn install(name: Option<&str>, config: Option<&Path>) -> Result<()> {
+fn home_dir() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("HOME is not set"))
+}
+
+/// Service managers `leshy service` can install into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InitSystem {
+    Systemd,
+    Launchd,
+    /// Alpine, Gentoo
+    Openrc,
+    /// Void
+    Runit,
+}
+
+impl InitSystem {
+    /// The service manager running this host, from the markers each one
+    /// leaves in /run (or, for OpenRC and runit, their install paths).
+    pub fn detect() -> Option<Self> {
+        if cfg!(target_os = "macos") {
+            return Some(Self::Launchd);
+        }
+        let exists = |path: &str| Path::new(path).exists();
+        if exists("/run/systemd/system") {
+            Some(Self::Systemd)
+        } else if exists("/run/openrc") || exists("/sbin/openrc-run") {
+            Some(Self::Openrc)
+        } else if exists("/run/runit") || exists("/etc/runit/runsvdir") {
+            Some(Self::Runit)
+        } else {
+            None
+        }
+    }
+}
+
+/// Extra settings for the generated service definition.
+#[derive(Debug, Default)]
+pub struct InstallOptions {
+    /// Environment variables of the process
+    pub env: Vec<(String, String)>,
+    /// Arguments passed after the config path
+    pub args: Vec<String>,
+    /// Add systemd sandboxing directives to the unit
+    pub hardened: bool,
+}
+
+/// A file of a generated service definition.
+pub struct ServiceFile {
+    pub path: PathBuf,
+    pub content: String,
+    pub executable: bool,
+}
+
+/// What writing a service definition did to the files on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    /// None of them existed
+    Created,
+    /// Some existed with other content
+    Updated,
+    Unchanged,
+}
+
+/// Write the files whose content differs from what is on disk, showing a
+/// diff for those that existed.
+fn write_files(files: &[ServiceFile]) -> Result<Change> {
+    let mut existed = false;
+    let mut changed = false;
+    for file in files {
+        let path = &file.path;
+        let current = std::fs::read_to_string(path).ok();
+        existed |= current.is_some();
+        if current.as_deref() == Some(file.content.as_str()) {
+            continue;
+        }
+        changed = true;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        std::fs::write(path, &file.content)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        if file.executable {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+                .with_context(|| format!("failed to make {} executable", path.display()))?;
+        }
+        match current {
+            Some(current) => {
+                println!("Updated {}:", path.display());
+                for line in diff_lines(&current, &file.content) {
+                    println!("  {line}");
+                }
+            }
+            None => println!("Wrote {}", path.display()),
+        }
+    }
+    Ok(match (existed, changed) {
+        (_, false) => Change::Unchanged,
+        (false, true) => Change::Created,
+        (true, true) => Change::Updated,
+    })
+}
+
+/// The lines removed from `old` (`-`) and added in `new` (`+`), in order.
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // common[i][j]: length of the longest common subsequence of old[i..]
+    // and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Parse a `KEY=VALUE` of `--env`.
+pub fn parse_env(value: &str) -> std::result::Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{value}'"))?;
+    let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("invalid environment variable name '{key}'"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// `word` as a single sh word: as is when nothing in it is special to the
+/// shell, else single-quoted.
+#[cfg(target_os = "linux")]
+fn shell_quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// `leshy service status|start|stop|restart`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Status,
+    Start,
+    Stop,
+    Restart,
+}
+
+impl Control {
+    /// The action as systemctl, rc-service and sv spell it.
+    #[cfg(target_os = "linux")]
+    fn verb(self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Start => "start",
+            Self::Stop => "stop",
+            Self::Restart => "restart",
+        }
+    }
+}
+
+/// `init` if given, else the detected service manager.
+fn select(init: Option<InitSystem>) -> Result<InitSystem> {
+    init.or_else(InitSystem::detect).ok_or_else(|| {
+        anyhow::anyhow!("could not detect the init system; pass --init systemd|openrc|runit")
+    })
+}
+
+/// Services installed with `--user` run as the installing user, so
+/// they're installed without sudo and only launchd supports them.
+fn check_user_service(init: InitSystem) -> Result<()> {
+    if init != InitSystem::Launchd {
+        anyhow::bail!("--user services are only supported with launchd (macOS)");
+    }
+    // SAFETY: geteuid never fails
+    if unsafe { libc::geteuid() } == 0 {
+        anyhow::bail!("--user services belong to the current user; run it without sudo");
+    }
+    Ok(())
+}
+
+/// Install the service, or with `print` only show the files it would
+/// write. Installing again over an existing service rewrites only what
+/// changed and restarts the service if it was.
+pub fn install(
+    name: Option<&str>,
+    config: Option<&Path>,
+    init: Option<InitSystem>,
+    user: bool,
+    options: &InstallOptions,
+    print: bool,
+) -> Result<()> {
     let name = name.unwrap_or(DEFAULT_NAME);
-    let config = config.unwrap_or_else(|| Path::new(DEFAULT_CONFIG));
     let binary = detect_binary();
+    let init = select(init)?;
+    if user {
+        check_user_service(init)?;
+    }
+    if options.hardened && init != InitSystem::Systemd {
+        anyhow::bail!("--hardened only applies to systemd units");
+    }
+    let config = match config {
+        Some(config) => config.to_path_buf(),
+        None if user => home_dir()?.join(USER_CONFIG),
+        None => PathBuf::from(DEFAULT_CONFIG),
+    };
+    let config = config.as_path();
+
+    if print {
+        let files = match init {
+            #[cfg(target_os = "linux")]
+            InitSystem::Systemd => linux::files(name, &binary, config, options),
+            #[cfg(target_os = "linux")]
+            InitSystem::Openrc => openrc::files(name, &binary, config, options),
+            #[cfg(target_os = "linux")]
+            InitSystem::Runit => runit::files(name, &binary, config, options),
+            #[cfg(target_os = "macos")]
+            InitSystem::Launchd => macos::files(name, &binary, config, user, options)?,
+            #[allow(unreachable_patterns)]
+            _ => anyhow::bail!("{init:?} services are not supported on this platform"),
+        };
+        for file in files {
+            println!("# {}\n{}", file.path.display(), file.content);
+        }
+        return Ok(());
+    }
 
+    if user {
+        warn_privileged_port(config);
+    }
     println!(
-        "Installing service '{name}' (binary: {}, config: {})",
+        "Installing {init:?} service '{name}' (binary: {}, config: {})",
         binary.display(),
         config.display()
     );
 
-    #[cfg(target_os = "linux")]
-    linux::install(name, &binary, config)?;
+    match init {
+        #[cfg(target_os = "linux")]
+        InitSystem::Systemd => linux::install(name, &binary, config, options)?,
+        #[cfg(target_os = "linux")]
+        InitSystem::Openrc => openrc::install(name, &binary, config, options)?,
+        #[cfg(target_os = "linux")]
+        InitSystem::Runit => runit::install(name, &binary, config, options)?,
+        #[cfg(target_os = "macos")]
+        InitSystem::Launchd => macos::install(name, &binary, config, user, options)?,
+        #[allow(unreachable_patterns)]
+        _ => anyhow::bail!("{init:?} services are not supported on this platform"),
+    }
+
+    Ok(())
+}
+
+/// Remove the service. With `purge`, also remove what it leaves behind:
+/// its logs and state, the config and a resolv.conf a crashed run didn't
+/// restore.
+pub fn uninstall(
+    name: Option<&str>,
+    config: Option<&Path>,
+    init: Option<InitSystem>,
+    user: bool,
+    purge: bool,
+) -> Result<()> {
+    let name = name.unwrap_or(DEFAULT_NAME);
+    let init = select(init)?;
+    if user {
+        check_user_service(init)?;
+    }
+
+    println!("Uninstalling {init:?} service '{name}'");
 
-    #[cfg(target_os = "macos")]
-    macos::install(name, &binary, config)?;
+    match init {
+        #[cfg(target_os = "linux")]
+        InitSystem::Systemd => linux::uninstall(name)?,
+        #[cfg(target_os = "linux")]
+        InitSystem::Openrc => openrc::uninstall(name)?,
+        #[cfg(target_os = "linux")]
+        InitSystem::Runit => runit::uninstall(name)?,
+        #[cfg(target_os = "macos")]
+        InitSystem::Launchd => macos::uninstall(name, user)?,
+        #[allow(unreachable_patterns)]
+        _ => anyhow::bail!("{init:?} services are not supported on this platform"),
+    }
 
-    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-    anyhow::bail!("service install is not supported on this platform");
+    // Written with `macos_resolvers`; there is no such directory elsewhere,
+    // and a user service can't have written them
+    if !user {
+        let dir = Path::new(crate::resolv::RESOLVER_DIR);
+        let removed = crate::resolv::remove_resolver_files(dir)?;
+        if removed > 0 {
+            println!("Removed {removed} resolver files from {}", dir.display());
+        }
+    }
 
+    if purge {
+        let config = match config {
+            Some(config) => config.to_path_buf(),
+            None if user => home_dir()?.join(USER_CONFIG),
+            None => PathBuf::from(DEFAULT_CONFIG),
+        };
+        purge_files(name, init, user, &config)?;
+    }
     Ok(())
 }
 
-pub fn uninstall(name: Option<&str>) -> Result<()> {
-    let name = name.unwrap_or(DEFAULT_NAME);
+/// Remove the files of an uninstalled service: the service manager's logs,
+/// the `state_file` and `query_log` of `config_path`, and the config.
+fn purge_files(name: &str, init: InitSystem, user: bool, config_path: &Path) -> Result<()> {
+    // The service is stopped, so this is only left by a crash
+    let resolv_conf = Path::new(crate::resolv::RESOLV_CONF);
+    if !user && crate::resolv::restore_leftover(resolv_conf)? {
+        println!("Restored {} left by a previous run", resolv_conf.display());
+    }
 
-    println!("Uninstalling service '{name}'");
+    let mut paths = match init {
+        #[cfg(target_os = "linux")]
+        InitSystem::Systemd => linux::data_paths(name),
+        #[cfg(target_os = "linux")]
+        InitSystem::Openrc => openrc::data_paths(name),
+        #[cfg(target_os = "linux")]
+        InitSystem::Runit => runit::data_paths(name),
+        #[cfg(target_os = "macos")]
+        InitSystem::Launchd => macos::data_paths(name, user)?,
+        #[allow(unreachable_patterns)]
+        _ => Vec::new(),
+    };
+    let files = match ConfigFiles::read(config_path) {
+        Ok(files) => Some(files),
+        Err(e) if config_path.exists() => {
+            println!(
+                "Warning: failed to load {}, leaving the files it names: {e:#}",
+                config_path.display()
+            );
+            None
+        }
+        Err(_) => None,
+    };
+    let written = files
+        .as_ref()
+        .map(|files| written_files(&files.server))
+        .unwrap_or_default();
+    paths.extend(config_paths(
+        config_path,
+        files.as_ref(),
+        &written,
+        &default_config_dirs(),
+    ));
+    paths.extend(written);
 
-    #[cfg(target_os = "linux")]
-    linux::uninstall(name)?;
+    for path in paths {
+        remove_path(&path)?;
+    }
+    Ok(())
+}
+
+/// The files leshy writes while running with `server`: the route state
+/// and the query log with its rotations.
+fn written_files(server: &ServerConfig) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(state) = &server.state_file {
+        files.push(state.clone());
+        files.push(state.with_extension("tmp"));
+    }
+    if let Some(log) = &server.query_log {
+        files.push(log.clone());
+        files.extend(rotated_logs(log));
+    }
+    files
+}
+
+/// `<log>.1`, `<log>.2`, ... next to `log`.
+fn rotated_logs(log: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (log.parent(), log.file_name()) else {
+        return Vec::new();
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let prefix = format!("{}.", name.to_string_lossy());
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut rotated: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.strip_prefix(&prefix)
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    rotated.sort();
+    rotated
+}
+
+/// The config to remove: its whole directory when that is one of
+/// `default_dirs` and holds nothing but the config's files and those leshy
+/// writes (`written`), otherwise the file and the files it includes.
+fn config_paths(
+    config: &Path,
+    files: Option<&ConfigFiles>,
+    written: &[PathBuf],
+    default_dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    let included = files.map_or(&[][..], |files| &files.included[..]);
+    let dir = config.parent().and_then(|dir| dir.canonicalize().ok());
+    if let (Some(dir), Some(files)) = (dir, files) {
+        if default_dirs
+            .iter()
+            .any(|default| default.canonicalize().ok() == Some(dir.clone()))
+        {
+            let owned: Vec<PathBuf> = std::iter::once(config.to_path_buf())
+                .chain(files.included.iter().cloned())
+                .chain(files.config_dir.iter().cloned())
+                .chain(written.iter().cloned())
+                .filter_map(|path| path.canonicalize().ok())
+                .collect();
+            if only_holds(&dir, &owned) {
+                return vec![dir];
+            }
+        }
+    }
+    std::iter::once(config.to_path_buf())
+        .chain(included.iter().cloned())
+        .collect()
+}
+
+/// /etc/leshy and the user's $XDG_CONFIG_HOME/leshy (~/.config/leshy).
+fn default_config_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Path::new(DEFAULT_CONFIG)
+        .parent()
+        .map(Path::to_path_buf)
+        .into_iter()
+        .collect();
+    let user_config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().ok().map(|home| home.join(".config")));
+    dirs.extend(user_config.map(|dir| dir.join(DEFAULT_NAME)));
+    dirs
+}
+
+/// Whether every file under `dir` is one of `owned`.
+fn only_holds(dir: &Path, owned: &[PathBuf]) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    entries.into_iter().all(|entry| {
+        let Ok(entry) = entry else {
+            return false;
+        };
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => only_holds(&path, owned),
+            Ok(_) => owned.contains(&path),
+            Err(_) => false,
+        }
+    })
+}
+
+/// Remove a file or directory tree, if it is there.
+fn remove_path(path: &Path) -> Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if metadata.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("failed to remove {}", path.display()))?;
+    println!("Removed {}", path.display());
+    Ok(())
+}
+
+/// Show, start, stop or restart an installed service through its
+/// service manager.
+pub fn control(
+    name: Option<&str>,
+    init: Option<InitSystem>,
+    user: bool,
+    action: Control,
+) -> Result<()> {
+    let name = name.unwrap_or(DEFAULT_NAME);
+    let init = select(init)?;
+    if user {
+        check_user_service(init)?;
+    }
+
+    match init {
+        #[cfg(target_os = "linux")]
+        InitSystem::Systemd => linux::control(name, action),
+        #[cfg(target_os = "linux")]
+        InitSystem::Openrc => openrc::control(name, action),
+        #[cfg(target_os = "linux")]
+        InitSystem::Runit => runit::control(name, action),
+        #[cfg(target_os = "macos")]
+        InitSystem::Launchd => macos::control(name, action, user),
+        #[allow(unreachable_patterns)]
+        _ => anyhow::bail!("{init:?} services are not supported on this platform"),
+    }
+}
 
-    #[cfg(target_os = "macos")]
-    macos::uninstall(name)?;
+/// Print the last `lines` lines of an installed service's log, then keep
+/// printing new ones with `follow`.
+pub fn logs(
+    name: Option<&str>,
+    init: Option<InitSystem>,
+    user: bool,
+    lines: usize,
+    follow: bool,
+) -> Result<()> {
+    let name = name.unwrap_or(DEFAULT_NAME);
+    let init = select(init)?;
+    if user {
+        check_user_service(init)?;
+    }
 
-    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-    anyhow::bail!("service uninstall is not supported on this platform");
+    match init {
+        #[cfg(target_os = "linux")]
+        InitSystem::Systemd => linux::logs(name, lines, follow),
+        #[cfg(target_os = "linux")]
+        InitSystem::Openrc => openrc::logs(name, lines, follow),
+        #[cfg(target_os = "linux")]
+        InitSystem::Runit => runit::logs(name, lines, follow),
+        #[cfg(target_os = "macos")]
+        InitSystem::Launchd => macos::logs(name, lines, follow, user),
+        #[allow(unreachable_patterns)]
+        _ => anyhow::bail!("{init:?} services are not supported on this platform"),
+    }
+}
 
+/// Run a service manager command with the terminal attached.
+fn run<I, S>(program: &str, args: I) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("failed to run {program}"))?;
+    if !status.success() {
+        anyhow::bail!("{program} failed ({status})");
+    }
     Ok(())
 }
+
+/// `tail` arguments printing the last `lines` lines of `files`; `-F`
+/// keeps following them across rotation.
+fn tail_args(files: &[PathBuf], lines: usize, follow: bool) -> Vec<String> {
+    let mut args = vec!["-n".to_string(), lines.to_string()];
+    if follow {
+        args.push("-F".to_string());
+    }
+    args.extend(files.iter().map(|file| file.display().to_string()));
+    args
+}
+
+fn tail(files: &[PathBuf], lines: usize, follow: bool) -> Result<()> {
+    run("tail", tail_args(files, lines, follow))
+}
+
+/// A user service can't bind ports below 1024, e.g. the default 53.
+fn warn_privileged_port(config: &Path) {
+    let Ok(config) = crate::config::Config::from_file_with_includes(config) else {
+        return;
+    };
+    let port = config.server.listen_address.port();
+    if port < 1024 {
+        println!(
+            "Warning: listen_address uses port {port}, which needs root; \
+             use a port above 1024 for a --user service"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_changed_lines() {
+        let old = "[Service]\nExecStart=/usr/bin/leshy a.toml\nRestart=on-failure\n";
+        let new =
+            "[Service]\nEnvironment=A=1\nExecStart=/usr/bin/leshy b.toml\nRestart=on-failure\n";
+        assert_eq!(
+            diff_lines(old, new),
+            [
+                "-ExecStart=/usr/bin/leshy a.toml",
+                "+Environment=A=1",
+                "+ExecStart=/usr/bin/leshy b.toml"
+            ]
+        );
+        assert!(diff_lines(new, new).is_empty());
+    }
+
+    #[test]
+    fn rewrites_only_changed_files() {
+        let dir = std::env::temp_dir().join(format!("leshy-service-{}", std::process::id()));
+        let file = |content: &str| ServiceFile {
+            path: dir.join("sv/run"),
+            content: content.to_string(),
+            executable: true,
+        };
+        assert_eq!(write_files(&[file("a\n")]).unwrap(), Change::Created);
+        assert_eq!(write_files(&[file("a\n")]).unwrap(), Change::Unchanged);
+        assert_eq!(write_files(&[file("b\n")]).unwrap(), Change::Updated);
+        assert_eq!(std::fs::read_to_string(dir.join("sv/run")).unwrap(), "b\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn purges_leshy_config_directory_only() {
+        let root = tempfile::tempdir().unwrap();
+        let write = |path: &Path, content: &str| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        let config = |dir: &Path| {
+            let config = dir.join("config.toml");
+            write(
+                &config,
+                "include = [\"zones.toml\"]\n[server]\nlisten_address = \"127.0.0.1:53\"\n\
+                 default_upstream = [\"1.1.1.1:53\"]\nstate_file = \"state.json\"\n",
+            );
+            write(&dir.join("zones.toml"), "include = [\"more.toml\"]\n");
+            write(&dir.join("more.toml"), "");
+            write(&dir.join("config.d/corp.toml"), "");
+            write(&dir.join("state.json"), "{}");
+            config
+        };
+        let paths = |config: &Path, defaults: &[PathBuf]| {
+            let files = ConfigFiles::read(config).unwrap();
+            let written = [config.with_file_name("state.json")];
+            config_paths(config, Some(&files), &written, defaults)
+        };
+
+        let leshy = root.path().join("leshy");
+        let leshy_config = config(&leshy);
+        let defaults = [leshy.clone()];
+        let whole_dir = vec![leshy.canonicalize().unwrap()];
+        assert_eq!(paths(&leshy_config, &defaults), whole_dir);
+
+        // A file leshy doesn't know of keeps the directory
+        write(&leshy.join("config.d/notes.txt"), "");
+        let canonical = |name: &str| leshy.join(name).canonicalize().unwrap();
+        let config_only = vec![
+            leshy_config.clone(),
+            canonical("zones.toml"),
+            canonical("more.toml"),
+        ];
+        assert_eq!(paths(&leshy_config, &defaults), config_only);
+
+        // Nor is a directory merely named after leshy removed, e.g. a checkout
+        let checkout = root.path().join("src/leshy");
+        let checkout_config = config(&checkout);
+        assert_eq!(
+            paths(&checkout_config, &defaults),
+            vec![
+                checkout_config.clone(),
+                checkout.join("zones.toml").canonicalize().unwrap(),
+                checkout.join("more.toml").canonicalize().unwrap(),
+            ]
+        );
+        assert_eq!(
+            config_paths(&checkout_config, None, &[], &defaults),
+            [checkout_config]
+        );
+    }
+
+    #[test]
+    fn finds_rotated_query_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("queries.jsonl");
+        for name in [
+            "queries.jsonl",
+            "queries.jsonl.1",
+            "queries.jsonl.2",
+            "queries.jsonl.bak",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        assert_eq!(
+            rotated_logs(&log),
+            [
+                dir.path().join("queries.jsonl.1"),
+                dir.path().join("queries.jsonl.2")
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_env_assignments() {
+        assert_eq!(
+            parse_env("RUST_LOG=leshy=debug").unwrap(),
+            ("RUST_LOG".to_string(), "leshy=debug".to_string())
+        );
+        assert!(parse_env("RUST_LOG").is_err());
+        assert!(parse_env("1X=y").is_err());
+        assert!(parse_env("A-B=y").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn quotes_shell_words() {
+        assert_eq!(
+            shell_quote("/etc/leshy/config.toml"),
+            "/etc/leshy/config.toml"
+        );
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn tails_log_files() {
+        let files = [PathBuf::from("/var/log/leshy.log")];
+        assert_eq!(
+            tail_args(&files, 50, false),
+            ["-n", "50", "/var/log/leshy.log"]
+        );
+        assert_eq!(
+            tail_args(&files, 10, true),
+            ["-n", "10", "-F", "/var/log/leshy.log"]
+        );
+    }
+}
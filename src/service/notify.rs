@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+/// Tell systemd the server is up (`Type=notify` units wait for this).
+pub fn ready() {
+    send("READY=1");
+}
+
+/// Tell systemd the server is alive; systemd restarts it when pings stop
+/// for longer than `WatchdogSec`.
+pub fn watchdog() {
+    send("WATCHDOG=1");
+}
+
+/// Tell systemd the server is shutting down.
+pub fn stopping() {
+    send("STOPPING=1");
+}
+
+/// How often systemd expects a watchdog ping; None if the watchdog is off
+/// or meant for another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok()?;
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(target_os = "linux")]
+fn send(state: &str) {
+    // Not started by systemd (or not as a notify unit)
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = notify(&socket, state) {
+        tracing::warn!(state, error = %e, "Failed to notify systemd");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_state: &str) {}
+
+/// Send `state` to the notification socket at `socket`; a leading `@`
+/// names an abstract socket.
+#[cfg(target_os = "linux")]
+fn notify(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_from_env() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        // Meant for another process, disabled or unset
+        assert_eq!(parse_watchdog(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(None, Some("42"), 42), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        notify(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
@@ -0,0 +1,175 @@
+use super::{shell_quote, Change, InstallOptions, ServiceFile};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn script_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("/etc/init.d/{name}"))
+}
+
+fn log_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("/var/log/{name}.log"))
+}
+
+/// `value` inside a double-quoted sh string.
+fn double_quote_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn generate_script(name: &str, binary: &Path, config: &Path, options: &InstallOptions) -> String {
+    let binary = double_quote_escape(&binary.display().to_string());
+    // openrc-run evals command_args, so its words are shell-quoted too
+    let command_args = std::iter::once(config.display().to_string())
+        .chain(options.args.iter().cloned())
+        .map(|word| shell_quote(&word))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let command_args = double_quote_escape(&command_args);
+    let exports: String = options
+        .env
+        .iter()
+        .map(|(key, value)| format!("export {key}={}\n", shell_quote(value)))
+        .collect();
+    let log = log_path(name);
+    let log = log.display();
+    format!(
+        "\
+#!/sbin/openrc-run
+
+description=\"{name} DNS-driven split-tunnel router\"
+{exports}command=\"{binary}\"
+command_args=\"{command_args}\"
+supervisor=supervise-daemon
+respawn_delay=5
+output_log=\"{log}\"
+error_log=\"{log}\"
+
+depend() {{
+\tneed net
+\tuse logger
+\tbefore dnsmasq
+}}
+"
+    )
+}
+
+pub fn files(
+    name: &str,
+    binary: &Path,
+    config: &Path,
+    options: &InstallOptions,
+) -> Vec<ServiceFile> {
+    vec![ServiceFile {
+        path: script_path(name),
+        content: generate_script(name, binary, config, options),
+        executable: true,
+    }]
+}
+
+pub fn install(name: &str, binary: &Path, config: &Path, options: &InstallOptions) -> Result<()> {
+    let change = super::write_files(&files(name, binary, config, options))?;
+    let enabled = Command::new("rc-update")
+        .args(["show", "default"])
+        .output()
+        .is_ok_and(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.split_whitespace().next() == Some(name))
+        });
+    if !enabled {
+        super::run("rc-update", ["add", name, "default"])?;
+    }
+
+    match change {
+        Change::Created => {
+            println!("Service {name} enabled. Start it with: sudo rc-service {name} start")
+        }
+        Change::Updated => {
+            super::run("rc-service", ["--ifstarted", name, "restart"])?;
+            println!("Service {name} updated (restarted if it was running)");
+        }
+        Change::Unchanged => println!("Service {name} is up to date"),
+    }
+    Ok(())
+}
+
+pub fn uninstall(name: &str) -> Result<()> {
+    let path = script_path(name);
+
+    // Stop and disable (best-effort)
+    let _ = Command::new("rc-service").args([name, "stop"]).status();
+    let _ = Command::new("rc-update")
+        .args(["del", name, "default"])
+        .status();
+
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .with_context(|| format!("failed to remove {}", path.display()))?;
+        println!("Removed {}", path.display());
+    } else {
+        println!(
+            "Init script {} does not exist, nothing to remove",
+            path.display()
+        );
+    }
+
+    println!("Service {name} uninstalled");
+    Ok(())
+}
+
+/// The log supervise-daemon writes the service output to.
+pub fn data_paths(name: &str) -> Vec<PathBuf> {
+    vec![log_path(name)]
+}
+
+pub fn control(name: &str, action: super::Control) -> Result<()> {
+    super::run("rc-service", [name, action.verb()])
+}
+
+pub fn logs(name: &str, lines: usize, follow: bool) -> Result<()> {
+    super::tail(&[log_path(name)], lines, follow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_is_supervised_and_runs_after_the_network() {
+        let script = generate_script(
+            "leshy-corp",
+            Path::new("/usr/bin/leshy"),
+            Path::new("/etc/leshy/corp.toml"),
+            &InstallOptions::default(),
+        );
+        assert!(script.starts_with("#!/sbin/openrc-run\n"));
+        assert!(script.contains("command=\"/usr/bin/leshy\""));
+        assert!(script.contains("command_args=\"/etc/leshy/corp.toml\""));
+        assert!(script.contains("supervisor=supervise-daemon"));
+        assert!(script.contains("need net"));
+        assert!(script.contains("/var/log/leshy-corp.log"));
+
+        let options = InstallOptions {
+            env: vec![("RUST_LOG".to_string(), "leshy=debug".to_string())],
+            args: vec!["--profile".to_string(), "my $office".to_string()],
+            hardened: false,
+        };
+        let script = generate_script(
+            "leshy",
+            Path::new("/usr/bin/leshy"),
+            Path::new("/etc/leshy/config.toml"),
+            &options,
+        );
+        assert!(script.contains("export RUST_LOG=leshy=debug\n"));
+        assert!(
+            script.contains("command_args=\"/etc/leshy/config.toml --profile 'my \\$office'\"\n")
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::Result;
+
+/// Switch to `user` (and `group`, else the user's primary group), keeping
+/// only CAP_NET_ADMIN, raised as an ambient capability so the `ip` and
+/// `route` commands leshy runs get it too. Capabilities are per thread:
+/// call this before any other thread is started.
+#[cfg(target_os = "linux")]
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<()> {
+    use anyhow::Context;
+    use caps::{CapSet, Capability, CapsHashSet};
+    use std::ffi::CString;
+
+    let account = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => account.gid,
+    };
+    // SAFETY: getuid/geteuid never fail
+    let (uid, euid) = unsafe { (libc::getuid(), libc::geteuid()) };
+    if euid != 0 {
+        if uid == account.uid && euid == account.uid {
+            return Ok(());
+        }
+        anyhow::bail!("switching to user '{user}' needs root");
+    }
+
+    let keep = CapsHashSet::from([Capability::CAP_NET_ADMIN]);
+    let cap_error = |what: &str| format!("failed to {what} capabilities");
+    for cap in caps::read(None, CapSet::Bounding).with_context(|| cap_error("read"))? {
+        if !keep.contains(&cap) {
+            caps::drop(None, CapSet::Bounding, cap).with_context(|| cap_error("bound"))?;
+        }
+    }
+
+    let name = CString::new(user)?;
+    // SAFETY: plain syscalls; `name` outlives initgroups
+    unsafe {
+        check(
+            libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0),
+            "keep capabilities",
+        )?;
+        check(
+            libc::initgroups(name.as_ptr(), gid),
+            "set supplementary groups",
+        )?;
+        check(libc::setgid(gid), "set group")?;
+        check(libc::setuid(account.uid), "set user")?;
+        check(
+            libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0),
+            "reset keep capabilities",
+        )?;
+    }
+
+    for set in [CapSet::Effective, CapSet::Inheritable, CapSet::Permitted] {
+        caps::set(None, set, &keep).with_context(|| cap_error("set"))?;
+    }
+    caps::raise(None, CapSet::Ambient, Capability::CAP_NET_ADMIN)
+        .with_context(|| cap_error("raise ambient"))?;
+
+    tracing::info!(
+        user,
+        uid = account.uid,
+        gid,
+        "Dropped privileges, keeping CAP_NET_ADMIN"
+    );
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_to(_user: &str, _group: Option<&str>) -> Result<()> {
+    anyhow::bail!("user/group are only supported on Linux")
+}
+
+#[cfg(target_os = "linux")]
+fn check(result: libc::c_int, what: &str) -> Result<()> {
+    if result != 0 {
+        let error = std::io::Error::last_os_error();
+        anyhow::bail!("failed to {what}: {error}");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Account {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+/// Buffer for the strings getpwnam_r/getgrnam_r return.
+#[cfg(target_os = "linux")]
+const LOOKUP_BUFFER: usize = 16 * 1024;
+
+#[cfg(target_os = "linux")]
+fn lookup_user(user: &str) -> Result<Account> {
+    let name = std::ffi::CString::new(user)?;
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    // SAFETY: passwd is plain data; getpwnam_r fills it from `buf`
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let result = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if result != 0 {
+        let error = std::io::Error::from_raw_os_error(result);
+        anyhow::bail!("failed to look up user '{user}': {error}");
+    }
+    if found.is_null() {
+        anyhow::bail!("user '{user}' does not exist");
+    }
+    Ok(Account {
+        uid: entry.pw_uid,
+        gid: entry.pw_gid,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group)?;
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    // SAFETY: group is plain data; getgrnam_r fills it from `buf`
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let result = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if result != 0 {
+        let error = std::io::Error::from_raw_os_error(result);
+        anyhow::bail!("failed to look up group '{group}': {error}");
+    }
+    if found.is_null() {
+        anyhow::bail!("group '{group}' does not exist");
+    }
+    Ok(entry.gr_gid)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_accounts() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+
+        let error = lookup_user("no-such-leshy-user").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "user 'no-such-leshy-user' does not exist"
+        );
+        assert!(lookup_group("no-such-leshy-group").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use super::{shell_quote, Change, InstallOptions, ServiceFile};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where service definitions live; enabled ones are linked from the
+/// directory runsvdir watches.
+const SV_DIR: &str = "/etc/sv";
+
+/// Directories runsvdir watches on Void (`/var/service`) and most other
+/// runit setups.
+const SERVICE_DIRS: [&str; 3] = ["/var/service", "/etc/service", "/service"];
+
+fn service_dir(name: &str) -> PathBuf {
+    Path::new(SV_DIR).join(name)
+}
+
+fn enabled_link(name: &str) -> Result<PathBuf> {
+    SERVICE_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|dir| dir.is_dir())
+        .map(|dir| dir.join(name))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "no runit service directory found (tried {})",
+                SERVICE_DIRS.join(", ")
+            )
+        })
+}
+
+fn generate_run(binary: &Path, config: &Path, options: &InstallOptions) -> String {
+    let command = [binary.display().to_string(), config.display().to_string()]
+        .into_iter()
+        .chain(options.args.iter().cloned())
+        .map(|word| shell_quote(&word))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let exports: String = options
+        .env
+        .iter()
+        .map(|(key, value)| format!("export {key}={}\n", shell_quote(value)))
+        .collect();
+    format!(
+        "\
+#!/bin/sh
+exec 2>&1
+{exports}exec {command}
+"
+    )
+}
+
+fn generate_log_run(name: &str) -> String {
+    format!(
+        "\
+#!/bin/sh
+mkdir -p /var/log/{name}
+exec svlogd -tt /var/log/{name}
+"
+    )
+}
+
+pub fn files(
+    name: &str,
+    binary: &Path,
+    config: &Path,
+    options: &InstallOptions,
+) -> Vec<ServiceFile> {
+    let dir = service_dir(name);
+    vec![
+        ServiceFile {
+            path: dir.join("run"),
+            content: generate_run(binary, config, options),
+            executable: true,
+        },
+        ServiceFile {
+            path: dir.join("log/run"),
+            content: generate_log_run(name),
+            executable: true,
+        },
+    ]
+}
+
+pub fn install(name: &str, binary: &Path, config: &Path, options: &InstallOptions) -> Result<()> {
+    let dir = service_dir(name);
+    let link = enabled_link(name)?;
+    let change = super::write_files(&files(name, binary, config, options))?;
+
+    // runsvdir starts the service within a few seconds of the link appearing
+    let linked = link.symlink_metadata().is_ok();
+    if !linked {
+        std::os::unix::fs::symlink(&dir, &link)
+            .with_context(|| format!("failed to link {}", link.display()))?;
+    }
+
+    match change {
+        Change::Updated if linked => {
+            // runsv runs the new script from the next start on
+            super::run("sv", ["restart", name])?;
+            println!("Service {name} updated and restarted");
+        }
+        Change::Unchanged if linked => println!("Service {name} is up to date"),
+        _ => println!(
+            "Service {name} enabled through {}. Check it with: sudo sv status {name}",
+            link.display()
+        ),
+    }
+    Ok(())
+}
+
+pub fn uninstall(name: &str) -> Result<()> {
+    let dir = service_dir(name);
+
+    // Stop (best-effort), then unlink so runsvdir lets go of it
+    let _ = Command::new("sv").args(["stop", name]).status();
+    for service_dir in SERVICE_DIRS {
+        let link = Path::new(service_dir).join(name);
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&link)
+                .with_context(|| format!("failed to remove {}", link.display()))?;
+            println!("Removed {}", link.display());
+        }
+    }
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("failed to remove {}", dir.display()))?;
+        println!("Removed {}", dir.display());
+    } else {
+        println!(
+            "Service directory {} does not exist, nothing to remove",
+            dir.display()
+        );
+    }
+
+    println!("Service {name} uninstalled");
+    Ok(())
+}
+
+/// The directory svlogd writes the service log to.
+pub fn data_paths(name: &str) -> Vec<PathBuf> {
+    vec![PathBuf::from(format!("/var/log/{name}"))]
+}
+
+pub fn control(name: &str, action: super::Control) -> Result<()> {
+    super::run("sv", [action.verb(), name])
+}
+
+/// svlogd's current file; rotated ones sit next to it.
+pub fn logs(name: &str, lines: usize, follow: bool) -> Result<()> {
+    let current = PathBuf::from(format!("/var/log/{name}/current"));
+    super::tail(&[current], lines, follow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_scripts_exec_leshy_and_svlogd() {
+        let run = generate_run(
+            Path::new("/usr/bin/leshy"),
+            Path::new("/etc/leshy/corp.toml"),
+            &InstallOptions::default(),
+        );
+        assert!(run.contains("exec 2>&1\nexec /usr/bin/leshy /etc/leshy/corp.toml\n"));
+        let options = InstallOptions {
+            env: vec![("RUST_LOG".to_string(), "leshy debug".to_string())],
+            args: vec!["--dry-run".to_string()],
+            hardened: false,
+        };
+        let run = generate_run(
+            Path::new("/usr/bin/leshy"),
+            Path::new("/etc/leshy/corp.toml"),
+            &options,
+        );
+        assert!(run.contains(
+            "export RUST_LOG='leshy debug'\nexec /usr/bin/leshy /etc/leshy/corp.toml --dry-run\n"
+        ));
+        let log = generate_log_run("leshy-corp");
+        assert!(log.contains("exec svlogd -tt /var/log/leshy-corp"));
+        assert_eq!(service_dir("leshy-corp"), Path::new("/etc/sv/leshy-corp"));
+    }
+}
@@ -0,0 +1,464 @@
+use serde_json::Value;
+use std::fmt::Write;
+
+/// Human-readable `leshy status`, from the admin API's `/status`, `/zones`,
+/// `/cache` and `/upstreams` responses.
+pub fn render_status(status: &Value, zones: &Value, cache: &Value, upstreams: &Value) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "leshy {}, up {}, config {}",
+        text(&status["version"]),
+        uptime(status["uptime_secs"].as_u64().unwrap_or_default()),
+        text(&status["config"]),
+    );
+    let _ = writeln!(
+        out,
+        "Routes: {} covering {} resolved IPs",
+        status["routes"], status["ips"]
+    );
+    let _ = writeln!(
+        out,
+        "Cache: {}/{} entries, {} hits of {} queries",
+        cache["entries"], cache["max_entries"], cache["hits"], cache["queries"]
+    );
+
+    let rows: Vec<[String; 5]> = array(zones)
+        .iter()
+        .map(|zone| {
+            let state = if zone["enabled"] == false {
+                "disabled"
+            } else if zone["withdrawn"] == true {
+                "withdrawn"
+            } else {
+                "active"
+            };
+            [
+                text(&zone["name"]),
+                text(&zone["route_type"]),
+                text(&zone["route_target"]),
+                zone["routes"].to_string(),
+                state.to_string(),
+            ]
+        })
+        .collect();
+    out.push('\n');
+    table(
+        &mut out,
+        ["ZONE", "TYPE", "TARGET", "ROUTES", "STATE"],
+        &rows,
+    );
+
+    let rows: Vec<[String; 8]> = array(upstreams)
+        .iter()
+        .map(|upstream| {
+            let zones: Vec<String> = array(&upstream["zones"]).iter().map(text).collect();
+            let health = if upstream["healthy"] == true {
+                "healthy".to_string()
+            } else {
+                format!("failing ({} in a row)", upstream["consecutive_failures"])
+            };
+            let rtt = &upstream["rtt"];
+            let rtt_ms = |field: &str| match rtt[field].as_f64() {
+                Some(ms) if rtt["responses"].as_u64().unwrap_or_default() > 0 => {
+                    format!("{ms}ms")
+                }
+                _ => "-".to_string(),
+            };
+            [
+                text(&upstream["address"]),
+                text(&upstream["protocol"]),
+                if zones.is_empty() {
+                    "(default)".to_string()
+                } else {
+                    zones.join(",")
+                },
+                upstream["answered"].to_string(),
+                upstream["failures"].to_string(),
+                rtt_ms("p50_ms"),
+                rtt_ms("p99_ms"),
+                health,
+            ]
+        })
+        .collect();
+    out.push('\n');
+    table(
+        &mut out,
+        [
+            "UPSTREAM", "PROTO", "ZONES", "ANSWERED", "FAILED", "P50", "P99", "HEALTH",
+        ],
+        &rows,
+    );
+
+    let reloads = array(&status["reloads"]);
+    out.push_str("\nRecent reloads:");
+    if reloads.is_empty() {
+        out.push_str(" none");
+    }
+    out.push('\n');
+    for reload in reloads {
+        let outcome = if reload["applied"] == true {
+            let mut changes: Vec<String> = array(&reload["added"])
+                .iter()
+                .map(|name| format!("+{}", text(name)))
+                .collect();
+            changes.extend(
+                array(&reload["removed"])
+                    .iter()
+                    .map(|name| format!("-{}", text(name))),
+            );
+            if changes.is_empty() {
+                "applied".to_string()
+            } else {
+                format!("applied ({})", changes.join(", "))
+            }
+        } else {
+            format!("failed: {}", text(&reload["error"]))
+        };
+        let _ = writeln!(out, "  {}  {outcome}", text(&reload["at"]));
+    }
+    out
+}
+
+/// Human-readable `leshy routes`, from the admin API's `/routes`.
+pub fn render_routes(routes: &Value) -> String {
+    let mut out = String::new();
+    let Some(zones) = routes.as_object() else {
+        return out;
+    };
+    for (name, zone_routes) in zones {
+        let prefixes = array(&zone_routes["routes"]);
+        let withdrawn = if zone_routes["withdrawn"] == true {
+            ", withdrawn"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            "{name}: {} routes for {} IPs{withdrawn}",
+            prefixes.len(),
+            zone_routes["ips"]
+        );
+        for prefix in prefixes {
+            let _ = writeln!(out, "  {}", text(prefix));
+        }
+    }
+    out
+}
+
+/// Human-readable `leshy top`, from the admin API's `/top`.
+pub fn render_top(top: &Value) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Over the last {}",
+        uptime(top["window_secs"].as_u64().unwrap_or_default())
+    );
+    let counts = |counts: &Value| -> Vec<[String; 2]> {
+        array(counts)
+            .iter()
+            .map(|count| [text(&count["name"]), count["count"].to_string()])
+            .collect()
+    };
+
+    out.push('\n');
+    table(
+        &mut out,
+        ["MOST QUERIED", "QUERIES"],
+        &counts(&top["queried"]),
+    );
+
+    let rows: Vec<[String; 5]> = array(&top["slowest"])
+        .iter()
+        .map(|query| {
+            [
+                text(&query["name"]),
+                text(&query["type"]),
+                text(&query["zone"]),
+                text(&query["upstream"]),
+                format!("{}ms", query["latency_ms"]),
+            ]
+        })
+        .collect();
+    out.push('\n');
+    table(
+        &mut out,
+        ["SLOWEST", "TYPE", "ZONE", "UPSTREAM", "LATENCY"],
+        &rows,
+    );
+
+    out.push('\n');
+    table(
+        &mut out,
+        ["MOST ROUTED", "ROUTES"],
+        &counts(&top["routing"]),
+    );
+    out
+}
+
+/// Human-readable `leshy cache stats`, from the admin API's `/cache`.
+pub fn render_cache_stats(cache: &Value) -> String {
+    let (hits, queries) = (
+        cache["hits"].as_u64().unwrap_or_default(),
+        cache["queries"].as_u64().unwrap_or_default(),
+    );
+    let rate = if queries == 0 {
+        0.0
+    } else {
+        hits as f64 * 100.0 / queries as f64
+    };
+    format!(
+        "Entries: {}/{}\nHits: {hits} of {queries} queries ({rate:.1}%)\n",
+        cache["entries"], cache["max_entries"]
+    )
+}
+
+/// Human-readable `leshy cache dump`, from the admin API's `/cache/entries`.
+pub fn render_cache_entries(entries: &Value) -> String {
+    let rows: Vec<[String; 6]> = array(entries)
+        .iter()
+        .map(|entry| {
+            let mut zone = text(&entry["zone"]);
+            if entry["scoped"] == true {
+                zone.push_str(" (scoped)");
+            }
+            let answers: Vec<String> = array(&entry["answers"]).iter().map(text).collect();
+            [
+                text(&entry["name"]),
+                text(&entry["type"]),
+                zone,
+                format!("{}s", entry["ttl"]),
+                entry["hits"].to_string(),
+                if answers.is_empty() {
+                    text(&entry["rcode"])
+                } else {
+                    answers.join(", ")
+                },
+            ]
+        })
+        .collect();
+    let mut out = String::new();
+    table(
+        &mut out,
+        ["NAME", "TYPE", "ZONE", "TTL", "HITS", "ANSWERS"],
+        &rows,
+    );
+    out
+}
+
+/// Human-readable `leshy resolve`, from a `Resolution`.
+pub fn render_resolution(resolution: &Value) -> String {
+    let mut out = String::new();
+    let name = text(&resolution["name"]);
+    match resolution["zone"].as_str() {
+        Some(zone) => {
+            let _ = writeln!(out, "{name} matches zone {zone}");
+        }
+        None => {
+            let _ = writeln!(out, "{name} matches no zone");
+        }
+    }
+    let upstreams: Vec<String> = array(&resolution["upstreams"]).iter().map(text).collect();
+    let _ = writeln!(
+        out,
+        "Upstreams ({}): {}",
+        text(&resolution["protocol"]),
+        upstreams.join(", ")
+    );
+    let answer = match resolution["answered_by"].as_str() {
+        Some(upstream) => format!("{} from {upstream}", text(&resolution["rcode"])),
+        None => format!("no answer ({})", text(&resolution["rcode"])),
+    };
+    let addresses: Vec<String> = array(&resolution["addresses"]).iter().map(text).collect();
+    let _ = writeln!(out, "Answer: {answer}");
+    if !addresses.is_empty() {
+        let _ = writeln!(out, "Addresses: {}", addresses.join(", "));
+    }
+
+    let routes = array(&resolution["routes"]);
+    if routes.is_empty() {
+        out.push_str("Routes: none\n");
+        return out;
+    }
+    out.push_str("Routes:\n");
+    for route in routes {
+        let (ip, zone) = (text(&route["ip"]), text(&route["zone"]));
+        let line = if route["excluded"] == true {
+            format!("{ip}: excluded by zone {zone}, not routed")
+        } else {
+            let state = if route["installed"] == true {
+                "installed"
+            } else {
+                "would be installed"
+            };
+            format!(
+                "{ip}: {} {} (zone {zone}, {state})",
+                text(&route["prefix"]),
+                text(&route["target"])
+            )
+        };
+        let _ = writeln!(out, "  {line}");
+    }
+    out
+}
+
+/// Write `rows` under `header` in left-aligned columns.
+fn table<const N: usize>(out: &mut String, header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let header = header.map(str::to_string);
+    for row in std::iter::once(&header).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        let _ = writeln!(out, "{}", line.join("  ").trim_end());
+    }
+}
+
+fn array(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// A JSON string without quotes; other values as JSON.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn uptime(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, _) => format!("{minutes}m {}s", secs % 60),
+        (0, _, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_status_and_routes() {
+        let status = json!({
+            "version": "0.3.1", "uptime_secs": 7500, "config": "/etc/leshy/config.toml",
+            "routes": 2, "ips": 5,
+            "reloads": [
+                { "at": "2026-10-16T12:43:35+00:00", "applied": true, "added": ["lab"], "removed": [], "error": null },
+                { "at": "2026-10-16T12:40:00+00:00", "applied": false, "added": [], "removed": [], "error": "bad" },
+            ],
+        });
+        let zones = json!([
+            { "name": "corp", "route_type": "via", "route_target": "198.51.100.1", "routes": 2, "enabled": true, "withdrawn": false },
+            { "name": "lab", "route_type": "dev", "route_target": "wg0", "routes": 0, "enabled": false, "withdrawn": true },
+        ]);
+        let cache = json!({ "entries": 3, "max_entries": 1000, "hits": 4, "queries": 9 });
+        let upstreams = json!([
+            { "address": "198.51.100.53:53", "protocol": "udp", "zones": [], "healthy": false, "answered": 7, "failures": 3, "consecutive_failures": 2,
+              "rtt": { "responses": 8, "p50_ms": 20.0, "p99_ms": 1000.0 } },
+            { "address": "198.51.100.54:53", "protocol": "tcp", "zones": ["corp"], "healthy": true, "answered": 0, "failures": 0, "consecutive_failures": 0,
+              "rtt": { "responses": 0, "p50_ms": 0.0, "p99_ms": 0.0 } },
+        ]);
+
+        let out = render_status(&status, &zones, &cache, &upstreams);
+        assert!(out.starts_with("leshy 0.3.1, up 2h 5m, config /etc/leshy/config.toml\n"));
+        assert!(out.contains("Cache: 3/1000 entries, 4 hits of 9 queries\n"));
+        assert!(out.contains("\nZONE  TYPE  TARGET        ROUTES  STATE\n"));
+        assert!(out.contains("\nlab   dev   wg0           0       disabled\n"));
+        assert!(out.contains(
+            "\n198.51.100.53:53  udp    (default)  7         3       20ms  1000ms  failing (2 in a row)\n"
+        ));
+        assert!(out.contains(
+            "\n198.51.100.54:53  tcp    corp       0         0       -     -       healthy\n"
+        ));
+        assert!(out.contains("  2026-10-16T12:43:35+00:00  applied (+lab)\n"));
+        assert!(out.contains("  2026-10-16T12:40:00+00:00  failed: bad\n"));
+
+        let routes = json!({
+            "corp": { "routes": ["198.51.100.0/24", "203.0.113.7/32"], "ips": 5, "withdrawn": false },
+            "lab": { "routes": [], "ips": 0, "withdrawn": true },
+        });
+        assert_eq!(
+            render_routes(&routes),
+            "corp: 2 routes for 5 IPs\n  198.51.100.0/24\n  203.0.113.7/32\nlab: 0 routes for 0 IPs, withdrawn\n"
+        );
+    }
+
+    #[test]
+    fn renders_cache() {
+        let cache = json!({ "entries": 2, "max_entries": 1000, "hits": 1, "queries": 8 });
+        assert_eq!(
+            render_cache_stats(&cache),
+            "Entries: 2/1000\nHits: 1 of 8 queries (12.5%)\n"
+        );
+
+        let entries = json!([
+            { "name": "www.corp.example", "type": "A", "zone": "corp", "scoped": false, "ttl": 240, "hits": 3, "rcode": "No Error", "answers": ["198.51.100.7", "198.51.100.8"] },
+            { "name": "nope.example", "type": "AAAA", "zone": null, "scoped": true, "ttl": 5, "hits": 0, "rcode": "Non-Existent Domain", "answers": [] },
+        ]);
+        assert_eq!(
+            render_cache_entries(&entries),
+            "NAME              TYPE  ZONE        TTL   HITS  ANSWERS\n\
+             www.corp.example  A     corp        240s  3     198.51.100.7, 198.51.100.8\n\
+             nope.example      AAAA  - (scoped)  5s    0     Non-Existent Domain\n"
+        );
+    }
+
+    #[test]
+    fn renders_top() {
+        let top = json!({
+            "window_secs": 2400,
+            "queried": [{ "name": "www.corp.example", "count": 42 }, { "name": "example.org", "count": 7 }],
+            "slowest": [{ "name": "slow.corp.example", "type": "AAAA", "zone": "corp", "upstream": "198.51.100.53:53", "latency_ms": 812.25, "at": "" }],
+            "routing": [],
+        });
+        assert_eq!(
+            render_top(&top),
+            "Over the last 40m 0s\n\
+             \n\
+             MOST QUERIED      QUERIES\n\
+             www.corp.example  42\n\
+             example.org       7\n\
+             \n\
+             SLOWEST            TYPE  ZONE  UPSTREAM          LATENCY\n\
+             slow.corp.example  AAAA  corp  198.51.100.53:53  812.25ms\n\
+             \n\
+             MOST ROUTED  ROUTES\n"
+        );
+    }
+
+    #[test]
+    fn renders_resolutions() {
+        let resolution = json!({
+            "name": "www.corp.example.", "zone": "corp",
+            "upstreams": ["198.51.100.53:53", "198.51.100.54:53"], "protocol": "tcp",
+            "answered_by": "198.51.100.54:53", "rcode": "No Error",
+            "addresses": ["198.51.100.7", "198.51.100.200"],
+            "routes": [
+                { "ip": "198.51.100.7", "zone": "corp", "target": "via 198.51.100.1", "prefix": "198.51.100.0/24", "installed": true, "excluded": false },
+                { "ip": "198.51.100.200", "zone": "corp", "target": "via 198.51.100.1", "prefix": null, "installed": false, "excluded": true },
+            ],
+        });
+        assert_eq!(
+            render_resolution(&resolution),
+            "www.corp.example. matches zone corp\n\
+             Upstreams (tcp): 198.51.100.53:53, 198.51.100.54:53\n\
+             Answer: No Error from 198.51.100.54:53\n\
+             Addresses: 198.51.100.7, 198.51.100.200\n\
+             Routes:\n  \
+             198.51.100.7: 198.51.100.0/24 via 198.51.100.1 (zone corp, installed)\n  \
+             198.51.100.200: excluded by zone corp, not routed\n"
+        );
+    }
+}
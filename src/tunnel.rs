@@ -0,0 +1,384 @@
+use crate::config::{SocksTunnel, TunnelProgram, ZoneConfig};
+use crate::dns::SharedHandler;
+use crate::hook::{remove_device_file, write_device_file};
+use crate::routing::{interfaces, is_device_file};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+/// How long a started process gets to create its interface
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A process that ran this long restarts after `restart_delay` again
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Longest wait between restarts
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+/// The `socks_tunnel` processes of the loaded zones. A reload restarts only
+/// those whose settings changed; dropping the supervisor kills them all.
+pub struct TunnelSupervisor {
+    handler: SharedHandler,
+    /// Zone name to (settings, route_target, supervising task)
+    running: HashMap<String, (SocksTunnel, String, JoinHandle<()>)>,
+}
+
+impl TunnelSupervisor {
+    pub fn new(handler: SharedHandler) -> Self {
+        Self {
+            handler,
+            running: HashMap::new(),
+        }
+    }
+
+    /// Start the processes of new zones and of zones whose `socks_tunnel`
+    /// changed, stopping those no zone wants any more.
+    pub fn sync(&mut self, zones: &[ZoneConfig]) {
+        self.running.retain(|name, (tunnel, target, task)| {
+            let wanted = zones.iter().any(|zone| {
+                zone.name == *name
+                    && zone.route_target == *target
+                    && zone.socks_tunnel.as_ref() == Some(tunnel)
+            });
+            if !wanted {
+                tracing::info!(zone = name, "Stopping socks tunnel");
+                task.abort();
+            }
+            wanted
+        });
+
+        for zone in zones {
+            let Some(tunnel) = &zone.socks_tunnel else {
+                continue;
+            };
+            if self.running.contains_key(&zone.name) {
+                continue;
+            }
+            let device_file =
+                is_device_file(&zone.route_target).then(|| PathBuf::from(&zone.route_target));
+            let task = tokio::spawn(supervise(
+                self.handler.clone(),
+                zone.name.clone(),
+                tunnel.clone(),
+                device_file,
+            ));
+            self.running.insert(
+                zone.name.clone(),
+                (tunnel.clone(), zone.route_target.clone(), task),
+            );
+        }
+    }
+}
+
+impl Drop for TunnelSupervisor {
+    fn drop(&mut self) {
+        for (_, _, task) in self.running.values() {
+            task.abort();
+        }
+    }
+}
+
+/// Keep a zone's process running until aborted. Each time it stops, the
+/// zone's routes are taken out and it is started again after the restart
+/// delay, doubled while it keeps stopping within a minute.
+async fn supervise(
+    handler: SharedHandler,
+    zone: String,
+    tunnel: SocksTunnel,
+    device_file: Option<PathBuf>,
+) {
+    let base = Duration::from_secs(tunnel.restart_delay);
+    let mut delay = base;
+    loop {
+        let started = Instant::now();
+        if let Err(e) = run(&handler, &zone, &tunnel, device_file.as_deref()).await {
+            tracing::warn!(
+                zone = zone,
+                device = tunnel.device,
+                error = format!("{e:#}"),
+                "Socks tunnel stopped"
+            );
+        }
+        if let Some(path) = &device_file {
+            if let Err(e) = remove_device_file(path) {
+                tracing::warn!(zone = zone, error = %e, "Failed to clear device file");
+            }
+        }
+        if let Err(e) = handler.load_full().tunnel_down(&zone).await {
+            tracing::warn!(zone = zone, error = %e, "Failed to withdraw zone routes");
+        }
+
+        if started.elapsed() >= STABLE_RUN {
+            delay = base;
+        }
+        tracing::info!(
+            zone = zone,
+            delay_secs = delay.as_secs(),
+            "Restarting socks tunnel"
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Start the process, wait for its interface, and move the zone's routes
+/// onto it. Only returns once the process is gone, with the reason.
+async fn run(
+    handler: &SharedHandler,
+    zone: &str,
+    tunnel: &SocksTunnel,
+    device_file: Option<&Path>,
+) -> anyhow::Result<()> {
+    let program = program_path(tunnel);
+    let mut command = Command::new(&program);
+    match tunnel.program {
+        TunnelProgram::Tun2socks => command.args(tun2socks_args(tunnel)),
+        TunnelProgram::HevSocks5Tunnel => command.arg(write_hev_config(zone, tunnel)?),
+    };
+    let mut child = command
+        .args(&tunnel.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to start '{}'", program.display()))?;
+    tracing::info!(
+        zone = zone,
+        device = tunnel.device,
+        pid = child.id(),
+        "Started socks tunnel"
+    );
+    let stdout = forward_output(zone, child.stdout.take());
+    let stderr = forward_output(zone, child.stderr.take());
+    let exited = |status: std::io::Result<ExitStatus>| async move {
+        let stderr = stderr.await.ok().flatten();
+        let last_line = stderr.or(stdout.await.ok().flatten());
+        match (status, last_line) {
+            (Err(e), _) => anyhow::anyhow!("failed to wait for the process: {e}"),
+            (Ok(status), Some(line)) => anyhow::anyhow!("exited ({status}): {line}"),
+            (Ok(status), None) => anyhow::anyhow!("exited ({status})"),
+        }
+    };
+
+    tokio::select! {
+        status = child.wait() => return Err(exited(status).await),
+        created = wait_for_device(&tunnel.device) => if !created {
+            anyhow::bail!(
+                "interface '{}' did not appear within {}s",
+                tunnel.device,
+                DEVICE_TIMEOUT.as_secs()
+            );
+        },
+    }
+    // hev-socks5-tunnel configures its interface itself
+    if tunnel.program == TunnelProgram::Tun2socks {
+        configure_device(&tunnel.device, tunnel.address.as_deref()).await?;
+    }
+    if let Some(path) = device_file {
+        write_device_file(path, &tunnel.device)?;
+    }
+    match handler.load_full().tunnel_up(zone).await {
+        Ok(routes) => tracing::info!(
+            zone = zone,
+            device = tunnel.device,
+            routes = routes,
+            "Socks tunnel up"
+        ),
+        Err(e) => {
+            tracing::warn!(zone = zone, error = %e, "Failed to move zone routes onto socks tunnel")
+        }
+    }
+
+    Err(exited(child.wait().await).await)
+}
+
+fn program_path(tunnel: &SocksTunnel) -> PathBuf {
+    tunnel.path.clone().unwrap_or_else(|| {
+        PathBuf::from(match tunnel.program {
+            TunnelProgram::Tun2socks => "tun2socks",
+            TunnelProgram::HevSocks5Tunnel => "hev-socks5-tunnel",
+        })
+    })
+}
+
+fn tun2socks_args(tunnel: &SocksTunnel) -> Vec<String> {
+    vec![
+        "-device".to_string(),
+        format!("tun://{}", tunnel.device),
+        "-proxy".to_string(),
+        tunnel.proxy.clone(),
+    ]
+}
+
+/// Write hev-socks5-tunnel's config where only leshy can read it, as it
+/// may hold the proxy's password.
+fn write_hev_config(zone: &str, tunnel: &SocksTunnel) -> anyhow::Result<PathBuf> {
+    let config = hev_config(tunnel)?;
+    let path = std::env::temp_dir().join(format!("leshy-{zone}-hev.yml"));
+    let _ = std::fs::remove_file(&path);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(config.as_bytes()))
+        .with_context(|| format!("failed to write '{}'", path.display()))?;
+    Ok(path)
+}
+
+/// hev-socks5-tunnel's YAML config: the interface, its address and the
+/// SOCKS5 server from `proxy`.
+fn hev_config(tunnel: &SocksTunnel) -> anyhow::Result<String> {
+    let proxy = tunnel
+        .proxy
+        .strip_prefix("socks5://")
+        .context("hev-socks5-tunnel only takes a socks5:// proxy")?
+        .trim_end_matches('/');
+    let (credentials, server) = match proxy.rsplit_once('@') {
+        Some((credentials, server)) => (Some(credentials), server),
+        None => (None, proxy),
+    };
+    let (host, port) = server
+        .rsplit_once(':')
+        .with_context(|| format!("proxy '{}' has no port", tunnel.proxy))?;
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("invalid port in proxy '{}'", tunnel.proxy))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let address = tunnel
+        .address
+        .as_deref()
+        .context("hev-socks5-tunnel needs an address")?;
+    let ip = address.split('/').next().unwrap_or(address);
+
+    let quote = |value: &str| format!("'{}'", value.replace('\'', "''"));
+    let mut config = format!(
+        "tunnel:\n  name: {}\n  ipv4: {ip}\nsocks5:\n  address: {}\n  port: {port}\n  udp: 'udp'\n",
+        quote(&tunnel.device),
+        quote(host)
+    );
+    if let Some((username, password)) = credentials.and_then(|c| c.split_once(':')) {
+        config.push_str(&format!(
+            "  username: {}\n  password: {}\n",
+            quote(username),
+            quote(password)
+        ));
+    }
+    Ok(config)
+}
+
+/// Log the process's output at debug level; resolves to its last line.
+fn forward_output(
+    zone: &str,
+    output: Option<impl AsyncRead + Unpin + Send + 'static>,
+) -> JoinHandle<Option<String>> {
+    let zone = zone.to_string();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(output?).lines();
+        let mut last = None;
+        while let Ok(Some(line)) = lines.next_line().await {
+            tracing::debug!(zone = zone, line = line, "Socks tunnel output");
+            last = Some(line);
+        }
+        last
+    })
+}
+
+async fn wait_for_device(device: &str) -> bool {
+    let deadline = Instant::now() + DEVICE_TIMEOUT;
+    while !interfaces::exists(device) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+/// Give tun2socks' interface its address and bring it up.
+#[cfg(target_os = "linux")]
+async fn configure_device(device: &str, address: Option<&str>) -> anyhow::Result<()> {
+    if let Some(address) = address {
+        run_command("ip", &["addr", "replace", address, "dev", device]).await?;
+    }
+    run_command("ip", &["link", "set", "dev", device, "up"]).await
+}
+
+/// Give tun2socks' interface its address and bring it up.
+#[cfg(not(target_os = "linux"))]
+async fn configure_device(device: &str, address: Option<&str>) -> anyhow::Result<()> {
+    match address {
+        // A utun interface is point-to-point: the address is its own peer
+        Some(address) => {
+            let ip = address.split('/').next().unwrap_or(address);
+            run_command("ifconfig", &[device, "inet", address, ip, "up"]).await
+        }
+        None => run_command("ifconfig", &[device, "up"]).await,
+    }
+}
+
+async fn run_command(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("failed to run {program}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel(toml: &str) -> SocksTunnel {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn builds_program_arguments_and_config() {
+        let tun2socks = tunnel("proxy = \"socks5://127.0.0.1:1080\"\ndevice = \"tun2\"");
+        assert_eq!(program_path(&tun2socks), Path::new("tun2socks"));
+        assert_eq!(
+            tun2socks_args(&tun2socks),
+            ["-device", "tun://tun2", "-proxy", "socks5://127.0.0.1:1080"]
+        );
+        assert_eq!(tun2socks.restart_delay, 5);
+
+        let hev = tunnel(
+            "program = \"hev-socks5-tunnel\"\nproxy = \"socks5://me:it's@[::1]:1080/\"\n\
+             device = \"tun3\"\naddress = \"198.18.0.1/15\"",
+        );
+        assert_eq!(program_path(&hev), Path::new("hev-socks5-tunnel"));
+        assert_eq!(
+            hev_config(&hev).unwrap(),
+            "tunnel:\n  name: 'tun3'\n  ipv4: 198.18.0.1\nsocks5:\n  address: '::1'\n  \
+             port: 1080\n  udp: 'udp'\n  username: 'me'\n  password: 'it''s'\n"
+        );
+
+        let no_port = SocksTunnel {
+            proxy: "socks5://127.0.0.1".to_string(),
+            ..hev.clone()
+        };
+        assert!(hev_config(&no_port).is_err());
+        let http = SocksTunnel {
+            proxy: "http://127.0.0.1:8080".to_string(),
+            ..hev
+        };
+        assert!(hev_config(&http).is_err());
+    }
+}
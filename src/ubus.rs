@@ -0,0 +1,73 @@
+use crate::admin::{self, AdminListen};
+use anyhow::Result;
+use serde_json::{json, Value};
+
+/// The methods `leshy ubus list` advertises to rpcd, with their argument
+/// types in its exec plugin notation.
+pub fn methods() -> Value {
+    json!({
+        "status": {},
+        "zones": {},
+        "routes": {},
+        "stats": {},
+        "upstreams": {},
+        "flush_cache": {},
+        "reload": {},
+        "enable": { "zone": "str" },
+        "disable": { "zone": "str" },
+    })
+}
+
+/// Answer a ubus call from the running daemon's admin API. ubus replies
+/// must be objects, so anything else comes back under the method's name.
+pub async fn call(listen: &AdminListen, method: &str, args: &Value) -> Result<Value> {
+    let (verb, path) = admin_request(method, args)?;
+    match admin::request(listen, verb, &path).await? {
+        Value::Object(fields) => Ok(Value::Object(fields)),
+        other => Ok(json!({ method: other })),
+    }
+}
+
+/// The admin API request behind a ubus method.
+fn admin_request(method: &str, args: &Value) -> Result<(&'static str, String)> {
+    let request = match method {
+        "status" | "zones" | "routes" | "stats" | "upstreams" => ("GET", format!("/{method}")),
+        "flush_cache" => ("POST", "/cache/flush".to_string()),
+        "reload" => ("POST", "/reload".to_string()),
+        "enable" | "disable" => {
+            let zone = match args["zone"].as_str() {
+                Some(zone) if !zone.is_empty() && !zone.contains(['/', '?']) => zone,
+                Some(zone) => anyhow::bail!("invalid zone '{zone}'"),
+                None => anyhow::bail!("{method} needs a zone"),
+            };
+            ("POST", format!("/zones/{zone}/{method}"))
+        }
+        _ => anyhow::bail!("unknown method '{method}'"),
+    };
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_methods_to_admin_requests() {
+        let methods = methods();
+        for method in methods.as_object().unwrap().keys() {
+            let args = json!({ "zone": "corp" });
+            assert!(admin_request(method, &args).is_ok(), "{method}");
+        }
+        assert_eq!(
+            admin_request("stats", &json!({})).unwrap(),
+            ("GET", "/stats".to_string())
+        );
+        assert_eq!(
+            admin_request("disable", &json!({ "zone": "corp" })).unwrap(),
+            ("POST", "/zones/corp/disable".to_string())
+        );
+        assert!(admin_request("enable", &json!({})).is_err());
+        assert!(admin_request("enable", &json!({ "zone": "../reload" })).is_err());
+        assert!(admin_request("shutdown", &json!({})).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "geoip")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(feature = "geoip")]
+use maxminddb::geoip2;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Country lookups in a MaxMind GeoIP2/GeoLite2 Country or City database.
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+    /// Modification time of the file when it was read
+    modified: Option<SystemTime>,
+}
+
+impl GeoIp {
+    #[cfg(feature = "geoip")]
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("Failed to open GeoIP database '{}'", path.display()))?;
+        tracing::info!(
+            path = %path.display(),
+            database = reader.metadata.database_type,
+            "Loaded GeoIP database"
+        );
+        Ok(Self {
+            reader,
+            modified: modified(path),
+        })
+    }
+
+    /// Builds without the geoip feature can't read the database.
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(path: &Path) -> Result<Self> {
+        anyhow::bail!(
+            "Failed to open GeoIP database '{}': leshy was built without the geoip feature",
+            path.display()
+        )
+    }
+
+    /// Whether the database file at `path` changed since it was read (e.g.
+    /// replaced by geoipupdate).
+    pub fn is_stale(&self, path: &Path) -> bool {
+        modified(path) != self.modified
+    }
+
+    /// ISO 3166-1 alpha-2 code of the country an IP is located in, if known.
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+}
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
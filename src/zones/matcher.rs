@@ -1,6 +1,9 @@
+use super::trie::DomainTrie;
+use super::Schedule;
 use crate::config::{ZoneConfig, ZoneMode};
+use crate::error::{LeshyError, Result};
+use crate::routing::{parse_cidr, prefix_contains};
 use regex::RegexSet;
-use std::collections::HashSet;
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 
@@ -29,9 +32,9 @@ pub struct MatchedZone {
 }
 
 impl MatchedZone {
-    /// Check if an IP falls within this zone's excluded CIDR ranges.
-    /// Always false for inclusive zones (they have no excluded CIDRs).
-    /// For exclusive zones, returns true if IPv4 matches any excluded range.
+    /// Check if an IP falls within this zone's excluded CIDR ranges
+    /// (`exclude_routes`, plus `static_routes` for exclusive zones).
+    /// Returns true if IPv4 matches any excluded range.
     pub fn is_excluded(&self, ip: IpAddr) -> bool {
         match ip {
             IpAddr::V4(v4) => self.excluded_cidrs.iter().any(|r| r.contains_v4(v4)),
@@ -40,20 +43,23 @@ impl MatchedZone {
     }
 }
 
-/// Matches only listed domains/patterns. Routes all resolved IPs.
+/// Matches only listed domains/patterns, minus `exclude_domains` and
+/// `exclude_patterns`. Routes all resolved IPs except those within
+/// `excluded_cidrs` (`exclude_routes`).
 #[derive(Debug)]
 struct InclusiveZone {
     config: Arc<ZoneConfig>,
-    domain_set: HashSet<String>,
     pattern_set: RegexSet,
+    excluded_patterns: RegexSet,
+    excluded_cidrs: Vec<CidrRange>,
 }
 
 /// Matches everything EXCEPT listed domains/patterns.
-/// Resolved IPs falling within `excluded_cidrs` are skipped.
+/// Resolved IPs falling within `excluded_cidrs` (`static_routes` and
+/// `exclude_routes`) are skipped.
 #[derive(Debug)]
 struct ExclusiveZone {
     config: Arc<ZoneConfig>,
-    excluded_domains: HashSet<String>,
     excluded_patterns: RegexSet,
     excluded_cidrs: Vec<CidrRange>,
 }
@@ -68,50 +74,77 @@ enum Zone {
 #[derive(Debug)]
 pub struct ZoneMatcher {
     zones: Vec<Zone>,
+    /// Each zone's parsed `clients`, by index in `zones`; empty = any client
+    clients: Vec<Vec<(IpAddr, u8)>>,
+    /// Each zone's `active_hours`/`active_days`, by index in `zones`
+    schedules: Vec<Option<Schedule>>,
+    /// Every zone's `domains`, keyed to the zone's index in `zones`
+    domains: DomainTrie,
+    /// Every inclusive zone's `exclude_domains`, keyed the same way
+    excluded_domains: DomainTrie,
 }
 
 impl ZoneMatcher {
-    pub fn new(zones: Vec<ZoneConfig>) -> anyhow::Result<Self> {
+    pub fn new(zones: Vec<ZoneConfig>) -> Result<Self> {
         let mut built = Vec::with_capacity(zones.len());
-
-        for zone_cfg in zones {
-            let domain_set: HashSet<String> =
-                zone_cfg.domains.iter().map(|d| d.to_lowercase()).collect();
+        let mut clients = Vec::with_capacity(zones.len());
+        let mut schedules = Vec::with_capacity(zones.len());
+        let mut domains = DomainTrie::new();
+        let mut excluded_domains = DomainTrie::new();
+
+        for (index, zone_cfg) in zones.into_iter().enumerate() {
+            for domain in &zone_cfg.domains {
+                domains.insert(domain, index);
+            }
+            for domain in &zone_cfg.exclude_domains {
+                excluded_domains.insert(domain, index);
+            }
 
             let pattern_set = RegexSet::new(&zone_cfg.patterns).map_err(|e| {
-                anyhow::anyhow!("Zone '{}': invalid regex pattern: {}", zone_cfg.name, e)
+                LeshyError::Config(format!(
+                    "Zone '{}': invalid regex pattern: {}",
+                    zone_cfg.name, e
+                ))
+            })?;
+
+            let excluded_patterns = RegexSet::new(&zone_cfg.exclude_patterns).map_err(|e| {
+                LeshyError::Config(format!(
+                    "Zone '{}': invalid exclude_patterns regex: {}",
+                    zone_cfg.name, e
+                ))
             })?;
 
+            let zone_clients = zone_cfg
+                .clients
+                .iter()
+                .map(|cidr| parse_cidr(cidr))
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| {
+                    LeshyError::Config(format!(
+                        "Zone '{}': invalid clients entry: {}",
+                        zone_cfg.name, e
+                    ))
+                })?;
+            clients.push(zone_clients);
+            let schedule = Schedule::parse(zone_cfg.active_hours.as_deref(), &zone_cfg.active_days)
+                .map_err(|e| LeshyError::Config(format!("Zone '{}': {}", zone_cfg.name, e)))?;
+            schedules.push(schedule);
+
             let config = Arc::new(zone_cfg);
 
             let zone = match config.mode {
                 ZoneMode::Inclusive => Zone::Inclusive(InclusiveZone {
+                    excluded_cidrs: parse_cidr_ranges(&config.exclude_routes, &config.name),
                     config,
-                    domain_set,
                     pattern_set,
+                    excluded_patterns,
                 }),
                 ZoneMode::Exclusive => {
-                    let excluded_cidrs = config
-                        .static_routes
-                        .iter()
-                        .filter_map(|cidr| {
-                            parse_cidr_range(cidr)
-                                .map_err(|e| {
-                                    tracing::warn!(
-                                        cidr = cidr,
-                                        zone = config.name,
-                                        error = %e,
-                                        "Failed to parse CIDR in exclusive zone, skipping"
-                                    );
-                                    e
-                                })
-                                .ok()
-                        })
-                        .collect();
+                    let mut excluded_cidrs = parse_cidr_ranges(&config.static_routes, &config.name);
+                    excluded_cidrs.extend(parse_cidr_ranges(&config.exclude_routes, &config.name));
 
                     Zone::Exclusive(ExclusiveZone {
                         config,
-                        excluded_domains: domain_set,
                         excluded_patterns: pattern_set,
                         excluded_cidrs,
                     })
@@ -121,41 +154,116 @@ impl ZoneMatcher {
             built.push(zone);
         }
 
-        Ok(Self { zones: built })
+        Ok(Self {
+            zones: built,
+            clients,
+            schedules,
+            domains,
+            excluded_domains,
+        })
+    }
+
+    /// Whether the zone at `index` applies to queries from `client` right
+    /// now: its schedule (if any) is active and it lists no `clients` or
+    /// one containing `client` (never an unknown client).
+    fn applies(&self, index: usize, client: Option<IpAddr>) -> bool {
+        if let Some(schedule) = &self.schedules[index] {
+            if !schedule.is_active() {
+                return false;
+            }
+        }
+        let ranges = &self.clients[index];
+        ranges.is_empty()
+            || client.is_some_and(|client| {
+                ranges
+                    .iter()
+                    .any(|&(network, prefix_len)| prefix_contains(network, prefix_len, client))
+            })
     }
 
-    /// Find the first zone that matches the given query name.
+    /// Find the first zone that matches the given query name, skipping
+    /// zones whose `clients` don't include the querying `client` and zones
+    /// outside their `active_hours`/`active_days`.
     /// Returns a `MatchedZone` that includes per-zone exclusion CIDRs.
-    pub fn find_zone(&self, qname: &str) -> Option<MatchedZone> {
-        let qname = qname.trim_end_matches('.');
+    pub fn find_zone(&self, qname: &str, client: Option<IpAddr>) -> Option<MatchedZone> {
+        self.match_zones(qname, client, true).pop()
+    }
+
+    /// The zone named `name`, whatever its `clients` and schedule.
+    pub fn zone(&self, name: &str) -> Option<MatchedZone> {
+        self.zones.iter().find_map(|zone| {
+            let (config, excluded_cidrs) = match zone {
+                Zone::Inclusive(z) => (&z.config, &z.excluded_cidrs),
+                Zone::Exclusive(z) => (&z.config, &z.excluded_cidrs),
+            };
+            (config.name == name).then(|| MatchedZone {
+                config: Arc::clone(config),
+                excluded_cidrs: excluded_cidrs.clone(),
+            })
+        })
+    }
 
-        for zone in &self.zones {
+    /// Find every inclusive zone that matches the given query name, in
+    /// config order, or else the first match, as `find_zone` would.
+    pub fn find_zones(&self, qname: &str, client: Option<IpAddr>) -> Vec<MatchedZone> {
+        self.match_zones(qname, client, false)
+    }
+
+    fn match_zones(&self, qname: &str, client: Option<IpAddr>, first: bool) -> Vec<MatchedZone> {
+        let qname = qname.trim_end_matches('.');
+        let lowercase = qname.to_lowercase();
+        let domain_hits = self.domains.matches(&lowercase);
+        let excluded_hits = self.excluded_domains.matches(&lowercase);
+        let mut matched = Vec::new();
+        // First exclusive zone match, used when no inclusive zone matches
+        let mut fallback = None;
+
+        for (index, zone) in self.zones.iter().enumerate() {
+            if !self.applies(index, client) {
+                continue;
+            }
+            let domain_hit = domain_hits.contains(&index);
             match zone {
                 Zone::Inclusive(z) => {
-                    if matches_entries(&z.domain_set, &z.pattern_set, qname, &z.config.name) {
-                        return Some(MatchedZone {
+                    if matches_entries(domain_hit, &z.pattern_set, qname, &z.config.name) {
+                        if excluded_hits.contains(&index) || z.excluded_patterns.is_match(qname) {
+                            tracing::debug!(
+                                zone = z.config.name,
+                                qname = qname,
+                                "Carved out of inclusive zone"
+                            );
+                            continue;
+                        }
+                        matched.push(MatchedZone {
                             config: Arc::clone(&z.config),
-                            excluded_cidrs: Vec::new(),
+                            excluded_cidrs: z.excluded_cidrs.clone(),
                         });
+                        if first {
+                            return matched;
+                        }
                     }
                 }
                 Zone::Exclusive(z) => {
-                    let is_excluded = matches_entries(
-                        &z.excluded_domains,
-                        &z.excluded_patterns,
-                        qname,
-                        &z.config.name,
-                    );
+                    let is_excluded =
+                        matches_entries(domain_hit, &z.excluded_patterns, qname, &z.config.name);
                     if !is_excluded {
+                        if fallback.is_some() {
+                            continue;
+                        }
                         tracing::debug!(
                             zone = z.config.name,
                             qname = qname,
                             "Exclusive zone match (not excluded)"
                         );
-                        return Some(MatchedZone {
+                        let zone = MatchedZone {
                             config: Arc::clone(&z.config),
                             excluded_cidrs: z.excluded_cidrs.clone(),
-                        });
+                        };
+                        if first {
+                            return vec![zone];
+                        }
+                        fallback = Some(zone);
+                        continue;
                     }
                     tracing::debug!(
                         zone = z.config.name,
@@ -166,35 +274,45 @@ impl ZoneMatcher {
             }
         }
 
-        tracing::debug!(qname = qname, "No zone match, using default");
-        None
+        if matched.is_empty() {
+            matched.extend(fallback);
+        }
+        if matched.is_empty() {
+            tracing::debug!(qname = qname, "No zone match, using default");
+        }
+        matched
+    }
+
+    /// Find the first zone applying to `client` that lists `country` (an
+    /// ISO code) in `countries`.
+    pub fn find_zone_by_country(
+        &self,
+        country: &str,
+        client: Option<IpAddr>,
+    ) -> Option<MatchedZone> {
+        self.zones.iter().enumerate().find_map(|(index, zone)| {
+            let (config, excluded_cidrs) = match zone {
+                Zone::Inclusive(z) => (&z.config, z.excluded_cidrs.clone()),
+                Zone::Exclusive(z) => (&z.config, z.excluded_cidrs.clone()),
+            };
+            let listed = config
+                .countries
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(country));
+            (listed && self.applies(index, client)).then(|| MatchedZone {
+                config: Arc::clone(config),
+                excluded_cidrs,
+            })
+        })
     }
 }
 
-/// Check whether a domain matches any entry in the domain set or pattern set.
-fn matches_entries(
-    domain_set: &HashSet<String>,
-    pattern_set: &RegexSet,
-    qname: &str,
-    zone_name: &str,
-) -> bool {
-    // Walk suffix labels for domain match
-    let lower = qname.to_lowercase();
-    let mut remaining = lower.as_str();
-    loop {
-        if domain_set.contains(remaining) {
-            tracing::debug!(
-                zone = zone_name,
-                domain = remaining,
-                qname = qname,
-                "Domain match"
-            );
-            return true;
-        }
-        match remaining.find('.') {
-            Some(pos) => remaining = &remaining[pos + 1..],
-            None => break,
-        }
+/// Check whether a domain matches the zone's domains (`domain_hit`, from
+/// the shared trie) or any entry in its pattern set.
+fn matches_entries(domain_hit: bool, pattern_set: &RegexSet, qname: &str, zone_name: &str) -> bool {
+    if domain_hit {
+        tracing::debug!(zone = zone_name, qname = qname, "Domain match");
+        return true;
     }
 
     // Pattern match (single RegexSet call)
@@ -206,6 +324,26 @@ fn matches_entries(
     false
 }
 
+/// Parse a zone's exclusion CIDRs, skipping (and logging) invalid entries.
+fn parse_cidr_ranges(cidrs: &[String], zone_name: &str) -> Vec<CidrRange> {
+    cidrs
+        .iter()
+        .filter_map(|cidr| {
+            parse_cidr_range(cidr)
+                .map_err(|e| {
+                    tracing::warn!(
+                        cidr = cidr,
+                        zone = zone_name,
+                        error = %e,
+                        "Failed to parse exclusion CIDR, skipping"
+                    );
+                    e
+                })
+                .ok()
+        })
+        .collect()
+}
+
 /// Parse a CIDR string like "10.0.0.0/8" into a CidrRange.
 /// Only supports IPv4. Returns an error for IPv6 or invalid input.
 fn parse_cidr_range(cidr: &str) -> anyhow::Result<CidrRange> {
@@ -243,18 +381,9 @@ mod tests {
 
     fn test_zone(name: &str, domains: Vec<&str>, patterns: Vec<&str>) -> ZoneConfig {
         ZoneConfig {
-            name: name.to_string(),
-            mode: Default::default(),
-            dns_servers: vec![],
-            route_type: crate::config::RouteType::Via,
-            route_target: "192.168.1.1".to_string(),
             domains: domains.into_iter().map(String::from).collect(),
             patterns: patterns.into_iter().map(String::from).collect(),
-            static_routes: vec![],
-            dns_protocol: Default::default(),
-            cache_min_ttl: None,
-            cache_max_ttl: None,
-            cache_negative_ttl: None,
+            ..ZoneConfig::new(name, crate::config::RouteType::Via, "192.168.1.1")
         }
     }
 
@@ -271,16 +400,16 @@ mod tests {
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
         // Exact match
-        assert!(matcher.find_zone("example.com").is_some());
+        assert!(matcher.find_zone("example.com", None).is_some());
 
         // Subdomain match
-        assert!(matcher.find_zone("www.example.com").is_some());
-        assert!(matcher.find_zone("api.prod.example.com").is_some());
+        assert!(matcher.find_zone("www.example.com", None).is_some());
+        assert!(matcher.find_zone("api.prod.example.com", None).is_some());
 
         // No match
-        assert!(matcher.find_zone("example.org").is_none());
-        assert!(matcher.find_zone("notexample.com").is_none());
-        assert!(matcher.find_zone("example.com.fake").is_none());
+        assert!(matcher.find_zone("example.org", None).is_none());
+        assert!(matcher.find_zone("notexample.com", None).is_none());
+        assert!(matcher.find_zone("example.com.fake", None).is_none());
     }
 
     #[test]
@@ -289,12 +418,12 @@ mod tests {
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
         // Pattern should match substring
-        assert!(matcher.find_zone("app.dev.intra.corp").is_some());
-        assert!(matcher.find_zone("intra.company.com").is_some());
-        assert!(matcher.find_zone("my-intra-instance").is_some());
+        assert!(matcher.find_zone("app.dev.intra.corp", None).is_some());
+        assert!(matcher.find_zone("intra.company.com", None).is_some());
+        assert!(matcher.find_zone("my-intra-instance", None).is_some());
 
         // No match
-        assert!(matcher.find_zone("github.com").is_none());
+        assert!(matcher.find_zone("github.com", None).is_none());
     }
 
     #[test]
@@ -313,15 +442,15 @@ mod tests {
         let matcher = ZoneMatcher::new(zones).unwrap();
 
         // Should match first zone (more specific)
-        let zone = matcher.find_zone("api.example.com").unwrap();
+        let zone = matcher.find_zone("api.example.com", None).unwrap();
         assert_eq!(zone.config.name, "specific");
 
         // Should match second zone
-        let zone = matcher.find_zone("www.example.com").unwrap();
+        let zone = matcher.find_zone("www.example.com", None).unwrap();
         assert_eq!(zone.config.name, "general");
 
         // Exact match on second zone
-        let zone = matcher.find_zone("example.com").unwrap();
+        let zone = matcher.find_zone("example.com", None).unwrap();
         assert_eq!(zone.config.name, "general");
     }
 
@@ -330,12 +459,12 @@ mod tests {
         let zone = test_zone("ru-zone", vec![], vec![r"\.ru$"]);
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
-        assert!(matcher.find_zone("example.ru").is_some());
-        assert!(matcher.find_zone("mail.yandex.ru").is_some());
-        assert!(matcher.find_zone("yandex.ru").is_some());
+        assert!(matcher.find_zone("example.ru", None).is_some());
+        assert!(matcher.find_zone("mail.yandex.ru", None).is_some());
+        assert!(matcher.find_zone("yandex.ru", None).is_some());
 
-        assert!(matcher.find_zone("example.com").is_none());
-        assert!(matcher.find_zone("ruble.com").is_none());
+        assert!(matcher.find_zone("example.com", None).is_none());
+        assert!(matcher.find_zone("ruble.com", None).is_none());
     }
 
     #[test]
@@ -343,11 +472,11 @@ mod tests {
         let zone = test_zone("corp-zone", vec![], vec!["^corp"]);
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
-        assert!(matcher.find_zone("corp.internal.com").is_some());
-        assert!(matcher.find_zone("corporate.net").is_some());
+        assert!(matcher.find_zone("corp.internal.com", None).is_some());
+        assert!(matcher.find_zone("corporate.net", None).is_some());
 
-        assert!(matcher.find_zone("my.corp").is_none());
-        assert!(matcher.find_zone("example.com").is_none());
+        assert!(matcher.find_zone("my.corp", None).is_none());
+        assert!(matcher.find_zone("example.com", None).is_none());
     }
 
     #[test]
@@ -356,12 +485,18 @@ mod tests {
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
         // Excluded domain → no match
-        assert!(matcher.find_zone("google.com").is_none());
-        assert!(matcher.find_zone("www.google.com").is_none());
+        assert!(matcher.find_zone("google.com", None).is_none());
+        assert!(matcher.find_zone("www.google.com", None).is_none());
 
         // Everything else → matches exclusive zone
-        assert_eq!(matcher.find_zone("example.com").unwrap().config.name, "vpn");
-        assert_eq!(matcher.find_zone("github.com").unwrap().config.name, "vpn");
+        assert_eq!(
+            matcher.find_zone("example.com", None).unwrap().config.name,
+            "vpn"
+        );
+        assert_eq!(
+            matcher.find_zone("github.com", None).unwrap().config.name,
+            "vpn"
+        );
     }
 
     #[test]
@@ -371,11 +506,11 @@ mod tests {
 
         // Empty exclusion list → matches everything
         assert_eq!(
-            matcher.find_zone("anything.com").unwrap().config.name,
+            matcher.find_zone("anything.com", None).unwrap().config.name,
             "catch-all"
         );
         assert_eq!(
-            matcher.find_zone("example.ru").unwrap().config.name,
+            matcher.find_zone("example.ru", None).unwrap().config.name,
             "catch-all"
         );
     }
@@ -391,7 +526,7 @@ mod tests {
         // Inclusive zone matched first
         assert_eq!(
             matcher
-                .find_zone("internal.company.com")
+                .find_zone("internal.company.com", None)
                 .unwrap()
                 .config
                 .name,
@@ -400,13 +535,13 @@ mod tests {
 
         // Not in inclusive zone, not excluded → exclusive catches it
         assert_eq!(
-            matcher.find_zone("example.com").unwrap().config.name,
+            matcher.find_zone("example.com", None).unwrap().config.name,
             "vpn-all"
         );
 
         // Excluded from exclusive zone → no match
-        assert!(matcher.find_zone("google.com").is_none());
-        assert!(matcher.find_zone("yandex.ru").is_none());
+        assert!(matcher.find_zone("google.com", None).is_none());
+        assert!(matcher.find_zone("yandex.ru", None).is_none());
     }
 
     #[test]
@@ -417,7 +552,7 @@ mod tests {
         };
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
-        let matched = matcher.find_zone("example.com").unwrap();
+        let matched = matcher.find_zone("example.com", None).unwrap();
 
         // IPs in excluded ranges
         assert!(matched.is_excluded(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
@@ -440,13 +575,102 @@ mod tests {
         };
         let matcher = ZoneMatcher::new(vec![zone]).unwrap();
 
-        let matched = matcher.find_zone("corp.example.com").unwrap();
+        let matched = matcher.find_zone("corp.example.com", None).unwrap();
 
         // Inclusive zones never exclude IPs (even if static_routes are present)
         assert!(!matched.is_excluded(IpAddr::V4(Ipv4Addr::new(172, 16, 0, 1))));
         assert!(!matched.is_excluded(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
     }
 
+    #[test]
+    fn test_exclude_routes() {
+        let corp = ZoneConfig {
+            exclude_routes: vec!["10.0.0.0/8".to_string(), "192.168.0.0/16".to_string()],
+            ..test_zone("corp", vec!["corp.example.com"], vec![])
+        };
+        let vpn = ZoneConfig {
+            static_routes: vec!["172.16.0.0/12".to_string()],
+            exclude_routes: vec!["100.64.0.0/10".to_string()],
+            ..exclusive_zone("vpn", vec![], vec![])
+        };
+        let matcher = ZoneMatcher::new(vec![corp, vpn]).unwrap();
+
+        let matched = matcher.find_zone("git.corp.example.com", None).unwrap();
+        assert!(matched.is_excluded(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert!(matched.is_excluded(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))));
+        assert!(!matched.is_excluded(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))));
+
+        // Exclusive zones combine static_routes and exclude_routes
+        let matched = matcher.find_zone("example.com", None).unwrap();
+        assert!(matched.is_excluded(IpAddr::V4(Ipv4Addr::new(172, 16, 0, 1))));
+        assert!(matched.is_excluded(IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1))));
+        assert!(!matched.is_excluded(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+    }
+
+    #[test]
+    fn test_inclusive_zone_exclusions() {
+        let vpn = ZoneConfig {
+            exclude_domains: vec!["public.company.com".to_string()],
+            exclude_patterns: vec!["^status\\.".to_string()],
+            ..test_zone("vpn", vec!["company.com"], vec![])
+        };
+        let public = test_zone("public", vec!["public.company.com"], vec![]);
+        let matcher = ZoneMatcher::new(vec![vpn, public]).unwrap();
+
+        let zone_name = |qname| {
+            matcher
+                .find_zone(qname, None)
+                .map(|z| z.config.name.clone())
+        };
+        assert_eq!(zone_name("git.company.com").as_deref(), Some("vpn"));
+        // Carved out: falls through to the next zone, or to the default
+        assert_eq!(
+            zone_name("www.public.company.com").as_deref(),
+            Some("public")
+        );
+        assert!(zone_name("status.company.com").is_none());
+    }
+
+    #[test]
+    fn test_zone_scoped_to_clients() {
+        let corp = ZoneConfig {
+            clients: vec!["192.168.1.0/24".to_string(), "fd00::7".to_string()],
+            ..test_zone("corp", vec!["example.com"], vec![])
+        };
+        let matcher = ZoneMatcher::new(vec![corp]).unwrap();
+
+        let laptop = Some("192.168.1.20".parse().unwrap());
+        let tv = Some("192.168.2.5".parse().unwrap());
+        assert!(matcher.find_zone("example.com", laptop).is_some());
+        assert!(matcher
+            .find_zone("example.com", Some("fd00::7".parse().unwrap()))
+            .is_some());
+        assert!(matcher.find_zone("example.com", tv).is_none());
+        assert!(matcher.find_zone("example.com", None).is_none());
+    }
+
+    #[test]
+    fn test_find_all_inclusive_zones() {
+        let zones = vec![
+            test_zone("monitor", vec![], vec!["example"]),
+            exclusive_zone("catch-all", vec!["local"], vec![]),
+            test_zone("vpn", vec!["example.com"], vec![]),
+        ];
+        let matcher = ZoneMatcher::new(zones).unwrap();
+
+        let names = |qname| {
+            matcher
+                .find_zones(qname, None)
+                .into_iter()
+                .map(|z| z.config.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("www.example.com"), vec!["monitor", "vpn"]);
+        // No inclusive match: the first match, as with find_zone
+        assert_eq!(names("other.org"), vec!["catch-all"]);
+        assert!(names("printer.local").is_empty());
+    }
+
     #[test]
     fn test_invalid_regex_pattern() {
         let zone = test_zone("bad", vec![], vec!["[unclosed"]);
@@ -455,4 +679,32 @@ mod tests {
         let err = result.unwrap_err().to_string();
         assert!(err.contains("bad"), "Error should mention zone name: {err}");
     }
+
+    #[test]
+    fn test_find_zone_by_country() {
+        let mut ru = test_zone("ru", vec![], vec![]);
+        ru.countries = vec!["RU".to_string(), "by".to_string()];
+        let matcher =
+            ZoneMatcher::new(vec![test_zone("corp", vec!["corp.com"], vec![]), ru]).unwrap();
+
+        assert_eq!(
+            matcher
+                .find_zone_by_country("RU", None)
+                .unwrap()
+                .config
+                .name,
+            "ru"
+        );
+        assert_eq!(
+            matcher
+                .find_zone_by_country("BY", None)
+                .unwrap()
+                .config
+                .name,
+            "ru"
+        );
+        assert!(matcher.find_zone_by_country("DE", None).is_none());
+        // Countries don't affect domain matching
+        assert!(matcher.find_zone("yandex.ru", None).is_none());
+    }
 }
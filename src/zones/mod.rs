@@ -1,3 +1,11 @@
+pub mod geoip;
 pub mod matcher;
+mod ruleset;
+mod schedule;
+pub mod tailscale;
+mod trie;
 
+pub use geoip::GeoIp;
 pub use matcher::{MatchedZone, ZoneMatcher};
+pub use ruleset::RuleSet;
+pub use schedule::Schedule;
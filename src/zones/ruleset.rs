@@ -0,0 +1,188 @@
+use crate::config::RuleSetFormat;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Zone entries read from a Clash or v2fly rule set, ready to be appended
+/// to the zone's `domains`, `patterns` and `static_routes`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RuleSet {
+    pub domains: Vec<String>,
+    pub patterns: Vec<String>,
+    pub static_routes: Vec<String>,
+    /// Rules leshy can't express (e.g. Clash PROCESS-NAME), skipped
+    pub skipped: usize,
+}
+
+impl RuleSet {
+    /// Read the rule set at `path`, following v2fly `include:` lines
+    /// (resolved against the including file's directory).
+    pub fn load(path: &Path, format: RuleSetFormat) -> anyhow::Result<Self> {
+        let mut rule_set = Self::default();
+        let mut seen = HashSet::new();
+        match format {
+            RuleSetFormat::Clash => rule_set.read(path, &mut seen, Self::parse_clash)?,
+            RuleSetFormat::V2fly => rule_set.read(path, &mut seen, Self::parse_v2fly)?,
+        }
+        Ok(rule_set)
+    }
+
+    fn read(
+        &mut self,
+        path: &Path,
+        seen: &mut HashSet<PathBuf>,
+        parse: fn(&mut Self, &str, &Path, &mut HashSet<PathBuf>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if !seen.insert(path.to_path_buf()) {
+            return Ok(()); // Already included
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read rule set '{}': {}", path.display(), e))?;
+        parse(self, &content, path, seen)
+            .map_err(|e| anyhow::anyhow!("rule set '{}': {}", path.display(), e))
+    }
+
+    /// Clash rule providers: classical rules (`DOMAIN-SUFFIX,google.com`),
+    /// domain entries (`+.google.com`, `.google.com`, `google.com`) or
+    /// ipcidr entries, as plain lines or a YAML `payload:` list.
+    fn parse_clash(
+        &mut self,
+        content: &str,
+        _path: &Path,
+        _seen: &mut HashSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        for line in content.lines() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            let entry = entry.strip_prefix("- ").unwrap_or(entry).trim();
+            let entry = entry.trim_matches(|c| c == '\'' || c == '"');
+            if entry.is_empty() || entry == "payload:" {
+                continue;
+            }
+
+            let Some((kind, value)) = entry.split_once(',') else {
+                if crate::routing::parse_cidr(entry).is_ok() {
+                    self.static_routes.push(entry.to_string());
+                } else if let Some(suffix) = entry.strip_prefix("+.") {
+                    self.domains.push(suffix.to_lowercase());
+                } else if let Some(parent) = entry.strip_prefix('.') {
+                    self.domains.push(format!("*.{}", parent.to_lowercase()));
+                } else {
+                    self.patterns.push(exact_pattern(entry));
+                }
+                continue;
+            };
+            // Drop trailing options such as `no-resolve`
+            let value = value.split(',').next().unwrap_or_default().trim();
+            match kind.trim() {
+                "DOMAIN-SUFFIX" => self.domains.push(value.to_lowercase()),
+                "DOMAIN" => self.patterns.push(exact_pattern(value)),
+                "DOMAIN-KEYWORD" => self.patterns.push(keyword_pattern(value)),
+                "DOMAIN-REGEX" => self.patterns.push(value.to_string()),
+                "IP-CIDR" | "IP-CIDR6" => {
+                    crate::routing::parse_cidr(value)?;
+                    self.static_routes.push(value.to_string());
+                }
+                kind => {
+                    tracing::debug!(kind = kind, value = value, "Skipping unsupported rule");
+                    self.skipped += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// v2fly domain-list-community lists: `google.com` / `domain:`
+    /// (domain and subdomains), `full:`, `keyword:`, `regexp:` and
+    /// `include:` lines; `@attribute` tags are ignored.
+    fn parse_v2fly(
+        &mut self,
+        content: &str,
+        path: &Path,
+        seen: &mut HashSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some(entry) = line.split_whitespace().next() else {
+                continue;
+            };
+            let (kind, value) = entry.split_once(':').unwrap_or(("domain", entry));
+            match kind {
+                "domain" => self.domains.push(value.to_lowercase()),
+                "full" => self.patterns.push(exact_pattern(value)),
+                "keyword" => self.patterns.push(keyword_pattern(value)),
+                "regexp" => self.patterns.push(value.to_string()),
+                "include" => {
+                    let dir = path.parent().unwrap_or(Path::new("."));
+                    self.read(&dir.join(value), seen, Self::parse_v2fly)?;
+                }
+                kind => anyhow::bail!("unknown rule type '{kind}' in '{entry}'"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pattern matching exactly `domain` (zones' `domains` always include
+/// subdomains).
+fn exact_pattern(domain: &str) -> String {
+    format!("(?i)^{}$", regex::escape(domain.trim_end_matches('.')))
+}
+
+/// Pattern matching any name containing `keyword`.
+fn keyword_pattern(keyword: &str) -> String {
+    format!("(?i){}", regex::escape(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_clash_rule_providers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("telegram.yaml");
+        std::fs::write(
+            &path,
+            "payload:\n  - DOMAIN-SUFFIX,T.me\n  - DOMAIN,web.telegram.org\n  \
+             - DOMAIN-KEYWORD,telegram\n  - IP-CIDR,91.108.4.0/22,no-resolve\n  \
+             - PROCESS-NAME,Telegram\n  - '+.tdesktop.com'\n  - '.telesco.pe'\n",
+        )
+        .unwrap();
+
+        let rule_set = RuleSet::load(&path, RuleSetFormat::Clash).unwrap();
+        assert_eq!(
+            rule_set.domains,
+            vec!["t.me", "tdesktop.com", "*.telesco.pe"]
+        );
+        assert_eq!(
+            rule_set.patterns,
+            vec![r"(?i)^web\.telegram\.org$", "(?i)telegram"]
+        );
+        assert_eq!(rule_set.static_routes, vec!["91.108.4.0/22"]);
+        assert_eq!(rule_set.skipped, 1);
+    }
+
+    #[test]
+    fn parses_v2fly_lists_with_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("google"),
+            "# Google\ninclude:youtube\ngoogle.com\nfull:www.google.cn @cn\nregexp:^gstatic\\d+\\.com$\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("youtube"),
+            "domain:youtube.com\nkeyword:ytimg\ninclude:google\n",
+        )
+        .unwrap();
+
+        let rule_set = RuleSet::load(&dir.path().join("google"), RuleSetFormat::V2fly).unwrap();
+        assert_eq!(rule_set.domains, vec!["youtube.com", "google.com"]);
+        assert_eq!(
+            rule_set.patterns,
+            vec!["(?i)ytimg", r"(?i)^www\.google\.cn$", r"^gstatic\d+\.com$"]
+        );
+
+        std::fs::write(dir.path().join("bad"), "geosite:cn\n").unwrap();
+        assert!(RuleSet::load(&dir.path().join("bad"), RuleSetFormat::V2fly).is_err());
+    }
+}
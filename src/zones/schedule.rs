@@ -0,0 +1,146 @@
+use chrono::{Datelike, Local, Timelike, Weekday};
+
+const DAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Mon),
+    ("tue", Weekday::Tue),
+    ("wed", Weekday::Wed),
+    ("thu", Weekday::Thu),
+    ("fri", Weekday::Fri),
+    ("sat", Weekday::Sat),
+    ("sun", Weekday::Sun),
+];
+
+/// When a zone is active, from its `active_hours` and `active_days`, in
+/// local time. A window ending before it starts (`22:00-06:00`) runs past
+/// midnight and counts as part of the day it started on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// Minutes since midnight; `start == end` means all day
+    start: u32,
+    end: u32,
+    /// Bit per weekday, Monday = bit 0
+    days: u8,
+}
+
+impl Schedule {
+    /// Build a zone's schedule; `None` if it sets neither option.
+    pub fn parse(hours: Option<&str>, days: &[String]) -> anyhow::Result<Option<Self>> {
+        if hours.is_none() && days.is_empty() {
+            return Ok(None);
+        }
+        let (start, end) = match hours {
+            Some(hours) => parse_hours(hours)?,
+            None => (0, 0),
+        };
+        let days = if days.is_empty() {
+            0x7f
+        } else {
+            days.iter()
+                .map(|entry| parse_days(entry))
+                .collect::<anyhow::Result<Vec<u8>>>()?
+                .into_iter()
+                .fold(0, |all, mask| all | mask)
+        };
+        Ok(Some(Self { start, end, days }))
+    }
+
+    /// Whether the zone is active right now.
+    pub fn is_active(&self) -> bool {
+        let now = Local::now();
+        self.is_active_at(now.weekday(), now.hour() * 60 + now.minute())
+    }
+
+    fn is_active_at(&self, day: Weekday, minute: u32) -> bool {
+        let on = |day: Weekday| self.days & (1 << day.num_days_from_monday()) != 0;
+        if self.start == self.end {
+            on(day)
+        } else if self.start < self.end {
+            on(day) && (self.start..self.end).contains(&minute)
+        } else if minute >= self.start {
+            on(day)
+        } else {
+            minute < self.end && on(day.pred())
+        }
+    }
+}
+
+/// Parse `HH:MM-HH:MM` into minutes since midnight.
+fn parse_hours(hours: &str) -> anyhow::Result<(u32, u32)> {
+    let invalid = || anyhow::anyhow!("invalid active_hours '{hours}' (expected HH:MM-HH:MM)");
+    let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+    let minutes = |time: &str| -> Option<u32> {
+        let (h, m) = time.trim().split_once(':')?;
+        let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+        // 24:00 ends a window at midnight
+        (h < 24 && m < 60 || h == 24 && m == 0).then_some(h * 60 + m)
+    };
+    let start = minutes(start)
+        .filter(|&m| m < 24 * 60)
+        .ok_or_else(invalid)?;
+    let end = minutes(end).ok_or_else(invalid)? % (24 * 60);
+    Ok((start, end))
+}
+
+/// Parse a day (`mon`) or an inclusive range of days (`mon-fri`, `sat-sun`)
+/// into a weekday bit mask.
+fn parse_days(entry: &str) -> anyhow::Result<u8> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .find(|(short, _)| name.trim().eq_ignore_ascii_case(short))
+            .map(|(_, day)| *day)
+            .ok_or_else(|| anyhow::anyhow!("invalid active_days entry '{entry}'"))
+    };
+    let (first, last) = match entry.split_once('-') {
+        Some((first, last)) => (day(first)?, day(last)?),
+        None => (day(entry)?, day(entry)?),
+    };
+    let mut mask = 0;
+    let mut current = first;
+    loop {
+        mask |= 1 << current.num_days_from_monday();
+        if current == last {
+            return Ok(mask);
+        }
+        current = current.succ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_hours_on_weekdays() {
+        let schedule = Schedule::parse(Some("09:00-18:00"), &["mon-fri".to_string()])
+            .unwrap()
+            .unwrap();
+        assert!(schedule.is_active_at(Weekday::Mon, 9 * 60));
+        assert!(schedule.is_active_at(Weekday::Fri, 17 * 60 + 59));
+        assert!(!schedule.is_active_at(Weekday::Fri, 18 * 60));
+        assert!(!schedule.is_active_at(Weekday::Sat, 12 * 60));
+    }
+
+    #[test]
+    fn overnight_window_belongs_to_its_start_day() {
+        let schedule = Schedule::parse(Some("22:00-06:00"), &["fri".to_string()])
+            .unwrap()
+            .unwrap();
+        assert!(schedule.is_active_at(Weekday::Fri, 23 * 60));
+        assert!(schedule.is_active_at(Weekday::Sat, 5 * 60));
+        assert!(!schedule.is_active_at(Weekday::Fri, 5 * 60));
+        assert!(!schedule.is_active_at(Weekday::Sat, 23 * 60));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert!(Schedule::parse(None, &[]).unwrap().is_none());
+        assert!(Schedule::parse(Some("9-18"), &[]).is_err());
+        assert!(Schedule::parse(Some("09:00-25:00"), &[]).is_err());
+        assert!(Schedule::parse(None, &["someday".to_string()]).is_err());
+        let weekend = Schedule::parse(Some("00:00-24:00"), &["sat-sun".to_string()])
+            .unwrap()
+            .unwrap();
+        assert!(weekend.is_active_at(Weekday::Sun, 23 * 60));
+        assert!(!weekend.is_active_at(Weekday::Mon, 0));
+    }
+}
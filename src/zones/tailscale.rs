@@ -0,0 +1,216 @@
+use crate::config::{DnsServerConfig, RouteType, ZoneConfig, ZoneMode};
+use crate::routing::interfaces;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// tailscaled's LocalAPI socket, where the Linux packages and the
+/// open-source macOS daemon put it
+const LOCALAPI_SOCKETS: [&str; 2] = [
+    "/var/run/tailscale/tailscaled.sock",
+    "/var/run/tailscaled.socket",
+];
+
+/// How long tailscaled gets to report its status
+const STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The interface tailscaled creates on Linux unless told otherwise
+const LINUX_INTERFACE: &str = "tailscale0";
+
+/// MagicDNS, answering for the tailnet (and its split DNS) on every node.
+pub const MAGIC_DNS: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 100, 100, 100)), 53);
+
+/// What tailscaled reports about this node.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Tailnet {
+    /// Interface holding the node's Tailscale address
+    pub interface: Option<String>,
+    /// e.g. `tailnet-1234.ts.net`, if MagicDNS is on
+    pub magic_dns_suffix: Option<String>,
+}
+
+impl Tailnet {
+    /// Ask tailscaled, over its LocalAPI socket or else through
+    /// `tailscale status --json`.
+    pub fn discover() -> anyhow::Result<Self> {
+        let status = localapi_status().or_else(|e| {
+            tracing::debug!(error = %e, "Tailscale LocalAPI unavailable, trying the CLI");
+            cli_status()
+        })?;
+        Ok(Self::from_status(&status, interfaces::with_address))
+    }
+
+    fn from_status(status: &Value, interface_of: impl Fn(IpAddr) -> Option<String>) -> Self {
+        let running = status["BackendState"] == "Running";
+        let interface = status["Self"]["TailscaleIPs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|ip| ip.as_str()?.parse().ok())
+            .find_map(interface_of)
+            .filter(|_| running);
+        let tailnet = &status["CurrentTailnet"];
+        let magic_dns_suffix = tailnet["MagicDNSSuffix"]
+            .as_str()
+            .filter(|suffix| !suffix.is_empty() && tailnet["MagicDNSEnabled"] != false)
+            .map(str::to_string);
+        Self {
+            interface,
+            magic_dns_suffix,
+        }
+    }
+}
+
+/// Fill in what `route_type = "tailscale"` zones leave out: the Tailscale
+/// interface as `route_target`, MagicDNS as `dns_servers`, and the tailnet's
+/// MagicDNS domain as `domains` when an inclusive zone lists no domains or
+/// patterns. tailscaled is only asked when something is missing.
+pub fn resolve_zones(zones: &mut [ZoneConfig]) -> anyhow::Result<()> {
+    let mut tailnet: Option<Tailnet> = None;
+    for zone in zones
+        .iter_mut()
+        .filter(|zone| zone.route_type == RouteType::Tailscale)
+    {
+        if zone.dns_servers.is_empty() {
+            zone.dns_servers.push(DnsServerConfig {
+                address: MAGIC_DNS,
+                cache_min_ttl: None,
+                cache_max_ttl: None,
+                cache_negative_ttl: None,
+            });
+        }
+        let wants_domain =
+            zone.mode == ZoneMode::Inclusive && zone.domains.is_empty() && zone.patterns.is_empty();
+        if !zone.route_target.is_empty() && !wants_domain {
+            continue;
+        }
+        let tailnet = tailnet.get_or_insert_with(|| {
+            Tailnet::discover().unwrap_or_else(|e| {
+                tracing::warn!(error = %e, "Failed to ask tailscaled for its status");
+                Tailnet::default()
+            })
+        });
+
+        if zone.route_target.is_empty() {
+            zone.route_target = match &tailnet.interface {
+                Some(interface) => interface.clone(),
+                None if cfg!(target_os = "linux") => {
+                    tracing::warn!(
+                        zone = zone.name,
+                        interface = LINUX_INTERFACE,
+                        "Tailscale interface not found, assuming the default"
+                    );
+                    LINUX_INTERFACE.to_string()
+                }
+                None => anyhow::bail!(
+                    "Zone '{}': Tailscale interface not found (is tailscaled running?); \
+                     set route_target to it",
+                    zone.name
+                ),
+            };
+        }
+        if wants_domain {
+            if let Some(suffix) = &tailnet.magic_dns_suffix {
+                zone.domains.push(suffix.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `GET /localapi/v0/status` over the first LocalAPI socket that answers.
+fn localapi_status() -> anyhow::Result<Value> {
+    let mut last_error = None;
+    for path in LOCALAPI_SOCKETS {
+        match UnixStream::connect(path) {
+            Ok(stream) => return localapi_get(stream, "/localapi/v0/status?peers=false"),
+            Err(e) => last_error = Some(anyhow::anyhow!("{path}: {e}")),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no LocalAPI socket")))
+}
+
+/// HTTP/1.0, so the body is never chunked.
+fn localapi_get(mut stream: UnixStream, path: &str) -> anyhow::Result<Value> {
+    stream.set_read_timeout(Some(STATUS_TIMEOUT))?;
+    stream.set_write_timeout(Some(STATUS_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {path} HTTP/1.0\r\nHost: local-tailscaled.sock\r\n\r\n"
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("malformed response from the LocalAPI"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        anyhow::bail!("LocalAPI answered {status}");
+    }
+    Ok(serde_json::from_str(body)?)
+}
+
+fn cli_status() -> anyhow::Result<Value> {
+    let output = std::process::Command::new("tailscale")
+        .args(["status", "--json", "--peers=false"])
+        .output()
+        .map_err(|e| anyhow::anyhow!("failed to run `tailscale status`: {e}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "`tailscale status` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = r#"{
+        "BackendState": "Running",
+        "Self": {"TailscaleIPs": ["100.101.102.103", "fd7a:115c:a1e0::1"]},
+        "CurrentTailnet": {"MagicDNSSuffix": "tailnet-1234.ts.net", "MagicDNSEnabled": true}
+    }"#;
+
+    fn interface_of(ip: IpAddr) -> Option<String> {
+        (ip == "100.101.102.103".parse::<IpAddr>().unwrap()).then(|| "utun4".to_string())
+    }
+
+    #[test]
+    fn reads_interface_and_magic_dns_from_status() {
+        let status: Value = serde_json::from_str(STATUS).unwrap();
+        assert_eq!(
+            Tailnet::from_status(&status, interface_of),
+            Tailnet {
+                interface: Some("utun4".to_string()),
+                magic_dns_suffix: Some("tailnet-1234.ts.net".to_string()),
+            }
+        );
+
+        let mut stopped = status.clone();
+        stopped["BackendState"] = "Stopped".into();
+        stopped["CurrentTailnet"]["MagicDNSEnabled"] = false.into();
+        assert_eq!(
+            Tailnet::from_status(&stopped, interface_of),
+            Tailnet::default()
+        );
+    }
+
+    #[test]
+    fn fills_in_magic_dns_without_asking_tailscaled() {
+        let mut zones: Vec<ZoneConfig> = vec![toml::from_str(
+            "name = \"tailnet\"\nroute_type = \"tailscale\"\nroute_target = \"tailscale0\"\n\
+             domains = [\"corp.example\"]",
+        )
+        .unwrap()];
+        resolve_zones(&mut zones).unwrap();
+        assert_eq!(zones[0].dns_servers[0].address, MAGIC_DNS);
+        assert_eq!(zones[0].route_target, "tailscale0");
+        assert_eq!(zones[0].domains, ["corp.example"]);
+    }
+}
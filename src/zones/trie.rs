@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+/// Reversed-label trie over every zone's `domains`: one walk over a query's
+/// labels (`com` -> `example` -> `www`) finds all zones listing the name or
+/// one of its parent domains, however many domains and zones there are.
+/// `*.example.com` entries match the subdomains only, not the apex.
+#[derive(Debug)]
+pub(crate) struct DomainTrie {
+    /// Node 0 is the root
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<str>, usize>,
+    /// Indexes of the zones listing the domain ending at this node
+    zones: Vec<usize>,
+    /// Indexes of the zones listing `*.` + the domain ending at this node
+    subdomain_zones: Vec<usize>,
+}
+
+impl DomainTrie {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+
+    /// Register `domain` (and so all of its subdomains) for a zone, or
+    /// only its subdomains for `*.domain`.
+    pub fn insert(&mut self, domain: &str, zone: usize) {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let (domain, wildcard) = match domain.strip_prefix("*.") {
+            Some(parent) => (parent, true),
+            None => (domain.as_str(), false),
+        };
+        let mut node = 0;
+        for label in domain.rsplit('.') {
+            node = match self.nodes[node].children.get(label) {
+                Some(&child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[node].children.insert(label.into(), child);
+                    child
+                }
+            };
+        }
+        let zones = if wildcard {
+            &mut self.nodes[node].subdomain_zones
+        } else {
+            &mut self.nodes[node].zones
+        };
+        if !zones.contains(&zone) {
+            zones.push(zone);
+        }
+    }
+
+    /// Indexes of the zones listing `qname` or a parent domain of it.
+    /// `qname` must be lowercase without the trailing dot.
+    pub fn matches(&self, qname: &str) -> Vec<usize> {
+        let mut zones = Vec::new();
+        let mut node = 0;
+        let mut labels = qname.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            match self.nodes[node].children.get(label) {
+                Some(&child) => {
+                    node = child;
+                    zones.extend_from_slice(&self.nodes[node].zones);
+                    if labels.peek().is_some() {
+                        zones.extend_from_slice(&self.nodes[node].subdomain_zones);
+                    }
+                }
+                None => break,
+            }
+        }
+        zones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_domain_and_subdomains_only() {
+        let mut trie = DomainTrie::new();
+        trie.insert("Example.com.", 0);
+        trie.insert("corp.example.com", 1);
+        trie.insert("example.org", 1);
+
+        assert_eq!(trie.matches("example.com"), vec![0]);
+        assert_eq!(trie.matches("git.corp.example.com"), vec![0, 1]);
+        assert_eq!(trie.matches("example.org"), vec![1]);
+        assert!(trie.matches("notexample.com").is_empty());
+        assert!(trie.matches("com").is_empty());
+        assert!(trie.matches("example.com.fake").is_empty());
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        let mut trie = DomainTrie::new();
+        trie.insert("*.example.com", 0);
+        trie.insert("example.com", 1);
+
+        assert_eq!(trie.matches("example.com"), vec![1]);
+        assert_eq!(trie.matches("www.example.com"), vec![1, 0]);
+        assert_eq!(trie.matches("a.b.example.com"), vec![1, 0]);
+        assert!(trie.matches("example.org").is_empty());
+    }
+
+    #[test]
+    fn handles_large_domain_lists() {
+        let mut trie = DomainTrie::new();
+        for i in 0..50_000 {
+            trie.insert(&format!("host{i}.blocked.example"), i % 3);
+        }
+        assert_eq!(
+            trie.matches("a.host49999.blocked.example"),
+            vec![49_999 % 3]
+        );
+        assert!(trie.matches("host50000.blocked.example").is_empty());
+    }
+}
@@ -237,6 +237,19 @@ patterns = []
     assert_eq!(config.zones[0].name, "valid");
     assert_eq!(config.zones[1].name, "valid2");
 
+    // Unless zone file errors are set to fail the load, naming the file and line
+    std::fs::write(
+        &config_path,
+        format!("{main_config}\nzone_file_errors = \"fail\"\n"),
+    )?;
+    let err = Config::from_file_with_includes(&config_path).unwrap_err();
+    let invalid = config_d.join("20-invalid.toml");
+    assert!(
+        err.to_string()
+            .starts_with(&format!("{}:1", invalid.display())),
+        "{err}"
+    );
+
     println!("✓ Invalid zone file skipping test passed!");
 
     Ok(())
@@ -335,3 +348,218 @@ patterns = []
 
     Ok(())
 }
+
+#[test]
+fn test_ip_rules_require_route_table() -> anyhow::Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let config_path = temp_dir.path().join("main.toml");
+
+    let base = r#"
+[server]
+listen_address = "127.0.0.1:15396"
+default_upstream = ["8.8.8.8:53"]
+
+[[zones]]
+name = "vpn"
+route_type = "via"
+route_target = "10.8.0.1"
+domains = ["example.com"]
+"#;
+
+    std::fs::write(
+        &config_path,
+        format!("{base}ip_rules = [{{ fwmark = 100 }}]\n"),
+    )?;
+    let err = Config::from_file_with_includes(&config_path).unwrap_err();
+    assert!(err.to_string().contains("ip_rules require route_table"));
+
+    std::fs::write(
+        &config_path,
+        format!("{base}route_table = 100\nip_rules = [{{ priority = 10 }}]\n"),
+    )?;
+    let err = Config::from_file_with_includes(&config_path).unwrap_err();
+    assert!(err.to_string().contains("needs fwmark or from"));
+
+    std::fs::write(
+        &config_path,
+        format!("{base}route_table = 100\nip_rules = [{{ fwmark = 100 }}]\n"),
+    )?;
+    let config = Config::from_file_with_includes(&config_path)?;
+    assert_eq!(config.zones[0].route_table, Some(100));
+    assert_eq!(config.zones[0].ip_rules[0].fwmark, Some(100));
+
+    println!("✓ ip_rules validation test passed!");
+
+    Ok(())
+}
+
+#[test]
+fn test_static_routes_file_relative_to_zone_file() -> anyhow::Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let config_path = temp_dir.path().join("main.toml");
+    let config_d = temp_dir.path().join("config.d");
+    std::fs::create_dir(&config_d)?;
+
+    std::fs::write(
+        &config_path,
+        r#"
+[server]
+listen_address = "127.0.0.1:15397"
+default_upstream = ["8.8.8.8:53"]
+"#,
+    )?;
+
+    // Zone with no domains: its routes come only from the file
+    std::fs::write(
+        config_d.join("telegram.toml"),
+        r#"
+[[zones]]
+name = "telegram"
+route_type = "via"
+route_target = "10.8.0.1"
+static_routes = ["1.2.3.4"]
+static_routes_file = "telegram.cidrs"
+"#,
+    )?;
+    std::fs::write(
+        config_d.join("telegram.cidrs"),
+        "# Telegram DCs\n149.154.160.0/20\n91.108.4.0/22 # DC1\n",
+    )?;
+
+    let config = Config::from_file_with_includes(&config_path)?;
+    let zone = &config.zones[0];
+    assert_eq!(
+        zone.static_routes,
+        vec!["1.2.3.4", "149.154.160.0/20", "91.108.4.0/22"]
+    );
+    assert_eq!(
+        config.static_routes_files(),
+        vec![config_d.join("telegram.cidrs")]
+    );
+
+    // A bad entry makes the zone file fail to load (and be skipped)
+    std::fs::write(config_d.join("telegram.cidrs"), "149.154.160.0/99\n")?;
+    let config = Config::from_file_with_includes(&config_path)?;
+    assert!(config.zones.is_empty());
+
+    println!("✓ static_routes_file test passed!");
+
+    Ok(())
+}
+
+#[test]
+fn test_domains_file_relative_to_zone_file() -> anyhow::Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let config_path = temp_dir.path().join("main.toml");
+    let config_d = temp_dir.path().join("config.d");
+    std::fs::create_dir(&config_d)?;
+
+    std::fs::write(
+        &config_path,
+        r#"
+[server]
+listen_address = "127.0.0.1:15398"
+default_upstream = ["8.8.8.8:53"]
+"#,
+    )?;
+
+    // Zone with no inline domains: they come only from the file
+    std::fs::write(
+        config_d.join("corp.toml"),
+        r#"
+[[zones]]
+name = "corp"
+route_type = "via"
+route_target = "10.8.0.1"
+domains_file = ["corp-domains.txt"]
+"#,
+    )?;
+    std::fs::write(
+        config_d.join("corp-domains.txt"),
+        "# Corp services\nJira.Company.com\ngit.company.com. # mirror\n",
+    )?;
+
+    let config = Config::from_file_with_includes(&config_path)?;
+    let zone = &config.zones[0];
+    assert_eq!(zone.domains, vec!["jira.company.com", "git.company.com"]);
+    assert_eq!(config.zone_files(), vec![config_d.join("corp-domains.txt")]);
+
+    // A missing file makes the zone file fail to load (and be skipped)
+    std::fs::remove_file(config_d.join("corp-domains.txt"))?;
+    let config = Config::from_file_with_includes(&config_path)?;
+    assert!(config.zones.is_empty());
+
+    println!("✓ domains_file test passed!");
+
+    Ok(())
+}
+
+#[test]
+fn test_include_directive() -> anyhow::Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let config_path = temp_dir.path().join("main.toml");
+    let zones_dir = temp_dir.path().join("zones");
+    std::fs::create_dir(&zones_dir)?;
+
+    std::fs::write(
+        &config_path,
+        r#"
+include = ["zones/*.toml", "extra.toml"]
+
+[server]
+listen_address = "127.0.0.1:15389"
+default_upstream = ["8.8.8.8:53"]
+
+[[zones]]
+name = "main"
+route_type = "blackhole"
+domains = ["main.example"]
+"#,
+    )?;
+
+    for (file, name) in [("b.toml", "media"), ("a.toml", "corp")] {
+        std::fs::write(
+            zones_dir.join(file),
+            format!(
+                "[[zones]]\nname = \"{name}\"\nroute_type = \"via\"\n\
+                 route_target = \"10.8.0.1\"\ndomains = [\"{name}.example\"]\n"
+            ),
+        )?;
+    }
+    // Included files may include others, relative to themselves; a file
+    // reached twice is loaded once
+    std::fs::write(
+        temp_dir.path().join("extra.toml"),
+        "include = [\"zones/a.toml\", \"nested/more.toml\"]\n",
+    )?;
+    std::fs::create_dir(temp_dir.path().join("nested"))?;
+    std::fs::write(
+        temp_dir.path().join("nested/more.toml"),
+        "[[zones]]\nname = \"more\"\nroute_type = \"blackhole\"\ndomains = [\"more.example\"]\n",
+    )?;
+
+    let config = Config::from_file_with_includes(&config_path)?;
+    let names: Vec<&str> = config.zones.iter().map(|z| z.name.as_str()).collect();
+    assert_eq!(names, vec!["main", "corp", "media", "more"]);
+    assert_eq!(config.included_files.len(), 4);
+
+    // A cycle back to an including file is an error
+    std::fs::write(
+        temp_dir.path().join("nested/more.toml"),
+        "include = [\"../extra.toml\"]\n",
+    )?;
+    let err = Config::from_file_with_includes(&config_path).unwrap_err();
+    assert!(err.to_string().contains("include cycle"), "{err}");
+
+    // So is a missing file that isn't a glob
+    std::fs::write(
+        temp_dir.path().join("extra.toml"),
+        "include = [\"gone.toml\"]\n",
+    )?;
+    let err = Config::from_file_with_includes(&config_path).unwrap_err();
+    assert!(err.to_string().contains("not found"), "{err}");
+
+    println!("✓ include directive test passed!");
+
+    Ok(())
+}
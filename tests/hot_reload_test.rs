@@ -1,13 +1,13 @@
 // Hot-reload Configuration Test
 // Tests reload functionality: channel-based config updates
 
+use arc_swap::ArcSwap;
 use leshy::config::Config;
-use leshy::dns::DnsHandler;
+use leshy::dns::{DnsHandler, SharedHandler};
 use leshy::reload::{get_new_zones, get_zones_to_cleanup};
 use leshy::zones::ZoneMatcher;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use tokio::time::{sleep, Duration};
 
 #[tokio::test]
@@ -32,7 +32,7 @@ patterns = []
     )?;
 
     let matcher = ZoneMatcher::new(initial_config.zones.clone())?;
-    let handler = Arc::new(RwLock::new(DnsHandler::new(
+    let handler: SharedHandler = Arc::new(ArcSwap::from_pointee(DnsHandler::new(
         initial_config.clone(),
         matcher,
     )?));
@@ -44,24 +44,26 @@ patterns = []
     let handler_clone = handler.clone();
     tokio::spawn(async move {
         while let Some(new_config) = reload_rx.recv().await {
-            let mut handler_guard = handler_clone.write().await;
-            let old_config = handler_guard.config().clone();
+            let current = handler_clone.load_full();
+            let old_config = current.config().clone();
 
             let zones_to_cleanup = get_zones_to_cleanup(&old_config.zones, &new_config.zones);
 
             for zone_name in zones_to_cleanup {
-                let _ = handler_guard.cleanup_zone(&zone_name).await;
+                let _ = current.cleanup_zone(&zone_name).await;
             }
 
             if let Ok(new_matcher) = ZoneMatcher::new(new_config.zones.clone()) {
-                let _ = handler_guard.update_config(new_config, new_matcher).await;
+                if let Ok(next) = current.reconfigure(new_config, new_matcher).await {
+                    handler_clone.store(Arc::new(next));
+                }
             }
         }
     });
 
     // Verify initial state
     {
-        let guard = handler.read().await;
+        let guard = handler.load();
         assert_eq!(guard.config().zones.len(), 1);
         assert_eq!(guard.config().zones[0].name, "zone1");
     }
@@ -92,7 +94,7 @@ patterns = []
 
     // Verify config was reloaded
     {
-        let guard = handler.read().await;
+        let guard = handler.load();
         assert_eq!(
             guard.config().zones.len(),
             1,
@@ -137,7 +139,7 @@ patterns = []
     )?;
 
     let matcher = ZoneMatcher::new(initial_config.zones.clone())?;
-    let mut handler = DnsHandler::new(initial_config.clone(), matcher)?;
+    let handler = DnsHandler::new(initial_config.clone(), matcher)?;
 
     assert_eq!(handler.config().zones.len(), 2, "Should have 2 zones");
 
@@ -165,7 +167,7 @@ patterns = []
     handler.cleanup_zone("zone_a").await?;
 
     // Update config
-    handler.update_config(new_config, new_matcher).await?;
+    let handler = handler.reconfigure(new_config, new_matcher).await?;
 
     assert_eq!(
         handler.config().zones.len(),